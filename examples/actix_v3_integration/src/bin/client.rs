@@ -35,10 +35,10 @@ async fn main() {
     });
 
     let args = FooRequest { a: 1, b: 3 };
-    let reply: Result<FooResponse, Error> = client.call_blocking("FooService.echo", args.clone());
+    let reply: Result<FooResponse, Error> = client.call_blocking("FooService.echo", &args);
     println!("{:?}", reply);
 
-    let reply: Result<FooResponse, Error> = client.call("FooService.increment_a", args.clone()).await;
+    let reply: Result<FooResponse, Error> = client.call("FooService.increment_a", &args).await;
     println!("{:?}", reply);
 
     let call: Call<FooResponse> = client.call("FooService.increment_b", args);
@@ -49,17 +49,17 @@ async fn main() {
     let args = BarRequest {
         content: "bar".to_string(),
     };
-    let reply: BarResponse = client.call_blocking("BarService.echo", args.clone()).unwrap();
+    let reply: BarResponse = client.call_blocking("BarService.echo", &args).unwrap();
     println!("{:?}", reply);
 
     // fourth request, bar exclaim
-    let reply: BarResponse = client.call("BarService.exclaim", args.clone()).await.unwrap();
+    let reply: BarResponse = client.call("BarService.exclaim", &args).await.unwrap();
     println!("{:?}", reply);
 
     let mut call: Call<()> = client.call("BarService.finite_loop", ());
     tokio::time::sleep(std::time::Duration::from_secs(2)).await;
     println!("Calling cancellation");
-    call.cancel();
+    call.cancel_detached();
     let reply = call.await; // this should give a `Canceled` error
     println!("{:?}", reply);
 
@@ -26,7 +26,7 @@ async fn run() {
     let mut call: Call<()> = client.call("Echo.finite_loop", ());
     task::sleep(Duration::from_secs(2)).await;
     println!("Calling cancellation");
-    call.cancel();
+    call.cancel_detached();
     println!(".awaiting on canceled call");
     let reply = call.await;
     println!("{:?}", reply);
@@ -19,18 +19,22 @@ async fn run() {
     println!("{:?}", reply);
 
     println!("Calling finite loop");
-    let call: Call<()> = client.call("Echo.finite_loop", ());
+    let mut call: Call<()> = client.call("Echo.finite_loop", ());
     sleep(Duration::from_secs(2)).await;
     println!("Calling cancellation");
-    call.cancel();
+    // waits for the server to acknowledge the cancellation
+    let ack = call.cancel().await;
+    println!("Cancellation acknowledged: {:?}", ack);
     let reply = call.await;
     println!("{:?}", reply);
 
     println!("Calling infinite loop");
-    let call: Call<()> = client.echo().infinite_loop(());
+    let mut call: Call<()> = client.echo().infinite_loop(());
     sleep(Duration::from_secs(3)).await;
     println!("Calling cancellation");
-    call.cancel();
+    // waits for the server to acknowledge the cancellation
+    let ack = call.cancel().await;
+    println!("Cancellation acknowledged: {:?}", ack);
     let reply = call.await;
     println!("{:?}", reply);
 
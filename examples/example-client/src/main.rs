@@ -15,6 +15,11 @@ async fn main() {
     let reply = client.arith().subtract((1, 89)).await;
     println!("[Arith]: 1 - 89 = {:?}", reply);
 
+    // the generated client can also be kept around and used for many calls
+    let arith = ArithClient::new(&client);
+    let reply = arith.add((7, 8)).await;
+    println!("[Arith]: 7 + 8 = {:?}", reply);
+
     let reply: Result<i32, _> = client.call("Calculator.multiply", (3i32, 4i32)).await;
     println!("[Calculator]: 3 * 4 = {:?}", reply);
 
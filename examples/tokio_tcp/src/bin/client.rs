@@ -26,7 +26,7 @@ async fn run() -> anyhow::Result<()> {
     let mut call: Call<()> = client.call("Echo.finite_loop", ());
     time::sleep(Duration::from_secs(2)).await;
     println!("Calling cancellation");
-    call.cancel();
+    call.cancel_detached();
 
     println!("Calling finite loop again");
     let call: Call<()> = client.call("Echo.finite_loop", ());
@@ -0,0 +1,52 @@
+use std::{fs::File, io::BufReader, path::Path};
+use std::io;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use rustls_pemfile::{certs, rsa_private_keys};
+use anyhow::Result;
+use toy_rpc::Client;
+
+use tokio_tls::{ADDR, rpc::*};
+
+// This is for demonstration purpose only
+const CA_CERT_PATH: &str = "certs/mtls/ca.cert";
+const CLIENT_CERT_PATH: &str = "certs/mtls/client.pem";
+const CLIENT_KEY_PATH: &str = "certs/mtls/client.key";
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    certs(&mut BufReader::new(File::open(Path::new(path))?))
+        .map(|v| v.into_iter().map(|vv| Certificate(vv)).collect())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert").into())
+}
+
+fn load_keys(path: &str) -> Result<Vec<PrivateKey>> {
+    rsa_private_keys(&mut BufReader::new(File::open(Path::new(path))?))
+        .map(|v| v.into_iter().map(|vv| PrivateKey(vv)).collect())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key").into())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    // the private CA that signed the certificate of the server
+    let mut root_certs = RootCertStore::empty();
+    for cert in load_certs(CA_CERT_PATH)? {
+        root_certs.add(&cert).unwrap();
+    }
+
+    // the certificate that the server requires from the client
+    let client_certs = load_certs(CLIENT_CERT_PATH)?;
+    let mut client_keys = load_keys(CLIENT_KEY_PATH)?;
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certs)
+        .with_single_cert(client_certs, client_keys.remove(0))?;
+
+    // connects to an IP address, and validates the certificate of the server against
+    // the host name "localhost"
+    let client = Client::dial_with_tls_config(ADDR, "localhost", config).await?;
+
+    let result = client.echo().echo_i32(3).await?;
+    println!("{:?}", &result);
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use tokio::net::TcpListener;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path};
+use std::sync::Arc;
+use rustls_pemfile::{certs, rsa_private_keys};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls::server::AllowAnyAuthenticatedClient;
+use toy_rpc::Server;
+use anyhow::Result;
+
+use tokio_tls::{ADDR, rpc::Echo};
+
+// This is for demonstration purpose only
+const CA_CERT_PATH: &str = "certs/mtls/ca.cert";
+const SERVER_CERT_PATH: &str = "certs/mtls/server.pem";
+const SERVER_KEY_PATH: &str = "certs/mtls/server.key";
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    certs(&mut BufReader::new(File::open(Path::new(path))?))
+        .map(|v| v.into_iter().map(|vv| Certificate(vv)).collect())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert").into())
+}
+
+fn load_keys(path: &str) -> Result<Vec<PrivateKey>> {
+    rsa_private_keys(&mut BufReader::new(File::open(Path::new(path))?))
+        .map(|v| v.into_iter().map(|vv| PrivateKey(vv)).collect())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key").into())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    // only the clients with a certificate signed by this CA are accepted
+    let mut client_roots = RootCertStore::empty();
+    for cert in load_certs(CA_CERT_PATH)? {
+        client_roots.add(&cert).unwrap();
+    }
+
+    let certs = load_certs(SERVER_CERT_PATH)?;
+    let mut keys = load_keys(SERVER_KEY_PATH)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots))
+        .with_single_cert(certs, keys.remove(0))?;
+
+    let echo = Arc::new(Echo { });
+    let server = Server::builder()
+        .register(echo)
+        .build();
+    let listener = TcpListener::bind(ADDR).await.unwrap();
+
+    server.accept_with_tls_config(listener, config).await.unwrap();
+
+    Ok(())
+}
@@ -25,10 +25,10 @@ async fn main() {
     let client = Client::dial_http_with_tls_config(addr, "localhost", config).await.unwrap();
 
     let args = FooRequest { a: 1, b: 3 };
-    let reply: Result<FooResponse, Error> = client.call_blocking("FooService.echo", args.clone());
+    let reply: Result<FooResponse, Error> = client.call_blocking("FooService.echo", &args);
     println!("{:?}", reply);
 
-    let reply: Result<FooResponse, Error> = client.call("FooService.increment_a", args.clone()).await;
+    let reply: Result<FooResponse, Error> = client.call("FooService.increment_a", &args).await;
     println!("{:?}", reply);
 
     let call = client.call("FooService.increment_b", args);
@@ -39,11 +39,11 @@ async fn main() {
     let args = BarRequest {
         content: "bar".to_string(),
     };
-    let reply: BarResponse = client.call_blocking("BarService.echo", args.clone()).unwrap();
+    let reply: BarResponse = client.call_blocking("BarService.echo", &args).unwrap();
     println!("{:?}", reply);
 
     // fourth request, bar exclaim
-    let reply: BarResponse = client.call("BarService.exclaim", args.clone()).await.unwrap();
+    let reply: BarResponse = client.call("BarService.exclaim", &args).await.unwrap();
     println!("{:?}", reply);
 
     // third request, get_counter
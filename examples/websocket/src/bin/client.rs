@@ -22,7 +22,7 @@ async fn run() {
     let mut call: Call<()> = client.call("Echo.finite_loop", ());
     sleep(Duration::from_secs(2)).await;
     println!("Calling cancellation");
-    call.cancel();
+    call.cancel_detached();
     let reply = call.await;
     println!("{:?}", reply);
 
@@ -30,7 +30,7 @@ async fn run() {
     let mut call: Call<()> = client.echo().infinite_loop(());
     sleep(Duration::from_secs(3)).await;
     println!("Calling cancellation");
-    call.cancel();
+    call.cancel_detached();
     let reply = call.await;
     println!("{:?}", reply);
 
@@ -4,7 +4,6 @@
 
 // #[cfg(any(feature = "server", feature = "client"))]
 mod util;
-#[cfg(all(feature = "client", feature = "runtime"))]
 use darling::FromMeta;
 // #[cfg(any(feature = "server", feature = "client"))]
 use util::item_impl::*;
@@ -242,7 +241,26 @@ pub fn impl_inner_deserializer(_: proc_macro::TokenStream) -> proc_macro::TokenS
 ///
 /// ### Note
 ///
-/// - The default service name generated will be the same as the name of the struct.
+/// - The default service name generated will be the same as the name of the struct,
+///   unless it is given with `#[export_impl(rename = "fooService")]`.
+/// - A method is exported under its own name, unless it is given with
+///   `#[export_method(name = "incrementA")]`. Exporting two methods under the same
+///   name is a compile error. A name must not be empty or contain `.`.
+/// - Methods without `#[export_method]` (ie. helper methods) cannot be called over RPC,
+///   and calling them fails with `Error::MethodNotFound`.
+/// - The impl block may be generic, ie. `impl<S: Storage + Send + Sync + 'static> KvService<S>`,
+///   and each instantiation is registered with `register_with_name`. The generated client
+///   takes the same type parameters, ie. `client.kv_service::<Sled>()` or
+///   `KvServiceClient::<_, Sled>::with_service_name(&client, name)`.
+/// - The `#[cfg(...)]` attributes of an exported method are copied onto the handler,
+///   the registration and the client method generated for it, so the method may be
+///   compiled out, ie. with `#[cfg(feature = "admin")]`.
+/// - The generated client is named after the struct with a `Client` suffix (ie.
+///   `AbacusClient`), and has one method per exported method that returns a
+///   `toy_rpc::client::Call`. It is created with `client.abacus()`,
+///   `AbacusClient::new(&client)`, or `AbacusClient::with_service_name(&client, name)`
+///   for a service registered under another name. The client is not generated with
+///   `#[export_impl(client = false)]`.
 ///
 /// ### Example - Export impl block
 ///
@@ -262,10 +280,29 @@ pub fn export_impl(
     _attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
+    // only read by the generated items of the enabled features
+    #[allow(unused_variables)]
+    let args = {
+        let attr_args = syn::parse_macro_input!(_attr as syn::AttributeArgs);
+        match ImplMacroArgs::from_list(&attr_args) {
+            Ok(v) => v,
+            Err(err) => {
+                return proc_macro::TokenStream::from(err.write_errors());
+            }
+        }
+    };
+
     // parse item
     let input = syn::parse_macro_input!(item as syn::ItemImpl);
+    let methods = input.items.iter().filter_map(|item| match item {
+        syn::ImplItem::Method(f) => Some((&f.attrs[..], &f.sig.ident)),
+        _ => None,
+    });
+    if let Err(err) = util::check_exported_method_names(methods) {
+        return err.to_compile_error().into();
+    }
     #[cfg(feature = "server")]
-    let (handler_impl, names, handler_idents) = transform_impl(input.clone());
+    let (handler_impl, names, handler_idents, cfgs) = transform_impl(input.clone());
 
     // extract Self type and use it for construct Ident for handler HashMap
     #[cfg(any(feature = "server", all(feature = "client", feature = "runtime")))]
@@ -276,38 +313,57 @@ pub fn export_impl(
             Err(err) => return err.to_compile_error().into(),
         }
     };
+    #[cfg(any(feature = "server", all(feature = "client", feature = "runtime")))]
+    let service_name = match util::parse_type_ident_from_type_path(type_path)
+        .and_then(|ident| service_name(args.rename, ident))
+    {
+        Ok(name) => name,
+        Err(err) => return err.to_compile_error().into(),
+    };
     #[cfg(feature = "server")]
-    let register_service_impl = impl_register_service_for_struct(type_path, names, handler_idents);
+    let register_service_impl = impl_register_service_for_struct(
+        type_path,
+        &input.generics,
+        &service_name,
+        names,
+        handler_idents,
+        cfgs,
+    );
 
     // generate client stub
     #[cfg(all(feature = "client", feature = "runtime"))]
-    let (client_ty, client_impl) = generate_service_client_for_struct(type_path, &input);
+    let (client_ty, client_impl) =
+        generate_service_client_for_struct(type_path, &service_name, &input);
     #[cfg(all(feature = "client", feature = "runtime"))]
-    let (stub_trait, stub_impl) = generate_client_stub_for_struct(type_path);
+    let (stub_trait, stub_impl) = generate_client_stub_for_struct(type_path, &input.generics);
 
     let input = remove_export_attr_from_impl(input);
     #[cfg(feature = "server")]
     let handler_impl = remove_export_attr_from_impl(handler_impl);
     #[cfg(all(feature = "client", feature = "runtime"))]
     let client_impl = remove_export_attr_from_impl(client_impl);
+    #[cfg(all(feature = "client", feature = "runtime"))]
+    let client_items = match args.client {
+        true => quote::quote! {
+            #client_ty
+            #client_impl
+            #stub_trait
+            #stub_impl
+        },
+        false => quote::quote! {},
+    };
 
     #[cfg(all(feature = "server", feature = "client", feature = "runtime"))]
     let output = quote::quote! {
         #input
         #handler_impl
         #register_service_impl
-        #client_ty
-        #client_impl
-        #stub_trait
-        #stub_impl
+        #client_items
     };
     #[cfg(all(not(feature = "server"), feature = "client", feature = "runtime"))]
     let output = quote::quote! {
         #input
-        #client_ty
-        #client_impl
-        #stub_trait
-        #stub_impl
+        #client_items
     };
     #[cfg(all(
         feature = "server",
@@ -336,6 +392,39 @@ pub fn export_impl(
 struct MacroArgs {
     #[darling(default)]
     impl_for_client: bool,
+    /// Whether the client of the service is generated
+    #[darling(default = "generate_client")]
+    client: bool,
+    /// Default service name, which is the name of the trait if not given
+    #[darling(default)]
+    rename: Option<String>,
+}
+
+#[derive(Debug, darling::FromMeta)]
+struct ImplMacroArgs {
+    /// Whether the client of the service is generated
+    #[darling(default = "generate_client")]
+    client: bool,
+    /// Default service name, which is the name of the struct if not given
+    #[darling(default)]
+    rename: Option<String>,
+}
+
+fn generate_client() -> bool {
+    true
+}
+
+/// The `rename` argument of the macro, or else the name of `ident`
+#[cfg(any(feature = "server", all(feature = "client", feature = "runtime")))]
+fn service_name(rename: Option<String>, ident: &syn::Ident) -> syn::Result<String> {
+    match rename {
+        Some(name) => match util::check_exported_name(&name) {
+            Ok(()) => Ok(name),
+            // points at the macro attribute
+            Err(msg) => Err(syn::Error::new(proc_macro::Span::call_site().into(), msg)),
+        },
+        None => Ok(ident.to_string()),
+    }
 }
 
 /// "Exports" methods defined in the trait with the `#[export_method]` attribute.
@@ -346,10 +435,17 @@ struct MacroArgs {
 ///
 /// ## Note
 ///
-/// - The default service name generated will be the same as the name of the trait.
+/// - The default service name generated will be the same as the name of the trait,
+///   unless it is given with `#[export_trait(rename = "fooService")]`. Methods can be
+///   renamed with `#[export_method(name = "...")]` as with `#[export_impl]`.
 ///
 /// - This macro should be placed on the trait definition.
 ///
+/// - The client generated for the trait (ie. `ArithClient`) checks the argument and
+///   the response types of the calls at compile time, so it stays in sync with the
+///   trait that the server implements. Use `#[export_trait(client = false)]` to skip
+///   generating it.
+///
 /// ## Example
 ///
 /// ```rust
@@ -362,12 +458,27 @@ struct MacroArgs {
 ///     async fn add(&self, args(i32, i32)) -> Result<i32, String>;
 /// }
 /// ```
+///
+/// On the client side
+///
+/// ```rust
+/// let arith = ArithClient::new(&client); // or `client.arith()`
+/// let sum: Result<i32, Error> = arith.add((1, 2)).await;
+///
+/// // the `Call` can be canceled like any other call
+/// let mut call = arith.add((3, 4));
+/// call.cancel_detached();
+///
+/// // a service that is registered with `register_with_name("math", ..)`
+/// let math = ArithClient::with_service_name(&client, "math");
+/// ```
 #[proc_macro_attribute]
 pub fn export_trait(
     _attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    #[cfg(all(feature = "client", feature = "runtime"))]
+    // only read by the generated items of the enabled features
+    #[allow(unused_variables)]
     let args = {
         let attr_args = syn::parse_macro_input!(_attr as syn::AttributeArgs);
         match MacroArgs::from_list(&attr_args) {
@@ -379,25 +490,40 @@ pub fn export_trait(
     };
 
     let input = syn::parse_macro_input!(item as syn::ItemTrait);
+    let methods = input.items.iter().filter_map(|item| match item {
+        syn::TraitItem::Method(f) => Some((&f.attrs[..], &f.sig.ident)),
+        _ => None,
+    });
+    if let Err(err) = util::check_exported_method_names(methods) {
+        return err.to_compile_error().into();
+    }
+    #[cfg(any(feature = "server", all(feature = "client", feature = "runtime")))]
+    let service_name = match service_name(args.rename, &input.ident) {
+        Ok(name) => name,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
     #[cfg(feature = "server")]
-    let (transformed_trait, transformed_trait_impl, names, handler_idents) =
+    let (transformed_trait, transformed_trait_impl, names, handler_idents, cfgs) =
         transform_trait(input.clone());
     #[cfg(feature = "server")]
     let local_registry = impl_local_registry_for_trait(
-        &input.ident,
+        &service_name,
         &transformed_trait.ident,
         names,
         handler_idents,
+        cfgs,
     );
 
     #[cfg(all(feature = "client", feature = "runtime"))]
-    let (client_ty, client_impl) = generate_service_client_for_trait(&input.ident, &input);
+    let (client_ty, client_impl) =
+        generate_service_client_for_trait(&input.ident, &service_name, &input);
     #[cfg(all(feature = "client", feature = "runtime"))]
     let (stub_trait, stub_impl) = generate_client_stub_for_trait(&input.ident);
 
     #[cfg(all(feature = "client", feature = "runtime"))]
     let trait_impl = {
-        let trait_impl = generate_trait_impl_for_client(&input);
+        let trait_impl = generate_trait_impl_for_client(&input, &service_name);
         remove_export_attr_from_impl(trait_impl)
     };
 
@@ -407,6 +533,17 @@ pub fn export_trait(
     #[cfg(feature = "server")]
     let transformed_trait_impl = remove_export_attr_from_impl(transformed_trait_impl);
 
+    #[cfg(all(feature = "client", feature = "runtime"))]
+    let client_items = match args.client {
+        true => quote::quote! {
+            #client_ty
+            #client_impl
+            #stub_trait
+            #stub_impl
+        },
+        false => quote::quote! {},
+    };
+
     #[cfg(all(feature = "server", feature = "client", feature = "runtime"))]
     let output = if args.impl_for_client {
         quote::quote! {
@@ -414,10 +551,7 @@ pub fn export_trait(
             #transformed_trait
             #transformed_trait_impl
             #local_registry
-            #client_ty
-            #client_impl
-            #stub_trait
-            #stub_impl
+            #client_items
             #trait_impl
         }
     } else {
@@ -426,29 +560,20 @@ pub fn export_trait(
             #transformed_trait
             #transformed_trait_impl
             #local_registry
-            #client_ty
-            #client_impl
-            #stub_trait
-            #stub_impl
+            #client_items
         }
     };
     #[cfg(all(not(feature = "server"), feature = "client", feature = "runtime"))]
     let output = if args.impl_for_client {
         quote::quote! {
             #input
-            #client_ty
-            #client_impl
-            #stub_trait
-            #stub_impl
+            #client_items
             #trait_impl
         }
     } else {
         quote::quote! {
             #input
-            #client_ty
-            #client_impl
-            #stub_trait
-            #stub_impl
+            #client_items
         }
     };
     #[cfg(all(
@@ -523,7 +648,8 @@ pub fn export_trait_impl(
     // and not with the trait impl because this will allow user to omit
     // specifying which method is "exported" on the impl.
     #[cfg(feature = "server")]
-    let register_impl = impl_register_service_for_trait_impl(trait_path, type_ident);
+    let register_impl =
+        impl_register_service_for_trait_impl(trait_path, type_ident, &input.generics);
 
     let input = remove_export_attr_from_impl(input);
 
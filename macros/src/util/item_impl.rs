@@ -48,9 +48,15 @@ use super::*;
 #[cfg(feature = "server")]
 pub(crate) fn transform_impl(
     input: syn::ItemImpl,
-) -> (syn::ItemImpl, Vec<String>, Vec<syn::Ident>) {
+) -> (
+    syn::ItemImpl,
+    Vec<String>,
+    Vec<syn::Ident>,
+    Vec<Vec<syn::Attribute>>,
+) {
     let mut names = Vec::new();
     let mut idents = Vec::new();
+    let mut cfgs = Vec::new();
     let mut output = filter_exported_impl_items(input);
 
     output.trait_ = None;
@@ -63,12 +69,16 @@ pub(crate) fn transform_impl(
             _ => None,
         })
         .for_each(|f| {
-            names.push(f.sig.ident.to_string());
+            // the names are checked by `check_exported_method_names` beforehand
+            let name = exported_method_name(&f.attrs, &f.sig.ident)
+                .unwrap_or_else(|_| f.sig.ident.to_string());
+            names.push(name);
+            cfgs.push(cfg_attrs(&f.attrs));
             transform_impl_item(f);
             idents.push(f.sig.ident.clone());
         });
 
-    (output, names, idents)
+    (output, names, idents, cfgs)
 }
 
 /// transform method to meet the signature of service function
@@ -129,21 +139,24 @@ pub(crate) fn remove_export_attr_from_impl(mut input: syn::ItemImpl) -> syn::Ite
 /// Generate implementation of the `toy_rpc::util::RegisterService` trait.
 ///
 /// The static hashmap of handlers will be returned by `handlers()` method.
-/// The service struct name will be returned by `default_name()` method.
+/// The service name, which is the struct name unless renamed, will be returned by
+/// `default_name()` method.
 ///
 #[cfg(feature = "server")]
 pub(crate) fn impl_register_service_for_struct(
     type_path: &syn::TypePath,
+    generics: &syn::Generics,
+    service_name: &str,
     names: Vec<String>,
     handler_idents: Vec<syn::Ident>,
+    cfgs: Vec<Vec<syn::Attribute>>,
 ) -> impl quote::ToTokens {
-    let type_ident = parse_type_ident_from_type_path(type_path).unwrap();
-    let service_name = type_ident.to_string();
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     let ret = quote::quote! {
-        impl toy_rpc::util::RegisterService for #type_path {
+        impl #impl_generics toy_rpc::util::RegisterService for #type_path #where_clause {
             fn handlers() -> std::collections::HashMap<&'static str, toy_rpc::service::AsyncHandler<Self>> {
-                let mut map = std::collections::HashMap::<&'static str, toy_rpc::service::AsyncHandler<#type_path>>::new();
-                #(map.insert(#names, #type_path::#handler_idents);)*;
+                let mut map = std::collections::HashMap::<&'static str, toy_rpc::service::AsyncHandler<Self>>::new();
+                #(#(#cfgs)* map.insert(#names, Self::#handler_idents);)*;
                 map
             }
 
@@ -169,42 +182,50 @@ pub(crate) fn filter_exported_impl_items(input: syn::ItemImpl) -> syn::ItemImpl
 #[cfg(all(feature = "client", feature = "runtime"))]
 pub(crate) fn generate_service_client_for_struct(
     type_path: &syn::TypePath,
+    service_name: &str,
     input: &syn::ItemImpl,
 ) -> (syn::Item, syn::ItemImpl) {
     let type_ident = parse_type_ident_from_type_path(type_path).unwrap();
     let concat_name = format!("{}{}", &type_ident.to_string(), CLIENT_SUFFIX);
     let client_ident = syn::Ident::new(&concat_name, type_ident.span());
+    let generics = client_generics(&input.generics);
+    let where_clause = &generics.where_clause;
 
+    // the client takes the type parameters of a generic service
     let client_struct = syn::parse_quote!(
-        pub struct #client_ident<'c, AckMode> {
+        pub struct #client_ident #generics #where_clause {
             client: &'c toy_rpc::client::Client<AckMode>,
             service_name: &'c str,
+            marker: std::marker::PhantomData<fn() -> #type_path>,
         }
     );
 
-    let client_impl = client_stub_impl_for_struct(type_ident, &client_ident, input);
+    let client_impl = client_stub_impl_for_struct(service_name, &client_ident, &generics, input);
     (client_struct, client_impl)
 }
 
 /// Generate client stub implementation that allows, conveniently, type checking with the RPC argument
 #[cfg(all(feature = "client", feature = "runtime"))]
 fn client_stub_impl_for_struct(
-    service_ident: &syn::Ident,
+    service_name: &str,
     client_ident: &syn::Ident,
+    generics: &syn::Generics,
     input: &syn::ItemImpl,
 ) -> syn::ItemImpl {
     let input = filter_exported_impl_items(input.clone());
-    let mut generated_items: Vec<syn::ImplItem> = Vec::new();
+    let mut generated_items = generate_client_constructors(service_name, true);
     input.items.iter().for_each(|item| {
         if let syn::ImplItem::Method(f) = item {
-            if let Some(method) = generate_client_stub_for_struct_method(service_ident, f) {
+            if let Some(mut method) = generate_client_stub_for_struct_method(f) {
+                method.attrs.extend(cfg_attrs(&f.attrs));
                 generated_items.push(syn::ImplItem::Method(method));
             }
         }
     });
 
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     let mut output: syn::ItemImpl = syn::parse_quote!(
-        impl<'c, AckMode> #client_ident<'c, AckMode> {
+        impl #impl_generics #client_ident #ty_generics #where_clause {
 
         }
     );
@@ -215,20 +236,17 @@ fn client_stub_impl_for_struct(
 
 #[cfg(all(feature = "client", feature = "runtime"))]
 pub(crate) fn generate_client_stub_for_struct_method(
-    service_ident: &syn::Ident,
     f: &syn::ImplItemMethod,
 ) -> Option<syn::ImplItemMethod> {
     if let syn::FnArg::Typed(pt) = f.sig.inputs.last().unwrap() {
         let fn_ident = &f.sig.ident;
+        let method = exported_method_name(&f.attrs, fn_ident).ok()?;
         let req_ty = &pt.ty;
 
         if let syn::ReturnType::Type(_, ret_ty) = f.sig.output.clone() {
             let ok_ty = get_ok_ident_from_type(ret_ty)?;
             return Some(generate_client_stub_for_struct_method_impl(
-                service_ident,
-                fn_ident,
-                &req_ty,
-                &ok_ty,
+                fn_ident, &method, &req_ty, &ok_ty,
             ));
         }
     }
@@ -240,6 +258,7 @@ pub(crate) fn generate_client_stub_for_struct_method(
 #[cfg(all(feature = "client", feature = "runtime"))]
 pub(crate) fn generate_client_stub_for_struct(
     type_path: &syn::TypePath,
+    generics: &syn::Generics,
 ) -> (syn::Item, syn::ItemImpl) {
     let type_ident = parse_type_ident_from_type_path(type_path).unwrap();
     let concat_name = format!("{}{}", &type_ident.to_string(), CLIENT_SUFFIX);
@@ -250,20 +269,24 @@ pub(crate) fn generate_client_stub_for_struct(
     let stub_ident = syn::Ident::new(&concat_name, type_ident.span());
     let stub_fn = parse_stub_fn_name(type_ident);
 
+    // the type parameters of a generic service are given when the client is created,
+    // ie. `client.kv_service::<Sled>()`
+    let mut fn_generics: syn::Generics = syn::parse_quote!(<'c>);
+    fn_generics.params.extend(generics.params.iter().cloned());
+    let where_clause = &generics.where_clause;
+    let client_generics = client_generics(generics);
+    let (_, client_ty_generics, _) = client_generics.split_for_impl();
+
     let stub_trait = syn::parse_quote!(
         pub trait #stub_ident<AckMode> {
-            fn #stub_fn<'c>(&'c self) -> #client_ident<AckMode>;
+            fn #stub_fn #fn_generics(&'c self) -> #client_ident #client_ty_generics #where_clause;
         }
     );
 
-    let service_name = type_ident.to_string();
     let stub_impl: syn::ItemImpl = syn::parse_quote!(
         impl<AckMode> #stub_ident<AckMode> for toy_rpc::client::Client<AckMode> {
-            fn #stub_fn<'c>(&'c self) -> #client_ident<AckMode> {
-                #client_ident {
-                    client: self,
-                    service_name: #service_name,
-                }
+            fn #stub_fn #fn_generics(&'c self) -> #client_ident #client_ty_generics #where_clause {
+                #client_ident::new(self)
             }
         }
     );
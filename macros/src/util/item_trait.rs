@@ -6,10 +6,17 @@ use super::*;
 #[cfg(feature = "server")]
 pub(crate) fn transform_trait(
     input: syn::ItemTrait,
-) -> (syn::ItemTrait, syn::ItemImpl, Vec<String>, Vec<syn::Ident>) {
+) -> (
+    syn::ItemTrait,
+    syn::ItemImpl,
+    Vec<String>,
+    Vec<syn::Ident>,
+    Vec<Vec<syn::Attribute>>,
+) {
     let mut names: Vec<String> = Vec::new();
     let mut idents: Vec<syn::Ident> = Vec::new();
     let mut handler_idents = Vec::new();
+    let mut cfgs = Vec::new();
     let input = filter_exported_trait_items(input.clone());
     let trait_ident = &input.ident;
 
@@ -17,8 +24,12 @@ pub(crate) fn transform_trait(
     let transformed_trait_ident = syn::Ident::new(&&concat_name, input.ident.span());
     input.items.iter().for_each(|item| {
         if let syn::TraitItem::Method(f) = item {
-            names.push(f.sig.ident.to_string());
+            // the names are checked by `check_exported_method_names` beforehand
+            let name = exported_method_name(&f.attrs, &f.sig.ident)
+                .unwrap_or_else(|_| f.sig.ident.to_string());
+            names.push(name);
             idents.push(f.sig.ident.clone());
+            cfgs.push(cfg_attrs(&f.attrs));
         }
     });
     let mut transformed_trait: syn::ItemTrait = syn::parse_quote!(
@@ -28,7 +39,8 @@ pub(crate) fn transform_trait(
     );
     for item in input.items.iter() {
         if let syn::TraitItem::Method(f) = item {
-            let f_gen = generate_transformed_trait_item(&f.sig.ident);
+            let mut f_gen = generate_transformed_trait_item(&f.sig.ident);
+            f_gen.attrs.extend(cfg_attrs(&f.attrs));
             handler_idents.push(f_gen.sig.ident.clone());
             let item_gen = syn::TraitItem::Method(f_gen);
             transformed_trait.items.push(item_gen);
@@ -48,6 +60,7 @@ pub(crate) fn transform_trait(
         transformed_trait_impl,
         names,
         handler_idents,
+        cfgs,
     )
 }
 
@@ -91,7 +104,9 @@ fn impl_transformed_trait(
             let handler_ident = &handler_item.sig.ident;
             let orig_ident = &orig_item.sig.ident;
 
+            let cfgs = cfg_attrs(&orig_item.attrs);
             let f: syn::ImplItemMethod = syn::parse_quote!(
+                #(#cfgs)*
                 fn #handler_ident(
                     self: std::sync::Arc<Self>,
                     mut deserializer: Box<dyn toy_rpc::erased_serde::Deserializer<'static> + Send>
@@ -131,12 +146,12 @@ pub(crate) fn remove_export_attr_from_trait(mut input: syn::ItemTrait) -> syn::I
 
 #[cfg(feature = "server")]
 pub(crate) fn impl_local_registry_for_trait(
-    orig_trait_ident: &syn::Ident,
+    service_name: &str,
     transformed_trait_ident: &syn::Ident,
     names: Vec<String>,
     handler_idents: Vec<syn::Ident>,
+    cfgs: Vec<Vec<syn::Attribute>>,
 ) -> impl quote::ToTokens {
-    let service_name = orig_trait_ident.to_string();
     let concat_name = format!("{}{}", transformed_trait_ident.to_string(), REGISTRY_SUFFIX);
     let registry_ident = syn::Ident::new(&concat_name, transformed_trait_ident.span());
     let ret = quote::quote! {
@@ -151,7 +166,7 @@ pub(crate) fn impl_local_registry_for_trait(
         {
             fn handlers() -> std::collections::HashMap<&'static str, toy_rpc::service::AsyncHandler<Self>> {
                 let mut map = std::collections::HashMap::<&'static str, toy_rpc::service::AsyncHandler<Self>>::new();
-                #(map.insert(#names, Self::#handler_idents);)*;
+                #(#(#cfgs)* map.insert(#names, Self::#handler_idents);)*;
                 map
             }
 
@@ -167,6 +182,7 @@ pub(crate) fn impl_local_registry_for_trait(
 pub(crate) fn impl_register_service_for_trait_impl(
     trait_path: &syn::Path,
     type_path: &syn::TypePath,
+    generics: &syn::Generics,
 ) -> impl quote::ToTokens {
     let trait_ident = &trait_path
         .segments
@@ -178,8 +194,9 @@ pub(crate) fn impl_register_service_for_trait_impl(
     let registry_name = format!("{}{}", transformed_trait_ident, REGISTRY_SUFFIX);
     let registry_ident = syn::Ident::new(&registry_name, transformed_trait_ident.span());
 
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     let ret = quote::quote! {
-        impl toy_rpc::util::RegisterService for #type_path {
+        impl #impl_generics toy_rpc::util::RegisterService for #type_path #where_clause {
             fn handlers() -> std::collections::HashMap<&'static str, toy_rpc::service::AsyncHandler<Self>> {
                 <Self as #registry_ident>::handlers()
             }
@@ -217,6 +234,7 @@ pub(crate) fn filter_exported_trait_items(input: syn::ItemTrait) -> syn::ItemTra
 #[cfg(all(feature = "client", feature = "runtime"))]
 pub(crate) fn generate_service_client_for_trait(
     trait_ident: &syn::Ident,
+    service_name: &str,
     input: &syn::ItemTrait,
 ) -> (syn::Item, syn::ItemImpl) {
     let concat_name = format!("{}{}", &trait_ident.to_string(), CLIENT_SUFFIX);
@@ -228,21 +246,22 @@ pub(crate) fn generate_service_client_for_trait(
             service_name: &'c str,
         }
     );
-    let client_impl = client_stub_impl_for_trait(trait_ident, &client_ident, input);
+    let client_impl = client_stub_impl_for_trait(service_name, &client_ident, input);
     (client_struct, client_impl)
 }
 
 #[cfg(all(feature = "client", feature = "runtime"))]
 fn client_stub_impl_for_trait(
-    service_ident: &syn::Ident,
+    service_name: &str,
     client_ident: &syn::Ident,
     input: &syn::ItemTrait,
 ) -> syn::ItemImpl {
     let input = filter_exported_trait_items(input.clone());
-    let mut generated_items: Vec<syn::ImplItem> = Vec::new();
+    let mut generated_items = generate_client_constructors(service_name, false);
     input.items.iter().for_each(|item| {
         if let syn::TraitItem::Method(f) = item {
-            if let Some(method) = generate_client_stub_for_trait_method(service_ident, f) {
+            if let Some(mut method) = generate_client_stub_for_trait_method(f) {
+                method.attrs.extend(cfg_attrs(&f.attrs));
                 generated_items.push(syn::ImplItem::Method(method))
             }
         }
@@ -258,21 +277,16 @@ fn client_stub_impl_for_trait(
 }
 
 #[cfg(all(feature = "client", feature = "runtime"))]
-fn generate_client_stub_for_trait_method(
-    service_ident: &syn::Ident,
-    f: &syn::TraitItemMethod,
-) -> Option<syn::ImplItemMethod> {
+fn generate_client_stub_for_trait_method(f: &syn::TraitItemMethod) -> Option<syn::ImplItemMethod> {
     if let syn::FnArg::Typed(pt) = f.sig.inputs.last().unwrap() {
         let fn_ident = &f.sig.ident;
+        let method = exported_method_name(&f.attrs, fn_ident).ok()?;
         let req_ty = &pt.ty;
 
         if let syn::ReturnType::Type(_, ret_ty) = f.sig.output.clone() {
             let ok_ty = get_ok_ident_from_type(ret_ty)?;
             return Some(generate_client_stub_for_struct_method_impl(
-                service_ident,
-                fn_ident,
-                &req_ty,
-                &ok_ty,
+                fn_ident, &method, &req_ty, &ok_ty,
             ));
         }
     }
@@ -298,14 +312,10 @@ pub(crate) fn generate_client_stub_for_trait(
         }
     );
 
-    let service_name = trait_ident.to_string();
     let stub_impl: syn::ItemImpl = syn::parse_quote!(
         impl<AckMode> #stub_ident<AckMode> for toy_rpc::client::Client<AckMode> {
             fn #stub_fn<'c>(&'c self) -> #client_ident<AckMode> {
-                #client_ident {
-                    client: self,
-                    service_name: #service_name,
-                }
+                #client_ident::new(self)
             }
         }
     );
@@ -314,14 +324,14 @@ pub(crate) fn generate_client_stub_for_trait(
 }
 
 #[cfg(all(feature = "client", feature = "runtime"))]
-pub fn generate_trait_impl_for_client(input: &syn::ItemTrait) -> syn::ItemImpl {
+pub fn generate_trait_impl_for_client(input: &syn::ItemTrait, service_name: &str) -> syn::ItemImpl {
     let service_ident = &input.ident;
     let input = filter_exported_trait_items(input.clone());
     let mut generated_items: Vec<syn::ImplItem> = Vec::new();
     input.items.iter().for_each(|item| {
         if let syn::TraitItem::Method(f) = item {
             generated_items.push(syn::ImplItem::Method(
-                generate_trait_method_impl_for_client(service_ident, f),
+                generate_trait_method_impl_for_client(service_name, f),
             ))
         }
     });
@@ -338,7 +348,7 @@ pub fn generate_trait_impl_for_client(input: &syn::ItemTrait) -> syn::ItemImpl {
 /// PANIC: panics if the argument ident is not found
 #[cfg(all(feature = "client", feature = "runtime"))]
 fn generate_trait_method_impl_for_client(
-    service_ident: &syn::Ident,
+    service_name: &str,
     method: &syn::TraitItemMethod,
 ) -> syn::ImplItemMethod {
     use std::ops::Deref;
@@ -355,7 +365,9 @@ fn generate_trait_method_impl_for_client(
         }
         _ => panic!("Argument ident not found"),
     };
-    let service_method = format!("{}.{}", service_ident, method_ident);
+    let method_name = exported_method_name(&method.attrs, method_ident)
+        .unwrap_or_else(|_| method_ident.to_string());
+    let service_method = format!("{}.{}", service_name, method_name);
     let block: syn::Block = syn::parse_quote!(
         {
             Box::pin(
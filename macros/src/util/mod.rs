@@ -119,22 +119,176 @@ fn is_exported(attr: &syn::Attribute) -> bool {
     }
 }
 
+/// The `#[cfg(...)]` attributes of a method, which are copied onto the items that are
+/// generated for the method so that they are compiled out along with it
+#[cfg(any(feature = "server", all(feature = "client", feature = "runtime")))]
+pub(crate) fn cfg_attrs(attrs: &[syn::Attribute]) -> Vec<syn::Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("cfg"))
+        .cloned()
+        .collect()
+}
+
+/// Checks that a service or method name can be used in `"{service}.{method}"`
+pub(crate) fn check_exported_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains('.') {
+        return Err(format!(
+            "`{}` cannot be exported, a name must not be empty or contain `.`",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Name that a method is exported under, which is the `name` given in
+/// `#[export_method(name = "...")]` or else the ident of the method
+pub(crate) fn exported_method_name(
+    attrs: &[syn::Attribute],
+    ident: &syn::Ident,
+) -> syn::Result<String> {
+    let attr = match attrs.iter().find(|attr| is_exported(attr)) {
+        Some(attr) => attr,
+        None => return Ok(ident.to_string()),
+    };
+    let list = match attr.parse_meta()? {
+        syn::Meta::Path(_) => return Ok(ident.to_string()),
+        syn::Meta::List(list) => list,
+        meta => {
+            return Err(syn::Error::new_spanned(
+                meta,
+                "Expecting `#[export_method]` or `#[export_method(name = \"...\")]`",
+            ))
+        }
+    };
+    let mut name = None;
+    for nested in list.nested.iter() {
+        match nested {
+            syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                match &nv.lit {
+                    syn::Lit::Str(lit) => {
+                        check_exported_name(&lit.value())
+                            .map_err(|msg| syn::Error::new_spanned(lit, msg))?;
+                        name = Some(lit.value());
+                    }
+                    lit => return Err(syn::Error::new_spanned(lit, "Expecting a string literal")),
+                }
+            }
+            other => {
+                return Err(syn::Error::new_spanned(
+                    other,
+                    "Unknown argument, expecting `name = \"...\"`",
+                ))
+            }
+        }
+    }
+    Ok(name.unwrap_or_else(|| ident.to_string()))
+}
+
+/// Returns an error pointing at the `#[export_method]` attribute of the first method
+/// whose exported name is already taken by another method. Methods without the
+/// attribute are skipped.
+pub(crate) fn check_exported_method_names<'a, I>(methods: I) -> syn::Result<()>
+where
+    I: Iterator<Item = (&'a [syn::Attribute], &'a syn::Ident)>,
+{
+    let mut names = std::collections::HashSet::new();
+    for (attrs, ident) in methods {
+        let attr = match attrs.iter().find(|attr| is_exported(attr)) {
+            Some(attr) => attr,
+            None => continue,
+        };
+        let name = exported_method_name(attrs, ident)?;
+        if !names.insert(name.clone()) {
+            return Err(syn::Error::new_spanned(
+                attr,
+                format!("Method name `{}` is exported more than once", name),
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(all(feature = "client", feature = "runtime"))]
 pub(crate) fn generate_client_stub_for_struct_method_impl(
-    service_ident: &syn::Ident,
     fn_ident: &syn::Ident,
+    method: &str,
     req_ty: &syn::Type,
     ok_ty: &syn::GenericArgument,
 ) -> syn::ImplItemMethod {
-    let service = service_ident.to_string();
-    let method = fn_ident.to_string();
-    let service_method = format!("{}.{}", service, method);
     syn::parse_quote!(
-        pub fn #fn_ident<A>(&'c self, args: A) -> toy_rpc::client::Call<#ok_ty>
+        pub fn #fn_ident<A>(&self, args: A) -> toy_rpc::client::Call<#ok_ty>
         where
-            A: std::borrow::Borrow<#req_ty> + Send + Sync + toy_rpc::serde::Serialize + 'static,
+            A: std::borrow::Borrow<#req_ty> + Send + Sync + toy_rpc::serde::Serialize,
         {
-            self.client.call(#service_method, args)
+            let service_method = format!("{}.{}", self.service_name, #method);
+            self.client.call(service_method, args)
         }
     )
 }
+
+/// Generics of the client of a service, which are `<'c, AckMode>` together with the
+/// generics of the impl block of a generic service
+#[cfg(all(feature = "client", feature = "runtime"))]
+pub(crate) fn client_generics(generics: &syn::Generics) -> syn::Generics {
+    let mut output: syn::Generics = syn::parse_quote!(<'c>);
+    // lifetimes are declared before the type parameters
+    let (lifetimes, others): (Vec<_>, Vec<_>) = generics
+        .params
+        .iter()
+        .cloned()
+        .partition(|param| matches!(param, syn::GenericParam::Lifetime(_)));
+    output.params.extend(lifetimes);
+    output.params.push(syn::parse_quote!(AckMode));
+    output.params.extend(others);
+    output.where_clause = generics.where_clause.clone();
+    output
+}
+
+/// Constructors of the generated client, which is named after the service by default.
+/// The client of an impl block has a `marker` field for the type of the service.
+#[cfg(all(feature = "client", feature = "runtime"))]
+pub(crate) fn generate_client_constructors(
+    service_name: &str,
+    has_marker: bool,
+) -> Vec<syn::ImplItem> {
+    let marker = match has_marker {
+        true => quote::quote!(marker: std::marker::PhantomData,),
+        false => quote::quote!(),
+    };
+    let new: syn::ImplItemMethod = syn::parse_quote!(
+        /// Calls the service under its default name
+        pub fn new(client: &'c toy_rpc::client::Client<AckMode>) -> Self {
+            Self {
+                client,
+                service_name: #service_name,
+                #marker
+            }
+        }
+    );
+    let with_service_name: syn::ImplItemMethod = syn::parse_quote!(
+        /// Calls the service registered under `service_name`, ie. with
+        /// `ServerBuilder::register_with_name`
+        pub fn with_service_name(
+            client: &'c toy_rpc::client::Client<AckMode>,
+            service_name: &'c str,
+        ) -> Self {
+            Self {
+                client,
+                service_name,
+                #marker
+            }
+        }
+    );
+    let service_name_fn: syn::ImplItemMethod = syn::parse_quote!(
+        /// Name of the service that is called
+        pub fn service_name(&self) -> &str {
+            self.service_name
+        }
+    );
+    vec![
+        syn::ImplItem::Method(new),
+        syn::ImplItem::Method(with_service_name),
+        syn::ImplItem::Method(service_name_fn),
+    ]
+}
@@ -0,0 +1,18 @@
+use toy_rpc_macros::export_impl;
+
+pub struct Foo;
+
+#[export_impl]
+impl Foo {
+    #[export_method]
+    async fn increment_a(&self, args: i32) -> Result<i32, String> {
+        Ok(args + 1)
+    }
+
+    #[export_method(name = "increment_a")]
+    async fn increment_b(&self, args: i32) -> Result<i32, String> {
+        Ok(args + 2)
+    }
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use toy_rpc_macros::export_trait;
+
+#[export_trait]
+pub trait Foo {
+    #[export_method(name = "incrementA")]
+    fn increment_a(&self, args: i32) -> Result<i32, String>;
+
+    #[export_method(name = "incrementA")]
+    fn increment_b(&self, args: i32) -> Result<i32, String>;
+}
+
+fn main() {}
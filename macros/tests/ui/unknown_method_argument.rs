@@ -0,0 +1,13 @@
+use toy_rpc_macros::export_impl;
+
+pub struct Foo;
+
+#[export_impl]
+impl Foo {
+    #[export_method(rename = "incrementA")]
+    async fn increment(&self, args: i32) -> Result<i32, String> {
+        Ok(args + 1)
+    }
+}
+
+fn main() {}
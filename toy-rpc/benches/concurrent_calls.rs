@@ -0,0 +1,60 @@
+//! Throughput of small calls made concurrently on one connection
+//!
+//! Every call inserts its response sender into the map of pending requests, and the
+//! response, a timeout or a cancellation removes it. The map is owned by the broker of
+//! the client, so this measures how the calls scale without a shared lock. Run it with
+//! `cargo bench --bench concurrent_calls`, and use `--save-baseline` and `--baseline`
+//! to compare two revisions.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::Arc;
+use tokio::{net::TcpListener, runtime::Runtime};
+use toy_rpc::{macros::export_impl, pubsub::AckModeNone, Client, Server};
+
+struct Echo {}
+
+#[export_impl]
+impl Echo {
+    #[export_method]
+    async fn echo(&self, n: u32) -> Result<u32, String> {
+        Ok(n)
+    }
+}
+
+/// Serves `Echo` on a free port of the loopback interface and dials it
+async fn connect() -> Client<AckModeNone> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Cannot bind to address");
+    let addr = listener.local_addr().expect("Listener has no address");
+    let server = Server::builder().register(Arc::new(Echo {})).build();
+    tokio::task::spawn(async move { server.accept(listener).await });
+    Client::dial(addr).await.expect("Error dialing server")
+}
+
+fn concurrent_calls(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let client = rt.block_on(connect());
+
+    let mut group = c.benchmark_group("concurrent_calls");
+    for concurrency in &[1u32, 16, 256, 1024] {
+        group.throughput(Throughput::Elements(*concurrency as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            concurrency,
+            |b, &n| {
+                b.to_async(&rt).iter(|| async {
+                    let calls = (0..n).map(|i| client.call::<_, u32>("Echo.echo", i));
+                    for reply in futures::future::join_all(calls).await {
+                        reply.expect("Call failed");
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+    rt.block_on(client.close());
+}
+
+criterion_group!(benches, concurrent_calls);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+//! Compile time checks on the combination of feature flags.
+//!
+//! Most impl blocks are gated behind the runtime and codec feature flags. Without these
+//! checks, an unsupported combination of feature flags compiles those impl blocks out
+//! silently and only shows up as "no method named ... found" errors in the user's code.
+//!
+//! None of the checks apply to the `docs` feature, which is used to build the
+//! documentation with all features enabled.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(feature = "docs")] {
+        // documentation is built with all features
+    } else if #[cfg(all(
+        feature = "http_tide",
+        any(
+            feature = "tokio_runtime",
+            feature = "http_actix_web",
+            feature = "http_warp",
+            feature = "http_axum"
+        )
+    ))] {
+        compile_error!(
+            "toy-rpc: `http_tide` runs on `async_std_runtime` and cannot be combined with \
+            `tokio_runtime`, `http_actix_web`, `http_warp` or `http_axum`"
+        );
+    } else if #[cfg(all(feature = "tokio_runtime", feature = "async_std_runtime"))] {
+        compile_error!(
+            "toy-rpc: `tokio_runtime` and `async_std_runtime` cannot be enabled at the same time. \
+            Note that `http_tide` enables `async_std_runtime`, and `http_actix_web`, `http_warp` \
+            and `http_axum` enable `tokio_runtime`"
+        );
+    } else if #[cfg(all(
+        any(feature = "server", feature = "client"),
+        not(any(feature = "tokio_runtime", feature = "async_std_runtime"))
+    ))] {
+        compile_error!(
+            "toy-rpc: `server` and `client` require a runtime. \
+            Enable either `tokio_runtime` or `async_std_runtime`"
+        );
+    } else if #[cfg(all(
+        feature = "tls",
+        not(any(feature = "tokio_runtime", feature = "async_std_runtime"))
+    ))] {
+        compile_error!(
+            "toy-rpc: `tls` requires a runtime. Enable either `tokio_runtime` or `async_std_runtime`"
+        );
+    } else if #[cfg(all(feature = "ws_tokio", not(feature = "tokio_runtime")))] {
+        compile_error!(
+            "toy-rpc: `ws_tokio` requires `tokio_runtime`. Use `ws_async_std` with `async_std_runtime`"
+        );
+    } else if #[cfg(all(feature = "ws_async_std", not(feature = "async_std_runtime")))] {
+        compile_error!(
+            "toy-rpc: `ws_async_std` requires `async_std_runtime`. Use `ws_tokio` with `tokio_runtime`"
+        );
+    } else if #[cfg(any(
+        all(feature = "serde_bincode", feature = "serde_json"),
+        all(feature = "serde_bincode", feature = "serde_cbor"),
+        all(feature = "serde_bincode", feature = "serde_rmp"),
+        all(feature = "serde_json", feature = "serde_cbor"),
+        all(feature = "serde_json", feature = "serde_rmp"),
+        all(feature = "serde_cbor", feature = "serde_rmp"),
+    ))] {
+        compile_error!(
+            "toy-rpc: only one of `serde_bincode`, `serde_json`, `serde_cbor` and `serde_rmp` \
+            can be enabled. `serde_bincode` is enabled by default, set `default-features = false` \
+            to use another codec"
+        );
+    } else if #[cfg(all(
+        any(feature = "server", feature = "client"),
+        not(any(
+            feature = "serde_bincode",
+            feature = "serde_json",
+            feature = "serde_cbor",
+            feature = "serde_rmp"
+        ))
+    ))] {
+        compile_error!(
+            "toy-rpc: `server` and `client` require a codec. Enable exactly one of \
+            `serde_bincode` (default), `serde_json`, `serde_cbor` or `serde_rmp`"
+        );
+    }
+}
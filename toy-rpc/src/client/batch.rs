@@ -0,0 +1,80 @@
+//! Batch of RPC calls
+
+use futures::future::join_all;
+use serde::de::DeserializeOwned;
+
+use crate::{protocol::OutboundBody, Error};
+
+use super::Client;
+
+/// A batch of RPC calls that are sent out together.
+///
+/// All the requests are handed to the connection before waiting for any response,
+/// so the whole batch takes a single round trip instead of one round trip per call.
+/// Each call is still an independent request with its own message id, so the server
+/// does not need to know about batches. All the calls in a batch have the same
+/// response type `Res`.
+///
+/// # Example
+///
+/// ```rust
+/// let replies: Vec<Result<i32, Error>> = client
+///     .batch()
+///     .push("Arith.add", (1i32, 2i32))
+///     .push("Arith.multiply", (3i32, 4i32))
+///     .send()
+///     .await;
+/// ```
+pub struct Batch<'c, AckMode, Res> {
+    client: &'c Client<AckMode>,
+    requests: Vec<(String, Box<OutboundBody>)>,
+    marker: std::marker::PhantomData<Res>,
+}
+
+impl<'c, AckMode, Res> Batch<'c, AckMode, Res>
+where
+    Res: DeserializeOwned + Send + 'static,
+{
+    pub(crate) fn new(client: &'c Client<AckMode>) -> Self {
+        Self {
+            client,
+            requests: Vec::new(),
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Adds a call to the batch. Nothing is sent until `send()` is called.
+    pub fn push<Req>(mut self, service_method: impl ToString, args: Req) -> Self
+    where
+        Req: serde::Serialize + Send + Sync + 'static,
+    {
+        self.requests
+            .push((service_method.to_string(), Box::new(args)));
+        self
+    }
+
+    /// Number of calls in the batch
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns `true` if there is no call in the batch
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends all the calls and waits for all the responses. The results are in the
+    /// same order as the calls are pushed.
+    ///
+    /// The calls are subject to the `max_in_flight` limit and the timeout of the client
+    /// like any other call.
+    pub async fn send(self) -> Vec<Result<Res, Error>> {
+        let client = self.client;
+        let calls: Vec<_> = self
+            .requests
+            .into_iter()
+            .map(|(service_method, args)| client.call(service_method, args))
+            .collect();
+        join_all(calls).await
+    }
+}
@@ -0,0 +1,231 @@
+//! Synchronous client for code that doesn't run an async runtime
+//!
+//! A `BlockingClient` wraps an async `Client` and waits for each of its calls on the
+//! calling thread. With `tokio_runtime`, it owns a small current-thread runtime that
+//! drives the connection, so the background tasks of the client only make progress
+//! while one of its methods is running. With `async_std_runtime`, the calls are waited
+//! for with `async_std::task::block_on`, and the connection is driven by the global
+//! executor of `async-std`.
+//!
+//! The methods must not be called from within an async runtime, ie. inside a
+//! `tokio::spawn`ed task, where blocking the thread stalls the other tasks.
+//!
+//! # Example
+//!
+//! ```rust
+//! let client = BlockingClient::connect("127.0.0.1:23333")?;
+//! let reply: i32 = client.call("Arith.add", (1i32, 6i32))?;
+//! let reply: Result<i32, Error> =
+//!     client.call_with_timeout("Arith.add", (1i32, 6i32), Duration::from_secs(1));
+//! client.close();
+//! ```
+
+use cfg_if::cfg_if;
+use std::{future::Future, time::Duration};
+
+use crate::{error::Error, pubsub::AckModeNone, util::CancellationToken};
+
+use super::{Client, CloseSummary};
+
+/// Blocking RPC client
+///
+/// See the [module level documentation](crate::client::blocking) for more details.
+pub struct BlockingClient {
+    /// Only `None` after it is taken by `close`
+    client: Option<Client<AckModeNone>>,
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    runtime: tokio::runtime::Runtime,
+}
+
+impl BlockingClient {
+    /// Invokes the named RPC method and blocks until the response is received
+    ///
+    /// The call times out after the default timeout of the client.
+    pub fn call<Req, Res>(&self, service_method: impl ToString, args: Req) -> Result<Res, Error>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Res: serde::de::DeserializeOwned + Send + 'static,
+    {
+        let call = self.client().call(service_method, args);
+        self.block_on(call)
+    }
+
+    /// Same as `call`, but fails with `Error::Timeout` if the response is not received
+    /// within `duration`
+    pub fn call_with_timeout<Req, Res>(
+        &self,
+        service_method: impl ToString,
+        args: Req,
+        duration: Duration,
+    ) -> Result<Res, Error>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Res: serde::de::DeserializeOwned + Send + 'static,
+    {
+        // the timeout is set on a clone, so it doesn't leak into a call made from
+        // another thread at the same time
+        let client = self.client().clone();
+        let call = client.set_next_timeout(duration).call(service_method, args);
+        self.block_on(call)
+    }
+
+    /// Same as `call`, but the call is canceled with `Error::Canceled` when `token` is
+    /// cancelled, ie. from another thread
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let token = CancellationToken::new();
+    /// let child = token.clone();
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(Duration::from_secs(1));
+    ///     child.cancel();
+    /// });
+    /// let reply: Result<(), Error> =
+    ///     client.call_with_cancellation("Service.wait_for_10secs", (), token);
+    /// ```
+    pub fn call_with_cancellation<Req, Res>(
+        &self,
+        service_method: impl ToString,
+        args: Req,
+        token: CancellationToken,
+    ) -> Result<Res, Error>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Res: serde::de::DeserializeOwned + Send + 'static,
+    {
+        let call = self
+            .client()
+            .call(service_method, args)
+            .bind_cancellation(token);
+        self.block_on(call)
+    }
+
+    /// Sets the default timeout of the calls
+    pub fn set_default_timeout(&mut self, duration: Duration) -> &Self {
+        if let Some(client) = self.client.as_mut() {
+            client.set_default_timeout(duration);
+        }
+        self
+    }
+
+    /// Returns whether the connection is still open
+    pub fn is_connected(&self) -> bool {
+        self.client().is_connected()
+    }
+
+    /// Returns the underlying async client, ie. to check its stats
+    pub fn client(&self) -> &Client<AckModeNone> {
+        self.client
+            .as_ref()
+            .expect("The client is only taken when it is closed")
+    }
+
+    /// Closes the connection, and blocks until the pending calls are finished or the
+    /// close timeout of the client is reached. See `Client::close`
+    pub fn close(mut self) -> CloseSummary {
+        match self.client.take() {
+            Some(client) => self.block_on(client.close()),
+            None => CloseSummary::default(),
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))] {
+        use tokio::net::ToSocketAddrs;
+
+        impl BlockingClient {
+            fn from_future<F, Fut>(f: F) -> Result<Self, Error>
+            where
+                F: FnOnce() -> Fut,
+                Fut: Future<Output = Result<Client<AckModeNone>, Error>>,
+            {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                let client = runtime.block_on(f())?;
+                Ok(Self {
+                    client: Some(client),
+                    runtime,
+                })
+            }
+
+            fn block_on<Fut: Future>(&self, fut: Fut) -> Fut::Output {
+                self.runtime.block_on(fut)
+            }
+        }
+    } else if #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))] {
+        use async_std::net::ToSocketAddrs;
+
+        impl BlockingClient {
+            fn from_future<F, Fut>(f: F) -> Result<Self, Error>
+            where
+                F: FnOnce() -> Fut,
+                Fut: Future<Output = Result<Client<AckModeNone>, Error>>,
+            {
+                let client = async_std::task::block_on(f())?;
+                Ok(Self {
+                    client: Some(client),
+                })
+            }
+
+            fn block_on<Fut: Future>(&self, fut: Fut) -> Fut::Output {
+                async_std::task::block_on(fut)
+            }
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(
+        feature = "docs",
+        all(
+            feature = "serde_bincode",
+            not(any(feature = "serde_json", feature = "serde_cbor", feature = "serde_rmp"))
+        ),
+        all(
+            feature = "serde_cbor",
+            not(any(feature = "serde_json", feature = "serde_bincode", feature = "serde_rmp")),
+        ),
+        all(
+            feature = "serde_json",
+            not(any(feature = "serde_bincode", feature = "serde_cbor", feature = "serde_rmp")),
+        ),
+        all(
+            feature = "serde_rmp",
+            not(any(feature = "serde_cbor", feature = "serde_json", feature = "serde_bincode")),
+        )
+    ))] {
+        use super::builder::ClientBuilder;
+
+        impl BlockingClient {
+            /// Connects to an RPC server over socket at the specified network address
+            ///
+            /// # Example
+            ///
+            /// ```rust
+            /// let client = BlockingClient::connect("127.0.0.1:23333")?;
+            /// ```
+            pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+                Self::connect_with(Client::builder(), addr)
+            }
+
+            /// Connects with the settings of `builder`, ie. to set a heartbeat or a
+            /// connect timeout
+            ///
+            /// # Example
+            ///
+            /// ```rust
+            /// let builder = Client::builder().set_connect_timeout(Duration::from_secs(1));
+            /// let client = BlockingClient::connect_with(builder, "127.0.0.1:23333")?;
+            /// ```
+            pub fn connect_with(
+                builder: ClientBuilder<AckModeNone>,
+                addr: impl ToSocketAddrs,
+            ) -> Result<Self, Error> {
+                Self::from_future(|| builder.dial(addr))
+            }
+        }
+    }
+}
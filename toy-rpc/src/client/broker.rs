@@ -1,7 +1,10 @@
 use cfg_if::cfg_if;
 use flume::Sender;
 use futures::channel::oneshot;
-use std::{marker::PhantomData, time::Duration};
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
 
 cfg_if! {
     if #[cfg(any(
@@ -9,22 +12,31 @@ cfg_if! {
         all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
         all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
     ))] {
-        use std::{sync::{Arc, atomic::Ordering}, collections::{HashMap, BTreeMap}};
+        use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, collections::{HashMap, BTreeMap, VecDeque}};
         use brw::{Context, Running};
         use futures::{Sink, SinkExt};
 
+        use crossbeam::atomic::AtomicCell;
+
         use crate::message::AtomicMessageId;
+        use crate::trace::{debug_event, debug_span, error_event, Instrument};
+        use crate::util::CancellationToken;
 
         use super::{writer::ClientWriterItem};
     }
 }
 
+#[cfg(feature = "metrics")]
+use super::metrics::{CallOutcome, CallTimer, ClientMetrics};
+
 use crate::{
-    codec::Marshal,
+    codec::{codec_name, Marshal},
     error::IoError,
     message::MessageId,
-    protocol::{InboundBody, OutboundBody},
+    protocol::{ConnectionInfo, ConnectionLimits, InboundBody, OutboundBody, RequestMetadata},
     pubsub::{AckModeAuto, AckModeManual, AckModeNone, SeqId},
+    stats::CallCounters,
+    util::SemaphorePermit,
     Error,
 };
 
@@ -35,18 +47,45 @@ use super::{pubsub::SubscriptionItem, ResponseResult};
     allow(dead_code)
 )]
 pub(crate) enum ClientBrokerItem {
-    Request {
-        id: MessageId,
-        service_method: String,
-        duration: Duration,
-        body: Box<OutboundBody>,
-        resp_tx: oneshot::Sender<Result<ResponseResult, Error>>,
-    },
+    Request(OutgoingRequest),
     Response {
         id: MessageId,
         result: ResponseResult,
+        /// Number of bytes of the response read from the connection
+        num_bytes: usize,
+    },
+    /// An item of a streaming response
+    StreamItem {
+        id: MessageId,
+        item: Box<InboundBody>,
+    },
+    /// An item to send on a bidirectional stream
+    OutgoingItem {
+        id: MessageId,
+        body: Box<OutboundBody>,
     },
-    Cancel(MessageId),
+    /// The client has no more items to send on the stream
+    OutgoingEnd(MessageId),
+    /// Cancels the request. The sender, if any, is notified when the server acknowledges
+    /// the cancellation
+    Cancel(MessageId, Option<oneshot::Sender<Result<(), Error>>>),
+    /// Cancels all pending requests. The sender is notified with the number of
+    /// requests that are canceled
+    CancelAll(oneshot::Sender<usize>),
+    /// `started` is sent the number of pending requests right away, and `done` is
+    /// notified once there are no pending requests
+    Drain {
+        started: oneshot::Sender<usize>,
+        done: oneshot::Sender<()>,
+    },
+    /// The request has timed out
+    Timeout(MessageId),
+    /// Sends the limits declared by the client to the server
+    StartHandshake,
+    /// Reply to the handshake with the limits declared by the server
+    Handshake(ConnectionLimits),
+    /// The server announced another codec. The field is the codec of the server
+    CodecMismatch(String),
     /// New publication to the server
     Publish {
         topic: String,
@@ -83,6 +122,11 @@ pub(crate) enum ClientBrokerItem {
     /// (Manual) Ack reply for incoming Publish message
     OutboundAck(SeqId),
 
+    /// Lets the writer schedule the flushes through the broker
+    AttachWriter,
+    /// The writer has messages in its write buffer to flush
+    Flush,
+
     /// Begin the stop process
     // #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
     Stopping,
@@ -90,7 +134,30 @@ pub(crate) enum ClientBrokerItem {
     /// Stop
     ///
     ///
-    Stop(Option<std::io::Error>),
+    Stop(Option<Error>),
+}
+
+/// A call made on the client, which is handed to the broker to be sent
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+pub(crate) struct OutgoingRequest {
+    pub id: MessageId,
+    pub service_method: String,
+    pub duration: Duration,
+    pub metadata: RequestMetadata,
+    /// Whether the request opens a bidirectional stream
+    pub opens_stream: bool,
+    /// Arguments of the call, already marshaled by the codec of the connection
+    pub body: Vec<u8>,
+    pub resp_tx: oneshot::Sender<Result<ResponseResult, Error>>,
+    pub stats: Arc<CallCounters>,
+    /// Receives the items of a streaming response
+    pub items: Option<Sender<Box<InboundBody>>>,
+    /// In-flight slot of the client, which is held until the request is finished.
+    /// Heartbeats are sent without one
+    pub permit: Option<SemaphorePermit>,
 }
 
 enum ClientBrokerState {
@@ -99,6 +166,43 @@ enum ClientBrokerState {
     Stopped,
 }
 
+/// A request that is waiting for an in-flight slot
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+struct QueuedRequest {
+    id: MessageId,
+    service_method: String,
+    duration: Duration,
+    metadata: RequestMetadata,
+    opens_stream: bool,
+    body: Vec<u8>,
+    stats: Arc<CallCounters>,
+    /// Items of the stream that are sent before the request itself is written
+    outgoing: Vec<ClientWriterItem>,
+    /// When the call is made, which the timeout sent to the server counts from
+    created_at: Instant,
+}
+
+/// A request that is waiting for the response
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+pub(crate) struct PendingRequest {
+    resp_tx: oneshot::Sender<Result<ResponseResult, Error>>,
+    stats: Arc<CallCounters>,
+    /// Receives the items of a streaming response. It is dropped together with the
+    /// pending request, which ends the stream of items.
+    items: Option<Sender<Box<InboundBody>>>,
+    /// Records the outcome of the call in the metrics of the client
+    #[cfg(feature = "metrics")]
+    timer: CallTimer,
+    /// Lets the next call of the client through once the request is finished
+    _permit: Option<SemaphorePermit>,
+}
+
 #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
 use ::async_std::task::{self};
 #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
@@ -112,11 +216,53 @@ use ::tokio::task::{self};
 pub(crate) struct ClientBroker<AckMode, C> {
     state: ClientBrokerState,
     pub count: Arc<AtomicMessageId>,
-    pub pending: HashMap<MessageId, oneshot::Sender<Result<ResponseResult, Error>>>,
+    /// Response senders of the pending requests, keyed by the message id.
+    ///
+    /// The map is owned by the broker task and every request, response, cancellation and
+    /// timeout is processed as a `ClientBrokerItem` on that task, so no lock is needed.
+    /// The broker lives as long as its connection, so a response can only complete a
+    /// request that was sent on the same connection, even after a new client reuses
+    /// the message ids.
+    pub pending: HashMap<MessageId, PendingRequest>,
+    /// Requests that are held back until the handshake is done, or because the server
+    /// allows fewer requests in flight than the client. These are also tracked in
+    /// `pending`. Every call holds an in-flight slot of the client before it reaches the
+    /// broker, so there are never more than `max_in_flight` of them
+    backlog: VecDeque<QueuedRequest>,
+    /// Whether the responses are delivered to the callers in the order of the message ids
+    ordered: bool,
+    /// Responses that are received but held back until every request with a lower
+    /// message id is finished. This is only used with `ordered`
+    held: BTreeMap<
+        MessageId,
+        (
+            oneshot::Sender<Result<ResponseResult, Error>>,
+            ResponseResult,
+        ),
+    >,
+    pub max_in_flight: usize,
+    pub num_pending: Arc<AtomicUsize>,
+    /// Limits declared by the client. Requests are held back until the server replies
+    /// to the handshake if this is `Some`
+    pub declared_limits: Option<ConnectionLimits>,
+    /// Whether the codec is announced to the server before the handshake
+    negotiate_codec: bool,
+    /// Whether the client is waiting for the reply to the handshake
+    awaiting_handshake: bool,
+    /// Information agreed on during the handshake, shared with the `Client`
+    pub connection_info: Arc<AtomicCell<Option<ConnectionInfo>>>,
     pub subscriptions: HashMap<String, Sender<SubscriptionItem>>,
     pub pending_acks: BTreeMap<MessageId, oneshot::Sender<()>>,
     pub pub_retry_timeout: Duration,
     pub max_num_retries: u32,
+    /// Cancelled when the broker is dropped, which is when the connection is lost or
+    /// closed. Shared with the `Client`
+    pub disconnected: CancellationToken,
+    /// Notified once there are no pending requests
+    drain_waiters: Vec<oneshot::Sender<()>>,
+    /// Per-method metrics of the calls, shared with the `Client`
+    #[cfg(feature = "metrics")]
+    call_metrics: Arc<ClientMetrics>,
 
     pub ack_mode: PhantomData<AckMode>,
     pub codec: PhantomData<C>,
@@ -132,33 +278,168 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
         count: Arc<AtomicMessageId>,
         pub_retry_timeout: Duration,
         max_num_retries: u32,
+        max_in_flight: usize,
+        num_pending: Arc<AtomicUsize>,
+        connection_info: Arc<AtomicCell<Option<ConnectionInfo>>>,
+        disconnected: CancellationToken,
     ) -> Self {
         Self {
             state: ClientBrokerState::Started,
             count,
             pending: HashMap::new(),
+            backlog: VecDeque::new(),
+            ordered: false,
+            held: BTreeMap::new(),
+            max_in_flight,
+            num_pending,
+            declared_limits: None,
+            negotiate_codec: false,
+            awaiting_handshake: false,
+            connection_info,
             subscriptions: HashMap::new(),
             pending_acks: BTreeMap::new(),
             pub_retry_timeout,
             max_num_retries,
+            disconnected,
+            drain_waiters: Vec::new(),
+            #[cfg(feature = "metrics")]
+            call_metrics: Arc::new(ClientMetrics::default()),
 
             ack_mode: PhantomData,
             codec: PhantomData,
         }
     }
 
+    /// Announces the codec to the server, which also requires the handshake
+    pub fn with_codec_negotiation(self, enabled: bool) -> Self {
+        Self {
+            negotiate_codec: enabled,
+            awaiting_handshake: self.awaiting_handshake || enabled,
+            ..self
+        }
+    }
+
+    /// Records the calls in `call_metrics`
+    #[cfg(feature = "metrics")]
+    pub fn with_call_metrics(mut self, call_metrics: Arc<ClientMetrics>) -> Self {
+        self.call_metrics = call_metrics;
+        self
+    }
+
+    /// Delivers the responses to the callers in the order of the message ids
+    pub fn with_ordered_responses(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Declares `limits` to the server in the handshake, and holds the requests back
+    /// until the server replies
+    pub fn with_declared_limits(mut self, limits: Option<ConnectionLimits>) -> Self {
+        self.awaiting_handshake |= limits.is_some();
+        self.declared_limits = limits;
+        self
+    }
+    fn num_in_flight(&self) -> usize {
+        self.pending.len() - self.backlog.len()
+    }
+
+    /// Whether another request can be sent out now
+    fn has_in_flight_slot(&self) -> bool {
+        !self.awaiting_handshake && self.num_in_flight() < self.max_in_flight
+    }
+
+    async fn write_request<'w, W>(writer: &'w mut W, request: QueuedRequest) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        let QueuedRequest {
+            id,
+            service_method,
+            duration,
+            metadata,
+            opens_stream,
+            body,
+            stats,
+            outgoing,
+            created_at,
+        } = request;
+        // the server is told how much time is left, so a request that waited in the
+        // backlog is not given its full timeout again
+        let duration = duration.saturating_sub(created_at.elapsed());
+        let item = ClientWriterItem::Request(
+            id,
+            service_method,
+            duration,
+            metadata,
+            opens_stream,
+            body,
+            stats,
+            Instant::now(),
+        );
+        let items = std::iter::once(item).chain(outgoing);
+        for item in items {
+            writer.send(item).await.map_err(|_| {
+                Error::IoError(IoError::new(
+                    std::io::ErrorKind::Other,
+                    "Writer is disconnected",
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
     async fn handle_request<'w, W>(
         &'w mut self,
         writer: &'w mut W,
-        id: MessageId,
-        service_method: String,
-        duration: Duration,
-        body: Box<OutboundBody>,
-        resp_tx: oneshot::Sender<Result<ResponseResult, Error>>,
+        ctx: &'w Arc<Context<ClientBrokerItem>>,
+        request: OutgoingRequest,
     ) -> Result<(), Error>
     where
         W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
     {
+        let OutgoingRequest {
+            id,
+            service_method,
+            duration,
+            mut metadata,
+            opens_stream,
+            body,
+            resp_tx,
+            stats,
+            items,
+            permit,
+        } = request;
+        let resp_tx = match self.reject_id_in_use(id, resp_tx) {
+            Some(resp_tx) => resp_tx,
+            None => return Ok(()),
+        };
+        let span = debug_span!("call", message_id = id, service_method = %service_method);
+        #[cfg(feature = "metrics")]
+        let timer = self.call_metrics.start(&service_method);
+        let request = QueuedRequest {
+            id,
+            service_method,
+            duration,
+            metadata,
+            opens_stream,
+            body,
+            stats: stats.clone(),
+            outgoing: Vec::new(),
+            created_at: Instant::now(),
+        };
+        if self.has_in_flight_slot() {
+            Self::write_request(writer, request)
+                .instrument(span.clone())
+                .await?;
+        } else {
+            log::debug!(
+                "Max number of in-flight requests reached, request {} is queued",
+                id
+            );
+            debug_event!(parent: &span, "Request is queued");
+            self.backlog.push_back(request);
+        }
+
         // fetch_add returns the previous value
         let (tx, rx) = oneshot::channel();
         let fut = async move {
@@ -168,14 +449,9 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
                 Err(_) => Err(Error::Canceled(id)),
             }
         };
-        let item = ClientWriterItem::Request(id, service_method, duration, body);
-        if let Err(_) = writer.send(item).await {
-            return Err(Error::IoError(IoError::new(
-                std::io::ErrorKind::Other,
-                "Writer is disconnected",
-            )));
-        }
 
+        debug_event!(parent: &span, "Request is pending");
+        let broker = ctx.broker.clone();
         task::spawn(async move {
             #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
             let timout_result = ::tokio::time::timeout(duration, fut).await;
@@ -185,9 +461,14 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
             let cancellation_result = match timout_result {
                 Ok(res) => res,
                 Err(_) => {
+                    error_event!("Request timed out");
                     if let Err(_) = resp_tx.send(Err(Error::Timeout(id))) {
                         log::trace!("InternalError: Unable to send Error::Timeout({}) over response channel, response receiver is dropped", id);
                     }
+                    // release the in-flight slot
+                    if let Err(_) = broker.send_async(ClientBrokerItem::Timeout(id)).await {
+                        log::trace!("Broker is dropped before Timeout({}) is handled", id);
+                    }
                     return;
                 }
             };
@@ -197,52 +478,373 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
                     resp_tx.send(response_result)
                         .unwrap_or_else(|_| log::trace!("InternalError: Unable to send RPC response over response channel, response receiver is dropped"));
                 }
-                Err(_) => {
-                    // RPC request is already canceled, simply return
-                    return;
+                Err(err) => {
+                    if let Error::Canceled(_) = err {
+                        // RPC request is already canceled, simply return
+                        debug_event!("Request is canceled");
+                        return;
+                    }
+                    error_event!(error = %err, "Request failed");
+                    resp_tx.send(Err(err))
+                        .unwrap_or_else(|_| log::trace!("InternalError: Unable to send error over response channel, response receiver is dropped"));
                 }
             };
-        });
+        }.instrument(span));
 
-        self.pending.insert(id, tx);
-        // request_result.map_err(|err| err.into())
+        self.pending.insert(
+            id,
+            PendingRequest {
+                resp_tx: tx,
+                stats,
+                items,
+                #[cfg(feature = "metrics")]
+                timer,
+                _permit: permit,
+            },
+        );
         Ok(())
     }
 
-    fn handle_response(&mut self, id: MessageId, result: ResponseResult) -> Result<(), Error> {
-        if let Some(tx) = self.pending.remove(&id) {
-            tx.send(Ok(result)).map_err(|_| {
-                Error::Internal("InternalError: client failed to send response over channel".into())
+    /// Fails a new request whose id is still used by a pending request, which would
+    /// otherwise be overwritten and never complete. This can only happen if the ids wrap
+    /// around while an earlier request is pending. The response sender is given back if
+    /// the id is free.
+    fn reject_id_in_use(
+        &self,
+        id: MessageId,
+        resp_tx: oneshot::Sender<Result<ResponseResult, Error>>,
+    ) -> Option<oneshot::Sender<Result<ResponseResult, Error>>> {
+        if !self.pending.contains_key(&id) {
+            return Some(resp_tx);
+        }
+        log::error!("Message id {} is still used by a pending request", id);
+        let err = Error::Internal(format!("Message id {} is still in use", id).into());
+        if resp_tx.send(Err(err)).is_err() {
+            log::trace!("Response receiver of request {} is dropped", id);
+        }
+        None
+    }
+
+    /// Removes the request from the backlog if it is still queued, and returns `true` if
+    /// the request was found in the backlog
+    fn remove_from_backlog(&mut self, id: MessageId) -> bool {
+        let len = self.backlog.len();
+        self.backlog.retain(|req| req.id != id);
+        self.backlog.len() != len
+    }
+
+    /// Delivers the held responses whose requests no longer wait on a request with a
+    /// lower message id, ie. because it is responded, canceled or timed out
+    fn release_held(&mut self) {
+        let lowest_pending = self.pending.keys().min().copied();
+        while let Some(id) = self.held.keys().next().copied() {
+            if matches!(lowest_pending, Some(lowest) if lowest < id) {
+                break;
+            }
+            if let Some((resp_tx, result)) = self.held.remove(&id) {
+                if resp_tx.send(Ok(result)).is_err() {
+                    log::trace!("Response receiver of request {} is dropped", id);
+                }
+            }
+        }
+    }
+
+    /// Removes the pending requests that nothing is waiting on anymore, ie. when the
+    /// task awaiting the response is gone without a `Timeout` or `Cancel` reaching the
+    /// broker. This is only a safety net, so it runs when a request times out rather than
+    /// on every message. Returns the ids of the removed requests that were already sent.
+    fn sweep_abandoned(&mut self) -> Vec<MessageId> {
+        let abandoned: Vec<MessageId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.resp_tx.is_canceled())
+            .map(|(id, _)| *id)
+            .collect();
+        abandoned
+            .into_iter()
+            .filter(|id| {
+                log::debug!("Removing abandoned request {}", id);
+                let _pending = self.pending.remove(id);
+                #[cfg(feature = "metrics")]
+                if let Some(pending) = _pending {
+                    pending.timer.finish(CallOutcome::Canceled);
+                }
+                !self.remove_from_backlog(*id)
             })
+            .collect()
+    }
+
+    /// Sends out queued requests as long as there are in-flight slots available
+    async fn drain_backlog<'w, W>(&'w mut self, writer: &'w mut W) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        while self.has_in_flight_slot() {
+            let request = match self.backlog.pop_front() {
+                Some(req) => req,
+                None => break,
+            };
+            let id = request.id;
+            if let Err(err) = Self::write_request(writer, request).await {
+                if let Some(pending) = self.pending.remove(&id) {
+                    let _ = pending.resp_tx.send(Err(IoError::new(
+                        std::io::ErrorKind::Other,
+                        "Writer is disconnected",
+                    )
+                    .into()));
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_response<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        id: MessageId,
+        result: ResponseResult,
+        num_bytes: usize,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        let res = if let Some(pending) = self.pending.remove(&id) {
+            pending.stats.set_response_bytes(num_bytes);
+            #[cfg(feature = "metrics")]
+            pending.timer.finish(match result {
+                Ok(_) => CallOutcome::Succeeded,
+                Err(_) => CallOutcome::Failed,
+            });
+            if self.ordered && self.pending.keys().any(|pending_id| *pending_id < id) {
+                debug_event!(message_id = id, "Response is held back");
+                self.held.insert(id, (pending.resp_tx, result));
+                Ok(())
+            } else {
+                pending.resp_tx.send(Ok(result)).map_err(|_| {
+                    Error::Internal(
+                        "InternalError: client failed to send response over channel".into(),
+                    )
+                })
+            }
         } else {
             Err(Error::Internal(
                 format!("InternalError: Response channel not found for id: {}", id).into(),
             ))
+        };
+        self.drain_backlog(writer).await?;
+        res
+    }
+
+    /// Forwards an item of a streaming response to the caller
+    fn handle_stream_item(&mut self, id: MessageId, item: Box<InboundBody>) -> Result<(), Error> {
+        match self
+            .pending
+            .get(&id)
+            .and_then(|pending| pending.items.as_ref())
+        {
+            Some(items) => {
+                // the caller may have stopped reading the stream
+                let _ = items.send(item);
+                Ok(())
+            }
+            None => Err(Error::Internal(
+                format!("InternalError: Stream channel not found for id: {}", id).into(),
+            )),
         }
     }
 
+    /// Writes an item or the end of a bidirectional stream after the request that opens
+    /// the stream. Items of a stream that is already finished are discarded.
+    async fn handle_outgoing<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        id: MessageId,
+        item: ClientWriterItem,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        if let Some(request) = self.backlog.iter_mut().find(|req| req.id == id) {
+            request.outgoing.push(item);
+            return Ok(());
+        }
+        if !self.pending.contains_key(&id) {
+            log::debug!("Discarding item of finished stream {}", id);
+            return Ok(());
+        }
+        writer.send(item).await.map_err(|_| {
+            Error::IoError(IoError::new(
+                std::io::ErrorKind::Other,
+                "Writer is disconnected",
+            ))
+        })
+    }
+
+    async fn handle_timeout<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        id: MessageId,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        // The response channel is already consumed by the timeout task, so the request
+        // is removed before it is swept as abandoned
+        let timed_out = self.pending.remove(&id);
+        // the response is already received but was held back
+        self.held.remove(&id);
+        let mut in_flight = self.sweep_abandoned();
+        if let Some(_pending) = timed_out {
+            #[cfg(feature = "metrics")]
+            _pending.timer.finish(CallOutcome::TimedOut);
+            if !self.remove_from_backlog(id) {
+                in_flight.push(id);
+            }
+        }
+        if !in_flight.is_empty() {
+            // the handlers on the server are no longer awaited
+            for id in in_flight {
+                writer
+                    .send(ClientWriterItem::Cancel(id))
+                    .await
+                    .map_err(|_| {
+                        Error::IoError(IoError::new(
+                            std::io::ErrorKind::Other,
+                            "Writer is disconnected",
+                        ))
+                    })?;
+            }
+            self.drain_backlog(writer).await?;
+        }
+        Ok(())
+    }
+
     async fn handle_cancel<'w, W>(
         &'w mut self,
         writer: &'w mut W,
         id: MessageId,
+        ack: Option<oneshot::Sender<Result<(), Error>>>,
     ) -> Result<(), Error>
     where
         W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
     {
-        if let Some(tx) = self.pending.remove(&id) {
-            tx.send(Err(Error::Canceled(id))).map_err(|_| {
-                Error::Internal(
-                    format!(
-                        "Unable to send Error::Canceled(Some({})) over response channel",
-                        id
+        if let Some((resp_tx, _)) = self.held.remove(&id) {
+            // the handler on the server is already finished
+            debug_event!(message_id = id, "Canceling held response");
+            let _ = resp_tx.send(Err(Error::Canceled(id)));
+            if let Some(ack) = ack {
+                let _ = ack.send(Ok(()));
+            }
+            return Ok(());
+        }
+        if let Some(pending) = self.pending.remove(&id) {
+            debug_event!(message_id = id, "Canceling request");
+            #[cfg(feature = "metrics")]
+            pending.timer.finish(CallOutcome::Canceled);
+            let is_queued = self.remove_from_backlog(id);
+            pending
+                .resp_tx
+                .send(Err(Error::Canceled(id)))
+                .map_err(|_| {
+                    Error::Internal(
+                        format!(
+                            "Unable to send Error::Canceled({}) over response channel",
+                            id
+                        )
+                        .into(),
                     )
-                    .into(),
-                )
-            })?;
+                })?;
+            if is_queued {
+                // the request never reached the server
+                if let Some(ack) = ack {
+                    let _ = ack.send(Ok(()));
+                }
+                return Ok(());
+            }
+        } else if ack.is_none() {
+            // the response is already received, ie. a completed `Call` is dropped
+            return Ok(());
+        }
+        if let Some(ack) = ack {
+            self.spawn_timed_task_waiting_for_cancel_ack(id, ack);
         }
+        // the cancellation goes out before the queued requests that take the released
+        // slot, so the server never sees more requests than the limit
         writer
             .send(ClientWriterItem::Cancel(id))
             .await
+            .map_err(|_| {
+                Error::IoError(IoError::new(
+                    std::io::ErrorKind::Other,
+                    "Writer is disconnected",
+                ))
+            })?;
+        self.drain_backlog(writer).await
+    }
+
+    /// Cancels every pending request, including the ones waiting for an in-flight slot,
+    /// and notifies `done` with the number of canceled requests
+    async fn handle_cancel_all<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        done: oneshot::Sender<usize>,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        let num_canceled = self.pending.len() + self.held.len();
+        // queued requests never reached the server and don't need a cancellation message
+        let queued: Vec<MessageId> = self.backlog.drain(..).map(|req| req.id).collect();
+        for (id, (resp_tx, _)) in std::mem::take(&mut self.held) {
+            let _ = resp_tx.send(Err(Error::Canceled(id)));
+        }
+        let mut res = Ok(());
+        for (id, pending) in self.pending.drain() {
+            debug_event!(message_id = id, "Canceling request");
+            #[cfg(feature = "metrics")]
+            pending.timer.finish(CallOutcome::Canceled);
+            let _ = pending.resp_tx.send(Err(Error::Canceled(id)));
+            if res.is_ok() && !queued.contains(&id) {
+                res = writer
+                    .send(ClientWriterItem::Cancel(id))
+                    .await
+                    .map_err(|_| {
+                        Error::IoError(IoError::new(
+                            std::io::ErrorKind::Other,
+                            "Writer is disconnected",
+                        ))
+                    });
+            }
+        }
+        let _ = done.send(num_canceled);
+        res
+    }
+
+    /// Sends the codec, if it is negotiated, and the limits declared by the client to
+    /// the server
+    async fn handle_start_handshake<'w, W>(&'w mut self, writer: &'w mut W) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        if !self.awaiting_handshake {
+            return Ok(());
+        }
+        if self.negotiate_codec {
+            writer
+                .send(ClientWriterItem::AnnounceCodec)
+                .await
+                .map_err(|_| {
+                    Error::IoError(IoError::new(
+                        std::io::ErrorKind::Other,
+                        "Writer is disconnected",
+                    ))
+                })?;
+        }
+        let limits = self.declared_limits.unwrap_or_default();
+        writer
+            .send(ClientWriterItem::Handshake(limits))
+            .await
             .map_err(|_| {
                 Error::IoError(IoError::new(
                     std::io::ErrorKind::Other,
@@ -251,6 +853,60 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
             })
     }
 
+    /// Fails the requests that were held back during the handshake. The connection is
+    /// closed by the server
+    fn handle_codec_mismatch(&mut self, found: String) -> Result<(), Error> {
+        let expected = codec_name();
+        error_event!(expected, found = %found, "Codec mismatch");
+        self.backlog.clear();
+        for (_, request) in self.pending.drain() {
+            let err = Error::CodecMismatch {
+                expected: expected.into(),
+                found: found.clone(),
+            };
+            let _ = request.resp_tx.send(Err(err));
+        }
+        Err(Error::CodecMismatch {
+            expected: expected.into(),
+            found,
+        })
+    }
+
+    /// Fails the pending requests once the connection is lost to missed heartbeats,
+    /// instead of leaving them to be canceled
+    fn handle_missed_heartbeats(&mut self, missed: u32) {
+        // the connection is seen as lost by the time the pending calls fail
+        self.disconnected.cancel();
+        self.backlog.clear();
+        for (_, (resp_tx, _)) in std::mem::take(&mut self.held) {
+            let _ = resp_tx.send(Err(Error::MissedHeartbeats(missed)));
+        }
+        for (_, request) in self.pending.drain() {
+            let _ = request.resp_tx.send(Err(Error::MissedHeartbeats(missed)));
+        }
+    }
+
+    /// Settles the limits of the connection and sends out the requests that were held
+    /// back during the handshake
+    async fn handle_handshake<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        server_limits: ConnectionLimits,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ClientWriterItem, Error = flume::SendError<ClientWriterItem>> + Send + Unpin,
+    {
+        let limits = self.declared_limits.unwrap_or_default().min(&server_limits);
+        debug_event!(limits = ?limits, "Handshake");
+        if let Some(max) = limits.max_concurrent_calls {
+            self.max_in_flight = self.max_in_flight.min(max as usize).max(1);
+            self.pending.reserve(self.max_in_flight);
+        }
+        self.connection_info.store(Some(ConnectionInfo { limits }));
+        self.awaiting_handshake = false;
+        self.drain_backlog(writer).await
+    }
+
     async fn handle_publish_inner<'w, W>(
         writer: &'w mut W,
         id: MessageId,
@@ -271,6 +927,35 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
             })
     }
 
+    /// Waits for the server to acknowledge the cancellation of request `id`
+    fn spawn_timed_task_waiting_for_cancel_ack(
+        &mut self,
+        id: MessageId,
+        ack: oneshot::Sender<Result<(), Error>>,
+    ) {
+        let duration = Duration::from_secs(super::DEFAULT_TIMEOUT_SECONDS);
+        let (tx, rx) = oneshot::channel::<()>();
+        task::spawn(async move {
+            #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+            let timeout_result = ::tokio::time::timeout(duration, rx).await;
+            #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+            let timeout_result = ::async_std::future::timeout(duration, rx).await;
+
+            let result = match timeout_result {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(_)) => Err(Error::IoError(IoError::new(
+                    std::io::ErrorKind::Other,
+                    "Connection is closed before the cancellation is acknowledged",
+                ))),
+                Err(_) => Err(Error::Timeout(id)),
+            };
+            let _ = ack.send(result);
+        });
+        // drops the senders of the acks that are timed out
+        self.pending_acks.retain(|_, tx| !tx.is_canceled());
+        self.pending_acks.insert(id, tx);
+    }
+
     fn spawn_timed_task_waiting_for_ack(
         &mut self,
         broker: Sender<ClientBrokerItem>,
@@ -471,6 +1156,19 @@ impl<AckMode, C> ClientBroker<AckMode, C> {
     }
 }
 
+#[cfg(any(
+    feature = "docs",
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+impl<AckMode, C> Drop for ClientBroker<AckMode, C> {
+    fn drop(&mut self) {
+        // this runs before the response senders in `pending` are dropped, so the
+        // connection is seen as lost by the time the pending calls fail
+        self.disconnected.cancel();
+    }
+}
+
 #[cfg(any(
     all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
     all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
@@ -631,20 +1329,43 @@ macro_rules! impl_broker_for_ack_modes {
                     W: Sink<Self::WriterItem, Error = flume::SendError<Self::WriterItem>> + Send + Unpin,
                 {
                     let res = match item {
-                        ClientBrokerItem::Request {
-                            id,
-                            service_method,
-                            duration,
-                            body,
-                            resp_tx,
-                        } => {
-                            self.handle_request(&mut writer, id, service_method, duration, body, resp_tx).await
+                        ClientBrokerItem::Request(request) => {
+                            self.handle_request(&mut writer, ctx, request).await
+                        }
+                        ClientBrokerItem::OutgoingItem { id, body } => {
+                            self.handle_outgoing(&mut writer, id, ClientWriterItem::StreamItem(id, body)).await
+                        }
+                        ClientBrokerItem::OutgoingEnd(id) => {
+                            self.handle_outgoing(&mut writer, id, ClientWriterItem::StreamEnd(id)).await
+                        }
+                        ClientBrokerItem::StreamItem { id, item } => {
+                            self.handle_stream_item(id, item)
                         }
-                        ClientBrokerItem::Response { id, result } => {
-                            self.handle_response(id, result)
+                        ClientBrokerItem::Response { id, result, num_bytes } => {
+                            self.handle_response(&mut writer, id, result, num_bytes).await
+                        },
+                        ClientBrokerItem::Cancel(id, ack) => {
+                            self.handle_cancel(&mut writer, id, ack).await
+                        },
+                        ClientBrokerItem::CancelAll(done) => {
+                            self.handle_cancel_all(&mut writer, done).await
+                        },
+                        ClientBrokerItem::Drain { started, done } => {
+                            let _ = started.send(self.pending.len() + self.held.len());
+                            self.drain_waiters.push(done);
+                            Ok(())
+                        },
+                        ClientBrokerItem::Timeout(id) => {
+                            self.handle_timeout(&mut writer, id).await
                         },
-                        ClientBrokerItem::Cancel(id) => {
-                            self.handle_cancel(&mut writer, id).await
+                        ClientBrokerItem::StartHandshake => {
+                            self.handle_start_handshake(&mut writer).await
+                        },
+                        ClientBrokerItem::Handshake(limits) => {
+                            self.handle_handshake(&mut writer, limits).await
+                        },
+                        ClientBrokerItem::CodecMismatch(found) => {
+                            self.handle_codec_mismatch(found)
                         },
                         ClientBrokerItem::Publish { topic, body } => {
                             self.handle_publish(&mut writer, ctx, topic, body).await
@@ -673,16 +1394,25 @@ macro_rules! impl_broker_for_ack_modes {
                         ClientBrokerItem::OutboundAck(seq_id) => {
                             self.handle_outbound_ack(&mut writer, seq_id).await
                         },
+                        ClientBrokerItem::AttachWriter => {
+                            writer.send(ClientWriterItem::Attach(ctx.broker.clone())).await
+                                .map_err(Into::into)
+                        },
+                        ClientBrokerItem::Flush => {
+                            writer.send(ClientWriterItem::Flush).await
+                                .map_err(Into::into)
+                        },
                         ClientBrokerItem::Stopping => {
                             // Stopping ONLY comes from control
                             self.handle_stopping(&mut writer).await
                         },
-                        ClientBrokerItem::Stop(io_err) => {
-                            // Stop ONLY comes from reader
+                        ClientBrokerItem::Stop(err) => {
+                            // Stop ONLY comes from reader and heartbeat
                             match self.state {
                                 ClientBrokerState::Started => {
-                                    if let Err(_) = self.handle_stopping(&mut writer).await {
-                                        todo!()
+                                    if let Err(err) = self.handle_stopping(&mut writer).await {
+                                        // the writer is gone already
+                                        log::debug!("{}", err);
                                     }
                                 },
                                 ClientBrokerState::Stopping => { },
@@ -698,9 +1428,23 @@ macro_rules! impl_broker_for_ack_modes {
                                 log::debug!("{}", err);
                             }
                             self.state = ClientBrokerState::Stopped;
-                            return Running::Stop(io_err.map(Into::into))
+                            if let Some(Error::MissedHeartbeats(missed)) = err {
+                                self.handle_missed_heartbeats(missed);
+                            }
+                            return Running::Stop(err)
                         }
                     };
+                    if !self.held.is_empty() {
+                        // a request that finished in any way no longer holds up the
+                        // responses after it
+                        self.release_held();
+                    }
+                    self.num_pending.store(self.pending.len() + self.held.len(), Ordering::Relaxed);
+                    if self.pending.is_empty() {
+                        for done in self.drain_waiters.drain(..) {
+                            let _ = done.send(());
+                        }
+                    }
 
                     Running::Continue(res)
                 }
@@ -710,3 +1454,128 @@ macro_rules! impl_broker_for_ack_modes {
 }
 
 impl_broker_for_ack_modes!(AckModeNone, AckModeAuto, AckModeManual);
+
+#[cfg(all(
+    test,
+    any(
+        all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+        all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+    )
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_id_does_not_overwrite_pending_request() {
+        let count = Arc::new(AtomicMessageId::new(MessageId::MAX));
+        let mut broker = ClientBroker::<AckModeNone, ()>::new(
+            count.clone(),
+            Duration::from_secs(1),
+            0,
+            16,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicCell::new(None)),
+            CancellationToken::new(),
+        );
+
+        // a slow request that is still pending when the ids wrap around
+        count.fetch_add(1, Ordering::Relaxed);
+        let slow = count.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(slow, 0);
+        let (slow_tx, mut slow_rx) = oneshot::channel();
+        broker.pending.insert(
+            slow,
+            PendingRequest {
+                resp_tx: slow_tx,
+                stats: Arc::new(CallCounters::default()),
+                items: None,
+                #[cfg(feature = "metrics")]
+                timer: broker.call_metrics.start("CommonTest.echo"),
+                _permit: None,
+            },
+        );
+
+        let (tx, mut rx) = oneshot::channel();
+        assert!(broker.reject_id_in_use(slow, tx).is_none());
+        match rx.try_recv() {
+            Ok(Some(Err(Error::Internal(_)))) => {}
+            _ => panic!("The new request is not rejected"),
+        }
+        // the pending request is left untouched
+        assert!(broker.pending.contains_key(&slow));
+        assert!(matches!(slow_rx.try_recv(), Ok(None)));
+
+        let (tx, _rx) = oneshot::channel();
+        assert!(broker.reject_id_in_use(slow + 1, tx).is_some());
+    }
+
+    #[test]
+    fn abandoned_requests_are_swept() {
+        let mut broker = ClientBroker::<AckModeNone, ()>::new(
+            Arc::new(AtomicMessageId::new(0)),
+            Duration::from_secs(1),
+            0,
+            16,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicCell::new(None)),
+            CancellationToken::new(),
+        );
+        let mut receivers = Vec::new();
+        for id in 0..3 {
+            let (resp_tx, rx) = oneshot::channel();
+            receivers.push(rx);
+            broker.pending.insert(
+                id,
+                PendingRequest {
+                    resp_tx,
+                    stats: Arc::new(CallCounters::default()),
+                    items: None,
+                    #[cfg(feature = "metrics")]
+                    timer: broker.call_metrics.start("CommonTest.echo"),
+                    _permit: None,
+                },
+            );
+        }
+
+        // nothing waits on request 1 anymore
+        drop(receivers.remove(1));
+        assert_eq!(broker.sweep_abandoned(), vec![1]);
+        assert_eq!(broker.pending.len(), 2);
+        assert!(!broker.pending.contains_key(&1));
+        assert!(broker.sweep_abandoned().is_empty());
+    }
+
+    #[test]
+    fn missed_heartbeats_fail_pending_requests() {
+        let disconnected = CancellationToken::new();
+        let mut broker = ClientBroker::<AckModeNone, ()>::new(
+            Arc::new(AtomicMessageId::new(0)),
+            Duration::from_secs(1),
+            0,
+            16,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicCell::new(None)),
+            disconnected.clone(),
+        );
+        let (resp_tx, mut rx) = oneshot::channel();
+        broker.pending.insert(
+            0,
+            PendingRequest {
+                resp_tx,
+                stats: Arc::new(CallCounters::default()),
+                items: None,
+                #[cfg(feature = "metrics")]
+                timer: broker.call_metrics.start("CommonTest.echo"),
+                _permit: None,
+            },
+        );
+
+        broker.handle_missed_heartbeats(3);
+        assert!(disconnected.is_cancelled());
+        assert!(broker.pending.is_empty());
+        match rx.try_recv() {
+            Ok(Some(Err(Error::MissedHeartbeats(3)))) => {}
+            _ => panic!("The pending request is not failed"),
+        }
+    }
+}
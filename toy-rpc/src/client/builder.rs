@@ -4,10 +4,19 @@ use std::marker::PhantomData;
 
 use cfg_if::cfg_if;
 
+use crate::protocol::ConnectionLimits;
 use crate::pubsub::{
     AckModeAuto, AckModeManual, AckModeNone, DEFAULT_PUB_RETRIES, DEFAULT_PUB_RETRY_TIMEOUT,
 };
 
+use super::{
+    heartbeat::Heartbeat,
+    interceptor::{ClientInterceptor, Interceptor, RequestHeader},
+    proxy::Proxy,
+    retry::RetryPolicy,
+    DEFAULT_CLOSE_TIMEOUT, DEFAULT_MAX_IN_FLIGHT,
+};
+
 #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
 use crate::transport::ws::WebSocketConn;
 
@@ -18,7 +27,7 @@ cfg_if! {
     ))] {
         #[cfg(feature = "tls")]
         use tokio_rustls::TlsConnector;
-        #[cfg(all(feature = "tls", feature = "ws_tokio"))]
+        #[cfg(feature = "ws_tokio")]
         use async_tungstenite::tokio::client_async;
         use tokio::net::TcpStream;
 
@@ -33,7 +42,7 @@ cfg_if! {
     ))] {
         #[cfg(feature = "tls")]
         use futures_rustls::TlsConnector;
-        #[cfg(all(feature = "tls", feature = "ws_async_std"))]
+        #[cfg(feature = "ws_async_std")]
         use async_tungstenite::client_async;
         use async_std::net::TcpStream;
 
@@ -45,6 +54,141 @@ cfg_if! {
     }
 }
 
+/// Fails with `Error::ConnectTimeout` if `fut` is not done within `timeout`. The
+/// future, ie. a half-open connection, is dropped when it times out.
+#[cfg(any(
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+pub(crate) async fn with_connect_timeout<T>(
+    timeout: Option<Duration>,
+    step: &str,
+    fut: impl std::future::Future<Output = Result<T, crate::Error>>,
+) -> Result<T, crate::Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return fut.await,
+    };
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    let result = ::tokio::time::timeout(timeout, fut).await;
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    let result = ::async_std::future::timeout(timeout, fut).await;
+    result.unwrap_or_else(|_| {
+        Err(crate::Error::ConnectTimeout {
+            step: step.into(),
+            timeout,
+        })
+    })
+}
+
+/// Resolves `addr` and tries the resolved addresses in order until one of them
+/// accepts the connection. Returns the connection along with all the resolved
+/// addresses and the index of the one that is connected.
+#[cfg(any(
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+pub(crate) async fn connect_any(
+    addr: impl ToSocketAddrs,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+) -> Result<(TcpStream, Vec<std::net::SocketAddr>, usize), crate::Error> {
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    let addrs: Vec<_> = tokio::net::lookup_host(addr).await?.collect();
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    let addrs: Vec<_> = addr.to_socket_addrs().await?.collect();
+    let (stream, index) = connect_in_turn(&addrs, 0, proxy, timeout).await?;
+    Ok((stream, addrs, index))
+}
+
+/// Tries the addresses in turn, starting from the one at `start` and wrapping around,
+/// and returns the first connection along with the index of its address. With a proxy,
+/// the connections are tunneled through the proxy. The timeout applies to every address.
+#[cfg(any(
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+pub(crate) async fn connect_in_turn(
+    addrs: &[std::net::SocketAddr],
+    start: usize,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+) -> Result<(TcpStream, usize), crate::Error> {
+    let mut last_err = None;
+    for i in 0..addrs.len() {
+        let index = (start + i) % addrs.len();
+        let addr = addrs[index];
+        let connect = async {
+            match proxy {
+                Some(proxy) => proxy.connect(&addr.ip().to_string(), addr.port()).await,
+                None => TcpStream::connect(addr).await.map_err(Into::into),
+            }
+        };
+        let result = with_connect_timeout(timeout, "Connection", connect).await;
+        match result {
+            Ok(stream) => return Ok((stream, index)),
+            Err(err) => {
+                log::debug!("Cannot connect to {}: {}", addr, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    let err = last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Address does not resolve to any socket address",
+        )
+        .into()
+    });
+    Err(err)
+}
+
+/// Connects to `host:port`, which is resolved by the proxy if there is one
+#[cfg(all(feature = "tls", any(feature = "ws_tokio", feature = "ws_async_std")))]
+async fn connect_host(
+    host: &str,
+    port: u16,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+) -> Result<TcpStream, crate::Error> {
+    let connect = async {
+        match proxy {
+            Some(proxy) => proxy.connect(host, port).await,
+            None => TcpStream::connect((host, port)).await.map_err(Into::into),
+        }
+    };
+    with_connect_timeout(timeout, "Connection", connect).await
+}
+
+/// Host and port that a WebSocket request is made to
+#[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+fn websocket_host_port(uri: &tungstenite::http::Uri) -> Result<(&str, u16), crate::Error> {
+    let host = uri
+        .host()
+        .ok_or_else(|| crate::Error::Internal("Invalid host address".into()))?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("ws")) => 80,
+        (None, Some("wss")) => 443,
+        _ => return Err(crate::Error::Internal("Invalid port".into())),
+    };
+    Ok((host, port))
+}
+
+/// Adds the headers of the WebSocket handshake that are missing from `request`.
+/// The headers that are already in the request are kept as they are.
+#[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+fn with_handshake_headers(
+    request: tungstenite::http::Request<()>,
+) -> Result<tungstenite::http::Request<()>, crate::Error> {
+    use tungstenite::client::IntoClientRequest;
+
+    let (parts, _) = request.into_parts();
+    let mut request = parts.uri.into_client_request()?;
+    request.headers_mut().extend(parts.headers);
+    Ok(request)
+}
+
 /// Client builder
 pub struct ClientBuilder<AckMode> {
     /// Marker for AckMode
@@ -57,6 +201,39 @@ pub struct ClientBuilder<AckMode> {
     /// The number of retries that a publisher will attempt if Ack is not received.
     /// This only affects when Ack is enabled (ie. AckModeAuto, AckModeManual)
     pub max_num_retries: u32,
+    /// The maximum number of requests that can be waiting for responses from the server.
+    /// A call beyond this limit waits until a previous request is responded, canceled,
+    /// or timed out before it is sent out.
+    pub max_in_flight: usize,
+    /// Whether the arguments of requests are serialized with the entries of all maps
+    /// sorted. See `toy_rpc::codec::canonical_bytes`
+    pub canonical_serialization: bool,
+    /// Timeout after which a connection sending a partial frame is closed
+    pub frame_read_timeout: Option<Duration>,
+    /// Capacities of the read and write buffers of the connection
+    pub buffer_capacity: Option<(usize, usize)>,
+    /// Limits declared to the server during the handshake. There is no handshake if
+    /// this is `None` and the codec is not negotiated
+    pub connection_limits: Option<ConnectionLimits>,
+    /// Whether the codec is announced to the server during the handshake
+    pub negotiate_codec: bool,
+    /// Interceptors that run on every outgoing request
+    pub(crate) interceptors: Vec<Interceptor>,
+    /// Retry policy of `Client::call_with_default_retry`
+    pub retry_policy: RetryPolicy,
+    /// Proxy that the connections are tunneled through
+    pub proxy: Option<Proxy>,
+    /// Timeout of each step of dialing, ie. the TCP connection, the TLS handshake
+    /// and the WebSocket upgrade
+    pub connect_timeout: Option<Duration>,
+    /// How long `Client::close` waits for the pending requests and the server
+    pub close_timeout: Duration,
+    /// Heartbeat that detects a dead connection. There is no heartbeat if this is `None`
+    pub heartbeat: Option<Heartbeat>,
+    /// Whether the responses are delivered to the callers in the order of the requests
+    pub ordered_responses: bool,
+    /// Whether a trace id is attached to every call that doesn't carry one
+    pub trace_ids: bool,
 }
 
 impl Default for ClientBuilder<AckModeNone> {
@@ -65,6 +242,20 @@ impl Default for ClientBuilder<AckModeNone> {
             ack_mode: PhantomData,
             pub_retry_timeout: DEFAULT_PUB_RETRY_TIMEOUT,
             max_num_retries: DEFAULT_PUB_RETRIES,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            canonical_serialization: false,
+            frame_read_timeout: None,
+            buffer_capacity: None,
+            connection_limits: None,
+            negotiate_codec: false,
+            interceptors: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            proxy: None,
+            connect_timeout: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            heartbeat: None,
+            ordered_responses: false,
+            trace_ids: false,
         }
     }
 }
@@ -76,6 +267,20 @@ impl<AckMode> ClientBuilder<AckMode> {
             ack_mode: PhantomData,
             pub_retry_timeout: DEFAULT_PUB_RETRY_TIMEOUT,
             max_num_retries: DEFAULT_PUB_RETRIES,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            canonical_serialization: false,
+            frame_read_timeout: None,
+            buffer_capacity: None,
+            connection_limits: None,
+            negotiate_codec: false,
+            interceptors: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            proxy: None,
+            connect_timeout: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            heartbeat: None,
+            ordered_responses: false,
+            trace_ids: false,
         }
     }
 
@@ -85,6 +290,20 @@ impl<AckMode> ClientBuilder<AckMode> {
             ack_mode: PhantomData,
             pub_retry_timeout: self.pub_retry_timeout,
             max_num_retries: self.max_num_retries,
+            max_in_flight: self.max_in_flight,
+            canonical_serialization: self.canonical_serialization,
+            frame_read_timeout: self.frame_read_timeout,
+            buffer_capacity: self.buffer_capacity,
+            connection_limits: self.connection_limits,
+            negotiate_codec: self.negotiate_codec,
+            interceptors: self.interceptors,
+            retry_policy: self.retry_policy,
+            proxy: self.proxy,
+            connect_timeout: self.connect_timeout,
+            close_timeout: self.close_timeout,
+            heartbeat: self.heartbeat,
+            ordered_responses: self.ordered_responses,
+            trace_ids: self.trace_ids,
         }
     }
 
@@ -94,6 +313,20 @@ impl<AckMode> ClientBuilder<AckMode> {
             ack_mode: PhantomData,
             pub_retry_timeout: self.pub_retry_timeout,
             max_num_retries: self.max_num_retries,
+            max_in_flight: self.max_in_flight,
+            canonical_serialization: self.canonical_serialization,
+            frame_read_timeout: self.frame_read_timeout,
+            buffer_capacity: self.buffer_capacity,
+            connection_limits: self.connection_limits,
+            negotiate_codec: self.negotiate_codec,
+            interceptors: self.interceptors,
+            retry_policy: self.retry_policy,
+            proxy: self.proxy,
+            connect_timeout: self.connect_timeout,
+            close_timeout: self.close_timeout,
+            heartbeat: self.heartbeat,
+            ordered_responses: self.ordered_responses,
+            trace_ids: self.trace_ids,
         }
     }
 
@@ -103,8 +336,220 @@ impl<AckMode> ClientBuilder<AckMode> {
             ack_mode: PhantomData,
             pub_retry_timeout: self.pub_retry_timeout,
             max_num_retries: self.max_num_retries,
+            max_in_flight: self.max_in_flight,
+            canonical_serialization: self.canonical_serialization,
+            frame_read_timeout: self.frame_read_timeout,
+            buffer_capacity: self.buffer_capacity,
+            connection_limits: self.connection_limits,
+            negotiate_codec: self.negotiate_codec,
+            interceptors: self.interceptors,
+            retry_policy: self.retry_policy,
+            proxy: self.proxy,
+            connect_timeout: self.connect_timeout,
+            close_timeout: self.close_timeout,
+            heartbeat: self.heartbeat,
+            ordered_responses: self.ordered_responses,
+            trace_ids: self.trace_ids,
+        }
+    }
+
+    /// Sets the maximum number of requests that can be in-flight at the same time.
+    ///
+    /// A call made beyond this limit is not sent to the broker until a slot is released
+    /// by a response, a cancellation or a timeout. Such a `Call` only sends its request
+    /// once it is polled, so the requests waiting for a slot are held by the callers
+    /// rather than queued by the client. Cancellations are never blocked by this limit.
+    /// A value of 0 is treated as 1.
+    pub fn set_max_in_flight(self, val: usize) -> Self {
+        Self {
+            max_in_flight: val.max(1),
+            ..self
+        }
+    }
+
+    /// Sets whether the responses are delivered to the callers in the order of the
+    /// requests. Responses are delivered as soon as they arrive by default.
+    ///
+    /// A response that arrives before the responses of earlier requests on the same
+    /// connection is held back until every earlier request is responded, canceled or
+    /// timed out, so a slow request holds up the responses after it. The order is the
+    /// order of the message ids, which is the order the calls are made in. Calls made at
+    /// the same time from several tasks have no defined order among themselves.
+    pub fn set_ordered_responses(self, val: bool) -> Self {
+        Self {
+            ordered_responses: val,
+            ..self
+        }
+    }
+
+    /// Sets whether a trace id is attached to every call. Trace ids are off by default.
+    ///
+    /// A call that doesn't carry a trace id in its metadata gets the trace id of the
+    /// request that is being handled if it is made from within a handler, and a new one
+    /// otherwise. The trace id puts every request into `Header::RequestWithMetadata`, which
+    /// servers older than request metadata cannot read. See `toy_rpc::trace_id`
+    pub fn set_trace_ids(self, val: bool) -> Self {
+        Self {
+            trace_ids: val,
+            ..self
         }
     }
+
+    /// Sets whether the arguments of requests are serialized canonically.
+    ///
+    /// With canonical serialization, the entries of all maps (ie. `HashMap`) in the
+    /// arguments are sorted, so the same arguments always produce the same request body.
+    /// This has the overhead of an intermediate copy of the arguments. The body is the
+    /// same as `toy_rpc::codec::canonical_bytes(&args)`.
+    pub fn set_canonical_serialization(self, val: bool) -> Self {
+        Self {
+            canonical_serialization: val,
+            ..self
+        }
+    }
+
+    /// Sets the timeout for receiving the rest of a frame once it has started to arrive.
+    /// There is no timeout by default.
+    ///
+    /// The timeout is reset whenever more bytes of the frame arrive, so large responses
+    /// are not affected as long as they keep making progress. A connection that stalls in
+    /// the middle of a frame is closed, and the pending requests fail.
+    ///
+    /// This does not apply to WebSocket connections or the `serde_json` codec.
+    pub fn set_frame_read_timeout(self, duration: Duration) -> Self {
+        Self {
+            frame_read_timeout: Some(duration),
+            ..self
+        }
+    }
+
+    /// Sets the capacities in bytes of the read and write buffers of the connection.
+    /// The buffers are 8 KiB each by default.
+    ///
+    /// The capacities don't limit the size of the messages. Frames larger than the
+    /// buffers are still read and written, only with more system calls. See
+    /// `Codec::with_capacity`.
+    ///
+    /// This does not apply to WebSocket connections or to `with_codec`.
+    pub fn set_buffer_capacity(self, read_capacity: usize, write_capacity: usize) -> Self {
+        Self {
+            buffer_capacity: Some((read_capacity, write_capacity)),
+            ..self
+        }
+    }
+
+    /// Declares hard limits to the server in a handshake when the connection is set up.
+    /// There is no handshake by default.
+    ///
+    /// The effective limits of the connection are the smaller of the limits declared by
+    /// the client and by the server (see `ServerBuilder::set_connection_limits`), and they
+    /// are available with `Client::connection_info()` once the server replies. Calls
+    /// beyond the max number of concurrent calls wait for a slot like the ones beyond
+    /// `max_in_flight`, and creating a subscriber beyond the max number of subscriptions
+    /// fails with `Error::Busy`.
+    ///
+    /// Calls are held back until the server replies to the handshake, so this requires
+    /// a server that supports the handshake.
+    pub fn set_connection_limits(self, limits: ConnectionLimits) -> Self {
+        Self {
+            connection_limits: Some(limits),
+            ..self
+        }
+    }
+
+    /// Announces the codec of the client to the server in a handshake when the connection
+    /// is set up. Nothing is announced by default.
+    ///
+    /// The calls fail with `Error::CodecMismatch` if the server replies with another
+    /// codec. The codec is still selected by the feature flags, so this only checks that
+    /// both peers agree. See `ServerBuilder::negotiate_codec` for what it can detect.
+    ///
+    /// Calls are held back until the server replies to the handshake, so this requires
+    /// a server that supports the handshake. A server that doesn't negotiate the codec
+    /// ignores the announcement.
+    pub fn negotiate_codec(self, enabled: bool) -> Self {
+        Self {
+            negotiate_codec: enabled,
+            ..self
+        }
+    }
+
+    /// Sets the retry policy of `Client::call_with_default_retry`. Calls are attempted
+    /// only once by default.
+    pub fn set_retry_policy(self, policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy: policy,
+            ..self
+        }
+    }
+
+    /// Tunnels the connections to the server through a SOCKS5 or HTTP `CONNECT` proxy.
+    /// See `toy_rpc::client::proxy`
+    pub fn set_proxy(self, proxy: Proxy) -> Self {
+        Self {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    /// Sets the timeout of dialing, which fails with an `Error::ConnectTimeout` when it
+    /// expires.
+    ///
+    /// The timeout applies to each step separately: the TCP connection to every address
+    /// that is tried (including the handshake with the proxy), the TLS handshake and the
+    /// WebSocket upgrade. Without TLS or a proxy, the WebSocket methods connect and
+    /// upgrade within a single timeout. There is no timeout by default.
+    pub fn set_connect_timeout(self, duration: Duration) -> Self {
+        Self {
+            connect_timeout: Some(duration),
+            ..self
+        }
+    }
+
+    /// Sets how long `Client::close` waits for the responses to the pending requests
+    /// and for the server to close its side of the connection. The default is
+    /// `DEFAULT_CLOSE_TIMEOUT`.
+    pub fn set_close_timeout(self, duration: Duration) -> Self {
+        Self {
+            close_timeout: duration,
+            ..self
+        }
+    }
+
+    /// Sends heartbeats on every connection, which is marked as lost when too many of
+    /// them are not answered in time. See `toy_rpc::client::heartbeat` for details.
+    /// There is no heartbeat by default.
+    pub fn set_heartbeat(self, heartbeat: Heartbeat) -> Self {
+        Self {
+            heartbeat: Some(heartbeat),
+            ..self
+        }
+    }
+
+    /// Adds an interceptor that runs on every outgoing request. Interceptors run in
+    /// the order they are added.
+    ///
+    /// The interceptor can attach metadata to the request, which is available to the
+    /// handlers on the server through `Context::metadata()`. See
+    /// `toy_rpc::client::interceptor` for details.
+    pub fn with_interceptor<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut RequestHeader) + Send + Sync + 'static,
+    {
+        self.interceptors.push(Box::new(f));
+        self
+    }
+
+    /// Adds an interceptor with hooks that run before each request is sent and after
+    /// its response is received. Interceptors added with `with_interceptor` and
+    /// `with_client_interceptor` run together in the order they are added.
+    pub fn with_client_interceptor<I>(mut self, interceptor: I) -> Self
+    where
+        I: ClientInterceptor,
+    {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
 }
 
 impl ClientBuilder<AckModeAuto> {
@@ -167,7 +612,7 @@ cfg_if! {
         )
     ))] {
         use std::{
-            sync::Arc, collections::HashMap, time::Duration,
+            sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize}}, collections::HashMap, time::Duration,
         };
 
         #[cfg(feature = "tls")]
@@ -177,14 +622,18 @@ cfg_if! {
         use crate::{
             client::Client,
             error::Error,
-            codec::{split::SplittableCodec, DefaultCodec},
+            codec::{marshal_erased, split::SplittableCodec, CodecRead, CodecWrite, DefaultCodec},
             message::AtomicMessageId,
+            stats::WriterStats,
+            util::{CancellationToken, Semaphore},
         };
 
         #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
         use crate::DEFAULT_RPC_PATH;
+        #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+        use tungstenite::{client::IntoClientRequest, http};
 
-        use super::{reader::ClientReader, writer::ClientWriter, broker};
+        use super::{reader::ClientReader, writer::ClientWriter, broker, interceptor::Interceptors, pool::PooledClient};
 
         macro_rules! impl_client_builder_for_ack_modes {
             ($($ack_mode:ty),*) => {
@@ -206,16 +655,16 @@ cfg_if! {
                             use rustls::client::ServerName;
                             use std::convert::TryFrom;
 
-                            let stream = TcpStream::connect(addr).await?;
+                            let (stream, addrs, index) = connect_any(addr, self.proxy.as_ref(), self.connect_timeout).await?;
                             let connector = TlsConnector::from(std::sync::Arc::new(config));
                             let domain = ServerName::try_from(domain)
                                 .map_err(|_| Error::Internal(Box::new(webpki::InvalidDnsNameError)))?;
-                            let tls_stream = connector.connect(domain, stream).await?;
+                            let handshake = async { connector.connect(domain, stream).await.map_err(Error::from) };
+                            let tls_stream = with_connect_timeout(self.connect_timeout, "TLS handshake", handshake).await?;
 
-                            Ok(
-                                ClientBuilder::<$ack_mode>::new()
-                                    .with_stream(tls_stream)
-                            )
+                            let mut client = self.with_stream(tls_stream);
+                            client.peer_addr = Some(addrs[index]);
+                            Ok(client)
                         }
 
                         #[cfg(all(
@@ -231,48 +680,87 @@ cfg_if! {
                         ))]
                         async fn websocket_client_with_tls_config(
                             self,
-                            url: url::Url,
+                            request: http::Request<()>,
                             domain: &str,
                             config: rustls::ClientConfig,
                         ) -> Result<Client<$ack_mode>, Error> {
-                            let host = url.host_str()
-                                .ok_or(Error::Internal("Invalid host address".into()))?;
-                            let port = url.port_or_known_default()
-                                .ok_or(Error::Internal("Invalid port".into()))?;
-                            let addr = (host, port);
-                            let stream = TcpStream::connect(addr).await?;
+                            use std::convert::TryFrom;
+
+                            let (host, port) = websocket_host_port(request.uri())?;
+                            let stream = connect_host(host, port, self.proxy.as_ref(), self.connect_timeout).await?;
                             let connector = TlsConnector::from(std::sync::Arc::new(config));
                             // let domain = webpki::DNSNameRef::try_from_ascii_str(domain)?;
                             let domain = rustls::client::ServerName::try_from(domain)
                                 .map_err(|_| Error::Internal(Box::new(webpki::InvalidDnsNameError)))?;
-                            let tls_stream = connector.connect(domain, stream).await?;
-                            let (ws_stream, _) = client_async(url, tls_stream).await?;
+                            let handshake = async { connector.connect(domain, stream).await.map_err(Error::from) };
+                            let tls_stream = with_connect_timeout(self.connect_timeout, "TLS handshake", handshake).await?;
+                            let request = with_handshake_headers(request)?;
+                            let upgrade = async { client_async(request, tls_stream).await.map_err(Error::from) };
+                            let (ws_stream, _) = with_connect_timeout(self.connect_timeout, "WebSocket handshake", upgrade).await?;
                             let ws_stream = WebSocketConn::new(ws_stream);
                             let codec = DefaultCodec::with_websocket(ws_stream);
-                            Ok(
-                                ClientBuilder::<$ack_mode>::new()
-                                    .with_codec(codec)
-                            )
+                            Ok(self.with_codec(codec))
                         }
 
                         #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
                         async fn dial_websocket_url(self, url: url::Url) -> Result<Client<$ack_mode>, Error> {
-                            let (ws_stream, _) = connect_async(&url).await?;
+                            self.dial_websocket_with_request(url.into_client_request()?).await
+                        }
+
+                        /// Connects to a WebSocket RPC server with a custom upgrade request,
+                        /// ie. to add the headers that are required by a gateway. The headers of
+                        /// the WebSocket handshake are added if the request doesn't have them.
+                        ///
+                        /// If the server rejects the upgrade, the error is
+                        /// `Error::WebSocketRejected` with the status, headers and body of the
+                        /// response of the server.
+                        /// `wss://` URLs require `dial_websocket_with_request_and_tls_config`.
+                        #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+                        #[cfg_attr(feature = "docs", doc(cfg(any(feature = "ws_tokio", feature = "ws_async_std"))))]
+                        pub async fn dial_websocket_with_request(self, request: http::Request<()>) -> Result<Client<$ack_mode>, Error> {
+                            let request = with_handshake_headers(request)?;
+                            if let Some(proxy) = &self.proxy {
+                                if request.uri().scheme_str() == Some("wss") {
+                                    return Err(Error::Internal(
+                                        "wss:// through a proxy requires dial_websocket_with_request_and_tls_config".into()
+                                    ));
+                                }
+                                let (host, port) = websocket_host_port(request.uri())?;
+                                let connect = proxy.connect(host, port);
+                                let stream = with_connect_timeout(self.connect_timeout, "Connection", connect).await?;
+                                let upgrade = async { client_async(request, stream).await.map_err(Error::from) };
+                                let (ws_stream, _) = with_connect_timeout(self.connect_timeout, "WebSocket handshake", upgrade).await?;
+                                let ws_stream = WebSocketConn::new(ws_stream);
+                                let codec = DefaultCodec::with_websocket(ws_stream);
+                                return Ok(self.with_codec(codec));
+                            }
+                            let connect = async { connect_async(request).await.map_err(Error::from) };
+                            let (ws_stream, _) = with_connect_timeout(self.connect_timeout, "WebSocket handshake", connect).await?;
                             let ws_stream = WebSocketConn::new(ws_stream);
                             let codec = DefaultCodec::with_websocket(ws_stream);
                             Ok(self.with_codec(codec))
                         }
 
                         /// Connects to an RPC server over socket at the specified network address
+                        ///
+                        /// If the address resolves to several socket addresses, ie. a slice of
+                        /// addresses or a host name with several records, they are tried in order
+                        /// until one of them accepts the connection. The connected address is
+                        /// returned by `Client::peer_addr`.
                         pub async fn dial(self, addr: impl ToSocketAddrs) -> Result<Client<$ack_mode>, Error> {
-                            let stream = TcpStream::connect(addr).await?;
-                            Ok(
-                                ClientBuilder::<$ack_mode>::new()
-                                    .with_stream(stream)
-                            )
+                            let (stream, addrs, index) = connect_any(addr, self.proxy.as_ref(), self.connect_timeout).await?;
+                            let mut client = self.with_stream(stream);
+                            client.peer_addr = Some(addrs[index]);
+                            Ok(client)
                         }
 
                         /// Connects to an RPC server with TLS enabled
+                        ///
+                        /// The TLS handshake is performed against the server name `domain` (SNI),
+                        /// which does not have to match `addr`, ie. to connect to an IP address.
+                        /// The trusted roots and the certificate of the client for mutual TLS
+                        /// are set up in `config`. A failed TCP connection or TLS handshake is
+                        /// returned as `Error::IoError`.
                         #[cfg(feature = "tls")]
                         pub async fn dial_with_tls_config(
                             self,
@@ -283,6 +771,41 @@ cfg_if! {
                             self.tcp_client_with_tls_config(addr, domain, config).await
                         }
 
+                        /// Connects to an RPC server over QUIC
+                        ///
+                        /// A new QUIC connection is made to `addr` with the server name
+                        /// `server_name`, and the client is served on a bidirectional stream
+                        /// of the connection. The trusted roots are set up in `config`. A failed
+                        /// connection is returned as `Error::IoError`.
+                        #[cfg(feature = "quic")]
+                        #[cfg_attr(feature = "docs", doc(cfg(feature = "quic")))]
+                        pub async fn dial_quic(
+                            self,
+                            addr: std::net::SocketAddr,
+                            server_name: &str,
+                            config: quinn::ClientConfig
+                        ) -> Result<Client<$ack_mode>, Error> {
+                            use crate::transport::quic::{as_io_err, QuicStream};
+
+                            let bind_addr: std::net::SocketAddr = match addr {
+                                std::net::SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                                std::net::SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+                            };
+                            // the endpoint is kept alive by the connection
+                            let endpoint = quinn::Endpoint::client(bind_addr)?;
+                            let connecting = endpoint.connect_with(config, addr, server_name).map_err(as_io_err)?;
+                            let connect = async {
+                                let connection = connecting.await.map_err(as_io_err)?;
+                                let (send, recv) = connection.open_bi().await.map_err(as_io_err)?;
+                                Ok::<_, Error>(QuicStream::new(send, recv))
+                            };
+                            let stream = with_connect_timeout(self.connect_timeout, "QUIC handshake", connect).await?;
+
+                            let mut client = self.with_stream(stream);
+                            client.peer_addr = Some(addr);
+                            Ok(client)
+                        }
+
                         /// Connects to an HTTP RPC server at the specified network address using WebSocket and the defatul codec.
                         #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
                         #[cfg_attr(feature = "docs", doc(cfg(any(feature = "ws_tokio", feature = "ws_async_std"))))]
@@ -290,8 +813,7 @@ cfg_if! {
                             let mut url = url::Url::parse(addr)?.join(DEFAULT_RPC_PATH)?;
                             url.set_scheme("ws").expect("Failed to change scheme to ws");
 
-                            ClientBuilder::<$ack_mode>::new()
-                                .dial_websocket_url(url).await
+                            self.dial_websocket_url(url).await
                         }
 
                         /// Connects to an HTTP RPC server with TLS enabled
@@ -311,7 +833,7 @@ cfg_if! {
                             let mut url = url::Url::parse(addr)?.join(DEFAULT_RPC_PATH)?;
                             url.set_scheme("ws").expect("Failed to change scheme to ws");
 
-                            self.websocket_client_with_tls_config(url, domain, config).await
+                            self.websocket_client_with_tls_config(url.into_client_request()?, domain, config).await
                         }
 
                         /// Similar to `dial`, this connects to an WebSocket RPC server at the specified network address using the defatul codec
@@ -326,6 +848,10 @@ cfg_if! {
                         }
 
                         /// Similar to `dial_websocket` but with TLS enabled
+                        ///
+                        /// The connection is made to the host and port of `addr`, which is a
+                        /// `ws://` or `wss://` url, and the TLS handshake is performed against
+                        /// the server name `domain`.
                         #[cfg(all(
                             feature = "tls",
                             any(
@@ -340,7 +866,24 @@ cfg_if! {
                             config: ClientConfig,
                         ) -> Result<Client<$ack_mode>, Error> {
                             let url = url::Url::parse(addr)?;
-                            self.websocket_client_with_tls_config(url, domain, config).await
+                            self.websocket_client_with_tls_config(url.into_client_request()?, domain, config).await
+                        }
+
+                        /// Similar to `dial_websocket_with_request` but with TLS enabled
+                        #[cfg(all(
+                            feature = "tls",
+                            any(
+                                feature = "ws_tokio",
+                                feature = "ws_async_std",
+                            )
+                        ))]
+                        pub async fn dial_websocket_with_request_and_tls_config(
+                            self,
+                            request: http::Request<()>,
+                            domain: &str,
+                            config: ClientConfig,
+                        ) -> Result<Client<$ack_mode>, Error> {
+                            self.websocket_client_with_tls_config(request, domain, config).await
                         }
 
                         /// Creates an RPC `Client` over a stream
@@ -350,34 +893,136 @@ cfg_if! {
                         where
                             T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
                         {
-                            let codec = DefaultCodec::new(stream);
+                            let codec = DefaultCodec::with_buffer_capacity(stream, self.buffer_capacity);
                             self.with_codec(codec)
                         }
 
                         /// Creates an RPC 'Client` over socket with a specified codec
                         #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
                         #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
-                        pub fn with_codec<C>(self, codec: C) -> Client<$ack_mode>
+                        pub fn with_codec<C>(mut self, codec: C) -> Client<$ack_mode>
+                        where
+                            C: SplittableCodec + Send + 'static,
+                        {
+                            let interceptors = Arc::new(std::mem::take(&mut self.interceptors));
+                            self.client_with_codec(codec, interceptors)
+                        }
+
+                        /// Opens `size` connections to the same RPC server, and returns a
+                        /// `PooledClient` that spreads the calls over the connections. A `size`
+                        /// of 0 is treated as 1.
+                        ///
+                        /// Every connection is set up with the settings of this builder. See
+                        /// `toy_rpc::client::pool` for details.
+                        ///
+                        /// If the address resolves to several socket addresses, the first one that
+                        /// accepts the connection is used for all the connections, and
+                        /// `PooledClient::redial` moves on to the next ones if it stops accepting.
+                        pub async fn dial_pool(mut self, addr: impl ToSocketAddrs, size: usize) -> Result<PooledClient<$ack_mode>, Error> {
+                            let proxy = self.proxy.clone();
+                            let timeout = self.connect_timeout;
+                            let (stream, addrs, mut index) = connect_any(addr, proxy.as_ref(), timeout).await?;
+                            let interceptors = Arc::new(std::mem::take(&mut self.interceptors));
+                            let connect = Box::new(move |stream: TcpStream, peer_addr| {
+                                let codec = DefaultCodec::with_buffer_capacity(stream, self.buffer_capacity);
+                                let mut client = self.client_with_codec(codec, interceptors.clone());
+                                client.peer_addr = Some(peer_addr);
+                                client
+                            });
+                            let mut clients = vec![connect(stream, addrs[index])];
+                            for _ in 1..size.max(1) {
+                                let (stream, i) = connect_in_turn(&addrs, index, proxy.as_ref(), timeout).await?;
+                                index = i;
+                                clients.push(connect(stream, addrs[index]));
+                            }
+                            Ok(PooledClient::new(clients, addrs, index, proxy, timeout, connect))
+                        }
+
+                        fn client_with_codec<C>(&self, codec: C, interceptors: Interceptors) -> Client<$ack_mode>
                         where
                             C: SplittableCodec + Send + 'static,
                         {
                             let count = Arc::new(AtomicMessageId::new(0));
-                            let (writer, reader) = codec.split();
+                            let num_pending = Arc::new(AtomicUsize::new(0));
+                            let connection_info = Arc::new(AtomicCell::new(None));
+                            let disconnected = CancellationToken::new();
+                            let (writer, mut reader) = codec.split();
+                            reader.set_frame_read_timeout(self.frame_read_timeout);
+                            let buffered = writer.flush_policy().is_buffered();
 
-                            let reader = ClientReader { reader };
-                            let writer = ClientWriter { writer };
+                            let stats = Arc::new(WriterStats::default());
+                            let reader = ClientReader {
+                                reader,
+                                stats: stats.clone(),
+                                negotiate_codec: self.negotiate_codec,
+                            };
+                            let writer = ClientWriter::new(writer, stats.clone());
                             let broker = broker::ClientBroker::<$ack_mode, C>::new(
-                                count.clone(), self.pub_retry_timeout, self.max_num_retries
-                            );
+                                count.clone(),
+                                self.pub_retry_timeout,
+                                self.max_num_retries,
+                                self.max_in_flight,
+                                num_pending.clone(),
+                                connection_info.clone(),
+                                disconnected.clone(),
+                            )
+                            .with_declared_limits(self.connection_limits)
+                            .with_ordered_responses(self.ordered_responses)
+                            .with_codec_negotiation(self.negotiate_codec);
+                            #[cfg(feature = "metrics")]
+                            let call_metrics = Arc::new(super::metrics::ClientMetrics::default());
+                            #[cfg(feature = "metrics")]
+                            let broker = broker.with_call_metrics(call_metrics.clone());
                             let (handle, broker) = brw::spawn(broker, reader, writer);
+                            if buffered {
+                                if let Err(err) = broker.send(broker::ClientBrokerItem::AttachWriter) {
+                                    log::error!("{}", err);
+                                }
+                            }
+                            if self.connection_limits.is_some() || self.negotiate_codec {
+                                // this is the first message on the connection
+                                if let Err(err) = broker.send(broker::ClientBrokerItem::StartHandshake) {
+                                    log::error!("{}", err);
+                                }
+                            }
+
+                            let closing = disconnected.child_token();
+                            if let Some(heartbeat) = self.heartbeat {
+                                let fut = super::heartbeat::run(heartbeat, broker.clone(), count.clone(), marshal_erased::<C>, stats.clone(), closing.clone());
+                                #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+                                ::tokio::task::spawn(fut);
+                                #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+                                ::async_std::task::spawn(fut);
+                            }
 
                             Client {
                                 count,
                                 default_timeout: Duration::from_secs(super::DEFAULT_TIMEOUT_SECONDS),
                                 next_timeout: AtomicCell::new(None),
-                                broker,
-                                broker_handle: Some(handle),
-                                subscriptions: HashMap::new(),
+                                broker: broker.clone(),
+                                shared: Arc::new(super::Shared {
+                                    broker,
+                                    broker_handle: Mutex::new(Some(handle)),
+                                    subscriptions: Mutex::new(HashMap::new()),
+                                    closing,
+                                    closed: AtomicBool::new(false),
+                                    terminated: CancellationToken::new(),
+                                }),
+                                num_pending,
+                                in_flight: Arc::new(Semaphore::new(self.max_in_flight)),
+                                stats,
+                                canonical_serialization: self.canonical_serialization,
+                                trace_ids: self.trace_ids,
+                                marshal: marshal_erased::<C>,
+                                interceptors,
+                                connection_limits: self.connection_limits,
+                                connection_info,
+                                retry_policy: self.retry_policy.clone(),
+                                peer_addr: None,
+                                disconnected,
+                                close_timeout: self.close_timeout,
+                                #[cfg(feature = "metrics")]
+                                call_metrics,
 
                                 ack_mode: PhantomData
                             }
@@ -3,6 +3,10 @@
 use std::{
     marker::PhantomData,
     pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
 
@@ -10,9 +14,16 @@ use flume::Sender;
 use futures::{channel::oneshot, Future};
 use serde::de::DeserializeOwned;
 
-use crate::{message::MessageId, protocol::InboundBody, Error};
+use crate::{
+    codec::ContentType,
+    message::MessageId,
+    protocol::InboundBody,
+    stats::{CallCounters, CallStats},
+    util::CancellationToken,
+    Error,
+};
 
-use super::{broker, ResponseResult};
+use super::{broker, interceptor::Observer, ResponseResult};
 
 enum CallStatus {
     Pending,
@@ -27,7 +38,8 @@ enum CallStatus {
 ///
 /// The type parameter `Res` is the `Ok` type of the result. `.await`ing on the `Call<Res>`
 /// will yield a `Result<Res, toy_rpc::Error>`. If a `Call` is dropped before the value is consumed
-/// by `.await`ing, the call will be canceled.
+/// by `.await`ing, the call will be canceled. Dropping a `Call` whose response is already
+/// received, or that has timed out, does not send a cancellation to the server.
 ///
 /// # Example
 ///
@@ -37,27 +49,56 @@ enum CallStatus {
 /// let result = call.await;
 ///
 /// // cancel the call regardless of whether the response is received or not
-/// let call: Call<()> = client.call("Arith.infinite_loop", ());
-/// call.cancel();
+/// let mut call: Call<()> = client.call("Arith.infinite_loop", ());
+/// // wait for the server to acknowledge the cancellation
+/// call.cancel().await.unwrap();
 /// // You can still .await on the canceled `Call` but will get an error
-/// let result = call.await; // Err(Error::Canceled(Some(id)))
+/// let result = call.await; // Err(Error::Canceled(id))
+/// ```
+///
+/// The `Call` is the same type with either runtime, and is `Send`, `Unpin` and
+/// `'static` for any `Res`, so it can be stored in a struct or a collection and polled
+/// or spawned later. The request is sent whether or not the `Call` is polled, unless the
+/// `max_in_flight` limit of the client is reached when the call is made. The request
+/// is then sent once the `Call` is polled and another request is finished.
+///
+/// ```rust
+/// struct Pending<Res> {
+///     calls: Vec<Call<Res>>,
+/// }
 /// ```
 #[pin_project::pin_project(PinnedDrop)]
-pub struct Call<Res: DeserializeOwned> {
+pub struct Call<Res> {
     status: CallStatus,
     id: MessageId,
     cancel: Sender<broker::ClientBrokerItem>,
     #[pin]
     done: oneshot::Receiver<Result<ResponseResult, Error>>,
-    marker: PhantomData<Res>,
+    /// The `Call` doesn't hold a `Res`, so it is `Send` whatever `Res` is
+    marker: PhantomData<fn() -> Res>,
     error: Option<Error>,
+    cancellation: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// Response that is received by `is_finished()` before the `Call` is polled
+    ready: Option<Result<Result<ResponseResult, Error>, oneshot::Canceled>>,
+    /// Shared with the `CancellationHandle`s
+    finished: Arc<AtomicBool>,
+    /// Shared with the client writer and broker
+    stats: Arc<CallCounters>,
+    /// Runs the `after_receive` hooks of the client interceptors
+    observer: Option<Observer>,
+    /// Content type of the response if it is not the codec of the connection
+    content_type: Option<ContentType>,
+    /// Waits for an in-flight slot and sends the request to the broker, if the request
+    /// is not sent yet
+    admission: Option<Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>>,
 }
 
-impl<Res: DeserializeOwned> Call<Res> {
+impl<Res> Call<Res> {
     pub(crate) fn new(
         id: MessageId,
         cancel: Sender<broker::ClientBrokerItem>,
         done: oneshot::Receiver<Result<ResponseResult, Error>>,
+        stats: Arc<CallCounters>,
     ) -> Self {
         Self {
             status: CallStatus::Pending,
@@ -66,6 +107,13 @@ impl<Res: DeserializeOwned> Call<Res> {
             done,
             marker: PhantomData,
             error: None,
+            cancellation: None,
+            ready: None,
+            finished: Arc::new(AtomicBool::new(false)),
+            stats,
+            observer: None,
+            content_type: None,
+            admission: None,
         }
     }
 
@@ -83,54 +131,222 @@ impl<Res: DeserializeOwned> Call<Res> {
             done,
             marker: PhantomData,
             error: Some(error),
+            cancellation: None,
+            ready: None,
+            finished: Arc::new(AtomicBool::new(true)),
+            stats: Arc::new(CallCounters::default()),
+            observer: None,
+            content_type: None,
+            admission: None,
         }
     }
+
+    /// Runs the `after_receive` hooks when the call resolves
+    pub(crate) fn with_observer(mut self, observer: Option<Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Deserializes the `Ok` response with `content_type`
+    pub(crate) fn with_content_type(mut self, content_type: Option<ContentType>) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Sends the request with `admission` when the `Call` is polled
+    pub(crate) fn with_admission(
+        mut self,
+        admission: impl Future<Output = Result<(), Error>> + Send + 'static,
+    ) -> Self {
+        self.admission = Some(Box::pin(admission));
+        self
+    }
 }
 
 #[pin_project::pinned_drop]
-impl<Res: DeserializeOwned> PinnedDrop for Call<Res> {
+impl<Res> PinnedDrop for Call<Res> {
     fn drop(self: Pin<&mut Self>) {
         let this = self.project();
         if let CallStatus::Pending = this.status {
-            if let Err(_) = this.cancel.send(broker::ClientBrokerItem::Cancel(*this.id)) {
-                log::error!("Failed to send cancellation message to client broker");
+            // the broker doesn't know about a request that is not sent yet
+            if this.ready.is_none() && this.admission.is_none() {
+                send_cancel(this.cancel, *this.id);
             }
         }
         *this.status = CallStatus::Dropped;
+        this.finished.store(true, Ordering::Release);
     }
 }
 
-impl<Res: DeserializeOwned> Call<Res> {
-    /// Cancel the RPC call
+impl<Res> Call<Res> {
+    /// Cancels the RPC call and returns a future that resolves when the server
+    /// acknowledges the cancellation, ie. when the handler is no longer running.
     ///
-    pub fn cancel(&mut self) {
-        if let Err(_) = self.cancel.send(broker::ClientBrokerItem::Cancel(self.id)) {
-            log::error!("Failed to send cancellation message to client broker");
+    /// The call is canceled right away even if the returned future is not `.await`ed.
+    /// The future resolves to `Ok(())` without waiting if the request has not been sent to the
+    /// server yet, ie. if it is still waiting for an in-flight slot. It resolves to `Err(Error::Timeout(id))` if the acknowledgment is not
+    /// received within the default timeout of 10 seconds, which is the case with servers
+    /// that do not acknowledge cancellations. Use `cancel_detached()` if the acknowledgment
+    /// is not needed.
+    pub fn cancel(&mut self) -> impl Future<Output = Result<(), Error>> {
+        let rx = match self.admission.take() {
+            // the request is dropped before it is sent
+            Some(_) => None,
+            None => {
+                let (tx, rx) = oneshot::channel();
+                if self
+                    .cancel
+                    .send(broker::ClientBrokerItem::Cancel(self.id, Some(tx)))
+                    .is_err()
+                {
+                    log::error!("Failed to send cancellation message to client broker");
+                }
+                Some(rx)
+            }
+        };
+        self.status = CallStatus::Canceled;
+        self.finished.store(true, Ordering::Release);
+
+        async move {
+            let rx = match rx {
+                Some(rx) => rx,
+                None => return Ok(()),
+            };
+            match rx.await {
+                Ok(res) => res,
+                Err(_) => Err(Error::IoError(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Client is closed before the cancellation is acknowledged",
+                ))),
+            }
+        }
+    }
+
+    /// Cancels the RPC call without waiting for the acknowledgment from the server
+    pub fn cancel_detached(&mut self) {
+        if self.admission.take().is_none() {
+            send_cancel(&self.cancel, self.id);
         }
         self.status = CallStatus::Canceled;
+        self.finished.store(true, Ordering::Release);
+    }
+
+    /// Returns `true` if the call is completed, canceled, or the result is already
+    /// available. This does not block or wait for the result.
+    pub fn is_finished(&mut self) -> bool {
+        if !matches!(self.status, CallStatus::Pending) || self.ready.is_some() {
+            return true;
+        }
+        match self.done.try_recv() {
+            Ok(None) => false,
+            Ok(Some(res)) => {
+                self.ready = Some(Ok(res));
+                self.finished.store(true, Ordering::Release);
+                true
+            }
+            Err(canceled) => {
+                self.ready = Some(Err(canceled));
+                self.finished.store(true, Ordering::Release);
+                true
+            }
+        }
+    }
+
+    /// Returns the number of bytes written for the request and read for the response,
+    /// including the framing overhead. The counts are zero until the request is written
+    /// and the response is read, respectively.
+    pub fn stats(&self) -> CallStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns a handle that can cancel the call from elsewhere, for example after the
+    /// `Call` is moved into another task.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let call: Call<()> = client.call("Arith.infinite_loop", ());
+    /// let handle = call.cancellation_handle();
+    /// let task = task::spawn(call);
+    /// handle.cancel();
+    /// let result = task.await; // Err(Error::Canceled(id))
+    /// ```
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        CancellationHandle {
+            id: self.id,
+            cancel: self.cancel.clone(),
+            finished: self.finished.clone(),
+        }
+    }
+
+    /// Cancels the call when the `token` is cancelled.
+    ///
+    /// The cancellation takes effect when the `Call` is being `.await`ed. A `Call`
+    /// that is dropped before completion is canceled regardless.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// // inside an RPC method, the downstream call is canceled together with the request
+    /// let ctx = toy_rpc::server::Context::current().unwrap();
+    /// let result: Result<i32, _> = client
+    ///     .call("Arith.add", (1i32, 6i32))
+    ///     .bind_cancellation(ctx.child_token())
+    ///     .await;
+    /// ```
+    pub fn bind_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(Box::pin(async move { token.cancelled().await }));
+        self
     }
 
     /// Gets the ID number of the call
     ///
-    /// Each client RPC call has a monotonically increasing ID number of type `u16`
+    /// Each client RPC call has a monotonically increasing ID number of type `u64` (`MessageId`)
     pub fn id(&self) -> MessageId {
         self.id
     }
-}
-
-impl<Res> Future for Call<Res>
-where
-    Res: serde::de::DeserializeOwned,
-{
-    type Output = Result<Res, Error>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    /// Polls for the body of the response, which is not deserialized yet. An error
+    /// response is already turned into an `Error`.
+    fn poll_response(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<RawResponse, Error>> {
         let this = self.project();
-        let done: Pin<
-            &mut oneshot::Receiver<Result<Result<Box<InboundBody>, Box<InboundBody>>, Error>>,
-        > = this.done;
+        if let CallStatus::Pending = this.status {
+            if let Some(admission) = this.admission.as_mut() {
+                // a `CancellationHandle` has no way to reach a request that is not sent yet
+                let is_cancelled = this.finished.load(Ordering::Acquire)
+                    || match this.cancellation.as_mut() {
+                        Some(cancellation) => cancellation.as_mut().poll(cx).is_ready(),
+                        None => false,
+                    };
+                let admitted = match admission.as_mut().poll(cx) {
+                    Poll::Pending if is_cancelled => Err(Error::Canceled(*this.id)),
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(res) => res,
+                };
+                *this.admission = None;
+                if let Err(err) = admitted {
+                    *this.status = CallStatus::Dropped;
+                    this.finished.store(true, Ordering::Release);
+                    return Poll::Ready(Err(err));
+                }
+                if is_cancelled {
+                    // the request is sent right before it is canceled
+                    send_cancel(this.cancel, *this.id);
+                    *this.status = CallStatus::Canceled;
+                    return Poll::Ready(Err(Error::Canceled(*this.id)));
+                }
+            }
+        }
+        let done: Pin<&mut oneshot::Receiver<Result<ResponseResult, Error>>> = this.done;
 
-        match done.poll(cx) {
+        let polled = match this.ready.take() {
+            Some(res) => Poll::Ready(res),
+            None => done.poll(cx),
+        };
+        match polled {
             Poll::Pending => match this.status {
                 CallStatus::Canceled => Poll::Ready(Err(Error::Canceled(*this.id))),
                 CallStatus::Dropped => {
@@ -142,16 +358,38 @@ where
                         }
                     }
                 }
+                CallStatus::Pending => {
+                    let is_cancelled = match this.cancellation.as_mut() {
+                        Some(cancellation) => cancellation.as_mut().poll(cx).is_ready(),
+                        None => false,
+                    };
+                    if is_cancelled {
+                        send_cancel(this.cancel, *this.id);
+                        *this.status = CallStatus::Canceled;
+                        this.finished.store(true, Ordering::Release);
+                        Poll::Ready(Err(Error::Canceled(*this.id)))
+                    } else {
+                        Poll::Pending
+                    }
+                }
                 _ => Poll::Pending,
             },
             Poll::Ready(res) => {
                 match this.status {
-                    CallStatus::Canceled | CallStatus::Dropped => {
-                        return Poll::Ready(Err(Error::Canceled(*this.id)))
+                    CallStatus::Canceled => return Poll::Ready(Err(Error::Canceled(*this.id))),
+                    CallStatus::Dropped => {
+                        // the response sender of a `Call` created with an error is
+                        // usually dropped already
+                        let err = this.error.take().unwrap_or(Error::Canceled(*this.id));
+                        return Poll::Ready(Err(err));
                     }
                     _ => {}
                 }
 
+                // the broker no longer tracks the request, so dropping the `Call`
+                // must not send a cancellation
+                *this.status = CallStatus::Received;
+                this.finished.store(true, Ordering::Release);
                 let res = match res {
                     Ok(val) => val,
                     Err(_canceled) => return Poll::Ready(Err(Error::Canceled(*this.id))),
@@ -161,17 +399,147 @@ where
                     Err(err) => return Poll::Ready(Err(err)),
                 };
                 let res = match res {
-                    Ok(mut resp_body) => erased_serde::deserialize(&mut resp_body)
-                        .map_err(|err| Error::ParseError(Box::new(err))),
-                    Err(mut err_body) => erased_serde::deserialize(&mut err_body).map_or_else(
-                        |err| Err(Error::ParseError(Box::new(err))),
-                        |msg| Err(Error::from_err_msg(msg)),
-                    ),
+                    Ok(resp_body) => match *this.content_type {
+                        Some(content_type) => {
+                            Ok(resp_body.with_from_bytes(content_type.from_bytes_fn()))
+                        }
+                        None => Ok(resp_body),
+                    },
+                    Err(err_body) => err_body
+                        .deserialize()
+                        .and_then(|msg| Err(Error::from_err_msg(msg))),
                 };
-
-                *this.status = CallStatus::Received;
                 Poll::Ready(res)
             }
         }
     }
 }
+
+impl<Res> Future for Call<Res>
+where
+    Res: FromResponse,
+{
+    type Output = Result<Res, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let res = futures::ready!(self.as_mut().poll_response(cx)).and_then(Res::from_response);
+        if let Some(observer) = self.project().observer.take() {
+            observer.finish(res.as_ref().map(|_| ()));
+        }
+        Poll::Ready(res)
+    }
+}
+
+/// Types that the response of a `Call` can be turned into, which are any type that
+/// can be deserialized and `RawResponse`
+pub trait FromResponse: Sized {
+    /// Turns the body of the response into `Self`
+    fn from_response(body: RawResponse) -> Result<Self, Error>;
+}
+
+impl<T: DeserializeOwned> FromResponse for T {
+    fn from_response(body: RawResponse) -> Result<Self, Error> {
+        body.deserialize()
+    }
+}
+
+impl FromResponse for RawResponse {
+    fn from_response(body: RawResponse) -> Result<Self, Error> {
+        Ok(body)
+    }
+}
+
+/// Body of a response that is not deserialized yet, which is the result of
+/// `Client::call_raw`
+///
+/// The bytes are the body as it is marshaled by the codec of the connection. A
+/// self-describing codec can deserialize them without knowing the type of the response,
+/// ie. into a `serde_json::Value` with `serde_json` or a `serde_cbor::Value` with
+/// `serde_cbor`. `bincode` is not self-describing, so with the default codec the bytes
+/// can only be deserialized into the type that the server has serialized, and are
+/// otherwise only useful to be passed on as they are, ie. by a proxy.
+pub struct RawResponse {
+    bytes: Vec<u8>,
+    from_bytes: fn(Vec<u8>) -> Box<InboundBody>,
+}
+
+impl RawResponse {
+    pub(crate) fn new(bytes: Vec<u8>, from_bytes: fn(Vec<u8>) -> Box<InboundBody>) -> Self {
+        Self { bytes, from_bytes }
+    }
+
+    pub(crate) fn with_from_bytes(mut self, from_bytes: fn(Vec<u8>) -> Box<InboundBody>) -> Self {
+        self.from_bytes = from_bytes;
+        self
+    }
+
+    /// Returns the bytes of the response body
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the bytes of the response body
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Deserializes the response with the codec of the connection, or with the content
+    /// type of the call if it is made with `Client::call_with_codec`
+    pub fn deserialize<T: DeserializeOwned>(self) -> Result<T, Error> {
+        let mut de = (self.from_bytes)(self.bytes);
+        erased_serde::deserialize(&mut de).map_err(|err| Error::ParseError(Box::new(err)))
+    }
+}
+
+impl std::fmt::Debug for RawResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawResponse")
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Best-effort, as there is nothing to cancel once the client broker is gone
+fn send_cancel(cancel: &Sender<broker::ClientBrokerItem>, id: MessageId) {
+    if cancel
+        .send(broker::ClientBrokerItem::Cancel(id, None))
+        .is_err()
+    {
+        log::debug!("Client broker is dropped before call {} is canceled", id);
+    }
+}
+
+/// A handle to cancel a `Call` without holding the `Call` itself
+///
+/// The handle can be cloned, and cancelling a call that is already finished does nothing.
+#[derive(Clone)]
+pub struct CancellationHandle {
+    id: MessageId,
+    cancel: Sender<broker::ClientBrokerItem>,
+    finished: Arc<AtomicBool>,
+}
+
+impl CancellationHandle {
+    /// Cancels the call. `.await`ing on the call will return `Err(Error::Canceled(id))`
+    ///
+    /// A call that is still waiting for an in-flight slot is canceled the next time it is
+    /// polled, without its request ever being sent.
+    pub fn cancel(&self) {
+        if !self.finished.swap(true, Ordering::AcqRel) {
+            send_cancel(&self.cancel, self.id);
+        }
+    }
+
+    /// Gets the ID number of the call
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+}
+
+impl std::fmt::Debug for CancellationHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancellationHandle")
+            .field("id", &self.id)
+            .finish()
+    }
+}
@@ -0,0 +1,302 @@
+//! Heartbeat that detects a dead connection
+//!
+//! A connection that is silently dropped, ie. by a NAT box between the client and the
+//! server, is only noticed by the client when a call on it times out. With a heartbeat
+//! set on the `ClientBuilder`, the client sends a small request to the reserved
+//! `toy_rpc::health::HEARTBEAT_METHOD` every `interval`, and waits `timeout` for the
+//! response. The server answers it right away without running any handler, and any
+//! response counts, so an older server that answers with `Error::ServiceNotFound`
+//! still keeps the connection alive.
+//!
+//! The round trip time of the last answered heartbeat, the number of heartbeats missed
+//! in a row and the heartbeat settings are kept in the `Liveness` of `Client::stats`.
+//!
+//! Once `max_missed` heartbeats in a row are not answered in time, the connection is
+//! marked as lost: the pending calls fail with `Error::MissedHeartbeats`,
+//! `Client::is_connected` returns `false` and `Client::on_disconnect` resolves. A
+//! `PooledClient` takes the connection out of the rotation, and `PooledClient::redial`
+//! replaces it.
+//!
+//! # Example
+//!
+//! ```rust
+//! let heartbeat = Heartbeat::new(Duration::from_secs(15), Duration::from_secs(5))
+//!     .with_max_missed(2);
+//! let client = Client::builder()
+//!     .set_heartbeat(heartbeat)
+//!     .dial(addr)
+//!     .await?;
+//! ```
+
+use std::time::Duration;
+
+/// Default number of heartbeats in a row that can be missed before the connection is
+/// marked as lost
+pub const DEFAULT_MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// How often heartbeats are sent and how long they are waited for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    max_missed: u32,
+}
+
+impl Heartbeat {
+    /// Sends a heartbeat every `interval`, which is answered within `timeout`
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            max_missed: DEFAULT_MAX_MISSED_HEARTBEATS,
+        }
+    }
+
+    /// Sets the number of heartbeats in a row that can be missed before the connection
+    /// is marked as lost. A value of 0 is treated as 1.
+    pub fn with_max_missed(self, max_missed: u32) -> Self {
+        Self {
+            max_missed: max_missed.max(1),
+            ..self
+        }
+    }
+
+    /// Time between two heartbeats
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Time a heartbeat is waited for
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Number of heartbeats in a row that can be missed
+    pub fn max_missed(&self) -> u32 {
+        self.max_missed
+    }
+}
+
+#[cfg(any(
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+pub(crate) use task::run;
+
+#[cfg(any(
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+mod task {
+    use flume::Sender;
+    use futures::channel::oneshot;
+    use std::sync::{atomic::Ordering, Arc};
+
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    use std::time::Instant;
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    use tokio::time::Instant;
+
+    use super::Heartbeat;
+    use crate::{
+        codec::MarshalFn,
+        error::Error,
+        health::HEARTBEAT_METHOD,
+        message::AtomicMessageId,
+        protocol::RequestMetadata,
+        stats::{CallCounters, WriterStats},
+        util::CancellationToken,
+    };
+
+    use crate::client::{
+        broker::{ClientBrokerItem, OutgoingRequest},
+        timeout,
+    };
+
+    /// Sends the heartbeats until the client is closed or the connection is lost
+    pub(crate) async fn run(
+        heartbeat: Heartbeat,
+        broker: Sender<ClientBrokerItem>,
+        count: Arc<AtomicMessageId>,
+        marshal: MarshalFn,
+        stats: Arc<WriterStats>,
+        closing: CancellationToken,
+    ) {
+        stats.set_heartbeat(heartbeat.interval, heartbeat.timeout);
+        let body = match marshal(&()) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("Unable to marshal the heartbeat: {}", err);
+                return;
+            }
+        };
+        let mut missed = 0;
+        let mut next = Instant::now() + heartbeat.interval;
+        loop {
+            let wait = next.saturating_duration_since(Instant::now());
+            if timeout(wait, closing.cancelled()).await.is_some() {
+                return;
+            }
+            next = Instant::now() + heartbeat.interval;
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let item = ClientBrokerItem::Request(OutgoingRequest {
+                id: count.fetch_add(1, Ordering::Relaxed),
+                service_method: HEARTBEAT_METHOD.into(),
+                duration: heartbeat.timeout,
+                metadata: RequestMetadata::new(),
+                opens_stream: false,
+                body: body.clone(),
+                resp_tx,
+                stats: Arc::new(CallCounters::default()),
+                items: None,
+                permit: None,
+            });
+            let sent_at = Instant::now();
+            if broker.send_async(item).await.is_err() {
+                return;
+            }
+            match resp_rx.await {
+                // an error response is an answer all the same
+                Ok(Ok(_)) => {
+                    missed = 0;
+                    stats.set_ping_rtt(sent_at.elapsed());
+                }
+                Ok(Err(Error::Timeout(_))) => {
+                    missed += 1;
+                    stats.set_missed_pongs(missed);
+                    log::debug!("Heartbeat is missed ({}/{})", missed, heartbeat.max_missed);
+                    if missed >= heartbeat.max_missed {
+                        let err = Error::MissedHeartbeats(missed);
+                        if let Err(err) = broker.send_async(ClientBrokerItem::Stop(Some(err))).await
+                        {
+                            log::debug!("{}", err);
+                        }
+                        return;
+                    }
+                }
+                // the connection is lost
+                Ok(Err(_)) | Err(_) => return,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+mod tests {
+    use flume::Receiver;
+    use std::{sync::Arc, time::Duration};
+    use tokio::time::{self, Instant};
+
+    use super::{run, Heartbeat};
+    use crate::{
+        client::{
+            broker::{ClientBrokerItem, OutgoingRequest},
+            RawResponse,
+        },
+        codec::{marshal_erased, DefaultCodec, EraseDeserializer},
+        error::Error,
+        health::HEARTBEAT_METHOD,
+        message::AtomicMessageId,
+        stats::WriterStats,
+        util::CancellationToken,
+    };
+
+    type C = DefaultCodec<(), (), ()>;
+
+    /// Runs the heartbeat against a fake broker, which is driven by the test
+    fn start(
+        heartbeat: Heartbeat,
+    ) -> (
+        Receiver<ClientBrokerItem>,
+        Arc<WriterStats>,
+        CancellationToken,
+    ) {
+        let (broker, items) = flume::unbounded();
+        let stats = Arc::new(WriterStats::default());
+        let closing = CancellationToken::new();
+        tokio::spawn(run(
+            heartbeat,
+            broker,
+            Arc::new(AtomicMessageId::new(0)),
+            marshal_erased::<C>,
+            stats.clone(),
+            closing.clone(),
+        ));
+        (items, stats, closing)
+    }
+
+    async fn next_request(items: &Receiver<ClientBrokerItem>) -> OutgoingRequest {
+        match items.recv_async().await {
+            Ok(ClientBrokerItem::Request(request)) => request,
+            _ => panic!("Expecting a heartbeat"),
+        }
+    }
+
+    #[tokio::test]
+    async fn answered_heartbeats_record_the_round_trip_time() {
+        time::pause();
+        let heartbeat = Heartbeat::new(Duration::from_secs(15), Duration::from_secs(5));
+        let (items, stats, closing) = start(heartbeat);
+
+        let request = next_request(&items).await;
+        let sent_at = Instant::now();
+        assert_eq!(request.service_method, HEARTBEAT_METHOD);
+        assert_eq!(request.duration, Duration::from_secs(5));
+        let liveness = stats.liveness();
+        assert_eq!(liveness.heartbeat_interval, Some(Duration::from_secs(15)));
+        assert_eq!(liveness.heartbeat_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(liveness.last_ping_rtt, None);
+
+        time::sleep(Duration::from_millis(30)).await;
+        let response = RawResponse::new(Vec::new(), C::from_bytes);
+        assert!(request.resp_tx.send(Ok(Ok(response))).is_ok());
+
+        // the next heartbeat is sent an interval after the previous one
+        let request = next_request(&items).await;
+        assert_eq!(sent_at.elapsed(), Duration::from_secs(15));
+        let liveness = stats.liveness();
+        assert_eq!(liveness.last_ping_rtt, Some(Duration::from_millis(30)));
+        assert_eq!(liveness.missed_pongs, 0);
+
+        // an error response is an answer all the same
+        let response = RawResponse::new(Vec::new(), C::from_bytes);
+        assert!(request.resp_tx.send(Ok(Err(response))).is_ok());
+        next_request(&items).await;
+        assert_eq!(
+            stats.liveness().last_ping_rtt,
+            Some(Duration::from_millis(0))
+        );
+
+        closing.cancel();
+    }
+
+    #[tokio::test]
+    async fn missed_heartbeats_stop_the_connection() {
+        time::pause();
+        let heartbeat =
+            Heartbeat::new(Duration::from_secs(1), Duration::from_millis(500)).with_max_missed(2);
+        let (items, stats, _closing) = start(heartbeat);
+
+        let request = next_request(&items).await;
+        assert!(request
+            .resp_tx
+            .send(Err(Error::Timeout(request.id)))
+            .is_ok());
+        let request = next_request(&items).await;
+        assert_eq!(stats.liveness().missed_pongs, 1);
+        assert!(request
+            .resp_tx
+            .send(Err(Error::Timeout(request.id)))
+            .is_ok());
+
+        match items.recv_async().await {
+            Ok(ClientBrokerItem::Stop(Some(Error::MissedHeartbeats(2)))) => {}
+            _ => panic!("Expecting the connection to be stopped"),
+        }
+        assert_eq!(stats.liveness().missed_pongs, 2);
+        assert_eq!(stats.liveness().last_ping_rtt, None);
+        // no more heartbeats are sent
+        assert!(items.recv_async().await.is_err());
+    }
+}
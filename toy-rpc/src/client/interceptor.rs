@@ -0,0 +1,178 @@
+//! Interceptors that run on every outgoing request
+//!
+//! Interceptors are registered with `ClientBuilder::with_interceptor` and run in the
+//! order they are registered, right before the request is handed to the background
+//! task that writes it to the connection. They can attach key-value metadata to the
+//! request, which is available to the handlers on the server through
+//! `Context::metadata()`.
+//!
+//! Interceptors that also need to see the outcome of the call implement the
+//! `ClientInterceptor` trait and are registered with
+//! `ClientBuilder::with_client_interceptor`. Their `after_receive` hook runs when the
+//! `Call` resolves, right before the result is handed to the caller, with the time
+//! elapsed since the request was sent.
+//!
+//! The metadata is sent as part of the request header (`Header::RequestWithMetadata`),
+//! and only when it is not empty. Requests without metadata use the same header as
+//! before, so servers that don't know about metadata can still serve them.
+//!
+//! # Example
+//!
+//! ```rust
+//! let client = Client::builder()
+//!     .with_interceptor(|header: &mut RequestHeader| {
+//!         header.insert_metadata("authorization", "Bearer token");
+//!     })
+//!     .dial(addr)
+//!     .await
+//!     .unwrap();
+//!
+//! // Counts the failed calls
+//! struct CountErrors(AtomicUsize);
+//!
+//! impl ClientInterceptor for CountErrors {
+//!     fn after_receive(&self, _: &RequestHeader, _: Duration, result: Result<(), &Error>) {
+//!         if result.is_err() {
+//!             self.0.fetch_add(1, Ordering::Relaxed);
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Error,
+    message::MessageId,
+    protocol::{OutboundBody, RequestMetadata},
+};
+
+/// Header of an outgoing request as seen by the interceptors
+#[derive(Debug, Clone)]
+pub struct RequestHeader {
+    id: MessageId,
+    service_method: String,
+    metadata: RequestMetadata,
+}
+
+impl RequestHeader {
+    pub(crate) fn new(id: MessageId, service_method: String) -> Self {
+        Self {
+            id,
+            service_method,
+            metadata: RequestMetadata::new(),
+        }
+    }
+
+    /// Message id of the request
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    /// RPC service and method in the format of "{Service}.{method}"
+    pub fn service_method(&self) -> &str {
+        &self.service_method
+    }
+
+    /// Metadata that will be sent with the request
+    pub fn metadata(&self) -> &RequestMetadata {
+        &self.metadata
+    }
+
+    /// Mutable access to the metadata that will be sent with the request
+    pub fn metadata_mut(&mut self) -> &mut RequestMetadata {
+        &mut self.metadata
+    }
+
+    /// Inserts a key-value pair into the metadata, returning the previous value of
+    /// the key if there is one
+    pub fn insert_metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Option<String> {
+        self.metadata.insert(key.into(), value.into())
+    }
+
+    pub(crate) fn into_parts(self) -> (String, RequestMetadata) {
+        (self.service_method, self.metadata)
+    }
+}
+
+/// Hooks that run on every call made by the client
+///
+/// Both hooks do nothing by default. Closures that take a `&mut RequestHeader` are
+/// interceptors that only implement `before_send`.
+pub trait ClientInterceptor: Send + Sync + 'static {
+    /// Runs before the request is handed to the background task that writes it to the
+    /// connection. The header can be modified, ie. to attach metadata, and the body is
+    /// the argument of the call.
+    fn before_send(&self, header: &mut RequestHeader, body: &OutboundBody) {
+        let _ = (header, body);
+    }
+
+    /// Runs when the call resolves, before the result is handed to the caller.
+    /// `elapsed` is the time since the request was handed to the background task, and
+    /// `result` is the outcome of the call, including errors such as timeouts and
+    /// cancellations. It does not run for a `Call` that is dropped without being awaited.
+    fn after_receive(&self, header: &RequestHeader, elapsed: Duration, result: Result<(), &Error>) {
+        let _ = (header, elapsed, result);
+    }
+}
+
+impl<F> ClientInterceptor for F
+where
+    F: Fn(&mut RequestHeader) + Send + Sync + 'static,
+{
+    fn before_send(&self, header: &mut RequestHeader, _: &OutboundBody) {
+        self(header)
+    }
+}
+
+/// An interceptor registered on the client
+pub(crate) type Interceptor = Box<dyn ClientInterceptor>;
+
+/// Interceptors registered on a client, in the order they are applied
+pub(crate) type Interceptors = Arc<Vec<Interceptor>>;
+
+/// Runs the interceptors on the header and body of an outgoing request
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+pub(crate) fn apply(interceptors: &Interceptors, header: &mut RequestHeader, body: &OutboundBody) {
+    for interceptor in interceptors.iter() {
+        interceptor.before_send(header, body);
+    }
+}
+
+/// Runs the `after_receive` hooks of the interceptors once the call resolves
+pub(crate) struct Observer {
+    interceptors: Interceptors,
+    header: RequestHeader,
+    sent_at: Instant,
+}
+
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+impl Observer {
+    pub(crate) fn new(interceptors: Interceptors, header: RequestHeader) -> Self {
+        Self {
+            interceptors,
+            header,
+            sent_at: Instant::now(),
+        }
+    }
+
+    pub(crate) fn finish(self, result: Result<(), &Error>) {
+        let elapsed = self.sent_at.elapsed();
+        for interceptor in self.interceptors.iter() {
+            interceptor.after_receive(&self.header, elapsed, result);
+        }
+    }
+}
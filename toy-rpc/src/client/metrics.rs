@@ -0,0 +1,302 @@
+//! Per-method metrics of the calls made by a client
+//!
+//! The metrics are enabled by the `metrics` feature and are recorded by the client
+//! broker, so every call is counted once whether it is awaited or not. Each service
+//! method keeps a set of atomic counters, which are looked up once when the request is
+//! handed to the broker. Counting a call costs a few relaxed atomic operations and two
+//! calls to `Instant::now()`.
+//!
+//! The latency of a call is the time from the request reaching the broker to the
+//! response being received, and is only recorded for the calls that receive a response.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the buckets of the latency histogram in microseconds. The last
+/// bucket has no upper bound.
+const LATENCY_BOUNDS_MICROS: [u64; 16] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+const NUM_BUCKETS: usize = LATENCY_BOUNDS_MICROS.len() + 1;
+
+/// How a call ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CallOutcome {
+    /// An `Ok` response is received
+    Succeeded,
+    /// An error response is received
+    Failed,
+    /// The call is canceled or dropped before the response is received
+    Canceled,
+    /// No response is received before the timeout
+    TimedOut,
+}
+
+/// Counters of the calls to a single service method
+#[derive(Debug, Default)]
+struct MethodCounters {
+    issued: AtomicU64,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    canceled: AtomicU64,
+    timed_out: AtomicU64,
+    latency: [AtomicU64; NUM_BUCKETS],
+    latency_sum_nanos: AtomicU64,
+}
+
+impl MethodCounters {
+    fn record(&self, outcome: CallOutcome, latency: Duration) {
+        let counter = match outcome {
+            CallOutcome::Succeeded => &self.succeeded,
+            CallOutcome::Failed => &self.failed,
+            CallOutcome::Canceled => &self.canceled,
+            CallOutcome::TimedOut => &self.timed_out,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if matches!(outcome, CallOutcome::Succeeded | CallOutcome::Failed) {
+            let micros = latency.as_micros() as u64;
+            let bucket = LATENCY_BOUNDS_MICROS
+                .iter()
+                .position(|bound| micros <= *bound)
+                .unwrap_or(NUM_BUCKETS - 1);
+            self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+            self.latency_sum_nanos
+                .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> MethodMetrics {
+        let buckets: Vec<(Duration, u64)> = LATENCY_BOUNDS_MICROS
+            .iter()
+            .map(|bound| Duration::from_micros(*bound))
+            .chain(std::iter::once(Duration::MAX))
+            .zip(self.latency.iter())
+            .map(|(bound, count)| (bound, count.load(Ordering::Relaxed)))
+            .collect();
+        MethodMetrics {
+            issued: self.issued.load(Ordering::Relaxed),
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            canceled: self.canceled.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            latency: LatencyHistogram {
+                count: buckets.iter().map(|(_, count)| count).sum(),
+                buckets,
+                sum: Duration::from_nanos(self.latency_sum_nanos.load(Ordering::Relaxed)),
+            },
+        }
+    }
+}
+
+/// Counters of the calls of a client, shared by the `Client` and its broker
+#[derive(Debug, Default)]
+pub(crate) struct ClientMetrics {
+    methods: RwLock<HashMap<String, Arc<MethodCounters>>>,
+}
+
+impl ClientMetrics {
+    /// Counts a call to `service_method` as issued. The returned `CallTimer` records the
+    /// outcome of the call
+    pub fn start(&self, service_method: &str) -> CallTimer {
+        let existing = match self.methods.read() {
+            Ok(methods) => methods.get(service_method).cloned(),
+            Err(poisoned) => poisoned.into_inner().get(service_method).cloned(),
+        };
+        let counters = match existing {
+            Some(counters) => counters,
+            None => {
+                let mut methods = match self.methods.write() {
+                    Ok(guard) => guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                methods
+                    .entry(service_method.to_string())
+                    .or_default()
+                    .clone()
+            }
+        };
+        counters.issued.fetch_add(1, Ordering::Relaxed);
+        CallTimer {
+            counters: Some(counters),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn snapshot(&self) -> ClientMetricsSnapshot {
+        let methods = match self.methods.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        ClientMetricsSnapshot {
+            methods: methods
+                .iter()
+                .map(|(name, counters)| (name.clone(), counters.snapshot()))
+                .collect(),
+        }
+    }
+}
+
+/// Records the outcome of a single call. A call that is dropped without an outcome,
+/// ie. when the request could not be sent or the connection is lost, is counted as
+/// failed.
+#[derive(Debug)]
+pub(crate) struct CallTimer {
+    counters: Option<Arc<MethodCounters>>,
+    started_at: Instant,
+}
+
+impl CallTimer {
+    pub fn finish(mut self, outcome: CallOutcome) {
+        if let Some(counters) = self.counters.take() {
+            counters.record(outcome, self.started_at.elapsed());
+        }
+    }
+}
+
+impl Drop for CallTimer {
+    fn drop(&mut self) {
+        // there is no response, so there is no latency either
+        if let Some(counters) = self.counters.take() {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Snapshot of the per-method metrics of a client, returned by `Client::call_metrics`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientMetricsSnapshot {
+    /// Metrics of each service method that is called, keyed by `"{service}.{method}"`
+    pub methods: BTreeMap<String, MethodMetrics>,
+}
+
+impl ClientMetricsSnapshot {
+    /// Returns the metrics of `service_method`, `None` if it is never called
+    pub fn method(&self, service_method: &str) -> Option<&MethodMetrics> {
+        self.methods.get(service_method)
+    }
+}
+
+/// Counts and latencies of the calls to a single service method
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodMetrics {
+    /// Number of calls made
+    pub issued: u64,
+    /// Number of calls that received an `Ok` response
+    pub succeeded: u64,
+    /// Number of calls that received an error response or could not be sent
+    pub failed: u64,
+    /// Number of calls that are canceled or dropped before the response is received
+    pub canceled: u64,
+    /// Number of calls that timed out
+    pub timed_out: u64,
+    /// Latency of the calls that received a response
+    pub latency: LatencyHistogram,
+}
+
+impl MethodMetrics {
+    /// Number of calls that haven't finished yet
+    pub fn in_flight(&self) -> u64 {
+        let finished = self.succeeded + self.failed + self.canceled + self.timed_out;
+        self.issued.saturating_sub(finished)
+    }
+}
+
+/// Histogram of the latency of calls
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// Upper bound (inclusive) of each bucket and the number of calls in the bucket. The
+    /// upper bound of the last bucket is `Duration::MAX`
+    pub buckets: Vec<(Duration, u64)>,
+    /// Number of calls recorded
+    pub count: u64,
+    /// Sum of the latencies of the calls recorded
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    /// Mean latency, `None` if nothing is recorded
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(Duration::from_nanos(
+                (self.sum.as_nanos() / count as u128) as u64,
+            )),
+        }
+    }
+
+    /// Upper bound of the bucket that contains the `q` quantile (ie. `0.99`), `None` if
+    /// nothing is recorded
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets
+            .iter()
+            .find(|(_, count)| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|(bound, _)| *bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_are_counted_per_method() {
+        let metrics = ClientMetrics::default();
+        metrics.start("Arith.add").finish(CallOutcome::Succeeded);
+        metrics.start("Arith.add").finish(CallOutcome::Failed);
+        metrics.start("Arith.div").finish(CallOutcome::TimedOut);
+        metrics.start("Arith.div").finish(CallOutcome::Canceled);
+        // dropped when the connection is lost
+        drop(metrics.start("Arith.div"));
+        let pending = metrics.start("Arith.div");
+
+        let snapshot = metrics.snapshot();
+        let add = snapshot.method("Arith.add").unwrap();
+        assert_eq!((add.issued, add.succeeded, add.failed), (2, 1, 1));
+        assert_eq!(add.latency.count, 2);
+        let div = snapshot.method("Arith.div").unwrap();
+        assert_eq!(div.issued, 4);
+        assert_eq!((div.failed, div.canceled, div.timed_out), (1, 1, 1));
+        assert_eq!(div.in_flight(), 1);
+        // only the calls that received a response have a latency
+        assert_eq!(div.latency.count, 0);
+        assert!(snapshot.method("Arith.mul").is_none());
+        drop(pending);
+    }
+
+    #[test]
+    fn latency_is_bucketed() {
+        let counters = MethodCounters::default();
+        counters.record(CallOutcome::Succeeded, Duration::from_micros(50));
+        counters.record(CallOutcome::Succeeded, Duration::from_micros(100));
+        counters.record(CallOutcome::Succeeded, Duration::from_millis(3));
+        counters.record(CallOutcome::Succeeded, Duration::from_secs(60));
+
+        let latency = counters.snapshot().latency;
+        assert_eq!(latency.count, 4);
+        assert_eq!(latency.buckets.len(), NUM_BUCKETS);
+        assert_eq!(latency.buckets[0], (Duration::from_micros(100), 2));
+        assert_eq!(latency.buckets[5], (Duration::from_millis(5), 1));
+        assert_eq!(latency.buckets[NUM_BUCKETS - 1], (Duration::MAX, 1));
+        assert_eq!(latency.quantile(0.5), Some(Duration::from_micros(100)));
+        assert_eq!(latency.quantile(0.75), Some(Duration::from_millis(5)));
+        assert_eq!(latency.quantile(1.0), Some(Duration::MAX));
+        assert!(latency.mean().unwrap() > Duration::from_secs(15));
+        assert_eq!(LatencyHistogram::default().quantile(0.5), None);
+    }
+}
@@ -3,20 +3,81 @@
 use cfg_if::cfg_if;
 use crossbeam::atomic::AtomicCell;
 use flume::Sender;
-use std::{any::TypeId, collections::HashMap, marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use crate::{message::AtomicMessageId, protocol::InboundBody, pubsub::AckModeNone};
+use crate::{
+    codec::MarshalFn,
+    message::AtomicMessageId,
+    protocol::{ConnectionInfo, ConnectionLimits, InboundBody},
+    pubsub::AckModeNone,
+    stats::{CallCounters, ConnectionStats, Metrics, WriterStats},
+    util::{lock, CancellationToken, Semaphore},
+};
 
+#[cfg(all(
+    feature = "blocking",
+    any(
+        all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+        all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+    )
+))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "blocking")))]
+pub mod blocking;
 pub(crate) mod broker;
 pub mod builder;
+pub mod heartbeat;
+pub mod interceptor;
+#[cfg(feature = "metrics")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "metrics")))]
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+pub mod metrics;
+pub mod proxy;
 pub mod pubsub;
 mod reader;
+pub mod retry;
+#[cfg(feature = "tls")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "tls")))]
+pub mod tls;
 mod writer;
 
-use broker::ClientBrokerItem;
+#[cfg(all(
+    feature = "blocking",
+    any(
+        all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+        all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+    )
+))]
+pub use blocking::BlockingClient;
+use broker::{ClientBrokerItem, OutgoingRequest};
 use builder::ClientBuilder;
+pub use heartbeat::Heartbeat;
+#[cfg(feature = "metrics")]
+pub use metrics::{ClientMetricsSnapshot, LatencyHistogram, MethodMetrics};
+pub use proxy::Proxy;
+pub use retry::RetryPolicy;
+#[cfg(feature = "tls")]
+pub use tls::TlsOptions;
+
+type ResponseResult = Result<RawResponse, RawResponse>;
 
-type ResponseResult = Result<Box<InboundBody>, Box<InboundBody>>;
+/// Default maximum number of requests that can be in-flight at the same time
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+
+/// Default time `Client::close` waits for the pending requests and the peer
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
 
 cfg_if! {
     if #[cfg(any(
@@ -26,9 +87,19 @@ cfg_if! {
     ))] {
         use futures::channel::oneshot;
 
-        use crate::{Error, protocol::{OutboundBody}};
+        use crate::{Error, protocol::RequestMetadata, trace_id::{TraceId, TRACE_ID_KEY}};
 
         const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+        /// Trace id of the request that is being handled if called from within a handler,
+        /// and a new one otherwise
+        fn current_trace_id() -> TraceId {
+            #[cfg(feature = "server")]
+            let current = crate::server::Context::current().and_then(|ctx| ctx.trace_id());
+            #[cfg(not(feature = "server"))]
+            let current = None;
+            current.unwrap_or_else(TraceId::new)
+        }
     }
 }
 
@@ -47,8 +118,41 @@ cfg_if! {
     }
 }
 
+/// Outcome of the requests that are pending when `Client::close` is called
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CloseSummary {
+    /// Number of pending requests that are responded before the connection is closed
+    pub flushed: usize,
+    /// Number of pending requests that are not responded within the close timeout.
+    /// These fail with `Error::Canceled`
+    pub dropped: usize,
+}
+
+impl std::ops::AddAssign for CloseSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.flushed += other.flushed;
+        self.dropped += other.dropped;
+    }
+}
+
 /// RPC client
 ///
+/// The client is cheap to clone, and all the clones share the same connection, so it
+/// can be used from many tasks at the same time. The connection is closed when the last
+/// clone is dropped or when any of them is closed. The timeouts set with
+/// `set_default_timeout` and `set_next_timeout` only apply to the clone they are set on.
+///
+/// # Example
+///
+/// ```rust
+/// let client = Client::dial(addr).await?;
+/// for i in 0..10i32 {
+///     let client = client.clone();
+///     tokio::spawn(async move {
+///         let reply: Result<i32, Error> = client.call("Arith.add", (i, 1i32)).await;
+///     });
+/// }
+/// ```
 #[cfg_attr(
     any(
         not(any(feature = "async_std_runtime", feature = "tokio_runtime")),
@@ -61,8 +165,32 @@ pub struct Client<AckMode> {
     default_timeout: Duration,
     next_timeout: AtomicCell<Option<Duration>>,
     broker: Sender<ClientBrokerItem>,
-    broker_handle: Option<JoinHandle<Result<(), Error>>>,
-    subscriptions: HashMap<String, TypeId>,
+    /// Shared by all the clones, and closes the connection when the last one is dropped
+    shared: Arc<Shared>,
+    num_pending: Arc<AtomicUsize>,
+    /// One permit per request in flight, which is held until the request is finished
+    in_flight: Arc<Semaphore>,
+    stats: Arc<WriterStats>,
+    canonical_serialization: bool,
+    /// Whether a trace id is attached to every call that doesn't carry one
+    trace_ids: bool,
+    /// Marshals the arguments of the calls with the codec of the connection
+    marshal: MarshalFn,
+    interceptors: interceptor::Interceptors,
+    /// Limits declared to the server during the handshake
+    connection_limits: Option<ConnectionLimits>,
+    connection_info: Arc<AtomicCell<Option<ConnectionInfo>>>,
+    /// Used by `call_with_default_retry`
+    retry_policy: RetryPolicy,
+    /// Address of the server if the client is connected with `dial`
+    peer_addr: Option<SocketAddr>,
+    /// Cancelled when the connection is lost or closed
+    disconnected: CancellationToken,
+    /// How long `close` waits for the pending requests and the peer
+    close_timeout: Duration,
+    /// Per-method metrics of the calls, shared with the broker
+    #[cfg(feature = "metrics")]
+    call_metrics: Arc<metrics::ClientMetrics>,
 
     ack_mode: PhantomData<AckMode>,
 }
@@ -91,6 +219,8 @@ cfg_if! {
     ))] {
         #[cfg(feature = "tls")]
         use rustls::{ClientConfig};
+        #[cfg(feature = "discovery")]
+        use crate::discovery::DiscoveredEndpoint;
 
         /// The following impl block is controlled by feature flag. It is enabled
         /// if and only if **exactly one** of the the following feature flag is turned on
@@ -114,6 +244,20 @@ cfg_if! {
             /// let addr = "127.0.0.1:8080";
             /// let client = Client::dial(addr).await.unwrap();
             /// ```
+            ///
+            /// `addr` can be anything that implements `ToSocketAddrs`, ie. a host name or an
+            /// IPv6 literal like `"[::1]:8080"`. The addresses that `addr` resolves to are
+            /// tried in order until one of them accepts the connection, and the error of the
+            /// last one is returned if none does. On a dual-stack host, `"localhost:8080"`
+            /// connects to whichever of `::1` and `127.0.0.1` the server listens on.
+            ///
+            /// ```rust
+            /// let client = Client::dial("localhost:8080").await.unwrap();
+            ///
+            /// let addrs: Vec<SocketAddr> = vec![primary, backup];
+            /// let client = Client::dial(&addrs[..]).await.unwrap();
+            /// println!("Connected to {:?}", client.peer_addr());
+            /// ```
             #[cfg_attr(feature = "docs", doc(cfg(feature = "tokio_runtime")))]
             pub async fn dial(addr: impl ToSocketAddrs)
                 -> Result<Self, Error>
@@ -121,8 +265,77 @@ cfg_if! {
                 ClientBuilder::default().dial(addr).await
             }
 
+            /// Similar to `dial`, but fails with an `Error::ConnectTimeout` if the server
+            /// doesn't accept the connection within `duration`, ie. when the host is
+            /// firewalled. See `ClientBuilder::set_connect_timeout`
+            ///
+            /// # Example
+            ///
+            /// ```rust
+            /// let client = Client::dial_timeout("10.0.0.5:23333", Duration::from_secs(3)).await?;
+            /// ```
+            pub async fn dial_timeout(addr: impl ToSocketAddrs, duration: Duration)
+                -> Result<Self, Error>
+            {
+                ClientBuilder::default().set_connect_timeout(duration).dial(addr).await
+            }
+
+            /// Opens `size` connections to an RPC server at the specified network address,
+            /// and spreads the calls over them
+            ///
+            /// # Example
+            ///
+            /// ```rust
+            /// let pool = Client::dial_pool("127.0.0.1:8080", 4).await.unwrap();
+            /// let reply: Result<i32, Error> = pool.call("Arith.add", (1i32, 6i32)).await;
+            /// ```
+            pub async fn dial_pool(addr: impl ToSocketAddrs, size: usize)
+                -> Result<PooledClient<AckModeNone>, Error>
+            {
+                ClientBuilder::default().dial_pool(addr, size).await
+            }
+
+            /// Browses the local network for servers advertised with mDNS/DNS-SD, and returns the
+            /// ones for which `filter` returns `true`.
+            ///
+            /// This waits for `discovery::DEFAULT_BROWSE_DURATION` to collect the responses.
+            ///
+            /// # Example
+            ///
+            /// ```rust
+            /// let endpoints = Client::discover(DEFAULT_SERVICE_TYPE, |endpoint| {
+            ///     endpoint.txt.get("owner").map(String::as_str) == Some("alice")
+            /// }).await.unwrap();
+            /// ```
+            #[cfg(feature = "discovery")]
+            #[cfg_attr(feature = "docs", doc(cfg(feature = "discovery")))]
+            pub async fn discover(
+                service_type: &str,
+                filter: impl Fn(&DiscoveredEndpoint) -> bool,
+            ) -> Result<Vec<DiscoveredEndpoint>, Error> {
+                let endpoints = crate::discovery::browse(service_type, crate::discovery::DEFAULT_BROWSE_DURATION).await?;
+                Ok(endpoints.into_iter().filter(|endpoint| filter(endpoint)).collect())
+            }
+
+            /// Connects to a server found by `Client::discover`
+            ///
+            /// Returns an error without connecting if the TXT record of the server shows a
+            /// codec or transport that the client doesn't use.
+            #[cfg(feature = "discovery")]
+            #[cfg_attr(feature = "docs", doc(cfg(feature = "discovery")))]
+            pub async fn dial_discovered(endpoint: &DiscoveredEndpoint) -> Result<Self, Error> {
+                endpoint.check_compatible()?;
+                Self::dial(endpoint.addr).await
+            }
+
             /// Connects to an RPC server with TLS enabled
             ///
+            /// The TLS handshake is performed against the server name `domain` (SNI),
+            /// which does not have to match `addr`, ie. to connect to an IP address.
+            /// The trusted roots and the certificate of the client for mutual TLS are
+            /// set up in `config`. A failed TCP connection or TLS handshake is returned
+            /// as `Error::IoError`.
+            ///
             /// A more detailed example can be found in the
             /// [GitHub repo](https://github.com/minghuaw/toy-rpc/blob/9793bf53909bd7ffa74967fae6267f973e03ec8a/examples/tokio_tls/src/bin/client.rs#L22)
             #[cfg(feature = "tls")]
@@ -135,6 +348,21 @@ cfg_if! {
                 ClientBuilder::default().dial_with_tls_config(addr, domain, config).await
             }
 
+            /// Connects to an RPC server over QUIC
+            ///
+            /// The client is served on a new bidirectional stream of a new QUIC connection
+            /// to `addr`, which is authenticated against `server_name`. See
+            /// `ClientBuilder::dial_quic`.
+            #[cfg(feature = "quic")]
+            #[cfg_attr(feature = "docs", doc(cfg(feature = "quic")))]
+            pub async fn dial_quic(
+                addr: SocketAddr,
+                server_name: &str,
+                config: quinn::ClientConfig
+            ) -> Result<Self, Error> {
+                ClientBuilder::default().dial_quic(addr, server_name, config).await
+            }
+
             /// Connects to an HTTP RPC server at the specified network address using WebSocket and the defatul codec.
             ///
             /// It is recommended to use "ws://" as the url scheme as opposed to "http://"; however, internally the url scheme
@@ -222,6 +450,10 @@ cfg_if! {
             }
 
             /// Similar to `dial_websocket` but with TLS enabled
+            ///
+            /// The connection is made to the host and port of `addr`, which is a `ws://`
+            /// or `wss://` url, and the TLS handshake is performed against the server name
+            /// `domain`.
             #[cfg(all(
                 feature = "tls",
                 any(
@@ -247,6 +479,49 @@ cfg_if! {
                 ClientBuilder::default().dial_websocket_with_tls_config(addr, domain, config).await
             }
 
+            /// Connects to a WebSocket RPC server with a custom upgrade request, ie. to
+            /// send the headers that an authenticating gateway requires
+            ///
+            /// If the upgrade is rejected, the error is `Error::WebSocketRejected`, which
+            /// holds the status, headers and body of the response of the server.
+            ///
+            /// # Example
+            ///
+            /// ```rust
+            /// use toy_rpc::http::Request;
+            ///
+            /// let request = Request::builder()
+            ///     .uri("ws://127.0.0.1:8080/_rpc_")
+            ///     .header("Authorization", "Bearer token")
+            ///     .body(())
+            ///     .unwrap();
+            /// let client = Client::dial_websocket_with_request(request).await.unwrap();
+            /// ```
+            #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+            #[cfg_attr(feature = "docs", doc(cfg(any(feature = "ws_tokio", feature = "ws_async_std"))))]
+            pub async fn dial_websocket_with_request(request: crate::http::Request<()>) -> Result<Self, Error> {
+                ClientBuilder::default().dial_websocket_with_request(request).await
+            }
+
+            /// Similar to `dial_websocket_with_request` but with TLS enabled, for
+            /// `wss://` URLs
+            #[cfg(all(
+                feature = "tls",
+                any(
+                    feature = "ws_tokio",
+                    feature = "ws_async_std",
+                )
+            ))]
+            pub async fn dial_websocket_with_request_and_tls_config(
+                request: crate::http::Request<()>,
+                domain: &str,
+                config: ClientConfig,
+            ) -> Result<Self, Error> {
+                ClientBuilder::default()
+                    .dial_websocket_with_request_and_tls_config(request, domain, config)
+                    .await
+            }
+
             /// Creates an RPC `Client` over a stream that implements `tokio::io::AsyncRead`
             /// and `tokio::io::AsyncWrite`
             ///
@@ -299,25 +574,136 @@ cfg_if! {
 }
 
 pub mod call;
-pub use call::Call;
+pub use call::{Call, CancellationHandle, FromResponse, RawResponse};
+
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub mod stream;
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub use stream::{CallStream, StreamSender};
+
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub mod batch;
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub use batch::Batch;
+
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub mod pool;
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub use pool::{Balance, PooledClient};
+
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub mod task;
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub use task::{JoinError, Task};
+
+/// State of the connection that is shared by all the clones of a `Client`
+#[cfg_attr(
+    not(any(feature = "async_std_runtime", feature = "tokio_runtime")),
+    allow(dead_code)
+)]
+struct Shared {
+    broker: Sender<ClientBrokerItem>,
+    broker_handle: Mutex<Option<JoinHandle<Result<(), Error>>>>,
+    /// Topics the client is subscribed to, with the type of the subscriber
+    subscriptions: Mutex<HashMap<String, TypeId>>,
+    /// Cancelled when the client is closed or dropped, which stops the heartbeat
+    closing: CancellationToken,
+    /// Whether `Client::close` is called already
+    closed: AtomicBool,
+    /// Cancelled once the connection is closed by `Client::close`
+    terminated: CancellationToken,
+}
+
+impl<AckMode> Clone for Client<AckMode> {
+    /// The clone shares the connection, but not the timeout set for the next call
+    fn clone(&self) -> Self {
+        Self {
+            count: self.count.clone(),
+            default_timeout: self.default_timeout,
+            next_timeout: AtomicCell::new(None),
+            broker: self.broker.clone(),
+            shared: self.shared.clone(),
+            num_pending: self.num_pending.clone(),
+            in_flight: self.in_flight.clone(),
+            stats: self.stats.clone(),
+            canonical_serialization: self.canonical_serialization,
+            trace_ids: self.trace_ids,
+            marshal: self.marshal,
+            interceptors: self.interceptors.clone(),
+            connection_limits: self.connection_limits,
+            connection_info: self.connection_info.clone(),
+            retry_policy: self.retry_policy.clone(),
+            peer_addr: self.peer_addr,
+            disconnected: self.disconnected.clone(),
+            close_timeout: self.close_timeout,
+            #[cfg(feature = "metrics")]
+            call_metrics: self.call_metrics.clone(),
+
+            ack_mode: PhantomData,
+        }
+    }
+}
 
 // seems like it still works even without this impl
-impl<AckMode> Drop for Client<AckMode> {
+impl Drop for Shared {
+    /// Tells the background tasks to stop without waiting for them.
+    ///
+    /// This only puts messages into the channel of the broker, so it does no IO and
+    /// needs no runtime. The messages are best-effort: the tasks may be gone already,
+    /// ie. if the connection is closed or the runtime is shut down before the client is
+    /// dropped, and then there is nothing left to stop.
     fn drop(&mut self) {
+        self.closing.cancel();
+        // `Client::close` has stopped the tasks already
+        if self.closed.load(Ordering::Acquire) {
+            return;
+        }
         if !self.broker.is_disconnected() {
-            for (topic, _) in self.subscriptions.drain() {
+            for (topic, _) in lock(&self.subscriptions).drain() {
                 self.broker
                     .try_send(broker::ClientBrokerItem::Unsubscribe { topic })
-                    .unwrap_or_else(|err| log::error!("{}", err));
+                    .unwrap_or_else(|err| log::debug!("{}", err));
             }
 
             // #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
             if let Err(err) = self.broker.try_send(broker::ClientBrokerItem::Stopping) {
-                log::error!("{}", err);
+                log::debug!("{}", err);
             }
             #[cfg(not(any(feature = "ws_tokio", feature = "ws_async_std")))]
             if let Err(err) = self.broker.try_send(broker::ClientBrokerItem::Stop(None)) {
-                log::error!("{}", err)
+                log::debug!("{}", err)
             }
 
             // // Drop impl does not provide graceful shutdown.
@@ -339,36 +725,254 @@ impl Client<AckModeNone> {
 }
 
 impl<AckMode> Client<AckMode> {
+    /// Returns the number of requests that are still waiting for a response.
+    ///
+    /// This includes both the requests that are already sent to the server and the
+    /// requests that are held back by the limits agreed on in the handshake. Calls that
+    /// are still waiting for one of the `max_in_flight` slots are not counted.
+    pub fn num_pending(&self) -> usize {
+        self.num_pending.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Returns `true` until the connection is lost, ie. when the server closes it or
+    /// the transport fails, or the client is closed.
+    ///
+    /// The client is marked as disconnected before the pending calls fail, so a call
+    /// that fails because the connection is lost always sees `false` here.
+    pub fn is_connected(&self) -> bool {
+        !self.disconnected.is_cancelled()
+    }
+
+    /// Returns a future that resolves when the connection is lost or closed, or right
+    /// away if it already is. The future doesn't borrow the client.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let disconnected = client.on_disconnect();
+    /// tokio::spawn(async move {
+    ///     disconnected.await;
+    ///     health.set_unhealthy();
+    /// });
+    /// ```
+    pub fn on_disconnect(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let disconnected = self.disconnected.clone();
+        async move { disconnected.cancelled().await }
+    }
+
+    /// Cancels all the requests that are still waiting for a response, and returns
+    /// the number of requests that are canceled.
+    ///
+    /// The `Call`s of the canceled requests resolve to `Err(Error::Canceled(id))`, and
+    /// a cancellation message is sent to the server for every request that has already
+    /// been sent out. Requests that are held back until the handshake is done are dropped
+    /// without reaching the server. Calls that are still waiting for one of the
+    /// `max_in_flight` slots are not sent to the broker yet, so they are not canceled.
+    /// Requests made concurrently with `cancel_all()` are either canceled or processed as
+    /// usual.
+    ///
+    /// Returns 0 if the connection is already closed.
+    pub async fn cancel_all(&self) -> usize {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        if self
+            .broker
+            .send_async(broker::ClientBrokerItem::CancelAll(tx))
+            .await
+            .is_err()
+        {
+            return 0;
+        }
+        rx.await.unwrap_or(0)
+    }
+
+    /// Returns the information agreed on with the server during the handshake, or `None`
+    /// if the client does not declare limits or the server has not replied yet.
+    /// See `ClientBuilder::set_connection_limits`.
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.connection_info.load()
+    }
+
+    /// Returns the limits the client abides by, which are the effective limits after
+    /// the handshake and the limits declared by the client before that
+    pub(crate) fn connection_limits(&self) -> Option<ConnectionLimits> {
+        match self.connection_info.load() {
+            Some(info) => Some(info.limits),
+            None => self.connection_limits,
+        }
+    }
+
+    /// Returns the timing statistics of the connection.
+    ///
+    /// The counters are cumulative, so calling `stats()` has no side effect. The rates
+    /// over an interval are computed from two snapshots with `ConnectionStats::since`.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    /// Address of the server that the client is connected to, `None` if the client is
+    /// not connected with `dial`, `dial_with_tls_config`, `dial_quic` or `dial_pool`, ie. it is
+    /// created over a stream or a WebSocket connection
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Returns the number of calls, bytes and errors since the client is connected.
+    /// This does not start a new interval of `stats()`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let metrics = client.metrics();
+    /// println!("{} requests, {} bytes sent", metrics.requests, metrics.bytes_sent);
+    /// ```
+    pub fn metrics(&self) -> Metrics {
+        self.stats.metrics().snapshot()
+    }
+
+    /// Returns the number of calls to each service method by outcome, and the latency
+    /// of the calls that received a response. See `toy_rpc::client::metrics`
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let snapshot = client.call_metrics();
+    /// if let Some(add) = snapshot.method("Arith.add") {
+    ///     println!("{} calls, p99 <= {:?}", add.issued, add.latency.quantile(0.99));
+    /// }
+    /// ```
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "metrics")))]
+    pub fn call_metrics(&self) -> ClientMetricsSnapshot {
+        self.call_metrics.snapshot()
+    }
+
+    /// Time at which a frame is last sent or received on the connection, `None` if
+    /// there is no frame yet. This does not start a new interval of `stats()`.
+    pub fn last_activity(&self) -> Option<std::time::Instant> {
+        self.stats.liveness().last_activity()
+    }
+
     /// Closes connection with the server
     ///
-    /// Dropping the client will close the connection as well
-    pub async fn close(mut self) {
+    /// The responses to the pending requests are waited for before the connection is
+    /// closed. The client then closes its side of the connection, ie. with the close
+    /// frame of WebSocket or the end of the TCP stream, and reads until the server
+    /// closes its side as well. Both are bounded by the close timeout (see
+    /// `ClientBuilder::set_close_timeout`). The requests that are still pending when
+    /// the timeout is reached are canceled and fail with `Error::Canceled`, and the
+    /// returned `CloseSummary` counts how many of the pending requests are responded
+    /// and how many are dropped.
+    ///
+    /// This closes the connection of all the clones of the client, whose calls fail
+    /// from then on. Dropping the last clone will close the connection as well, but
+    /// without waiting. Closing a client whose connection is already closed does nothing
+    /// and returns an empty summary.
+    ///
+    /// `close` is idempotent. Once it is called on one of the clones, closing another
+    /// clone only waits for the connection to be closed and returns an empty summary,
+    /// and dropping the clients sends nothing to the tasks that are stopped already.
+    /// When `close` returns, the reader, the writer and the broker of the connection
+    /// are stopped.
+    pub async fn close(self) -> CloseSummary {
+        if self.shared.closed.swap(true, Ordering::AcqRel) {
+            self.shared.terminated.cancelled().await;
+            return CloseSummary::default();
+        }
+        let summary = self.close_connection().await;
+        self.shared.terminated.cancel();
+        summary
+    }
+
+    async fn close_connection(&self) -> CloseSummary {
+        let deadline = std::time::Instant::now() + self.close_timeout;
+        self.shared.closing.cancel();
         // log::debug!("Unsunscribe all");
-        for (topic, _) in self.subscriptions.drain() {
+        let subscriptions: Vec<_> = lock(&self.shared.subscriptions).drain().collect();
+        for (topic, _) in subscriptions {
             self.broker
                 .send_async(broker::ClientBrokerItem::Unsubscribe { topic })
                 .await
-                .unwrap_or_else(|err| log::error!("{}", err));
+                .unwrap_or_else(|err| log::debug!("{}", err));
         }
 
-        self.broker
-            .send_async(broker::ClientBrokerItem::Stopping)
+        let mut summary = CloseSummary::default();
+        let (started_tx, started_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        if self
+            .broker
+            .send_async(broker::ClientBrokerItem::Drain {
+                started: started_tx,
+                done: done_tx,
+            })
             .await
-            .unwrap_or_else(|err| log::error!("{}", err));
+            .is_ok()
+        {
+            // the sender is dropped without a count if the broker stops in between
+            let num_pending = started_rx.await.unwrap_or(0);
+            summary.dropped = match timeout(self.close_timeout, done_rx).await {
+                Some(Ok(())) => 0,
+                // the connection is lost, and the requests still pending fail with it
+                Some(Err(_)) => self.num_pending(),
+                None => {
+                    log::debug!("Pending requests are not responded before the close timeout");
+                    self.cancel_all().await
+                }
+            };
+            summary.flushed = num_pending.saturating_sub(summary.dropped);
+        }
 
-        #[cfg(not(any(feature = "ws_tokio", feature = "ws_async_std")))]
         self.broker
-            .send_async(broker::ClientBrokerItem::Stop(None))
+            .send_async(broker::ClientBrokerItem::Stopping)
             .await
-            .unwrap_or_else(|err| log::error!("{}", err));
+            .unwrap_or_else(|err| log::debug!("{}", err));
 
-        #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
-        if let Some(handle) = self.broker_handle.take() {
-            let _ = handle.await;
+        let handle = lock(&self.shared.broker_handle).take();
+        if let Some(mut handle) = handle {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if timeout(remaining, &mut handle).await.is_none() {
+                log::debug!("Server does not close the connection before the close timeout");
+                self.broker
+                    .send_async(broker::ClientBrokerItem::Stop(None))
+                    .await
+                    .unwrap_or_else(|err| log::debug!("{}", err));
+                // the tasks stop without waiting for the server anymore
+                if timeout(self.close_timeout, handle).await.is_none() {
+                    log::error!("Client tasks do not stop after the close timeout");
+                }
+            }
         }
+        summary
     }
 }
 
+/// The error of a call that is made after the broker is dropped, which is when the
+/// connection is dropped as well
+#[cfg(any(
+    feature = "docs",
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+fn broker_disconnected() -> Error {
+    Error::IoError(std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "Cannot connect to client side broker",
+    ))
+}
+
+/// Returns `None` if `fut` is not done within `duration`
+#[cfg(any(
+    feature = "docs",
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+async fn timeout<F: std::future::Future>(duration: Duration, fut: F) -> Option<F::Output> {
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    let result = ::tokio::time::timeout(duration, fut).await;
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    let result = ::async_std::future::timeout(duration, fut).await;
+    result.ok()
+}
+
 // =============================================================================
 // Public functions
 // =============================================================================
@@ -379,9 +983,10 @@ cfg_if! {
         all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
         all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
     ))] {
-        use std::sync::atomic::Ordering;
-
-        use crate::{codec::split::SplittableCodec};
+        use crate::{
+            codec::{content_type::CONTENT_TYPE_KEY, split::SplittableCodec, ContentType},
+            health::{HealthStatus, DEFAULT_HEALTH_CHECK_TIMEOUT, HEALTH_SERVICE},
+        };
 
         impl<AckMode> Client<AckMode> {
             /// Sets the default timeout duration for this client
@@ -441,7 +1046,7 @@ cfg_if! {
                 args: Req,
             ) -> Result<Res, Error>
             where
-                Req: serde::Serialize + Send + Sync + 'static,
+                Req: serde::Serialize + Send + Sync,
                 Res: serde::de::DeserializeOwned + Send + 'static,
             {
                 let call = self.call(service_method, args);
@@ -471,6 +1076,9 @@ cfg_if! {
             /// `Call` can be cancelled by calling the `cancel()` function.
             /// The request will be sent in a background task.
             ///
+            /// The arguments are serialized before `call` returns, so they can be borrowed
+            /// (ie. `&str`, a slice or `&large_struct`) without cloning them.
+            ///
             /// Example
             ///
             /// ```rust
@@ -478,11 +1086,15 @@ cfg_if! {
             /// let call: Call<i32> = client.call("SomeService.echo_i32", 7i32);
             /// let reply: Result<i32, toy_rpc::Error> = call.await;
             ///
+            /// // Borrow the arguments
+            /// let name = String::from("toy-rpc");
+            /// let reply: Result<String, toy_rpc::Error> = client.call("SomeService.echo_str", name.as_str()).await;
+            ///
             /// // Cancel the call
-            /// let call: Call<()> = client.call("SomeService.infinite_loop", ());
+            /// let mut call: Call<()> = client.call("SomeService.infinite_loop", ());
             /// // cancel takes a reference
-            /// // .await on a canceled `Call` will return `Err(Error::Canceled(Some(id)))`
-            /// call.cancel();
+            /// // .await on a canceled `Call` will return `Err(Error::Canceled(id))`
+            /// call.cancel_detached();
             /// let reply = call.await;
             /// println!("This should be a Err(Error::Canceled) {:?}", reply);
             /// ```
@@ -490,41 +1102,436 @@ cfg_if! {
             #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
             pub fn call<Req, Res>(&self, service_method: impl ToString, args: Req) -> Call<Res>
             where
-                Req: serde::Serialize + Send + Sync + 'static,
+                Req: serde::Serialize + Send + Sync,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                self.call_with_metadata(service_method, args, RequestMetadata::new())
+            }
+
+            /// Invokes the named RPC function call without knowing the type of the response
+            ///
+            /// The `RawResponse` holds the body of the response as it is marshaled by the
+            /// codec, which can be deserialized later or passed on as it is. An error
+            /// response is still returned as an `Err`. See `RawResponse` for what can be done
+            /// with the bytes of each codec.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let response: RawResponse = client.call_raw("SomeService.echo_i32", 7i32).await?;
+            /// let reply: i32 = response.deserialize()?;
+            /// ```
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
+            pub fn call_raw<Req>(&self, service_method: impl ToString, args: Req) -> Call<RawResponse>
+            where
+                Req: serde::Serialize + Send + Sync,
+            {
+                self.start_call(service_method, args, RequestMetadata::new(), false, None, None)
+            }
+
+            /// Invokes the named RPC function call with arguments and a response that are
+            /// only known at runtime, ie. in a debugging tool or a proxy
+            ///
+            /// This is only available with the `serde_json` codec. With another codec, use
+            /// `call_raw` instead.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let reply: serde_json::Value = client
+            ///     .call_dynamic("SomeService.echo_i32", serde_json::json!(7))
+            ///     .await?;
+            /// ```
+            #[cfg(feature = "serde_json")]
+            #[cfg_attr(feature = "docs", doc(cfg(feature = "serde_json")))]
+            pub fn call_dynamic(
+                &self,
+                service_method: impl ToString,
+                args: serde_json::Value,
+            ) -> Call<serde_json::Value> {
+                self.call(service_method, args)
+            }
+
+            /// Invokes the named RPC function call asynchronously with key-value metadata,
+            /// which is available to the handler on the server through `Context::metadata()`
+            ///
+            /// The metadata is sent in the request header and only when it is not empty.
+            /// The interceptors of the client run after the metadata is attached, so they
+            /// can add to or override it.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let mut metadata = RequestMetadata::new();
+            /// metadata.insert("tenant".into(), "acme".into());
+            /// let reply: Result<i32, Error> = client
+            ///     .call_with_metadata("Arith.add", (1i32, 6i32), metadata)
+            ///     .await;
+            /// ```
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
+            pub fn call_with_metadata<Req, Res>(
+                &self,
+                service_method: impl ToString,
+                args: Req,
+                metadata: RequestMetadata,
+            ) -> Call<Res>
+            where
+                Req: serde::Serialize + Send + Sync,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                self.start_call(service_method, args, metadata, false, None, None)
+            }
+
+            /// Invokes the named RPC function call in a task of its own and returns a `Task`
+            ///
+            /// Unlike the join handles of the runtimes, the `Task` is the same type with
+            /// either runtime. `.await`ing the `Task` returns
+            /// `Result<Result<Res, toy_rpc::Error>, JoinError>`, and `Task::cancel()` aborts
+            /// the task and cancels the call.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let task: Task<i32> = client.spawn_task("SomeService.echo_i32", 7i32);
+            /// let reply: Result<i32, toy_rpc::Error> = task.await.unwrap();
+            /// ```
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
+            pub fn spawn_task<Req, Res>(&self, service_method: impl ToString, args: Req) -> Task<Res>
+            where
+                Req: serde::Serialize + Send + Sync,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                Task::spawn(self.call(service_method, args))
+            }
+
+            /// Invokes the named RPC function call with the arguments and the response
+            /// serialized with `content_type` instead of the codec of the connection
+            ///
+            /// The content type is sent in the request metadata, and the server
+            /// deserializes the arguments and serializes the response of this call with
+            /// it. Errors returned by the handler are still serialized with the codec of
+            /// the connection. This lets a service that returns large binary bodies use
+            /// `bincode` on a connection whose codec is ie. `serde_cbor`. A content type
+            /// other than its own is not supported by the `serde_json` codec, and the call
+            /// fails right away.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let blob: Vec<u8> = client
+            ///     .call_with_codec("Storage.get", "key", ContentType::Bincode)
+            ///     .await?;
+            /// ```
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
+            pub fn call_with_codec<Req, Res>(
+                &self,
+                service_method: impl ToString,
+                args: Req,
+                content_type: ContentType,
+            ) -> Call<Res>
+            where
+                Req: serde::Serialize + Send + Sync,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                let mut metadata = RequestMetadata::new();
+                metadata.insert(CONTENT_TYPE_KEY.into(), content_type.as_str().into());
+                self.start_call(service_method, args, metadata, false, None, None)
+            }
+
+            /// Invokes the named RPC function call that responds with a stream of items,
+            /// and returns the items as a `Stream`
+            ///
+            /// The handler sends the items with `Context::send_item` or
+            /// `Context::send_stream`, and the stream ends when the handler returns. An
+            /// error returned by the handler is the last item of the stream. The handler
+            /// should return `Result<(), E>`, since the `Ok` value is not passed to the
+            /// caller. The timeout of the client applies to the whole stream.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let mut stream: CallStream<u32> = client.call_stream("Counter.count", 3u32);
+            /// while let Some(item) = stream.next().await {
+            ///     println!("{:?}", item); // Ok(0), Ok(1), Ok(2)
+            /// }
+            /// ```
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
+            pub fn call_stream<Req, Res>(&self, service_method: impl ToString, args: Req) -> CallStream<Res>
+            where
+                Req: serde::Serialize + Send + Sync,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                let (tx, rx) = flume::unbounded();
+                let call = self.start_call(service_method, args, RequestMetadata::new(), false, Some(tx), None);
+                CallStream::new(call, rx)
+            }
+
+            /// Opens a bidirectional stream with the named RPC function, and returns a
+            /// `StreamSender` to send items to the handler and a `CallStream` of the items
+            /// sent by the handler
+            ///
+            /// `args` is the argument of the handler, which takes the stream of items sent
+            /// by the client with `Context::incoming` and sends its own items with
+            /// `Context::send_item`. The client closes its side of the stream by closing or
+            /// dropping the `StreamSender`, and the stream ends in both directions when the
+            /// handler returns. The timeout of the client applies to the whole stream.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let (mut tx, mut rx): (StreamSender<u32>, CallStream<u32>) =
+            ///     client.open_stream("Echo.double", ());
+            /// tx.send(1).await?;
+            /// assert_eq!(rx.next().await.unwrap()?, 2);
+            /// tx.close().await?;
+            /// ```
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))))]
+            #[cfg_attr(feature = "docs", doc(cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))))]
+            pub fn open_stream<Req, Item, Res>(
+                &self,
+                service_method: impl ToString,
+                args: Req,
+            ) -> (StreamSender<Item>, CallStream<Res>)
+            where
+                Req: serde::Serialize + Send + Sync,
+                Item: serde::Serialize + Send + Sync + 'static,
                 Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                let (tx, rx) = flume::unbounded();
+                let call: Call<()> = self.start_call(service_method, args, RequestMetadata::new(), true, Some(tx), None);
+                let sender = StreamSender::new(call.id(), self.broker.clone());
+                (sender, CallStream::new(call, rx))
+            }
+
+            fn start_call<Req, Res>(
+                &self,
+                service_method: impl ToString,
+                args: Req,
+                metadata: RequestMetadata,
+                opens_stream: bool,
+                items: Option<flume::Sender<Box<InboundBody>>>,
+                timeout: Option<Duration>,
+            ) -> Call<Res>
+            where
+                Req: serde::Serialize + Send + Sync,
+                Res: Send + 'static,
             {
                 // Prepare RPC request
                 let id = self.count.fetch_add(1, Ordering::Relaxed);
-                let service_method = service_method.to_string();
-                let duration = match self.next_timeout.swap(None) {
+                let mut header = interceptor::RequestHeader::new(id, service_method.to_string());
+                *header.metadata_mut() = metadata;
+                if self.trace_ids && !header.metadata().contains_key(TRACE_ID_KEY) {
+                    current_trace_id().insert_into(header.metadata_mut());
+                }
+                interceptor::apply(&self.interceptors, &mut header, &args);
+                let observer = match self.interceptors.is_empty() {
+                    true => None,
+                    false => Some(interceptor::Observer::new(self.interceptors.clone(), header.clone())),
+                };
+                let (service_method, metadata) = header.into_parts();
+                // a timeout of the call itself leaves the one set for the next call
+                let duration = match timeout.or_else(|| self.next_timeout.swap(None)) {
                     Some(dur) => dur,
                     None => self.default_timeout.clone()
                 };
-                let body = Box::new(args) as Box<OutboundBody>;
                 let (resp_tx, resp_rx) = oneshot::channel();
+                let stats = Arc::new(CallCounters::default());
+                let content_type = match ContentType::from_metadata(&metadata) {
+                    Ok(content_type) => content_type,
+                    Err(err) => {
+                        return Call::<Res>::with_error(id, self.broker.clone(), resp_rx, err)
+                            .with_observer(observer)
+                    }
+                };
+                let marshal = |body: &(dyn erased_serde::Serialize + Send + Sync)| match content_type {
+                    Some(content_type) => content_type.marshal(body),
+                    None => (self.marshal)(body),
+                };
+                // the arguments are serialized here, so only bytes go through the broker
+                let body = if self.canonical_serialization {
+                    crate::codec::to_canonical_value(&args)
+                        .and_then(|value| marshal(&value))
+                } else {
+                    marshal(&args)
+                };
+                let body = match body {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let err = Error::ParseError(err);
+                        return Call::<Res>::with_error(id, self.broker.clone(), resp_rx, err)
+                            .with_observer(observer)
+                    }
+                };
 
-                if let Err(err) = self.broker.send(
-                    ClientBrokerItem::Request{
+                let request = {
+                    let stats = stats.clone();
+                    move |permit, duration| ClientBrokerItem::Request(OutgoingRequest {
                         id,
                         service_method,
                         duration,
+                        metadata,
+                        opens_stream,
                         body,
                         resp_tx,
+                        stats,
+                        items,
+                        permit: Some(permit),
+                    })
+                };
+                match self.in_flight.try_acquire() {
+                    Some(permit) => {
+                        if let Err(err) = self.broker.send(request(permit, duration)) {
+                            log::error!("{}", err);
+                            return Call::<Res>::with_error(id, self.broker.clone(), resp_rx, broker_disconnected())
+                                .with_observer(observer)
+                        }
+                        Call::<Res>::new(id, self.broker.clone(), resp_rx, stats)
+                            .with_observer(observer)
+                            .with_content_type(content_type)
+                    }
+                    None => {
+                        // the request is sent once the `Call` is polled and a slot is free
+                        log::debug!("Max number of in-flight requests reached, request {} waits for a slot", id);
+                        let in_flight = self.in_flight.clone();
+                        let broker = self.broker.clone();
+                        let admission = async move {
+                            // the time spent waiting counts towards the timeout of the call
+                            let started = std::time::Instant::now();
+                            let permit = match timeout(duration, in_flight.acquire()).await {
+                                Some(permit) => permit,
+                                None => return Err(Error::Timeout(id)),
+                            };
+                            let duration = duration.saturating_sub(started.elapsed());
+                            broker.send_async(request(permit, duration)).await.map_err(|err| {
+                                log::error!("{}", err);
+                                broker_disconnected()
+                            })
+                        };
+                        Call::<Res>::new(id, self.broker.clone(), resp_rx, stats)
+                            .with_observer(observer)
+                            .with_content_type(content_type)
+                            .with_admission(admission)
+                    }
+                }
+            }
+
+            /// Pings the built-in health-check service of the server, which is registered
+            /// with `ServerBuilder::enable_health_check`, and returns `Ok(())` if it answers
+            /// within `DEFAULT_HEALTH_CHECK_TIMEOUT`
+            ///
+            /// The ping is a regular request, so it goes through the same connection and
+            /// codec as the other calls. It fails with `Error::ServiceNotFound` if the
+            /// health check is not enabled on the server.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// if let Err(err) = client.health_check().await {
+            ///     println!("Server is unhealthy: {}", err);
+            /// }
+            /// ```
+            pub async fn health_check(&self) -> Result<(), Error> {
+                let service_method = format!("{}.ping", HEALTH_SERVICE);
+                let call: Call<HealthStatus> = self.start_call(
+                    service_method,
+                    (),
+                    RequestMetadata::new(),
+                    false,
+                    None,
+                    Some(DEFAULT_HEALTH_CHECK_TIMEOUT),
+                );
+                call.await.map(|_| ())
+            }
+
+            /// Creates a batch of calls that are sent out together, so the whole batch
+            /// takes a single round trip. The results are returned in the same order
+            /// as the calls are pushed.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let replies: Vec<Result<i32, Error>> = client
+            ///     .batch()
+            ///     .push("Arith.add", (1i32, 2i32))
+            ///     .push("Arith.multiply", (3i32, 4i32))
+            ///     .send()
+            ///     .await;
+            /// ```
+            pub fn batch<Res>(&self) -> Batch<'_, AckMode, Res>
+            where
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                Batch::new(self)
+            }
+
+            /// Invokes the named RPC function and sends the request again if it fails
+            /// with an error that is retryable under `policy`, ie. a connection failure or
+            /// a timeout. Only use this with handlers that are safe to run more than once.
+            ///
+            /// Every attempt is a new request with its own message id, and the failed
+            /// attempt is no longer tracked by the client. If every attempt fails, the
+            /// error of the last one is returned as `Error::RetriesExhausted` with the
+            /// number of attempts. Errors that are not retryable, such as the ones
+            /// returned by the handler, are returned as they are.
+            ///
+            /// Example
+            ///
+            /// ```rust
+            /// let policy = RetryPolicy::exponential(3, Duration::from_millis(100));
+            /// let reply: Result<i32, Error> = client
+            ///     .call_with_retry("Arith.add", &(1i32, 6i32), policy)
+            ///     .await;
+            /// ```
+            pub async fn call_with_retry<Req, Res>(
+                &self,
+                service_method: impl ToString,
+                args: &Req,
+                policy: RetryPolicy,
+            ) -> Result<Res, Error>
+            where
+                Req: serde::Serialize + Send + Sync + ?Sized,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                let service_method = service_method.to_string();
+                let mut attempt = 1;
+                loop {
+                    let err = match self.call(service_method.clone(), args).await {
+                        Ok(res) => return Ok(res),
+                        Err(err) => err,
+                    };
+                    if !policy.is_retryable(&err) {
+                        return Err(err);
+                    }
+                    if attempt >= policy.max_attempts() {
+                        return match attempt {
+                            1 => Err(err),
+                            attempts => Err(Error::RetriesExhausted { attempts, last: Box::new(err) }),
+                        };
                     }
-                ) {
-                    log::error!("{}", err);
-                    // If Broker is dropped, then the connection is dropped as well
-                    let err = Error::IoError(
-                        std::io::Error::new(
-                            std::io::ErrorKind::NotConnected,
-                            "Cannot connect to client side broker"
-                        )
-                    );
-                    return Call::<Res>::with_error(id, self.broker.clone(), resp_rx, err)
+                    log::debug!("Attempt {} of {} failed: {}", attempt, &service_method, err);
+                    retry::sleep(policy.backoff(attempt)).await;
+                    attempt += 1;
                 }
+            }
 
-                // Creates Call
-                Call::<Res>::new(id, self.broker.clone(), resp_rx)
+            /// Same as `call_with_retry` with the retry policy of the client, which is set
+            /// with `ClientBuilder::set_retry_policy`. The call is attempted only once if
+            /// no policy is set.
+            pub async fn call_with_default_retry<Req, Res>(
+                &self,
+                service_method: impl ToString,
+                args: &Req,
+            ) -> Result<Res, Error>
+            where
+                Req: serde::Serialize + Send + Sync + ?Sized,
+                Res: serde::de::DeserializeOwned + Send + 'static,
+            {
+                self.call_with_retry(service_method, args, self.retry_policy.clone()).await
             }
         }
     }
@@ -0,0 +1,224 @@
+//! Pool of connections to the same server
+//!
+//! Every connection of a `PooledClient` is a `Client` of its own, with its own
+//! background tasks and table of pending requests, so the frames of one connection are
+//! not held up by the writer of another. Each call is made on one connection, picked
+//! in a round-robin fashion or by the least number of pending requests, and the
+//! cancellation of the call goes to the same connection as the request.
+//!
+//! A connection that is closed, ie. by the server or a network failure, is taken out
+//! of the rotation, and the calls that were pending on it fail. New calls fail right
+//! away if none of the connections is open. `PooledClient::redial` replaces the closed
+//! connections. If the address of the pool resolves to several socket addresses, the
+//! connections are made to the first one that accepts them, and `redial` moves on to
+//! the next address when the current one stops accepting connections.
+//!
+//! # Example
+//!
+//! ```rust
+//! let pool = Client::dial_pool(addr, 4).await.unwrap();
+//! let reply: Result<i32, Error> = pool.call("Arith.add", (1i32, 6i32)).await;
+//! ```
+
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use futures::channel::oneshot;
+
+use crate::{error::Error, protocol::RequestMetadata};
+
+use super::{builder::connect_in_turn, proxy::Proxy, Call, Client, CloseSummary};
+
+#[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+use async_std::net::TcpStream;
+#[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+use tokio::net::TcpStream;
+
+/// Sets up a client over a new connection to the given address with the settings of
+/// the `ClientBuilder`
+pub(crate) type Connect<AckMode> =
+    Box<dyn Fn(TcpStream, SocketAddr) -> Client<AckMode> + Send + Sync>;
+
+/// How a `PooledClient` picks the connection of a call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// Takes the open connections in turn
+    #[default]
+    RoundRobin,
+    /// Takes the open connection with the least number of pending requests
+    LeastPending,
+}
+
+/// A client that spreads the calls over several connections to the same server,
+/// returned by `Client::dial_pool` and `ClientBuilder::dial_pool`
+pub struct PooledClient<AckMode> {
+    clients: Vec<Client<AckMode>>,
+    next: AtomicUsize,
+    balance: Balance,
+    /// All the addresses that the address of the pool resolves to
+    addrs: Vec<SocketAddr>,
+    /// Index of the address that new connections are made to
+    active: usize,
+    /// Proxy that the connections are tunneled through
+    proxy: Option<Proxy>,
+    /// Timeout of every new connection
+    connect_timeout: Option<Duration>,
+    connect: Connect<AckMode>,
+}
+
+impl<AckMode> PooledClient<AckMode> {
+    pub(crate) fn new(
+        clients: Vec<Client<AckMode>>,
+        addrs: Vec<SocketAddr>,
+        active: usize,
+        proxy: Option<Proxy>,
+        connect_timeout: Option<Duration>,
+        connect: Connect<AckMode>,
+    ) -> Self {
+        Self {
+            clients,
+            next: AtomicUsize::new(0),
+            balance: Balance::default(),
+            addrs,
+            active,
+            proxy,
+            connect_timeout,
+            connect,
+        }
+    }
+
+    /// Sets how the connection of each call is picked. The connections are taken in
+    /// turn by default.
+    pub fn set_balance(&mut self, balance: Balance) -> &Self {
+        self.balance = balance;
+        self
+    }
+
+    /// Sets the default timeout of the calls on every connection
+    pub fn set_default_timeout(&mut self, duration: Duration) -> &Self {
+        for client in self.clients.iter_mut() {
+            client.set_default_timeout(duration);
+        }
+        self
+    }
+
+    /// Address of the server that new connections are made to
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[self.active]
+    }
+
+    /// All the addresses that the address of the pool resolves to, in the order they
+    /// are tried
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Number of connections in the pool, including the closed ones
+    pub fn size(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Number of connections that are still open
+    pub fn num_connected(&self) -> usize {
+        self.clients
+            .iter()
+            .filter(|client| client.is_connected())
+            .count()
+    }
+
+    /// Number of requests that are waiting for a response on all the connections
+    pub fn num_pending(&self) -> usize {
+        self.clients.iter().map(|client| client.num_pending()).sum()
+    }
+
+    /// Picks the connection of the next call, or returns `None` if all the connections
+    /// are closed
+    pub fn next_client(&self) -> Option<&Client<AckMode>> {
+        match self.balance {
+            Balance::RoundRobin => {
+                let len = self.clients.len();
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len)
+                    .map(|i| &self.clients[(start + i) % len])
+                    .find(|client| client.is_connected())
+            }
+            Balance::LeastPending => self
+                .clients
+                .iter()
+                .filter(|client| client.is_connected())
+                .min_by_key(|client| client.num_pending()),
+        }
+    }
+
+    /// Replaces the connections that are closed with new connections, and returns the
+    /// number of connections that are replaced
+    ///
+    /// The new connections are made to the same server as before. If it does not accept
+    /// the connection, the other addresses are tried in turn, and the first one that
+    /// accepts becomes the address of the pool.
+    pub async fn redial(&mut self) -> Result<usize, Error> {
+        let mut num_replaced = 0;
+        for client in self.clients.iter_mut() {
+            if client.is_connected() {
+                continue;
+            }
+            let (stream, index) = connect_in_turn(
+                &self.addrs,
+                self.active,
+                self.proxy.as_ref(),
+                self.connect_timeout,
+            )
+            .await?;
+            self.active = index;
+            *client = (self.connect)(stream, self.addrs[index]);
+            num_replaced += 1;
+        }
+        Ok(num_replaced)
+    }
+
+    /// Closes all the connections, and returns the sum of their `CloseSummary`
+    pub async fn close(self) -> CloseSummary {
+        let mut summary = CloseSummary::default();
+        for client in self.clients {
+            summary += client.close().await;
+        }
+        summary
+    }
+
+    /// Invokes the named RPC function call on the next connection. See `Client::call`
+    pub fn call<Req, Res>(&self, service_method: impl ToString, args: Req) -> Call<Res>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Res: serde::de::DeserializeOwned + Send + 'static,
+    {
+        self.call_with_metadata(service_method, args, RequestMetadata::new())
+    }
+
+    /// Invokes the named RPC function call with key-value metadata on the next
+    /// connection. See `Client::call_with_metadata`
+    pub fn call_with_metadata<Req, Res>(
+        &self,
+        service_method: impl ToString,
+        args: Req,
+        metadata: RequestMetadata,
+    ) -> Call<Res>
+    where
+        Req: serde::Serialize + Send + Sync,
+        Res: serde::de::DeserializeOwned + Send + 'static,
+    {
+        match self.next_client() {
+            Some(client) => client.call_with_metadata(service_method, args, metadata),
+            None => {
+                let (_, done) = oneshot::channel();
+                let err = Error::IoError(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "All the connections of the pool are closed",
+                ));
+                Call::with_error(0, self.clients[0].broker.clone(), done, err)
+            }
+        }
+    }
+}
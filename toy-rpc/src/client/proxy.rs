@@ -0,0 +1,312 @@
+//! Connecting to the server through a proxy
+//!
+//! With a proxy set on the `ClientBuilder`, the client connects to the proxy and asks it
+//! to open a tunnel to the server, either with SOCKS5 (RFC 1928, with the
+//! username/password authentication of RFC 1929) or with an HTTP `CONNECT` request. The
+//! codec, or the TLS and WebSocket handshakes, then run over the tunnel as if it were a
+//! direct connection.
+//!
+//! `dial` and `dial_pool` resolve the address of the server locally and ask the proxy
+//! for the resolved IP addresses in turn. The WebSocket methods pass the host name of
+//! the url on to the proxy, which resolves it instead. A proxy that refuses to open the
+//! tunnel fails the dial with `Error::ProxyError`.
+//!
+//! # Example
+//!
+//! ```rust
+//! let proxy = Proxy::socks5("10.0.0.1:1080").with_auth("user", "password");
+//! let client = Client::builder()
+//!     .set_proxy(proxy)
+//!     .dial("rpc.internal:23333")
+//!     .await?;
+//! ```
+
+#![cfg_attr(
+    not(any(
+        all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+        all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+    )),
+    allow(dead_code, unused_imports)
+)]
+
+use std::net::IpAddr;
+
+use crate::error::Error;
+
+#[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+use async_std::net::TcpStream;
+#[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+use futures::{AsyncReadExt, AsyncWriteExt};
+#[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Longest response to a `CONNECT` request that is accepted from an HTTP proxy
+const MAX_HTTP_RESPONSE_LEN: usize = 8 * 1024;
+
+/// Protocol spoken with the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS5 `CONNECT` command
+    Socks5,
+    /// HTTP `CONNECT` request
+    HttpConnect,
+}
+
+/// A proxy that the client connects through
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    addr: String,
+    kind: ProxyKind,
+    auth: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// SOCKS5 proxy listening on `addr`, ie. "10.0.0.1:1080"
+    pub fn socks5(addr: impl ToString) -> Self {
+        Self {
+            addr: addr.to_string(),
+            kind: ProxyKind::Socks5,
+            auth: None,
+        }
+    }
+
+    /// HTTP proxy listening on `addr` that supports the `CONNECT` method
+    pub fn http_connect(addr: impl ToString) -> Self {
+        Self {
+            addr: addr.to_string(),
+            kind: ProxyKind::HttpConnect,
+            auth: None,
+        }
+    }
+
+    /// Authenticates with the proxy. This is the username/password method of SOCKS5,
+    /// or the basic `Proxy-Authorization` of an HTTP proxy.
+    pub fn with_auth(self, username: impl ToString, password: impl ToString) -> Self {
+        Self {
+            auth: Some((username.to_string(), password.to_string())),
+            ..self
+        }
+    }
+
+    /// Address of the proxy
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Protocol spoken with the proxy
+    pub fn kind(&self) -> ProxyKind {
+        self.kind
+    }
+}
+
+#[cfg(any(
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+impl Proxy {
+    /// Connects to the proxy and opens a tunnel to `host:port`, where `host` is an IP
+    /// address or a host name that is resolved by the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        // IPv6 addresses are bracketed in urls
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut stream = TcpStream::connect(self.addr.as_str()).await?;
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_handshake(&mut stream, host, port).await?,
+            ProxyKind::HttpConnect => self.http_handshake(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    async fn socks5_handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), Error> {
+        const VERSION: u8 = 0x05;
+        const NO_AUTH: u8 = 0x00;
+        const USERNAME_PASSWORD: u8 = 0x02;
+
+        let method = if self.auth.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(Error::ProxyError("Proxy does not speak SOCKS5".into()));
+        }
+        if reply[1] != method {
+            return Err(Error::ProxyError(
+                "Proxy does not accept the authentication method".into(),
+            ));
+        }
+
+        if let Some((username, password)) = &self.auth {
+            let username = socks5_field(username, "Username")?;
+            let password = socks5_field(password, "Password")?;
+            let mut msg = vec![0x01, username.len() as u8];
+            msg.extend_from_slice(username);
+            msg.push(password.len() as u8);
+            msg.extend_from_slice(password);
+            stream.write_all(&msg).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(Error::ProxyError("Proxy rejected the credentials".into()));
+            }
+        }
+
+        let mut msg = vec![VERSION, 0x01, 0x00];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                msg.push(0x01);
+                msg.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                msg.push(0x04);
+                msg.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let host = socks5_field(host, "Host name")?;
+                msg.push(0x03);
+                msg.push(host.len() as u8);
+                msg.extend_from_slice(host);
+            }
+        }
+        msg.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&msg).await?;
+
+        // VER, REP, RSV and ATYP, followed by the address bound by the proxy
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(Error::ProxyError(socks5_reply(reply[1]).into()));
+        }
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(Error::ProxyError("Invalid SOCKS5 reply".into())),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), Error> {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = &self.auth {
+            let credentials = format!("{}:{}", username, password);
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(credentials.as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // reads byte by byte so that nothing after the response is consumed
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE_LEN {
+                return Err(Error::ProxyError(
+                    "Response of the proxy is too long".into(),
+                ));
+            }
+            stream.read_exact(&mut byte).await?;
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(version), Some("200")) if version.starts_with("HTTP/1.") => Ok(()),
+            (Some(version), Some(_)) if version.starts_with("HTTP/1.") => Err(Error::ProxyError(
+                format!("Proxy responded with \"{}\"", status_line),
+            )),
+            _ => Err(Error::ProxyError("Proxy does not speak HTTP/1".into())),
+        }
+    }
+}
+
+/// Username, password and host name are sent with a length of one byte
+fn socks5_field<'a>(val: &'a str, name: &str) -> Result<&'a [u8], Error> {
+    match val.len() {
+        1..=255 => Ok(val.as_bytes()),
+        _ => Err(Error::ProxyError(format!(
+            "{} must be 1 to 255 bytes long",
+            name
+        ))),
+    }
+}
+
+fn socks5_reply(code: u8) -> &'static str {
+    match code {
+        0x01 => "General SOCKS server failure",
+        0x02 => "Connection not allowed by ruleset",
+        0x03 => "Network unreachable",
+        0x04 => "Host unreachable",
+        0x05 => "Connection refused",
+        0x06 => "TTL expired",
+        0x07 => "Command not supported",
+        0x08 => "Address type not supported",
+        _ => "Unknown SOCKS5 failure",
+    }
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_of_credentials() {
+        assert_eq!(
+            base64(b"Aladdin:open sesame"),
+            "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(b""), "");
+    }
+}
@@ -12,6 +12,7 @@ use std::task::{Context, Poll};
 
 use super::{broker::ClientBrokerItem, Client};
 use crate::pubsub::{AckModeAuto, AckModeManual, AckModeNone, SeqId};
+use crate::util::lock;
 use crate::{
     error::Error,
     protocol::{InboundBody, OutboundBody},
@@ -191,18 +192,23 @@ impl<AckMode> Client<AckMode> {
     /// Unsubscribe from a topic
     pub async fn unsubscribe<T: Topic + 'static>(&mut self) -> Result<(), Error> {
         let topic = T::topic();
-        if let Some(type_id) = self.subscriptions.get(&topic) {
-            if type_id == &TypeId::of::<T>() {
-                self.subscriptions.remove(&topic);
-                self.broker
-                    .send_async(ClientBrokerItem::Unsubscribe { topic })
-                    .await?;
-                return Ok(());
+        let is_subscribed = {
+            let mut subscriptions = lock(&self.shared.subscriptions);
+            let is_subscribed = subscriptions.get(&topic) == Some(&TypeId::of::<T>());
+            if is_subscribed {
+                subscriptions.remove(&topic);
             }
+            is_subscribed
+        };
+        if !is_subscribed {
+            return Err(Error::Internal(
+                format!("Not registered to topic: {}", topic).into(),
+            ));
         }
-        Err(Error::Internal(
-            format!("Not registered to topic: {}", topic).into(),
-        ))
+        self.broker
+            .send_async(ClientBrokerItem::Unsubscribe { topic })
+            .await?;
+        Ok(())
     }
 
     fn create_subscriber_rx<T: Topic + 'static>(
@@ -215,13 +221,22 @@ impl<AckMode> Client<AckMode> {
         };
         let topic = T::topic();
 
+        let mut subscriptions = lock(&self.shared.subscriptions);
         // Check if there is an existing subscriber
-        if self.subscriptions.contains_key(&topic) {
+        if subscriptions.contains_key(&topic) {
             return Err(Error::Internal(
                 "Only one local subscriber per topic is allowed".into(),
             ));
         }
-        self.subscriptions.insert(topic.clone(), TypeId::of::<T>());
+        let max_subscriptions = self
+            .connection_limits()
+            .and_then(|limits| limits.max_subscriptions);
+        if let Some(max) = max_subscriptions {
+            if subscriptions.len() >= max as usize {
+                return Err(Error::Busy);
+            }
+        }
+        subscriptions.insert(topic.clone(), TypeId::of::<T>());
 
         // Create new subscription
         if let Err(err) = self.broker.send(ClientBrokerItem::Subscribe {
@@ -239,8 +254,9 @@ impl<AckMode> Client<AckMode> {
         cap: Option<NonZeroUsize>,
     ) -> Result<Receiver<SubscriptionItem>, Error> {
         let topic = T::topic();
-        match self.subscriptions.get(&topic) {
-            Some(entry) => match &TypeId::of::<T>() == entry {
+        let type_id = lock(&self.shared.subscriptions).get(&topic).copied();
+        match type_id {
+            Some(entry) => match TypeId::of::<T>() == entry {
                 true => {
                     let (tx, rx) = match cap {
                         Some(n) => flume::bounded(n.get()),
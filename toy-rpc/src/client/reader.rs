@@ -4,37 +4,68 @@ use futures::Sink;
 use futures::SinkExt;
 
 use super::broker::ClientBrokerItem;
+use super::call::RawResponse;
+use crate::codec::codec_name;
 use crate::error::CodecError;
 use crate::error::IoError;
-use crate::protocol::{Header, InboundBody};
+use crate::protocol::{announced_codec, Header, InboundBody};
 use crate::pubsub::SeqId;
+use crate::stats::WriterStats;
+use crate::trace::{debug_event, debug_span, Instrument};
 use crate::{codec::CodecRead, Error};
+use std::sync::Arc;
 
 pub(crate) struct ClientReader<R> {
     pub reader: R,
+    pub stats: Arc<WriterStats>,
+    /// Whether the first message is expected to be the codec announcement of the server
+    pub negotiate_codec: bool,
 }
 
-#[async_trait]
-impl<R: CodecRead> brw::Reader for ClientReader<R> {
-    type BrokerItem = ClientBrokerItem;
-    type Ok = ();
-    type Error = Error;
+impl<R: CodecRead> ClientReader<R> {
+    /// Reads the header of the next message. The codec announcement of the server, if
+    /// any, is checked and skipped. `None` is returned if the codec doesn't match
+    async fn read_header<B>(&mut self, broker: &mut B) -> Option<Result<Header, CodecError>>
+    where
+        B: Sink<ClientBrokerItem, Error = flume::SendError<ClientBrokerItem>> + Send + Unpin,
+    {
+        if !self.negotiate_codec {
+            return self.reader.read_header().await;
+        }
+        self.negotiate_codec = false;
+        let payload = match self.reader.read_header_bytes().await? {
+            Ok(payload) => payload,
+            Err(err) => return Some(Err(err.into())),
+        };
+        match announced_codec(&payload) {
+            Some(codec) if codec == codec_name() => self.reader.read_header().await,
+            Some(codec) => {
+                let _ = broker
+                    .send(ClientBrokerItem::CodecMismatch(codec.to_string()))
+                    .await;
+                None
+            }
+            None => {
+                log::debug!("Server doesn't negotiate the codec");
+                Some(R::unmarshal(&payload).map_err(Into::into))
+            }
+        }
+    }
 
-    async fn op<B>(
-        &mut self,
-        mut broker: B,
-    ) -> Running<Result<Self::Ok, Self::Error>, Option<Self::Error>>
+    /// Reads the next message and sends it to the broker
+    async fn read_message<B>(&mut self, mut broker: B) -> Running<Result<(), Error>, Option<Error>>
     where
-        B: Sink<Self::BrokerItem, Error = flume::SendError<Self::BrokerItem>> + Send + Unpin,
+        B: Sink<ClientBrokerItem, Error = flume::SendError<ClientBrokerItem>> + Send + Unpin,
     {
-        if let Some(header) = self.reader.read_header().await {
+        let bytes_before = self.reader.bytes_read();
+        if let Some(header) = self.read_header(&mut broker).await {
             let header: Header = match header {
                 Ok(header) => header,
                 Err(err) => {
                     match err {
                         CodecError::IoError(e) => {
                             // pass back IoError
-                            match broker.send(ClientBrokerItem::Stop(Some(e))).await {
+                            match broker.send(ClientBrokerItem::Stop(Some(e.into()))).await {
                                 Ok(_) => return Running::Stop(None),
                                 Err(e) => return Running::Stop(Some(e.into())),
                             }
@@ -47,10 +78,14 @@ impl<R: CodecRead> brw::Reader for ClientReader<R> {
 
             match header {
                 Header::Response { id, is_ok } => {
+                    debug_event!(message_id = id, is_ok, "Received response");
                     // Ack will not come with a body
-                    let deserializer: Box<InboundBody> = match self.reader.read_body().await {
+                    let span = debug_span!("read_response", message_id = id);
+                    // the body is deserialized by the `Call`, once the type of the response
+                    // is known
+                    let body = match self.reader.read_bytes().instrument(span).await {
                         Some(res) => match res {
-                            Ok(de) => de,
+                            Ok(bytes) => RawResponse::new(bytes, R::from_bytes),
                             Err(err) => return Running::Continue(Err(err.into())),
                         },
                         None => {
@@ -58,18 +93,27 @@ impl<R: CodecRead> brw::Reader for ClientReader<R> {
                                 std::io::ErrorKind::UnexpectedEof,
                                 "Expecting response body",
                             );
-                            match broker.send(ClientBrokerItem::Stop(Some(err))).await {
+                            match broker.send(ClientBrokerItem::Stop(Some(err.into()))).await {
                                 Ok(_) => return Running::Stop(None),
                                 Err(e) => return Running::Stop(Some(e.into())),
                             }
                         }
                     };
+                    self.stats.metrics().add_response(is_ok);
                     let result = match is_ok {
-                        true => Ok(deserializer),
-                        false => Err(deserializer),
+                        true => Ok(body),
+                        false => Err(body),
                     };
 
-                    if let Err(err) = broker.send(ClientBrokerItem::Response { id, result }).await {
+                    let num_bytes = (self.reader.bytes_read() - bytes_before) as usize;
+                    if let Err(err) = broker
+                        .send(ClientBrokerItem::Response {
+                            id,
+                            result,
+                            num_bytes,
+                        })
+                        .await
+                    {
                         return Running::Continue(Err(err.into()));
                     }
                     Running::Continue(Ok(()))
@@ -85,7 +129,7 @@ impl<R: CodecRead> brw::Reader for ClientReader<R> {
                                 std::io::ErrorKind::UnexpectedEof,
                                 "Expecting Publish body",
                             );
-                            match broker.send(ClientBrokerItem::Stop(Some(err))).await {
+                            match broker.send(ClientBrokerItem::Stop(Some(err.into()))).await {
                                 Ok(_) => return Running::Stop(None),
                                 Err(e) => return Running::Stop(Some(e.into())),
                             }
@@ -102,6 +146,39 @@ impl<R: CodecRead> brw::Reader for ClientReader<R> {
                             .map_err(|err| err.into()),
                     )
                 }
+                Header::StreamItem { id } => {
+                    let item: Box<InboundBody> = match self.reader.read_body().await {
+                        Some(res) => match res {
+                            Ok(de) => de,
+                            Err(err) => return Running::Continue(Err(err.into())),
+                        },
+                        None => {
+                            let err = IoError::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "Expecting stream item body",
+                            );
+                            match broker.send(ClientBrokerItem::Stop(Some(err.into()))).await {
+                                Ok(_) => return Running::Stop(None),
+                                Err(e) => return Running::Stop(Some(e.into())),
+                            }
+                        }
+                    };
+                    Running::Continue(
+                        broker
+                            .send(ClientBrokerItem::StreamItem { id, item })
+                            .await
+                            .map_err(|err| err.into()),
+                    )
+                }
+                Header::Handshake(limits) => {
+                    let _ = self.reader.read_bytes().await;
+                    Running::Continue(
+                        broker
+                            .send(ClientBrokerItem::Handshake(limits))
+                            .await
+                            .map_err(|err| err.into()),
+                    )
+                }
                 Header::Ack(id) => {
                     let seq_id = SeqId::new(id);
                     Running::Continue(
@@ -120,3 +197,25 @@ impl<R: CodecRead> brw::Reader for ClientReader<R> {
         }
     }
 }
+
+#[async_trait]
+impl<R: CodecRead> brw::Reader for ClientReader<R> {
+    type BrokerItem = ClientBrokerItem;
+    type Ok = ();
+    type Error = Error;
+
+    async fn op<B>(
+        &mut self,
+        broker: B,
+    ) -> Running<Result<Self::Ok, Self::Error>, Option<Self::Error>>
+    where
+        B: Sink<Self::BrokerItem, Error = flume::SendError<Self::BrokerItem>> + Send + Unpin,
+    {
+        let running = self.read_message(broker).await;
+        self.stats.set_bytes_read(self.reader.bytes_read());
+        if let Running::Continue(Err(_)) = &running {
+            self.stats.metrics().add_error();
+        }
+        running
+    }
+}
@@ -0,0 +1,209 @@
+//! Retry policy of idempotent calls
+//!
+//! A call made with `Client::call_with_retry` is sent again when it fails with an
+//! error that the policy considers retryable, which by default are the transport-level
+//! failures (`Error::IoError` and `Error::MissedHeartbeats`) and timeouts
+//! (`Error::Timeout`). Errors returned by the handler are never retried by default.
+//! Every attempt is a new request with its own message id, and the failed attempt is no
+//! longer tracked by the client.
+//!
+//! # Example
+//!
+//! ```rust
+//! let policy = RetryPolicy::exponential(3, Duration::from_millis(100))
+//!     .with_max_backoff(Duration::from_secs(1));
+//! let reply: Result<i32, Error> = client
+//!     .call_with_retry("Arith.add", &(1i32, 6i32), policy)
+//!     .await;
+//! ```
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::error::Error;
+
+type Retryable = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// When and how often a failed call is sent again
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    multiplier: f64,
+    max_backoff: Option<Duration>,
+    jitter: bool,
+    retryable: Retryable,
+}
+
+impl Default for RetryPolicy {
+    /// The call is attempted only once
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl RetryPolicy {
+    /// The call is attempted only once
+    pub fn none() -> Self {
+        Self::fixed(1, Duration::from_secs(0))
+    }
+
+    /// The call is attempted at most `max_attempts` times, waiting `backoff` between
+    /// the attempts
+    pub fn fixed(max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff: backoff,
+            multiplier: 1.0,
+            max_backoff: None,
+            jitter: false,
+            retryable: Arc::new(is_transport_error),
+        }
+    }
+
+    /// The call is attempted at most `max_attempts` times. The wait before the second
+    /// attempt is `initial_backoff`, and it doubles before every following attempt.
+    pub fn exponential(max_attempts: u32, initial_backoff: Duration) -> Self {
+        Self {
+            multiplier: 2.0,
+            ..Self::fixed(max_attempts, initial_backoff)
+        }
+    }
+
+    /// Sets the longest wait between two attempts
+    pub fn with_max_backoff(self, max_backoff: Duration) -> Self {
+        Self {
+            max_backoff: Some(max_backoff),
+            ..self
+        }
+    }
+
+    /// Sets whether the waits are randomly shortened by up to half, so that clients
+    /// that failed at the same time don't retry at the same time
+    pub fn with_jitter(self, jitter: bool) -> Self {
+        Self { jitter, ..self }
+    }
+
+    /// Sets the predicate that decides whether a failed call is sent again
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// // retry when the server is too busy as well
+    /// let policy = RetryPolicy::fixed(3, Duration::from_millis(50))
+    ///     .with_retryable(|err| matches!(err, Error::IoError(_) | Error::Timeout(_) | Error::Busy));
+    /// ```
+    pub fn with_retryable<F>(self, retryable: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            retryable: Arc::new(retryable),
+            ..self
+        }
+    }
+
+    /// Maximum number of attempts, including the first one
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns `true` if a call that failed with `err` should be sent again
+    pub fn is_retryable(&self, err: &Error) -> bool {
+        (self.retryable)(err)
+    }
+
+    /// Returns the wait after the failed `attempt`, which starts from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let mut secs = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exp);
+        if let Some(max_backoff) = self.max_backoff {
+            secs = secs.min(max_backoff.as_secs_f64());
+        }
+        if self.jitter {
+            secs *= 0.5 + 0.5 * random_fraction();
+        }
+        Duration::from_secs_f64(secs.min(u32::MAX as f64))
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+/// Connection failures and timeouts are retried by default
+fn is_transport_error(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::IoError(_) | Error::MissedHeartbeats(_) | Error::Timeout(_)
+    )
+}
+
+/// A number in `[0, 1)` that is good enough to spread out the retries
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Waits before the next attempt
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+))]
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    ::tokio::time::sleep(duration).await;
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    ::async_std::task::sleep(duration).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy::exponential(5, Duration::from_millis(100))
+            .with_max_backoff(Duration::from_millis(300));
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn jitter_shortens_the_backoff_by_up_to_half() {
+        let policy = RetryPolicy::fixed(3, Duration::from_millis(100)).with_jitter(true);
+        for _ in 0..100 {
+            let backoff = policy.backoff(1);
+            assert!(backoff >= Duration::from_millis(49));
+            assert!(backoff <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn only_transport_errors_are_retried_by_default() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.max_attempts(), 1);
+        assert!(policy.is_retryable(&Error::Timeout(1)));
+        assert!(policy.is_retryable(&Error::IoError(std::io::ErrorKind::ConnectionReset.into())));
+        assert!(policy.is_retryable(&Error::MissedHeartbeats(3)));
+        assert!(!policy.is_retryable(&Error::ExecutionError("bad".into())));
+        assert!(!policy.is_retryable(&Error::MethodNotFound));
+    }
+}
@@ -0,0 +1,220 @@
+//! Streaming response and bidirectional stream of a RPC call
+
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use flume::{r#async::RecvStream, Sender};
+use futures::{Future, Sink, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+
+use crate::{message::MessageId, protocol::InboundBody, Error};
+
+use super::{broker::ClientBrokerItem, Call};
+
+/// Items of a streaming response, returned by `Client::call_stream`
+///
+/// The stream yields the items in the order they are sent by the handler, and ends
+/// when the handler returns. If the handler returns an error, or the call times out
+/// or is canceled, the error is the last item of the stream. Dropping the stream
+/// before it ends cancels the call.
+///
+/// # Example
+///
+/// ```rust
+/// let mut stream: CallStream<String> = client.call_stream("Logs.tail", 100u32);
+/// while let Some(line) = stream.next().await {
+///     println!("{}", line?);
+/// }
+/// ```
+pub struct CallStream<Res> {
+    /// Resolves when the response that terminates the stream is received
+    call: Option<Call<()>>,
+    items: RecvStream<'static, Box<InboundBody>>,
+    /// Outcome of the call, which is yielded after the remaining items
+    trailer: Option<Result<(), Error>>,
+    is_done: bool,
+    marker: PhantomData<fn() -> Res>,
+}
+
+impl<Res> CallStream<Res> {
+    pub(crate) fn new(call: Call<()>, items: flume::Receiver<Box<InboundBody>>) -> Self {
+        Self {
+            call: Some(call),
+            items: items.into_stream(),
+            trailer: None,
+            is_done: false,
+            marker: PhantomData,
+        }
+    }
+
+    /// Cancels the call without waiting for the acknowledgment from the server. The
+    /// stream ends with `Err(Error::Canceled(id))`
+    pub fn cancel(&mut self) {
+        if let Some(call) = self.call.as_mut() {
+            call.cancel_detached();
+        }
+    }
+
+    /// Gets the ID number of the call
+    pub fn id(&self) -> Option<MessageId> {
+        self.call.as_ref().map(|call| call.id())
+    }
+}
+
+impl<Res> Stream for CallStream<Res>
+where
+    Res: DeserializeOwned,
+{
+    type Item = Result<Res, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.is_done {
+            return Poll::Ready(None);
+        }
+        if let Some(call) = this.call.as_mut() {
+            if let Poll::Ready(res) = Pin::new(call).poll(cx) {
+                this.call = None;
+                this.trailer = Some(res);
+            }
+        }
+
+        // The items are forwarded before the response that terminates the stream, and
+        // the channel is closed once the response is received
+        match this.items.poll_next_unpin(cx) {
+            Poll::Ready(Some(mut item)) => {
+                let item = erased_serde::deserialize(&mut item)
+                    .map_err(|err| Error::ParseError(Box::new(err)));
+                return Poll::Ready(Some(item));
+            }
+            Poll::Ready(None) => {}
+            Poll::Pending => {
+                // an error ends the stream without waiting for the channel to close
+                if !matches!(this.trailer, Some(Err(_))) {
+                    return Poll::Pending;
+                }
+            }
+        }
+        if this.call.is_some() {
+            return Poll::Pending;
+        }
+        this.is_done = true;
+        match this.trailer.take() {
+            Some(Err(err)) => Poll::Ready(Some(Err(err))),
+            _ => Poll::Ready(None),
+        }
+    }
+}
+
+impl<Res> std::fmt::Debug for CallStream<Res> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallStream")
+            .field("id", &self.id())
+            .field("is_done", &self.is_done)
+            .finish()
+    }
+}
+
+/// Sends the items of the client on a bidirectional stream, returned by
+/// `Client::open_stream`
+///
+/// Closing or dropping the sender tells the handler that the client has no more items
+/// to send. Items sent after the call is finished are discarded.
+pub struct StreamSender<T> {
+    id: MessageId,
+    broker: Sender<ClientBrokerItem>,
+    is_closed: bool,
+    marker: PhantomData<fn(T)>,
+}
+
+impl<T> StreamSender<T> {
+    pub(crate) fn new(id: MessageId, broker: Sender<ClientBrokerItem>) -> Self {
+        Self {
+            id,
+            broker,
+            is_closed: false,
+            marker: PhantomData,
+        }
+    }
+
+    /// Gets the ID number of the call
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    /// Tells the handler that the client has no more items to send. Closing the sender
+    /// more than once does nothing.
+    pub fn close_stream(&mut self) {
+        if self.is_closed {
+            return;
+        }
+        self.is_closed = true;
+        if self
+            .broker
+            .send(ClientBrokerItem::OutgoingEnd(self.id))
+            .is_err()
+        {
+            log::debug!(
+                "Client broker is dropped before stream {} is closed",
+                self.id
+            );
+        }
+    }
+}
+
+impl<T> StreamSender<T>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    /// Sends an item to the handler without waiting
+    pub fn send_item(&self, item: T) -> Result<(), Error> {
+        if self.is_closed {
+            return Err(Error::Internal(
+                format!("Stream {} is already closed", self.id).into(),
+            ));
+        }
+        let item = ClientBrokerItem::OutgoingItem {
+            id: self.id,
+            body: Box::new(item),
+        };
+        self.broker.send(item).map_err(|_| {
+            Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Cannot connect to client side broker",
+            ))
+        })
+    }
+}
+
+impl<T> Sink<T> for StreamSender<T>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.send_item(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().close_stream();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Drop for StreamSender<T> {
+    fn drop(&mut self) {
+        self.close_stream();
+    }
+}
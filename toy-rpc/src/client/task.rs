@@ -0,0 +1,114 @@
+//! RPC calls that run in a task of their own
+//!
+//! `Client::spawn_task` returns a `Task`, which is the same type with either runtime, so
+//! the code that spawns calls doesn't depend on the join handle of the runtime.
+
+use cfg_if::cfg_if;
+use futures::{
+    future::{AbortHandle, Abortable, Aborted},
+    Future, FutureExt,
+};
+use std::{
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{util::panic_message, Error};
+
+cfg_if! {
+    if #[cfg(any(
+        feature = "docs",
+        all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+    ))] {
+        use ::tokio::task::{self, JoinHandle};
+    } else if #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))] {
+        use ::async_std::task::{self, JoinHandle};
+    }
+}
+
+/// What the spawned future returns, unless it is aborted or it panics
+type Outcome<Res> = Result<std::thread::Result<Result<Res, Error>>, Aborted>;
+
+/// The error of a `Task` that didn't run to completion
+#[derive(Debug, thiserror::Error)]
+pub enum JoinError {
+    /// The task is canceled with `Task::cancel`, or the runtime is shutting down
+    #[error("Task is canceled")]
+    Canceled,
+    /// The task panicked. The message of the panic is kept if it is a string
+    #[error("Task panicked: {0}")]
+    Panicked(String),
+}
+
+/// A RPC call that runs in a task of its own, which is returned by `Client::spawn_task`
+///
+/// The result is obtained by `.await`ing the `Task`, which returns
+/// `Result<Result<Res, toy_rpc::Error>, JoinError>`. The outer `Result` is whether the task
+/// ran to completion, and the inner one is the result of the call. Dropping a `Task`
+/// detaches it, and the call keeps running in the background.
+///
+/// # Example
+///
+/// ```rust
+/// let task: Task<i32> = client.spawn_task("Arith.add", (1i32, 6i32));
+/// let reply = task.await.unwrap(); // Ok(7)
+///
+/// // the call is canceled on the server as well
+/// let task: Task<()> = client.spawn_task("Arith.infinite_loop", ());
+/// task.cancel();
+/// let result = task.await; // Err(JoinError::Canceled)
+/// ```
+pub struct Task<Res> {
+    handle: JoinHandle<Outcome<Res>>,
+    abort: AbortHandle,
+}
+
+impl<Res: Send + 'static> Task<Res> {
+    pub(crate) fn spawn<F>(fut: F) -> Self
+    where
+        F: Future<Output = Result<Res, Error>> + Send + 'static,
+    {
+        let (abort, registration) = AbortHandle::new_pair();
+        // the panics are caught so that they are reported the same with both runtimes
+        let fut = Abortable::new(AssertUnwindSafe(fut).catch_unwind(), registration);
+        Self {
+            handle: task::spawn(fut),
+            abort,
+        }
+    }
+}
+
+impl<Res> Task<Res> {
+    /// Aborts the task, which drops the call and thus cancels it on the server like
+    /// dropping a pending `Call`. Nothing happens if the task is already finished.
+    pub fn cancel(&self) {
+        self.abort.abort();
+    }
+}
+
+impl<Res> Future for Task<Res> {
+    type Output = Result<Result<Res, Error>, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let handle = Pin::new(&mut self.handle);
+        // the panics are caught already, so tokio only fails if the task is canceled
+        // because the runtime is shutting down
+        #[cfg(any(
+            feature = "docs",
+            all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
+        ))]
+        let outcome = match futures::ready!(handle.poll(cx)) {
+            Ok(outcome) => outcome,
+            Err(_) => return Poll::Ready(Err(JoinError::Canceled)),
+        };
+        #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+        let outcome = futures::ready!(handle.poll(cx));
+
+        Poll::Ready(match outcome {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(panic)) => Err(JoinError::Panicked(panic_message(&*panic))),
+            Err(Aborted) => Err(JoinError::Canceled),
+        })
+    }
+}
@@ -0,0 +1,173 @@
+//! TLS settings of the client that are not covered by the `rustls` builders
+//!
+//! With certificate pinning, the client trusts the server by the SHA-256 digest of the
+//! public key (SPKI) in its certificate instead of a CA store. The certificate can be
+//! self-signed, and it is neither checked against the server name nor for expiry, but
+//! the server still has to prove that it holds the private key of the pinned public key.
+//! The pin stays the same when the certificate is renewed with the same key.
+//!
+//! The digest of a certificate can be computed with `spki_sha256`, or with
+//!
+//! ```sh
+//! openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256
+//! ```
+//!
+//! # Example
+//!
+//! ```rust
+//! let config = TlsOptions::new().pin_sha256(&PIN).client_config();
+//! let client = Client::dial_with_tls_config("10.0.0.5:23333", "localhost", config).await?;
+//! ```
+//!
+//! A server certificate that doesn't match any of the pins fails the handshake with an
+//! `Error::IoError` that says the pin doesn't match.
+
+use std::{fmt::Write, sync::Arc, time::SystemTime};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ServerName,
+};
+
+use crate::error::Error;
+
+/// TLS options of the client
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pins: Vec<[u8; 32]>,
+}
+
+impl TlsOptions {
+    /// Creates options without any pin
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the servers whose certificate has a public key with the SHA-256 digest
+    /// `digest`. More than one pin can be added, ie. to rotate the key of the server.
+    pub fn pin_sha256(mut self, digest: &[u8; 32]) -> Self {
+        self.pins.push(*digest);
+        self
+    }
+
+    /// Verifier of the server certificate that only accepts the pinned public keys.
+    /// A verifier without any pin rejects every certificate.
+    ///
+    /// This is for a `ClientConfig` that is otherwise customized, ie. with a client
+    /// certificate for mutual TLS.
+    ///
+    /// ```rust
+    /// let config = ClientConfig::builder()
+    ///     .with_safe_defaults()
+    ///     .with_custom_certificate_verifier(options.verifier())
+    ///     .with_single_cert(client_certs, client_key)?;
+    /// ```
+    pub fn verifier(&self) -> Arc<dyn ServerCertVerifier> {
+        Arc::new(PinnedVerifier {
+            pins: self.pins.clone(),
+        })
+    }
+
+    /// `ClientConfig` with the safe defaults of `rustls` that only accepts the pinned
+    /// public keys. It works with all the `*_with_tls_config` methods of the client.
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(self.verifier())
+            .with_no_client_auth()
+    }
+}
+
+/// SHA-256 digest of the public key (SPKI) of a DER encoded certificate
+pub fn spki_sha256(cert: &[u8]) -> Result<[u8; 32], Error> {
+    let spki =
+        spki(cert).ok_or_else(|| Error::ParseError("Invalid certificate encoding".into()))?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest.as_ref());
+    Ok(out)
+}
+
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest =
+            spki_sha256(&end_entity.0).map_err(|_| rustls::Error::InvalidCertificateEncoding)?;
+        if self.pins.iter().any(|pin| pin == &digest) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let mut hex = String::with_capacity(64);
+        for byte in digest.iter() {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        Err(rustls::Error::General(format!(
+            "Certificate pin mismatch, the SPKI SHA-256 of the server is {}",
+            hex
+        )))
+    }
+}
+
+const SEQUENCE: u8 = 0x30;
+/// Tag of the optional `[0] EXPLICIT Version` of `TBSCertificate`
+const VERSION: u8 = 0xa0;
+
+/// Finds the `SubjectPublicKeyInfo` of a certificate, which comes after the serial
+/// number, signature algorithm, issuer, validity and subject of `TBSCertificate`
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (tag, _, cert, _) = der_element(cert)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (tag, _, mut tbs, _) = der_element(cert)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    if let (VERSION, _, _, rest) = der_element(tbs)? {
+        tbs = rest;
+    }
+    for _ in 0..5 {
+        let (_, _, _, rest) = der_element(tbs)?;
+        tbs = rest;
+    }
+    match der_element(tbs)? {
+        (SEQUENCE, spki, _, _) => Some(spki),
+        _ => None,
+    }
+}
+
+/// Tag, whole element, contents and the rest of the input
+type DerElement<'a> = (u8, &'a [u8], &'a [u8], &'a [u8]);
+
+/// Splits the first DER element off `input`
+fn der_element(input: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header_len = input.len() - rest.len();
+    Some((tag, &input[..header_len + len], &rest[..len], &rest[len..]))
+}
@@ -10,60 +10,111 @@ cfg_if! {
         all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
         all(feature = "tokio_runtime", not(feature = "async_std_runtime"))
     ))] {
-        use std::time::Duration;
+        use std::time::{Duration, Instant};
         use async_trait::async_trait;
         use brw::Running;
+        use flume::Sender;
 
         use crate::{
-            Error, codec::CodecWrite,
+            Error, codec::{codec_name, CodecWrite},
             message::{
                 Metadata, CANCELLATION_TOKEN, CANCELLATION_TOKEN_DELIM, MessageId
             },
             protocol::{
-                Header, OutboundBody
+                codec_announcement, ConnectionLimits, Header, OutboundBody, RequestMetadata
             },
-            util:: GracefulShutdown
+            util:: GracefulShutdown,
+            stats::{CallCounters, WriterStats, WriterTimer},
+            trace::{debug_event, debug_span, error_event, Instrument},
         };
 
+        use super::broker::ClientBrokerItem;
+
         pub enum ClientWriterItem {
-            Request(MessageId, String, Duration, Box<OutboundBody>),
+            /// The `bool` is whether the request opens a bidirectional stream, the body is
+            /// already marshaled, and the last field is the time at which the request is put
+            /// into the writer channel
+            Request(MessageId, String, Duration, RequestMetadata, bool, Vec<u8>, Arc<CallCounters>, Instant),
+            /// An item sent on a bidirectional stream
+            StreamItem(MessageId, Box<OutboundBody>),
+            /// The end of the items sent on a bidirectional stream
+            StreamEnd(MessageId),
             Publish(MessageId, String, Arc<Vec<u8>>),
             Subscribe(MessageId, String),
             Unsubscribe(MessageId, String),
+            Handshake(ConnectionLimits),
+            /// Announces the codec of the client before the handshake
+            AnnounceCodec,
 
             // Client will respond to Publish message sent from the server
             // Thus needs to reply with the seq_id
             Ack(SeqId),
             Cancel(MessageId),
+            /// Gives the writer a way to schedule the flushes
+            Attach(Sender<ClientBrokerItem>),
+            /// Flushes the messages that are buffered by the flush policy of the codec
+            Flush,
             Stopping,
             Stop,
         }
 
         pub struct ClientWriter<W> {
-            pub writer: W
+            pub writer: W,
+            timer: WriterTimer,
+            broker: Option<Sender<ClientBrokerItem>>,
+            /// Whether a `Flush` is on its way back from the broker
+            flush_scheduled: bool,
         }
 
         impl<W: CodecWrite> ClientWriter<W> {
+            pub fn new(writer: W, stats: Arc<WriterStats>) -> Self {
+                Self {
+                    writer,
+                    timer: WriterTimer::new(stats),
+                    broker: None,
+                    flush_scheduled: false,
+                }
+            }
+
+            /// Makes sure the messages left in the write buffer are flushed once the
+            /// messages that are already queued are written. The `Flush` goes through the
+            /// broker, so it reaches the writer behind everything that is queued.
+            async fn schedule_flush(&mut self) -> Result<(), Error> {
+                if self.flush_scheduled {
+                    return Ok(())
+                }
+                if let Some(broker) = &self.broker {
+                    if broker.send_async(ClientBrokerItem::Flush).await.is_ok() {
+                        self.flush_scheduled = true;
+                        return Ok(())
+                    }
+                }
+                self.writer.flush().await.map_err(Into::into)
+            }
+
+            /// Writes the header and body of a request and returns the number of bytes written
             pub async fn write_request(
                 &mut self,
                 header: Header,
                 body: &(dyn erased_serde::Serialize + Send + Sync),
-            ) -> Result<(), Error> {
+            ) -> Result<usize, Error> {
                 let id = header.id();
-                self.writer.write_header(header).await?;
-                self.writer.write_body(id, body).await?;
-                Ok(())
+                let n = self.writer.write_header(header).await?;
+                let m = self.writer.write_body(id, body).await?;
+                Ok(n + m)
             }
 
-            pub async fn write_publish_item(
+            /// Writes a header and a body that is already marshaled, and returns the number
+            /// of bytes written
+            pub async fn write_marshaled(
                 &mut self,
                 header: Header,
                 bytes: &[u8]
-            ) -> Result<(), Error> {
+            ) -> Result<usize, Error> {
                 let id = header.id();
-                self.writer.write_header(header).await?;
-                self.writer.write_body_bytes(id, bytes).await?;
-                Ok(())
+                let n = self.writer.write_header(header).await?;
+                let m = self.writer.write_body_bytes(id, bytes).await?;
+                Ok(n + m)
             }
         }
 
@@ -74,15 +125,39 @@ cfg_if! {
             type Error = Error;
 
             async fn op(&mut self, item: Self::Item) -> Running<Result<Self::Ok, Self::Error>, Option<Self::Error>> {
+                let enqueued_at = match &item {
+                    ClientWriterItem::Request(_, _, _, _, _, _, _, enqueued_at) => Some(*enqueued_at),
+                    _ => None,
+                };
+                let start = self.timer.start(enqueued_at);
                 let res = match item {
-                    ClientWriterItem::Request(id, service_method, duration, body) => {
-                        let header = Header::Request{id, service_method, timeout: duration};
+                    ClientWriterItem::Request(id, service_method, duration, metadata, opens_stream, body, stats, _) => {
+                        let span = debug_span!("write_request", message_id = id, service_method = %service_method);
+                        let header = match opens_stream {
+                            true => Header::StreamOpen { id, service_method, timeout: duration, metadata },
+                            false => Header::request(id, service_method, duration, metadata),
+                        };
                         log::debug!("{:?}", &header);
+                        let res = self.write_marshaled(header, &body).instrument(span).await;
+                        if let Ok(n) = &res {
+                            stats.set_request_bytes(*n);
+                            self.timer.stats().metrics().add_request();
+                        }
+                        res
+                    },
+                    ClientWriterItem::StreamItem(id, body) => {
+                        let header = Header::StreamItem { id };
                         self.write_request(header, &body).await
                     },
+                    ClientWriterItem::StreamEnd(id) => {
+                        let header = Header::StreamEnd(id);
+                        log::debug!("{:?}", &header);
+                        self.write_request(header, &()).await
+                    },
                     ClientWriterItem::Cancel(id) => {
                         let header = Header::Cancel(id);
                         log::debug!("{:?}", &header);
+                        debug_event!(message_id = id, "Writing cancellation");
                         let body: String =
                             format!("{}{}{}", CANCELLATION_TOKEN, CANCELLATION_TOKEN_DELIM, id);
                         let body = Box::new(body) as Box<OutboundBody>;
@@ -91,7 +166,7 @@ cfg_if! {
                     ClientWriterItem::Publish(id, topic, body) => {
                         let header = Header::Publish{id, topic};
                         log::debug!("{:?}", &header);
-                        self.write_publish_item(header, &body).await
+                        self.write_marshaled(header, &body).await
                     },
                     ClientWriterItem::Subscribe(id, topic) => {
                         let header = Header::Subscribe{id, topic};
@@ -103,6 +178,15 @@ cfg_if! {
                         log::debug!("{:?}", &header);
                         self.write_request(header, &()).await
                     },
+                    ClientWriterItem::Handshake(limits) => {
+                        let header = Header::Handshake(limits);
+                        log::debug!("{:?}", &header);
+                        self.write_request(header, &()).await
+                    },
+                    ClientWriterItem::AnnounceCodec => {
+                        self.writer.write_header_bytes(0, &codec_announcement(codec_name())).await
+                            .map_err(Into::into)
+                    },
                     ClientWriterItem::Ack(seq_id) => {
                         let header = Header::Ack(seq_id.0);
                         log::debug!("{:?}", &header);
@@ -110,21 +194,44 @@ cfg_if! {
                         self.writer.write_header(header).await
                             .map_err(Into::into)
                     },
+                    ClientWriterItem::Attach(broker) => {
+                        self.broker = Some(broker);
+                        Ok(0)
+                    },
+                    ClientWriterItem::Flush => {
+                        self.flush_scheduled = false;
+                        self.writer.flush().await.map(|_| 0).map_err(Into::into)
+                    },
                     ClientWriterItem::Stopping => {
-                        Ok(self.writer.close().await)
+                        self.writer.close().await;
+                        Ok(0)
                     },
                     ClientWriterItem::Stop => {
                         return Running::Stop(None)
                     }
                 };
+                let res = match res {
+                    Ok(n) if n > 0 && self.writer.flush_policy().is_buffered() => {
+                        self.schedule_flush().await.map(|_| n)
+                    },
+                    res => res,
+                };
+                let num_bytes = match &res {
+                    Ok(n) => *n,
+                    Err(_) => {
+                        self.timer.stats().metrics().add_error();
+                        0
+                    }
+                };
+                self.timer.end(start, num_bytes);
 
-                Running::Continue(res)
+                Running::Continue(res.map(|_| ()))
             }
 
             async fn handle_result(res: Result<Self::Ok, Self::Error>) -> Running<(), Option<Self::Error>> {
                 if let Err(err) = res {
-                    #[cfg(feature = "debug")]
                     log::error!("{:?}", err);
+                    error_event!(error = %err, "Error writing to the connection");
 
                     // Drop the writer if unable to write to connection
                     if let Error::IoError(_) = &err {
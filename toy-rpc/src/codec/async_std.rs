@@ -21,6 +21,7 @@ where
         Self {
             reader,
             writer,
+            flush_policy: FlushPolicy::default(),
             conn_type: PhantomData,
         }
     }
@@ -38,6 +39,34 @@ where
 
         Self::with_reader_writer(reader, writer)
     }
+
+    /// Creates a `Codec` with a stream whose read and write buffers have the given
+    /// capacities in bytes, instead of the default of 8 KiB each.
+    ///
+    /// The capacities are not limits on the size of the messages. A frame larger than the
+    /// read buffer is read in several reads, and a frame larger than the write buffer is
+    /// written to the stream directly. Larger buffers mostly save system calls when the
+    /// frames are large or many small frames are sent at once. With
+    /// `ServerBuilder::set_response_chunk_size`, a write buffer that is a bit larger than
+    /// the chunk size lets each chunk go out in one write.
+    pub fn with_capacity(stream: T, read_capacity: usize, write_capacity: usize) -> Self {
+        let (reader, writer) = stream.split();
+        let reader = BufReader::with_capacity(read_capacity, reader);
+        let writer = BufWriter::with_capacity(write_capacity, writer);
+
+        Self::with_reader_writer(reader, writer)
+    }
+
+    /// Uses the default capacities if `capacity` is `None`
+    #[cfg_attr(not(any(feature = "server", feature = "client")), allow(dead_code))]
+    pub(crate) fn with_buffer_capacity(stream: T, capacity: Option<(usize, usize)>) -> Self {
+        match capacity {
+            Some((read_capacity, write_capacity)) => {
+                Self::with_capacity(stream, read_capacity, write_capacity)
+            }
+            None => Self::new(stream),
+        }
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,645 @@
+//! Canonical (deterministic) serialization
+//!
+//! Serializing the same logical value twice does not necessarily yield the same bytes. The
+//! iteration order of a `HashMap` depends on its random state, and every codec writes map
+//! entries in iteration order, so two equal `HashMap`s are likely to be serialized
+//! differently.
+//!
+//! `canonical_bytes` serializes a value into an intermediate tree, sorts the entries of every
+//! map by the bytewise lexicographic order of the encoded keys, and then serializes the tree
+//! with the enabled codec. Everything other than the order of map entries is identical to
+//! serializing the value directly, so the output can be deserialized as usual.
+//!
+//! - `serde_cbor`: map keys are ordered as required by the deterministic encoding of RFC 8949
+//! (section 4.2.1)
+//! - `serde_json`: map keys are ordered by their encoded JSON strings, which is the order of
+//! the Unicode code points for keys that do not need escaping (RFC 8785 orders by UTF-16 code
+//! units, which only differs for characters outside of the Basic Multilingual Plane)
+//! - `serde_bincode` and `serde_rmp`: the encodings are deterministic except for the order of
+//! map entries, which is sorted as above
+
+use serde::ser::{self, Serialize, Serializer};
+use std::fmt;
+
+use super::{Codec, Marshal};
+use crate::error::ParseError;
+
+/// Serializes `value` with the enabled codec, and with the entries of every map sorted so
+/// that equal values always produce the same bytes.
+///
+/// This can be used to compute cache keys or signatures that agree with a client using
+/// `ClientBuilder::set_canonical_serialization(true)`.
+///
+/// # Example
+///
+/// ```rust
+/// let mut a = HashMap::new();
+/// a.insert("foo", 1);
+/// a.insert("bar", 2);
+/// let mut b = HashMap::new();
+/// b.insert("bar", 2);
+/// b.insert("foo", 1);
+/// assert_eq!(canonical_bytes(&a).unwrap(), canonical_bytes(&b).unwrap());
+/// ```
+pub fn canonical_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ParseError> {
+    let value = to_canonical_value(value)?;
+    marshal(&value)
+}
+
+/// Serializes `value` into a tree with all maps sorted
+pub(crate) fn to_canonical_value<T: Serialize + ?Sized>(
+    value: &T,
+) -> Result<CanonicalValue, ParseError> {
+    value
+        .serialize(ValueSerializer)
+        .map_err(|err| Box::new(err) as ParseError)
+}
+
+fn marshal<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ParseError> {
+    <Codec<(), (), ()> as Marshal>::marshal(&value)
+}
+
+/// The serde data model of a value with all maps sorted
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CanonicalValue {
+    Bool(bool),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    None,
+    Some(Box<CanonicalValue>),
+    Unit,
+    UnitStruct(&'static str),
+    UnitVariant(&'static str, u32, &'static str),
+    NewtypeStruct(&'static str, Box<CanonicalValue>),
+    NewtypeVariant(&'static str, u32, &'static str, Box<CanonicalValue>),
+    Seq(Vec<CanonicalValue>),
+    Tuple(Vec<CanonicalValue>),
+    TupleStruct(&'static str, Vec<CanonicalValue>),
+    TupleVariant(&'static str, u32, &'static str, Vec<CanonicalValue>),
+    Map(Vec<(CanonicalValue, CanonicalValue)>),
+    Struct(&'static str, Vec<(&'static str, CanonicalValue)>),
+    StructVariant(
+        &'static str,
+        u32,
+        &'static str,
+        Vec<(&'static str, CanonicalValue)>,
+    ),
+}
+
+impl Serialize for CanonicalValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{
+            SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+            SerializeTupleStruct, SerializeTupleVariant,
+        };
+
+        match self {
+            CanonicalValue::Bool(v) => serializer.serialize_bool(*v),
+            CanonicalValue::I8(v) => serializer.serialize_i8(*v),
+            CanonicalValue::I16(v) => serializer.serialize_i16(*v),
+            CanonicalValue::I32(v) => serializer.serialize_i32(*v),
+            CanonicalValue::I64(v) => serializer.serialize_i64(*v),
+            CanonicalValue::I128(v) => serializer.serialize_i128(*v),
+            CanonicalValue::U8(v) => serializer.serialize_u8(*v),
+            CanonicalValue::U16(v) => serializer.serialize_u16(*v),
+            CanonicalValue::U32(v) => serializer.serialize_u32(*v),
+            CanonicalValue::U64(v) => serializer.serialize_u64(*v),
+            CanonicalValue::U128(v) => serializer.serialize_u128(*v),
+            CanonicalValue::F32(v) => serializer.serialize_f32(*v),
+            CanonicalValue::F64(v) => serializer.serialize_f64(*v),
+            CanonicalValue::Char(v) => serializer.serialize_char(*v),
+            CanonicalValue::Str(v) => serializer.serialize_str(v),
+            CanonicalValue::Bytes(v) => serializer.serialize_bytes(v),
+            CanonicalValue::None => serializer.serialize_none(),
+            CanonicalValue::Some(v) => serializer.serialize_some(v),
+            CanonicalValue::Unit => serializer.serialize_unit(),
+            CanonicalValue::UnitStruct(name) => serializer.serialize_unit_struct(name),
+            CanonicalValue::UnitVariant(name, index, variant) => {
+                serializer.serialize_unit_variant(name, *index, variant)
+            }
+            CanonicalValue::NewtypeStruct(name, v) => serializer.serialize_newtype_struct(name, v),
+            CanonicalValue::NewtypeVariant(name, index, variant, v) => {
+                serializer.serialize_newtype_variant(name, *index, variant, v)
+            }
+            CanonicalValue::Seq(elems) => {
+                let mut seq = serializer.serialize_seq(Some(elems.len()))?;
+                for elem in elems {
+                    seq.serialize_element(elem)?;
+                }
+                seq.end()
+            }
+            CanonicalValue::Tuple(elems) => {
+                let mut tuple = serializer.serialize_tuple(elems.len())?;
+                for elem in elems {
+                    tuple.serialize_element(elem)?;
+                }
+                tuple.end()
+            }
+            CanonicalValue::TupleStruct(name, fields) => {
+                let mut tuple = serializer.serialize_tuple_struct(name, fields.len())?;
+                for field in fields {
+                    tuple.serialize_field(field)?;
+                }
+                tuple.end()
+            }
+            CanonicalValue::TupleVariant(name, index, variant, fields) => {
+                let mut tuple =
+                    serializer.serialize_tuple_variant(name, *index, variant, fields.len())?;
+                for field in fields {
+                    tuple.serialize_field(field)?;
+                }
+                tuple.end()
+            }
+            CanonicalValue::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            CanonicalValue::Struct(name, fields) => {
+                let mut s = serializer.serialize_struct(name, fields.len())?;
+                for (key, value) in fields {
+                    s.serialize_field(key, value)?;
+                }
+                s.end()
+            }
+            CanonicalValue::StructVariant(name, index, variant, fields) => {
+                let mut s =
+                    serializer.serialize_struct_variant(name, *index, variant, fields.len())?;
+                for (key, value) in fields {
+                    s.serialize_field(key, value)?;
+                }
+                s.end()
+            }
+        }
+    }
+}
+
+/// Error of serializing into a `CanonicalValue`
+#[derive(Debug)]
+pub(crate) struct CanonicalError(String);
+
+impl fmt::Display for CanonicalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CanonicalError {}
+
+impl ser::Error for CanonicalError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        CanonicalError(msg.to_string())
+    }
+}
+
+struct ValueSerializer;
+
+type Result<T, E = CanonicalError> = std::result::Result<T, E>;
+
+impl Serializer for ValueSerializer {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVec;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeFields;
+    type SerializeStructVariant = SerializeFields;
+
+    fn serialize_bool(self, v: bool) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::I8(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::I16(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::I32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::I64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::I128(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::U8(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::U16(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::U32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::U64(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::U128(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::Str(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::Some(Box::new(value.serialize(self)?)))
+    }
+
+    fn serialize_unit(self) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::Unit)
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::UnitStruct(name))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::UnitVariant(name, variant_index, variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::NewtypeStruct(
+            name,
+            Box::new(value.serialize(self)?),
+        ))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<CanonicalValue> {
+        Ok(CanonicalValue::NewtypeVariant(
+            name,
+            variant_index,
+            variant,
+            Box::new(value.serialize(self)?),
+        ))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(VecKind::Seq, len))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(VecKind::Tuple, Some(len)))
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(VecKind::TupleStruct(name), Some(len)))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec> {
+        Ok(SerializeVec::new(
+            VecKind::TupleVariant(name, variant_index, variant),
+            Some(len),
+        ))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<SerializeFields> {
+        Ok(SerializeFields {
+            variant: None,
+            name,
+            fields: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeFields> {
+        Ok(SerializeFields {
+            variant: Some((variant_index, variant)),
+            name,
+            fields: Vec::with_capacity(len),
+        })
+    }
+}
+
+enum VecKind {
+    Seq,
+    Tuple,
+    TupleStruct(&'static str),
+    TupleVariant(&'static str, u32, &'static str),
+}
+
+struct SerializeVec {
+    kind: VecKind,
+    elems: Vec<CanonicalValue>,
+}
+
+impl SerializeVec {
+    fn new(kind: VecKind, len: Option<usize>) -> Self {
+        Self {
+            kind,
+            elems: Vec::with_capacity(len.unwrap_or(0)),
+        }
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.elems.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> CanonicalValue {
+        match self.kind {
+            VecKind::Seq => CanonicalValue::Seq(self.elems),
+            VecKind::Tuple => CanonicalValue::Tuple(self.elems),
+            VecKind::TupleStruct(name) => CanonicalValue::TupleStruct(name, self.elems),
+            VecKind::TupleVariant(name, index, variant) => {
+                CanonicalValue::TupleVariant(name, index, variant, self.elems)
+            }
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVec {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        Ok(self.finish())
+    }
+}
+
+struct SerializeMap {
+    entries: Vec<(CanonicalValue, CanonicalValue)>,
+    next_key: Option<CanonicalValue>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.next_key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| CanonicalError("serialize_value called before serialize_key".into()))?;
+        self.entries.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        // sort by the encoded keys
+        let mut entries = self
+            .entries
+            .into_iter()
+            .map(|(key, value)| {
+                let encoded = marshal(&key).map_err(|err| CanonicalError(err.to_string()))?;
+                Ok((encoded, key, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(CanonicalValue::Map(
+            entries
+                .into_iter()
+                .map(|(_, key, value)| (key, value))
+                .collect(),
+        ))
+    }
+}
+
+struct SerializeFields {
+    variant: Option<(u32, &'static str)>,
+    name: &'static str,
+    fields: Vec<(&'static str, CanonicalValue)>,
+}
+
+impl SerializeFields {
+    fn push<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.fields.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn finish(self) -> CanonicalValue {
+        match self.variant {
+            Some((index, variant)) => {
+                CanonicalValue::StructVariant(self.name, index, variant, self.fields)
+            }
+            None => CanonicalValue::Struct(self.name, self.fields),
+        }
+    }
+}
+
+impl ser::SerializeStruct for SerializeFields {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        Ok(self.finish())
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeFields {
+    type Ok = CanonicalValue;
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<CanonicalValue> {
+        Ok(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::Serialize;
+
+    use super::*;
+    use crate::codec::Unmarshal;
+
+    #[derive(Serialize)]
+    struct Nested {
+        name: &'static str,
+        tags: HashMap<String, Vec<u32>>,
+        inner: Option<HashMap<u64, String>>,
+    }
+
+    fn nested(keys: &[u64]) -> Nested {
+        let mut tags = HashMap::new();
+        let mut inner = HashMap::new();
+        for &k in keys {
+            tags.insert(format!("tag-{}", k), vec![k as u32; (k % 3) as usize]);
+            inner.insert(k, k.to_string());
+        }
+        Nested {
+            name: "nested",
+            tags,
+            inner: Some(inner),
+        }
+    }
+
+    #[test]
+    fn hash_maps_serialize_to_the_same_bytes() {
+        let keys: Vec<u64> = (0..64).map(|i| (i * 7919) % 1000).collect();
+        let expected = canonical_bytes(&nested(&keys)).unwrap();
+
+        for i in 0..32 {
+            // every HashMap has its own random state, and the insertion order is rotated
+            let mut keys = keys.clone();
+            keys.rotate_left(i);
+            if i % 2 == 1 {
+                keys.reverse();
+            }
+            assert_eq!(canonical_bytes(&nested(&keys)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn canonical_bytes_round_trip() {
+        let mut map = HashMap::new();
+        for i in 0..32u32 {
+            map.insert(format!("key-{}", i), i);
+        }
+        let bytes = canonical_bytes(&map).unwrap();
+        let decoded: HashMap<String, u32> =
+            <Codec<(), (), ()> as Unmarshal>::unmarshal(&bytes).unwrap();
+        assert_eq!(decoded, map);
+
+        // a sorted map without any HashMap is serialized as is
+        let sorted: BTreeMap<u32, u32> = (0..10).map(|i| (i, i * 2)).collect();
+        assert_eq!(canonical_bytes(&sorted).unwrap(), marshal(&sorted).unwrap());
+    }
+}
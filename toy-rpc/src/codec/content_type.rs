@@ -0,0 +1,204 @@
+//! Content types that the body of a single call can be serialized with, regardless of
+//! the codec of the connection
+//!
+//! The content type of a call is sent in the request metadata under
+//! `CONTENT_TYPE_KEY`. The server deserializes the arguments and serializes the `Ok`
+//! response with the same content type. Error responses and every other message are
+//! still serialized with the codec of the connection.
+//!
+//! A body of another content type is carried as opaque bytes, which needs a transport
+//! that frames every message. The `serde_json` codec is line delimited over raw TCP and
+//! uses text messages on WebSocket, so it can only carry its own content type. The
+//! `http_actix_web` integration always uses the codec of the connection.
+
+use std::io::Cursor;
+
+use bincode::Options;
+use erased_serde as erased;
+
+use crate::error::{Error, ParseError};
+use crate::protocol::{InboundBody, RequestMetadata};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "serde_json")] {
+        use serde_json as json;
+    } else if #[cfg(feature = "content_type_json")] {
+        use content_type_json as json;
+    }
+}
+
+/// Key of the request metadata that carries the content type of the body
+pub const CONTENT_TYPE_KEY: &str = "__content_type";
+
+/// Serialization format of the body of a single call. See `Client::call_with_codec`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    /// `bincode` with the same options as the `serde_bincode` codec
+    Bincode,
+    /// `serde_json`, which is available with the `serde_json` codec or the
+    /// `content_type_json` feature
+    #[cfg(any(feature = "serde_json", feature = "content_type_json"))]
+    #[cfg_attr(
+        feature = "docs",
+        doc(cfg(any(feature = "serde_json", feature = "content_type_json")))
+    )]
+    Json,
+}
+
+impl ContentType {
+    /// Name of the content type as it is sent in the request metadata
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentType::Bincode => "bincode",
+            #[cfg(any(feature = "serde_json", feature = "content_type_json"))]
+            ContentType::Json => "json",
+        }
+    }
+
+    /// Parses the name of a content type, `None` if it is unknown or not enabled
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bincode" => Some(ContentType::Bincode),
+            #[cfg(any(feature = "serde_json", feature = "content_type_json"))]
+            "json" => Some(ContentType::Json),
+            _ => None,
+        }
+    }
+
+    /// Content type of the codec of the connection, if it is one of `ContentType`
+    fn native() -> Option<Self> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "serde_json")] {
+                Some(ContentType::Json)
+            } else if #[cfg(feature = "serde_bincode")] {
+                Some(ContentType::Bincode)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Returns `None` if the body is serialized with the codec of the connection anyway,
+    /// and an error if the codec of the connection cannot carry the content type
+    pub(crate) fn resolve(self) -> Result<Option<Self>, Error> {
+        if Self::native() == Some(self) {
+            return Ok(None);
+        }
+        if cfg!(feature = "serde_json") {
+            return Err(Error::Internal(
+                format!(
+                    "The serde_json codec cannot carry a body of content type {}",
+                    self.as_str()
+                )
+                .into(),
+            ));
+        }
+        Ok(Some(self))
+    }
+
+    /// Reads the content type of the body from the request metadata. `Ok(None)` means
+    /// the body is serialized with the codec of the connection.
+    pub(crate) fn from_metadata(metadata: &RequestMetadata) -> Result<Option<Self>, Error> {
+        match metadata.get(CONTENT_TYPE_KEY) {
+            Some(name) => match Self::from_name(name) {
+                Some(content_type) => content_type.resolve(),
+                None => Err(Error::InvalidArgument),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Serializes a body
+    pub(crate) fn marshal(
+        self,
+        body: &(dyn erased::Serialize + Send + Sync),
+    ) -> Result<Vec<u8>, ParseError> {
+        match self {
+            ContentType::Bincode => bincode_options().serialize(&body).map_err(Into::into),
+            #[cfg(any(feature = "serde_json", feature = "content_type_json"))]
+            ContentType::Json => json::to_vec(&body).map_err(Into::into),
+        }
+    }
+
+    /// The function that creates the deserializer of a body
+    pub(crate) fn from_bytes_fn(self) -> fn(Vec<u8>) -> Box<InboundBody> {
+        match self {
+            ContentType::Bincode => bincode_from_bytes,
+            #[cfg(any(feature = "serde_json", feature = "content_type_json"))]
+            ContentType::Json => json_from_bytes,
+        }
+    }
+}
+
+impl std::fmt::Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Same options as the `serde_bincode` codec
+fn bincode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new().with_varint_encoding()
+}
+
+fn bincode_from_bytes(buf: Vec<u8>) -> Box<InboundBody> {
+    let de = bincode::Deserializer::with_reader(Cursor::new(buf), bincode_options());
+    let de_owned = super::DeserializerOwned::new(de);
+    Box::new(<dyn erased::Deserializer>::erase(de_owned))
+}
+
+#[cfg(any(feature = "serde_json", feature = "content_type_json"))]
+fn json_from_bytes(buf: Vec<u8>) -> Box<InboundBody> {
+    let de = json::Deserializer::from_reader(Cursor::new(buf));
+    let de_owned = super::DeserializerOwned::new(de);
+    Box::new(<dyn erased::Deserializer>::erase(de_owned))
+}
+
+// The codec of the connection implements these already
+#[cfg(not(feature = "serde_bincode"))]
+impl<'de, R, O> serde::Deserializer<'de> for super::DeserializerOwned<bincode::Deserializer<R, O>>
+where
+    R: bincode::BincodeRead<'de>,
+    O: bincode::Options,
+{
+    type Error = <&'de mut bincode::Deserializer<R, O> as serde::Deserializer<'de>>::Error;
+
+    crate::macros::impl_inner_deserializer!();
+}
+
+#[cfg(all(feature = "content_type_json", not(feature = "serde_json")))]
+impl<'de, R> serde::Deserializer<'de> for super::DeserializerOwned<json::Deserializer<R>>
+where
+    R: json::de::Read<'de>,
+{
+    type Error = <&'de mut json::Deserializer<R> as serde::Deserializer<'de>>::Error;
+
+    crate::macros::impl_inner_deserializer!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_bincode() {
+        let bytes = ContentType::Bincode.marshal(&(7u32, "seven")).unwrap();
+        let mut de = (ContentType::Bincode.from_bytes_fn())(bytes);
+        let value: (u32, String) = erased::deserialize(&mut de).unwrap();
+        assert_eq!(value, (7, "seven".to_string()));
+    }
+
+    #[test]
+    fn unknown_content_type() {
+        let mut metadata = RequestMetadata::new();
+        metadata.insert(CONTENT_TYPE_KEY.into(), "xml".into());
+        assert!(matches!(
+            ContentType::from_metadata(&metadata),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            ContentType::from_metadata(&RequestMetadata::new()),
+            Ok(None)
+        ));
+    }
+}
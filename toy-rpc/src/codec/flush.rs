@@ -0,0 +1,47 @@
+//! When the messages written by a codec are flushed to the transport
+//!
+//! By default, every frame is flushed as soon as it is written, which keeps the latency
+//! of each message low but costs a write to the transport per frame. With the other
+//! policies, the frames are kept in the write buffer of the codec so that many small
+//! messages can go out in a few writes. The writers of the client and the server flush
+//! the buffer once they have written every message that is queued for the connection,
+//! so a message is never held back for longer than it takes to write the messages
+//! queued before it.
+//!
+//! The policy applies to the codecs over `AsyncRead` and `AsyncWrite` (ie. raw TCP and
+//! TLS). It is ignored by the WebSocket transport, which sends every message on its own.
+//!
+//! # Example
+//!
+//! ```rust
+//! let stream = TcpStream::connect(addr).await?;
+//! let codec = DefaultCodec::new(stream).with_flush_policy(FlushPolicy::Manual);
+//! let client = Client::with_codec(codec);
+//! ```
+
+/// When the messages written by a codec are flushed. See the module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Every frame is flushed as soon as it is written. This is the default.
+    EveryFrame,
+    /// The frames are only flushed by `CodecWrite::flush`, when the write buffer is
+    /// full, or when the connection is closed
+    Manual,
+    /// Every frame is flushed as far as the transport takes it without waiting. The
+    /// rest stays in the write buffer like with `Manual`, so the frames written while
+    /// the transport is busy are coalesced.
+    OnIdle,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy::EveryFrame
+    }
+}
+
+impl FlushPolicy {
+    /// Whether the frames can be left in the write buffer until `CodecWrite::flush`
+    pub fn is_buffered(&self) -> bool {
+        !matches!(self, FlushPolicy::EveryFrame)
+    }
+}
@@ -30,7 +30,8 @@ cfg_if! {
         use std::io::Cursor; // serde doesn't support AsyncRead
 
         use super::{
-            Codec, CodecRead, CodecWrite, DeserializerOwned, EraseDeserializer, Marshal, Unmarshal,
+            Codec, CodecRead, CodecWrite, DeserializerOwned, EraseDeserializer, FlushPolicy, Marshal,
+            Unmarshal,
         };
         use crate::error::ParseError;
         use crate::macros::impl_inner_deserializer;
@@ -50,6 +51,8 @@ cfg_if! {
         }
 
         impl<R, W, C> Marshal for Codec<R, W, C> {
+            const IS_TEXT: bool = true;
+
             fn marshal<S: serde::Serialize>(val: &S) -> Result<Vec<u8>, ParseError> {
                 serde_json::to_vec(val)
                     .map(|mut v| {
@@ -73,12 +73,34 @@ cfg_if! {
                             // EOF, probably client closed connection
                             return None;
                         }
+                        self.bytes_read += n as u64;
 
                         Some(Ok(buf.into_bytes()))
                     }
                     Err(err) => return Some(Err(err.into())),
                 }
             }
+
+            fn bytes_read(&self) -> u64 {
+                self.bytes_read
+            }
+        }
+
+        impl<W, C> CodecWriteHalf<W, C, ConnTypeReadWrite>
+        where
+            W: AsyncWrite + Send + Unpin,
+        {
+            /// Flushes a message that is just written according to the flush policy
+            async fn flush_with_policy(&mut self) -> Result<(), IoError> {
+                match self.flush_policy {
+                    FlushPolicy::EveryFrame => self.writer.flush().await,
+                    FlushPolicy::Manual => Ok(()),
+                    FlushPolicy::OnIdle => {
+                        use futures::FutureExt;
+                        self.writer.flush().now_or_never().unwrap_or(Ok(()))
+                    }
+                }
+            }
         }
 
         #[async_trait]
@@ -87,36 +109,44 @@ cfg_if! {
             W: AsyncWrite + Send + Unpin,
             C: Marshal + Send,
         {
-            async fn write_header<H>(&mut self, header: H) -> Result<(), CodecError>
+            async fn write_header<H>(&mut self, header: H) -> Result<usize, CodecError>
             where
                 H: serde::Serialize + Metadata + Send,
             {
                 let _ = header.id();
                 let buf = Self::marshal(&header)?;
 
-                let _ = self.writer.write(&buf).await?;
-                self.writer.flush().await?;
+                self.writer.write_all(&buf).await?;
+                self.flush_with_policy().await?;
 
-                Ok(())
+                Ok(buf.len())
             }
 
             async fn write_body(
                 &mut self,
                 _id: MessageId,
                 body: &(dyn erased::Serialize + Send + Sync),
-            ) -> Result<(), CodecError> {
+            ) -> Result<usize, CodecError> {
                 let buf = Self::marshal(&body)?;
 
-                let _ = self.writer.write(&buf).await?;
-                self.writer.flush().await?;
+                self.writer.write_all(&buf).await?;
+                self.flush_with_policy().await?;
+
+                Ok(buf.len())
+            }
+
+            async fn write_body_bytes(&mut self, _: MessageId, bytes: &[u8]) -> Result<usize, IoError> {
+                self.writer.write_all(bytes).await?;
+                self.flush_with_policy().await?;
+                Ok(bytes.len())
+            }
 
-                Ok(())
+            async fn flush(&mut self) -> Result<(), IoError> {
+                self.writer.flush().await
             }
 
-            async fn write_body_bytes(&mut self, _: MessageId, bytes: &[u8]) -> Result<(), IoError> {
-                let _ = self.writer.write(bytes).await?;
-                self.writer.flush().await?;
-                Ok(())
+            fn flush_policy(&self) -> FlushPolicy {
+                self.flush_policy
             }
         }
 
@@ -137,6 +167,10 @@ cfg_if! {
                     },
                     CodecReadHalf::<R, Self, ConnTypeReadWrite> {
                         reader: self.reader,
+                        frame_read_timeout: None,
+                        header_id: None,
+                        interleaved: Default::default(),
+                        bytes_read: 0,
                         marker: PhantomData,
                         conn_type: PhantomData
                     }
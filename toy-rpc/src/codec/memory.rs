@@ -0,0 +1,99 @@
+//! In-memory connection between a client and a server
+//!
+//! The two ends are connected with an in-process byte stream (`tokio::io::duplex` with
+//! `tokio_runtime`, and a pair of `piper` pipes with `async_std_runtime`) and use the
+//! `DefaultCodec`, so the whole request/response path is exercised without binding a
+//! port. This is meant for tests of handlers and cancellation, and is enabled by the
+//! `testing` feature.
+
+use cfg_if::cfg_if;
+use std::future::Future;
+
+use crate::{pubsub::AckModeNone, Client, Error, Server};
+
+/// Number of bytes that can be buffered in each direction of the connection
+const IN_MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+cfg_if! {
+    if #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))] {
+        fn duplex() -> (::tokio::io::DuplexStream, ::tokio::io::DuplexStream) {
+            ::tokio::io::duplex(IN_MEMORY_BUFFER_SIZE)
+        }
+    } else if #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))] {
+        use futures::{AsyncRead, AsyncWrite};
+        use std::{
+            io,
+            pin::Pin,
+            task::{Context, Poll},
+        };
+
+        /// One end of the connection, which reads from one pipe and writes to the other
+        struct DuplexStream {
+            reader: piper::Reader,
+            writer: piper::Writer,
+        }
+
+        fn duplex() -> (DuplexStream, DuplexStream) {
+            let (client_reader, server_writer) = piper::pipe(IN_MEMORY_BUFFER_SIZE);
+            let (server_reader, client_writer) = piper::pipe(IN_MEMORY_BUFFER_SIZE);
+            (
+                DuplexStream { reader: client_reader, writer: client_writer },
+                DuplexStream { reader: server_reader, writer: server_writer },
+            )
+        }
+
+        impl AsyncRead for DuplexStream {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.reader).poll_read(cx, buf)
+            }
+        }
+
+        impl AsyncWrite for DuplexStream {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.writer).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.writer).poll_flush(cx)
+            }
+
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.writer).poll_close(cx)
+            }
+        }
+    }
+}
+
+/// Connects a new `Client` to `server` over an in-memory byte stream
+///
+/// Returns the client and the future that serves the connection, which must be spawned
+/// or otherwise polled for the calls to be answered. The future resolves once the
+/// connection is closed, ie. when the client is closed or dropped.
+///
+/// # Example
+///
+/// ```rust
+/// let server = Server::builder().register(example_service).build();
+/// let (client, serving) = toy_rpc::codec::connect_in_memory(&server);
+/// tokio::spawn(serving);
+/// let reply: i32 = client.call("Arith.add", (1i32, 2i32)).await?;
+/// ```
+pub fn connect_in_memory(
+    server: &Server<AckModeNone>,
+) -> (
+    Client<AckModeNone>,
+    impl Future<Output = Result<(), Error>> + Send + 'static,
+) {
+    let (client_end, server_end) = duplex();
+    let server = server.clone();
+    let serving = async move { server.serve_stream(server_end).await };
+    (Client::with_stream(client_end), serving)
+}
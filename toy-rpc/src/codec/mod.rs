@@ -2,16 +2,25 @@
 //! for the `DefaultCodec`
 //! Default codec implementations are feature gated behind the following features
 //! `serde_bincode`, `serde_json`, `serde_cbor`, `serde_rmp`.
+//!
+//! Only one of the features can be enabled, so the codec is fixed when the crate is
+//! built. Peers can still check that they use the same codec when a connection is set
+//! up, see `ServerBuilder::negotiate_codec`. The body of a single call can still be
+//! serialized with another `ContentType` than the codec, see the `content_type` module.
 
 use async_trait::async_trait;
 use cfg_if::cfg_if;
 use erased_serde as erased;
 use std::marker::PhantomData;
+use std::time::Duration;
 
 use crate::error::{CodecError, IoError, ParseError};
 use crate::message::{MessageId, Metadata};
 use crate::protocol::InboundBody;
 
+pub mod flush;
+pub use flush::FlushPolicy;
+
 pub mod split;
 
 cfg_if! {
@@ -158,6 +167,90 @@ cfg_if! {
             )))
         )]
         pub mod rmp;
+
+        #[cfg(any(
+            feature = "serde_bincode",
+            feature = "serde_json",
+            feature = "serde_cbor",
+            feature = "serde_rmp",
+        ))]
+        mod canonical;
+        #[cfg(any(
+            feature = "serde_bincode",
+            feature = "serde_json",
+            feature = "serde_cbor",
+            feature = "serde_rmp",
+        ))]
+        pub mod content_type;
+        #[cfg(any(
+            feature = "serde_bincode",
+            feature = "serde_json",
+            feature = "serde_cbor",
+            feature = "serde_rmp",
+        ))]
+        pub use content_type::ContentType;
+        #[cfg(any(
+            feature = "serde_bincode",
+            feature = "serde_json",
+            feature = "serde_cbor",
+            feature = "serde_rmp",
+        ))]
+        pub use canonical::canonical_bytes;
+        #[cfg(all(
+            feature = "client",
+            any(
+                feature = "serde_bincode",
+                feature = "serde_json",
+                feature = "serde_cbor",
+                feature = "serde_rmp",
+            )
+        ))]
+        pub(crate) use canonical::to_canonical_value;
+
+        #[cfg(all(
+            feature = "testing",
+            feature = "server",
+            feature = "client",
+            any(
+                feature = "serde_bincode",
+                feature = "serde_json",
+                feature = "serde_cbor",
+                feature = "serde_rmp",
+            )
+        ))]
+        mod memory;
+        #[cfg(all(
+            feature = "testing",
+            feature = "server",
+            feature = "client",
+            any(
+                feature = "serde_bincode",
+                feature = "serde_json",
+                feature = "serde_cbor",
+                feature = "serde_rmp",
+            )
+        ))]
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "testing")))]
+        pub use memory::connect_in_memory;
+    }
+}
+
+/// Name of the codec enabled by the feature flags
+#[cfg_attr(
+    not(any(feature = "server", feature = "client", feature = "discovery")),
+    allow(dead_code)
+)]
+pub(crate) fn codec_name() -> &'static str {
+    cfg_if! {
+        if #[cfg(feature = "serde_json")] {
+            "json"
+        } else if #[cfg(feature = "serde_cbor")] {
+            "cbor"
+        } else if #[cfg(feature = "serde_rmp")] {
+            "rmp"
+        } else {
+            "bincode"
+        }
     }
 }
 
@@ -192,9 +285,21 @@ pub struct Reserved {}
 pub struct Codec<R, W, C> {
     reader: R,
     writer: W,
+    flush_policy: FlushPolicy,
     conn_type: PhantomData<C>,
 }
 
+impl<R, W, C> Codec<R, W, C> {
+    /// Sets when the messages written by the codec are flushed to the transport.
+    /// Every frame is flushed as soon as it is written by default. See `FlushPolicy`
+    pub fn with_flush_policy(self, policy: FlushPolicy) -> Self {
+        Self {
+            flush_policy: policy,
+            ..self
+        }
+    }
+}
+
 cfg_if! {
     if #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))] {
         use futures::stream::{SplitSink, SplitStream};
@@ -223,6 +328,7 @@ cfg_if! {
                 Self {
                     reader,
                     writer,
+                    flush_policy: FlushPolicy::default(),
                     conn_type: PhantomData,
                 }
             }
@@ -249,6 +355,7 @@ impl
         Self {
             reader,
             writer,
+            flush_policy: FlushPolicy::default(),
             conn_type: PhantomData,
         }
     }
@@ -283,6 +390,7 @@ where
         Self {
             reader,
             writer,
+            flush_policy: FlushPolicy::default(),
             conn_type: PhantomData,
         }
     }
@@ -316,6 +424,7 @@ where
         Self {
             reader,
             writer,
+            flush_policy: FlushPolicy::default(),
             conn_type: PhantomData,
         }
     }
@@ -350,13 +459,32 @@ pub trait CodecRead: Send + Unmarshal + EraseDeserializer {
 
     /// Reads the frame body as raw bytes
     async fn read_bytes(&mut self) -> Option<Result<Vec<u8>, IoError>>;
+
+    /// Reads the header of the message as raw bytes, ie. the codec announcement of the
+    /// peer, which is not marshaled with the codec
+    async fn read_header_bytes(&mut self) -> Option<Result<Vec<u8>, IoError>> {
+        self.read_bytes().await
+    }
+
+    /// Total number of bytes consumed from the transport so far, which includes the
+    /// framing of every message (ie. the magic and version bytes and the frame header)
+    fn bytes_read(&self) -> u64;
+
+    /// Sets the timeout after which a partially received frame closes the connection.
+    ///
+    /// This only affects the framed transport used over TCP and TLS. It is ignored
+    /// by the WebSocket transport and by the `serde_json` codec, which is line delimited.
+    fn set_frame_read_timeout(&mut self, _timeout: Option<Duration>) {}
 }
 
 /// A codec that can write the header and body of a message
+///
+/// All the write methods return the number of bytes written to the transport, which
+/// includes the framing (ie. the magic and version bytes and the frame header).
 #[async_trait]
 pub trait CodecWrite: Send + Marshal {
     /// Writes the header of the message
-    async fn write_header<H>(&mut self, header: H) -> Result<(), CodecError>
+    async fn write_header<H>(&mut self, header: H) -> Result<usize, CodecError>
     where
         H: serde::Serialize + Metadata + Send;
 
@@ -365,10 +493,57 @@ pub trait CodecWrite: Send + Marshal {
         &mut self,
         id: MessageId,
         body: &(dyn erased::Serialize + Send + Sync),
-    ) -> Result<(), CodecError>;
+    ) -> Result<usize, CodecError>;
 
     /// Writes body as raw bytes
-    async fn write_body_bytes(&mut self, id: MessageId, bytes: &[u8]) -> Result<(), IoError>;
+    async fn write_body_bytes(&mut self, id: MessageId, bytes: &[u8]) -> Result<usize, IoError>;
+
+    /// Writes a header as raw bytes, ie. the codec announcement, which is not marshaled
+    /// with the codec
+    async fn write_header_bytes(&mut self, id: MessageId, bytes: &[u8]) -> Result<usize, IoError> {
+        self.write_body_bytes(id, bytes).await
+    }
+
+    /// Flushes the messages that are left in the write buffer by the `FlushPolicy` of
+    /// the codec
+    async fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+
+    /// When the messages written are flushed to the transport. The writers of the client
+    /// and the server call `flush` once every queued message is written if the policy
+    /// is buffered.
+    fn flush_policy(&self) -> FlushPolicy {
+        FlushPolicy::EveryFrame
+    }
+
+    /// Whether the transport can send a body in multiple chunks with `write_body_chunk`.
+    ///
+    /// Only the framed transport used over TCP and TLS supports this.
+    fn supports_body_chunks(&self) -> bool {
+        false
+    }
+
+    /// Writes the `index`-th chunk of a body that is split into multiple frames. The
+    /// frames of other messages may be written between the chunks. The header of the
+    /// message is expected to be written right before the last chunk.
+    ///
+    /// The default implementation only supports bodies that are written in a single chunk.
+    async fn write_body_chunk(
+        &mut self,
+        id: MessageId,
+        index: usize,
+        chunk: &[u8],
+        is_last: bool,
+    ) -> Result<usize, IoError> {
+        if index == 0 && is_last {
+            return self.write_body_bytes(id, chunk).await;
+        }
+        Err(IoError::new(
+            std::io::ErrorKind::Unsupported,
+            "The transport does not support chunked bodies",
+        ))
+    }
 }
 
 cfg_if! {
@@ -422,10 +597,29 @@ cfg_if! {
 
 /// This trait should be implemented by serializer (Codec) to serialize messages into bytes
 pub trait Marshal {
+    /// Whether the marshaled bytes are UTF-8 text, ie. JSON. Such payloads are sent in
+    /// text messages on WebSocket instead of binary messages.
+    const IS_TEXT: bool = false;
+
     /// Marshals/serializes an object into `Vec<u8>`
     fn marshal<S: serde::Serialize>(val: &S) -> Result<Vec<u8>, ParseError>;
 }
 
+/// Marshals a type-erased body with the `Marshal` impl of a codec
+///
+/// The client keeps one of these instead of the type of its codec, so the arguments of
+/// a call are serialized right away in `Client::call` rather than boxed and sent
+/// through the broker first.
+pub(crate) type MarshalFn =
+    fn(&(dyn erased::Serialize + Send + Sync)) -> Result<Vec<u8>, ParseError>;
+
+/// The `MarshalFn` of the codec `C`
+pub(crate) fn marshal_erased<C: Marshal>(
+    body: &(dyn erased::Serialize + Send + Sync),
+) -> Result<Vec<u8>, ParseError> {
+    C::marshal(&body)
+}
+
 /// This trait should be implemented by deserializer (Codec) to deserialize messages from bytes
 pub trait Unmarshal {
     /// Unmarshals an object from bytes
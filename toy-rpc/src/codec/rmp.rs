@@ -31,18 +31,13 @@ cfg_if! {
 
         impl<R, W, C> Marshal for Codec<R, W, C> {
             fn marshal<S: serde::Serialize>(val: &S) -> Result<Vec<u8>, ParseError> {
-                let mut buf = Vec::new();
-                match val.serialize(&mut rmp_serde::Serializer::new(&mut buf)) {
-                    Ok(_) => Ok(buf),
-                    Err(e) => Err(e.into()),
-                }
+                rmp_serde::to_vec(val).map_err(|e| e.into())
             }
         }
 
         impl<R, W, C> Unmarshal for Codec<R, W, C> {
             fn unmarshal<'de, D: serde::Deserialize<'de>>(buf: &'de [u8]) -> Result<D, ParseError> {
-                let mut de = rmp_serde::Deserializer::new(buf);
-                serde::Deserialize::deserialize(&mut de).map_err(|e| e.into())
+                rmp_serde::from_slice(buf).map_err(|e| e.into())
             }
         }
 
@@ -55,3 +50,70 @@ cfg_if! {
         }
     }
 }
+
+#[cfg(all(
+    test,
+    feature = "serde_rmp",
+    not(any(
+        feature = "serde_json",
+        feature = "serde_cbor",
+        feature = "serde_bincode"
+    )),
+    feature = "tokio_runtime",
+    not(feature = "async_std_runtime")
+))]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::codec::{split::SplittableCodec, Codec, CodecRead, CodecWrite};
+    use crate::protocol::Header;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Inner {
+        id: u64,
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Nested {
+        name: String,
+        inner: Option<Inner>,
+        scores: BTreeMap<String, i16>,
+        pair: (u8, f64),
+    }
+
+    #[test]
+    fn nested_struct_round_trip() {
+        let rt = ::tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (a, b) = ::tokio::io::duplex(1024);
+            let (mut writer, _) = Codec::new(a).split();
+            let (_, mut reader) = Codec::new(b).split();
+
+            let mut scores = BTreeMap::new();
+            scores.insert("a".to_string(), -300);
+            scores.insert("b".to_string(), i16::MAX);
+            let body = Nested {
+                name: "nested".to_string(),
+                inner: Some(Inner {
+                    id: u64::MAX,
+                    tags: vec!["x".to_string(), "y".to_string()],
+                }),
+                scores,
+                pair: (7, 0.5),
+            };
+
+            let header = Header::Response { id: 3, is_ok: true };
+            writer.write_header(header).await.unwrap();
+            writer.write_body(3, &body).await.unwrap();
+
+            let header: Header = reader.read_header().await.unwrap().unwrap();
+            assert!(matches!(header, Header::Response { id: 3, is_ok: true }));
+            let mut de = reader.read_body().await.unwrap().unwrap();
+            let received: Nested = erased_serde::deserialize(&mut de).unwrap();
+            assert_eq!(received, body);
+        });
+    }
+}
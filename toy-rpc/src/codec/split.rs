@@ -2,7 +2,9 @@
 
 #[cfg(any(feature = "tokio_runtime", feature = "async_std_runtime"))]
 use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
+use std::time::Duration;
 
 use crate::util::GracefulShutdown;
 
@@ -11,13 +13,38 @@ use super::*;
 #[allow(dead_code)]
 pub(crate) struct CodecReadHalf<R, C, CT> {
     pub reader: R,
+    /// Idle-progress timeout of reading a frame
+    pub frame_read_timeout: Option<Duration>,
+    /// Id of the message whose header frame was read last
+    pub header_id: Option<MessageId>,
+    /// Total number of bytes read
+    pub bytes_read: u64,
+    /// Frames that arrived ahead of the message that is being read
+    pub interleaved: Interleaved,
     pub marker: PhantomData<C>,
     pub conn_type: PhantomData<CT>,
 }
 
+/// Frames that arrive ahead of the message that is being read.
+///
+/// The chunks of a large body may be interleaved with the frames of other messages
+/// (see `ServerBuilder::set_response_chunk_size`), so the frames of other messages that
+/// arrive while a body is read are kept here until they are read.
+#[derive(Debug, Default)]
+pub(crate) struct Interleaved {
+    /// Header frames that are not read yet
+    pub headers: VecDeque<(MessageId, Vec<u8>)>,
+    /// Bodies whose chunks are still arriving
+    pub partial: HashMap<MessageId, Vec<u8>>,
+    /// Bodies that are complete but not read yet
+    pub complete: HashMap<MessageId, Vec<u8>>,
+}
+
 #[allow(dead_code)]
 pub(crate) struct CodecWriteHalf<W, C, CT> {
     pub writer: W,
+    /// When the frames written are flushed to the transport
+    pub flush_policy: FlushPolicy,
     pub marker: PhantomData<C>,
     pub conn_type: PhantomData<CT>,
 }
@@ -26,6 +53,8 @@ impl<W, C, CT> Marshal for CodecWriteHalf<W, C, CT>
 where
     C: Marshal,
 {
+    const IS_TEXT: bool = C::IS_TEXT;
+
     fn marshal<S: serde::Serialize>(val: &S) -> Result<Vec<u8>, ParseError> {
         C::marshal(val)
     }
@@ -87,21 +116,138 @@ cfg_if! {
             )
         )
     ))] {
-        use crate::transport::frame::{PayloadType, FrameRead, FrameWrite, FrameHeader};
+        use crate::transport::frame::{Frame, PayloadType, FrameRead, FrameWrite, FrameHeader};
         use crate::error::IoError;
 
+        impl<R, C> CodecReadHalf<R, C, ConnTypeReadWrite>
+        where
+            R: FrameRead + Send + Unpin,
+            C: Send,
+        {
+            /// Reads the next frame. Frames other than the header frame are expected to
+            /// follow the last header frame without delay
+            async fn read_next_frame(&mut self, is_header: bool) -> Option<Result<Frame, IoError>> {
+                let res = match self.frame_read_timeout {
+                    Some(timeout) => {
+                        let continuation_of = if is_header { None } else { self.header_id };
+                        self.reader.read_frame_with_timeout(timeout, continuation_of).await?
+                    },
+                    None => self.reader.read_frame().await?,
+                };
+                match res {
+                    Ok(frame) => {
+                        self.bytes_read += frame.wire_len() as u64;
+                        Some(Ok(frame))
+                    },
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                        // the stream cannot be resynchronized after a partially read frame
+                        log::error!("Closing connection: {}", err);
+                        None
+                    },
+                    Err(err) => Some(Err(err)),
+                }
+            }
+
+            /// Keeps a body frame that belongs to a message other than the one being read.
+            fn stash_body_frame(&mut self, frame: Frame) {
+                let Frame { message_id, payload_type, mut payload, .. } = frame;
+                let body = match self.interleaved.partial.remove(&message_id) {
+                    Some(mut body) => {
+                        body.append(&mut payload);
+                        body
+                    },
+                    None => payload,
+                };
+                match payload_type {
+                    PayloadType::Partial => { self.interleaved.partial.insert(message_id, body); },
+                    _ => { self.interleaved.complete.insert(message_id, body); },
+                }
+            }
+
+            /// Returns the payload of the next header frame
+            async fn next_header_payload(&mut self) -> Option<Result<Vec<u8>, IoError>> {
+                if let Some((id, payload)) = self.interleaved.headers.pop_front() {
+                    self.header_id = Some(id);
+                    return Some(Ok(payload));
+                }
+                loop {
+                    let frame = match self.read_next_frame(true).await? {
+                        Ok(frame) => frame,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    match frame.payload_type {
+                        PayloadType::Header => {
+                            self.header_id = Some(frame.message_id);
+                            return Some(Ok(frame.payload));
+                        },
+                        _ => self.stash_body_frame(frame),
+                    }
+                }
+            }
+
+            /// Returns the body of the message whose header is read last. Frames of other
+            /// messages that arrive in the meantime are kept for later
+            async fn next_body_payload(&mut self) -> Option<Result<Vec<u8>, IoError>> {
+                let id = self.header_id;
+                if let Some(body) = id.and_then(|id| self.interleaved.complete.remove(&id)) {
+                    return Some(Ok(body));
+                }
+                loop {
+                    let frame = match self.read_next_frame(false).await? {
+                        Ok(frame) => frame,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    match frame.payload_type {
+                        PayloadType::Header => {
+                            self.interleaved.headers.push_back((frame.message_id, frame.payload));
+                        },
+                        PayloadType::Partial => self.stash_body_frame(frame),
+                        _ if Some(frame.message_id) != id => self.stash_body_frame(frame),
+                        _ => {
+                            let mut body = self.interleaved.partial.remove(&frame.message_id).unwrap_or_default();
+                            if body.is_empty() {
+                                return Some(Ok(frame.payload));
+                            }
+                            body.extend_from_slice(&frame.payload);
+                            return Some(Ok(body));
+                        }
+                    }
+                }
+            }
+        }
+
         #[async_trait]
         impl<R, C> CodecRead for CodecReadHalf<R, C, ConnTypeReadWrite>
         where
             R: FrameRead + Send + Unpin,
             C: Unmarshal + EraseDeserializer + Send
         {
+            async fn read_header<H>(&mut self) -> Option<Result<H, CodecError>>
+            where
+                H: serde::de::DeserializeOwned,
+            {
+                Some(
+                    self.next_header_payload()
+                        .await?
+                        .map_err(Into::into)
+                        .and_then(|payload| Self::unmarshal(&payload).map_err(Into::into)),
+                )
+            }
+
             async fn read_bytes(&mut self) -> Option<Result<Vec<u8>, IoError>> {
-                self.reader.read_frame().await
-                    .map(|res| {
-                        res.map(|f| f.payload)
-                            .map_err(Into::into)
-                    })
+                self.next_body_payload().await
+            }
+
+            async fn read_header_bytes(&mut self) -> Option<Result<Vec<u8>, IoError>> {
+                self.next_header_payload().await
+            }
+
+            fn bytes_read(&self) -> u64 {
+                self.bytes_read
+            }
+
+            fn set_frame_read_timeout(&mut self, timeout: Option<Duration>) {
+                self.frame_read_timeout = timeout;
             }
         }
 
@@ -111,7 +257,7 @@ cfg_if! {
             W: FrameWrite + Send + Unpin,
             C: Marshal + Send,
         {
-            async fn write_header<H>(&mut self, header: H) -> Result<(), CodecError>
+            async fn write_header<H>(&mut self, header: H) -> Result<usize, CodecError>
             where
                 H: serde::Serialize + Metadata + Send,
             {
@@ -122,28 +268,58 @@ cfg_if! {
                 // let frame = Frame::new(id, 0, PayloadType::Header, buf);
                 let frame_header = FrameHeader::new(id, 0, PayloadType::Header, buf.len() as u32);
 
-                writer.write_frame(frame_header, &buf).await?;
-                Ok(())
+                let n = writer.write_frame_with_policy(frame_header, &buf, self.flush_policy).await?;
+                Ok(n)
             }
 
             async fn write_body(
                 &mut self,
                 id: MessageId,
                 body: &(dyn erased::Serialize + Send + Sync),
-            ) -> Result<(), CodecError> {
+            ) -> Result<usize, CodecError> {
                 let writer = &mut self.writer;
                 let buf = Self::marshal(&body)?;
                 // let frame = Frame::new(id.to_owned(), 1, PayloadType::Data, buf.to_owned());
                 let frame_header = FrameHeader::new(id, 1, PayloadType::Data, buf.len() as u32);
-                writer.write_frame(frame_header, &buf).await?;
-                Ok(())
+                let n = writer.write_frame_with_policy(frame_header, &buf, self.flush_policy).await?;
+                Ok(n)
             }
 
-            async fn write_body_bytes(&mut self, id: MessageId, bytes: &[u8]) -> Result<(), IoError> {
+            async fn write_body_bytes(&mut self, id: MessageId, bytes: &[u8]) -> Result<usize, IoError> {
                 // let frame = Frame::new(*id, 1, PayloadType::Data, bytes);
                 let frame_header = FrameHeader::new(id, 1, PayloadType::Data, bytes.len() as u32);
-                self.writer.write_frame(frame_header, bytes).await?;
-                Ok(())
+                self.writer.write_frame_with_policy(frame_header, bytes, self.flush_policy).await
+            }
+
+            async fn flush(&mut self) -> Result<(), IoError> {
+                self.writer.flush_frames().await
+            }
+
+            fn flush_policy(&self) -> FlushPolicy {
+                self.flush_policy
+            }
+
+            async fn write_header_bytes(&mut self, id: MessageId, bytes: &[u8]) -> Result<usize, IoError> {
+                let frame_header = FrameHeader::new(id, 0, PayloadType::Header, bytes.len() as u32);
+                self.writer.write_frame_with_policy(frame_header, bytes, self.flush_policy).await
+            }
+
+            fn supports_body_chunks(&self) -> bool {
+                true
+            }
+
+            async fn write_body_chunk(
+                &mut self,
+                id: MessageId,
+                index: usize,
+                chunk: &[u8],
+                is_last: bool,
+            ) -> Result<usize, IoError> {
+                let payload_type = if is_last { PayloadType::Data } else { PayloadType::Partial };
+                // frame ids of the body start from 1 and wrap around for very long bodies
+                let frame_id = (index % u8::MAX as usize) as u8 + 1;
+                let frame_header = FrameHeader::new(id, frame_id, payload_type, chunk.len() as u32);
+                self.writer.write_frame_with_policy(frame_header, chunk, self.flush_policy).await
             }
         }
 
@@ -159,11 +335,16 @@ cfg_if! {
                 (
                     CodecWriteHalf::<W, Self, ConnTypeReadWrite> {
                         writer: self.writer,
+                        flush_policy: self.flush_policy,
                         marker: PhantomData,
                         conn_type: PhantomData,
                     },
                     CodecReadHalf::<R, Self, ConnTypeReadWrite> {
                         reader: self.reader,
+                        frame_read_timeout: None,
+                        header_id: None,
+                        interleaved: Default::default(),
+                        bytes_read: 0,
                         marker: PhantomData,
                         conn_type: PhantomData
                     }
@@ -220,8 +401,15 @@ cfg_if! {
             C: Unmarshal + EraseDeserializer + Send
         {
             async fn read_bytes(&mut self) -> Option<Result<Vec<u8>, IoError>> {
-                self.reader.read_payload().await
-                    .map(|res| res.map_err(Into::into))
+                let res = self.reader.read_payload().await?;
+                if let Ok(payload) = &res {
+                    self.bytes_read += payload.len() as u64;
+                }
+                Some(res)
+            }
+
+            fn bytes_read(&self) -> u64 {
+                self.bytes_read
             }
         }
 
@@ -232,30 +420,43 @@ cfg_if! {
             W: PayloadWrite + Send,
             C: Marshal + Send,
         {
-            async fn write_header<H>(&mut self, header: H) -> Result<(), CodecError>
+            async fn write_header<H>(&mut self, header: H) -> Result<usize, CodecError>
             where
                 H: serde::Serialize + Metadata + Send,
             {
-                let writer = &mut self.writer;
                 let buf = Self::marshal(&header)?;
-                writer.write_payload(&buf).await?;
-                Ok(())
+                self.write_payload(&buf).await?;
+                Ok(buf.len())
             }
 
             async fn write_body(
                 &mut self,
                 _: MessageId,
                 body: &(dyn erased::Serialize + Send + Sync),
-            ) -> Result<(), CodecError> {
+            ) -> Result<usize, CodecError> {
                 let buf = Self::marshal(&body)?;
-                let writer = &mut self.writer;
-                writer.write_payload(&buf).await?;
-                Ok(())
+                self.write_payload(&buf).await?;
+                Ok(buf.len())
             }
 
-            async fn write_body_bytes(&mut self, _: MessageId, bytes: &[u8]) -> Result<(), IoError> {
-                self.writer.write_payload(bytes).await?;
-                Ok(())
+            async fn write_body_bytes(&mut self, _: MessageId, bytes: &[u8]) -> Result<usize, IoError> {
+                self.write_payload(bytes).await?;
+                Ok(bytes.len())
+            }
+        }
+
+        #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+        impl<W, C> CodecWriteHalf<W, C, ConnTypePayload>
+        where
+            W: PayloadWrite + Send,
+            C: Marshal + Send,
+        {
+            /// Sends the payload in a text message if the codec marshals into text
+            async fn write_payload(&mut self, payload: &[u8]) -> Result<(), IoError> {
+                match C::IS_TEXT {
+                    true => self.writer.write_text_payload(payload).await,
+                    false => self.writer.write_payload(payload).await,
+                }
             }
         }
 
@@ -284,11 +485,16 @@ cfg_if! {
                 (
                     CodecWriteHalf::<W, Self, ConnTypePayload> {
                         writer: self.writer,
+                        flush_policy: self.flush_policy,
                         marker: PhantomData,
                         conn_type: PhantomData,
                     },
                     CodecReadHalf::<R, Self, ConnTypePayload> {
                         reader: self.reader,
+                        frame_read_timeout: None,
+                        header_id: None,
+                        interleaved: Default::default(),
+                        bytes_read: 0,
                         marker: PhantomData,
                         conn_type: PhantomData
                     }
@@ -297,3 +503,99 @@ cfg_if! {
         }
     }
 }
+
+#[cfg(all(
+    test,
+    feature = "tokio_runtime",
+    not(feature = "async_std_runtime"),
+    not(feature = "serde_json")
+))]
+mod tests {
+    use super::*;
+    use crate::protocol::Header;
+    use crate::transport::frame::{FrameHeader, FrameWrite, PayloadType};
+
+    fn read_half<R>(reader: R) -> CodecReadHalf<R, (), ConnTypeReadWrite> {
+        CodecReadHalf {
+            reader,
+            frame_read_timeout: None,
+            header_id: None,
+            bytes_read: 0,
+            interleaved: Default::default(),
+            marker: PhantomData,
+            conn_type: PhantomData,
+        }
+    }
+
+    async fn write<W: FrameWrite + Send>(
+        writer: &mut W,
+        id: MessageId,
+        ty: PayloadType,
+        payload: &[u8],
+    ) {
+        let header = FrameHeader::new(id, 0, ty, payload.len() as u32);
+        writer.write_frame(header, payload).await.unwrap();
+    }
+
+    #[test]
+    fn chunked_body_is_reassembled_around_other_messages() {
+        let rt = ::tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut tx, rx) = ::tokio::io::duplex(1024);
+            write(&mut tx, 1, PayloadType::Partial, b"ab").await;
+            write(&mut tx, 2, PayloadType::Header, b"h2").await;
+            write(&mut tx, 2, PayloadType::Data, b"x").await;
+            write(&mut tx, 1, PayloadType::Partial, b"cd").await;
+            write(&mut tx, 1, PayloadType::Header, b"h1").await;
+            write(&mut tx, 1, PayloadType::Data, b"ef").await;
+
+            let mut reader = read_half(rx);
+            assert_eq!(reader.next_header_payload().await.unwrap().unwrap(), b"h2");
+            assert_eq!(reader.next_body_payload().await.unwrap().unwrap(), b"x");
+            assert_eq!(reader.next_header_payload().await.unwrap().unwrap(), b"h1");
+            assert_eq!(
+                reader.next_body_payload().await.unwrap().unwrap(),
+                b"abcdef"
+            );
+        });
+    }
+
+    #[test]
+    fn frames_arriving_before_the_body_are_kept() {
+        let rt = ::tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut tx, rx) = ::tokio::io::duplex(1024);
+            write(&mut tx, 1, PayloadType::Header, b"h1").await;
+            write(&mut tx, 2, PayloadType::Header, b"h2").await;
+            write(&mut tx, 2, PayloadType::Data, b"y").await;
+            write(&mut tx, 1, PayloadType::Data, b"x").await;
+
+            let mut reader = read_half(rx);
+            assert_eq!(reader.next_header_payload().await.unwrap().unwrap(), b"h1");
+            assert_eq!(reader.next_body_payload().await.unwrap().unwrap(), b"x");
+            assert_eq!(reader.next_header_payload().await.unwrap().unwrap(), b"h2");
+            assert_eq!(reader.next_body_payload().await.unwrap().unwrap(), b"y");
+        });
+    }
+
+    #[test]
+    fn empty_body_is_deserialized_as_unit() {
+        let rt = ::tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (a, b) = ::tokio::io::duplex(1024);
+            let (mut writer, _) = Codec::new(a).split();
+            let (_, mut reader) = Codec::new(b).split();
+
+            writer
+                .write_header(Header::Response { id: 3, is_ok: true })
+                .await
+                .unwrap();
+            writer.write_body(3, &()).await.unwrap();
+
+            let header: Header = reader.read_header().await.unwrap().unwrap();
+            assert!(matches!(header, Header::Response { id: 3, is_ok: true }));
+            let mut de = reader.read_body().await.unwrap().unwrap();
+            let _: () = erased_serde::deserialize(&mut de).unwrap();
+        });
+    }
+}
@@ -0,0 +1,332 @@
+//! Zero-configuration discovery of servers on the local network with mDNS/DNS-SD
+//!
+//! This is meant as a development tool. A server built with `ServerBuilder::advertise_mdns`
+//! advertises the address it is accepting connections on, and `Client::discover` browses
+//! for the advertised servers. The advertisement is withdrawn when the server stops
+//! accepting connections. The mDNS responder and browser are the ones of `mdns-sd`, which
+//! run on a daemon thread and work over both IPv4 and IPv6.
+//!
+//! Every advertisement carries the TXT entries `codec`, `version` and `transport` in
+//! addition to the ones supplied in `ServiceAdvert`, which `Client::dial_discovered`
+//! uses to check that it can talk to the server.
+//!
+//! # Example
+//!
+//! ```rust
+//! // server
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .advertise_mdns(ServiceAdvert::new("my-dev-server").set_txt("owner", "alice"))
+//!     .build();
+//! let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+//! server.accept(listener).await.unwrap();
+//!
+//! // client
+//! let endpoints = Client::discover(DEFAULT_SERVICE_TYPE, |endpoint| {
+//!     endpoint.instance_name == "my-dev-server"
+//! }).await.unwrap();
+//! let client = Client::dial_discovered(&endpoints[0]).await.unwrap();
+//! ```
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::codec::codec_name;
+use crate::error::Error;
+
+/// The service type used when none is specified
+pub const DEFAULT_SERVICE_TYPE: &str = "_toy-rpc._tcp";
+
+/// How long `Client::discover` waits for servers to respond
+pub const DEFAULT_BROWSE_DURATION: Duration = Duration::from_secs(1);
+
+/// TXT key of the codec used by the server
+pub const TXT_CODEC: &str = "codec";
+/// TXT key of the version of `toy-rpc` used by the server
+pub const TXT_VERSION: &str = "version";
+/// TXT key of the transport the server accepts connections on
+pub const TXT_TRANSPORT: &str = "transport";
+
+/// Description of a server to advertise
+#[derive(Debug, Clone)]
+pub struct ServiceAdvert {
+    /// Name of this instance of the service, which must be unique on the local network
+    pub instance_name: String,
+    /// DNS-SD service type, `DEFAULT_SERVICE_TYPE` by default
+    pub service_type: String,
+    /// Additional metadata published in the TXT record
+    pub txt: HashMap<String, String>,
+}
+
+impl ServiceAdvert {
+    /// Creates an advert with the default service type
+    pub fn new(instance_name: impl Into<String>) -> Self {
+        Self {
+            instance_name: instance_name.into(),
+            service_type: DEFAULT_SERVICE_TYPE.into(),
+            txt: HashMap::new(),
+        }
+    }
+
+    /// Sets the DNS-SD service type, eg. `"_my-app._tcp"`
+    pub fn set_service_type(self, service_type: impl Into<String>) -> Self {
+        Self {
+            service_type: service_type.into(),
+            ..self
+        }
+    }
+
+    /// Adds an entry to the TXT record
+    pub fn set_txt(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.txt.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// A server found by browsing
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredEndpoint {
+    /// Name of the instance
+    pub instance_name: String,
+    /// Address the server is accepting connections on
+    pub addr: SocketAddr,
+    /// Metadata from the TXT record
+    pub txt: HashMap<String, String>,
+}
+
+impl DiscoveredEndpoint {
+    /// Codec used by the server
+    pub fn codec(&self) -> Option<&str> {
+        self.txt.get(TXT_CODEC).map(String::as_str)
+    }
+
+    /// Version of `toy-rpc` used by the server
+    pub fn version(&self) -> Option<&str> {
+        self.txt.get(TXT_VERSION).map(String::as_str)
+    }
+
+    /// Transport the server accepts connections on
+    pub fn transport(&self) -> Option<&str> {
+        self.txt.get(TXT_TRANSPORT).map(String::as_str)
+    }
+
+    /// Returns `Error::IncompatibleServer` if the client cannot talk to the server
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn check_compatible(&self) -> Result<(), Error> {
+        if let Some(codec) = self.codec() {
+            if codec != codec_name() {
+                return Err(Error::IncompatibleServer(format!(
+                    "Server {} uses codec {} while the client uses {}",
+                    self.instance_name,
+                    codec,
+                    codec_name()
+                )));
+            }
+        }
+        if let Some(transport) = self.transport() {
+            if transport != "tcp" {
+                return Err(Error::IncompatibleServer(format!(
+                    "Server {} uses unsupported transport {}",
+                    self.instance_name, transport
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An active advertisement, which is withdrawn when dropped
+pub(crate) struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        // the daemon tells the browsers to forget about the server before it shuts down
+        if let Err(err) = self.daemon.unregister(&self.fullname) {
+            log::error!("Failed to withdraw mDNS advertisement: {}", err);
+        }
+        if let Err(err) = self.daemon.shutdown() {
+            log::error!("Failed to shut down mDNS daemon: {}", err);
+        }
+    }
+}
+
+/// Starts advertising the server accepting connections on `addr`. The server is
+/// advertised with the addresses of all the interfaces, both IPv4 and IPv6, if `addr`
+/// is unspecified.
+pub(crate) fn advertise(advert: &ServiceAdvert, addr: SocketAddr) -> io::Result<Advertisement> {
+    if addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Loopback addresses are not advertised on the local network",
+        ));
+    }
+
+    let mut txt = advert.txt.clone();
+    txt.insert(TXT_CODEC.into(), codec_name().into());
+    txt.insert(TXT_VERSION.into(), env!("CARGO_PKG_VERSION").into());
+    txt.insert(TXT_TRANSPORT.into(), "tcp".into());
+
+    let service_type = service_domain(&advert.service_type);
+    let ip = match addr.ip().is_unspecified() {
+        true => String::new(),
+        false => addr.ip().to_string(),
+    };
+    let info = ServiceInfo::new(
+        &service_type,
+        &advert.instance_name,
+        &host_name(&advert.instance_name),
+        ip.as_str(),
+        addr.port(),
+        txt,
+    )
+    .map_err(mdns_error)?;
+    let info = match addr.ip().is_unspecified() {
+        true => info.enable_addr_auto(),
+        false => info,
+    };
+    let fullname = info.get_fullname().to_string();
+
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    daemon.register(info).map_err(mdns_error)?;
+    log::info!("Advertising {} at {}", fullname, addr);
+    Ok(Advertisement { daemon, fullname })
+}
+
+/// Browses for servers of the `service_type` for the `duration`
+#[cfg(any(
+    feature = "docs",
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+pub async fn browse(service_type: &str, duration: Duration) -> io::Result<Vec<DiscoveredEndpoint>> {
+    let service_type = service_domain(service_type);
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    let events = daemon.browse(&service_type).map_err(mdns_error)?;
+
+    let deadline = std::time::Instant::now() + duration;
+    let mut resolved = HashMap::new();
+    loop {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        let event = match timeout(remaining, events.recv_async()).await {
+            Some(Ok(event)) => event,
+            // the daemon is gone or the time is up
+            Some(Err(_)) | None => break,
+        };
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                if let Some(endpoint) = to_endpoint(&info, &service_type) {
+                    resolved.insert(info.get_fullname().to_string(), endpoint);
+                }
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                resolved.remove(&fullname);
+            }
+            _ => {}
+        }
+    }
+
+    if let Err(err) = daemon.shutdown() {
+        log::error!("Failed to shut down mDNS daemon: {}", err);
+    }
+    Ok(resolved.into_values().collect())
+}
+
+/// Returns `None` if `fut` is not done within `duration`
+#[cfg(any(
+    feature = "docs",
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime"))
+))]
+async fn timeout<F: std::future::Future>(duration: Duration, fut: F) -> Option<F::Output> {
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    let result = ::tokio::time::timeout(duration, fut).await;
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    let result = ::async_std::future::timeout(duration, fut).await;
+    result.ok()
+}
+
+/// Turns a resolved service into an endpoint. An IPv4 address is preferred over an
+/// IPv6 one if the server is advertised with both.
+fn to_endpoint(info: &ServiceInfo, service_type: &str) -> Option<DiscoveredEndpoint> {
+    let instance_name = info
+        .get_fullname()
+        .strip_suffix(service_type)?
+        .trim_end_matches('.')
+        .to_string();
+    let ip = info
+        .get_addresses()
+        .iter()
+        .map(|ip| IpAddr::from(*ip))
+        .min_by_key(|ip| (ip.is_ipv6(), *ip))?;
+    let txt = info
+        .get_properties()
+        .iter()
+        .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
+        .collect();
+    Some(DiscoveredEndpoint {
+        instance_name,
+        addr: SocketAddr::new(ip, info.get_port()),
+        txt,
+    })
+}
+
+/// `<service type>.local.`, which is the form `mdns-sd` expects
+fn service_domain(service_type: &str) -> String {
+    let service_type = service_type
+        .trim_end_matches('.')
+        .trim_end_matches(".local");
+    format!("{}.local.", service_type)
+}
+
+/// `<instance>.local.` with everything but alphanumerics replaced by `-`
+fn host_name(instance: &str) -> String {
+    let label: String = instance
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("{}.local.", label)
+}
+
+fn mdns_error(err: mdns_sd::Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_in_the_local_domain() {
+        assert_eq!(service_domain("_toy-rpc._tcp"), "_toy-rpc._tcp.local.");
+        assert_eq!(
+            service_domain("_toy-rpc._tcp.local."),
+            "_toy-rpc._tcp.local."
+        );
+        assert_eq!(host_name("dev.server 1"), "dev-server-1.local.");
+    }
+
+    #[test]
+    fn codec_mismatch_is_incompatible() {
+        let mut endpoint = DiscoveredEndpoint {
+            instance_name: "dev".into(),
+            addr: "192.168.1.2:23333".parse().unwrap(),
+            txt: HashMap::new(),
+        };
+        assert!(endpoint.check_compatible().is_ok());
+
+        endpoint.txt.insert(TXT_CODEC.into(), "unknown".into());
+        assert!(matches!(
+            endpoint.check_compatible(),
+            Err(Error::IncompatibleServer(_))
+        ));
+    }
+}
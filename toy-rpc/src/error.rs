@@ -1,6 +1,6 @@
 //! Custom errors
 
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use crate::message::{ErrorMessage, MessageId};
 
@@ -31,12 +31,25 @@ pub enum CodecError {
 impl From<CodecError> for Error {
     fn from(err: CodecError) -> Self {
         match err {
-            CodecError::IoError(err) => Error::IoError(err),
+            CodecError::IoError(err) => err.into(),
             CodecError::ParseError(err) => Error::ParseError(err),
         }
     }
 }
 
+/// The peer writes frames of a different version of the framing
+///
+/// The transport returns it as the inner error of a `std::io::Error` with
+/// `ErrorKind::InvalidData`, which is turned into `Error::VersionMismatch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Frame version mismatch. Expecting version {expected}, found version {found}")]
+pub struct VersionMismatch {
+    /// Version of the framing of this end
+    pub expected: u8,
+    /// Version of the framing of the peer
+    pub found: u8,
+}
+
 /// Custom error type
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -48,12 +61,16 @@ pub enum Error {
     ///
     /// This is expected to see changes in version 0.9.
     #[error("{0:?}")]
-    IoError(#[from] std::io::Error),
+    IoError(#[source] std::io::Error),
 
     /// Errors with serialization/deserialization
     #[error("{0}")]
     ParseError(#[from] ParseError),
 
+    /// The peer uses a different version of the framing. See `toy_rpc::transport`
+    #[error("{0}")]
+    VersionMismatch(VersionMismatch),
+
     /// Errors with server or client
     #[error("InternalError: {0}")]
     Internal(Box<dyn std::error::Error + Send + Sync>),
@@ -87,18 +104,112 @@ pub enum Error {
     #[error("Request ({0}) reached timeout")]
     Timeout(MessageId),
 
+    /// A step of dialing the server did not finish within the timeout set with
+    /// `ClientBuilder::set_connect_timeout`, ie. because the host is firewalled
+    #[error("{step} timed out after {timeout:?}")]
+    ConnectTimeout {
+        /// Step of dialing that timed out, ie. "Connection" or "TLS handshake"
+        step: String,
+        /// Timeout of the step
+        timeout: Duration,
+    },
+
     /// Maximum number of retries is reached before an Ack is received
     #[error("Maximum number of retries is reached for message {0}")]
     MaxRetriesReached(MessageId),
+
+    /// The connection is lost because the given number of heartbeats in a row are not
+    /// answered in time. See `toy_rpc::client::Heartbeat`
+    #[error("Connection is lost after {0} missed heartbeats")]
+    MissedHeartbeats(u32),
+
+    /// The request exceeds one of the limits declared during the handshake.
+    /// See `toy_rpc::protocol::ConnectionLimits`
+    #[error("Busy")]
+    Busy,
+
+    /// The request is rejected by the rate limiter of the connection.
+    /// See `toy_rpc::server::rate_limit`
+    #[error("RateLimited")]
+    RateLimited,
+
+    /// The call failed in all the attempts allowed by its retry policy.
+    /// See `toy_rpc::client::RetryPolicy`
+    #[error("Call failed after {attempts} attempts: {last}")]
+    RetriesExhausted {
+        /// Number of attempts that were made
+        attempts: u32,
+        /// Error of the last attempt
+        last: Box<Error>,
+    },
+
+    /// The peer announced another codec during the handshake.
+    /// See `toy_rpc::server::ServerBuilder::negotiate_codec`
+    #[error("Codec mismatch. Expecting {expected}, found {found}")]
+    CodecMismatch {
+        /// Codec of this end
+        expected: String,
+        /// Codec announced by the peer
+        found: String,
+    },
+
+    /// The proxy of the client refused to open the tunnel to the server, or failed the
+    /// handshake. See `toy_rpc::client::proxy`
+    #[error("ProxyError: {0}")]
+    ProxyError(String),
+
+    /// The server found by `Client::discover` uses a codec or a transport that the
+    /// client doesn't. See `toy_rpc::discovery`
+    #[error("IncompatibleServer: {0}")]
+    IncompatibleServer(String),
+
+    /// The server rejected the WebSocket upgrade, ie. because the request is not
+    /// authorized. The response of the server is kept for diagnosis.
+    #[error("WebSocket handshake is rejected with status {status}")]
+    WebSocketRejected {
+        /// Status code of the response
+        status: u16,
+        /// Headers of the response, with the names in lowercase
+        headers: Vec<(String, String)>,
+        /// Body of the response, if any
+        body: Option<String>,
+    },
 }
 
 impl Error {
+    /// Returns `true` if the request timed out, ie. because the server is slow
+    /// to respond, or if dialing timed out. See `Client::set_next_timeout` and
+    /// `ClientBuilder::set_connect_timeout`
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Timeout(_) | Self::ConnectTimeout { .. })
+    }
+
+    /// Returns `true` if the request is canceled by the caller, as opposed to failing
+    /// because the connection is broken (`Error::IoError`)
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Canceled(_))
+    }
+
     pub(crate) fn from_err_msg(msg: ErrorMessage) -> Self {
         match msg {
             ErrorMessage::InvalidArgument => Self::InvalidArgument,
             ErrorMessage::ServiceNotFound => Self::ServiceNotFound,
             ErrorMessage::MethodNotFound => Self::MethodNotFound,
             ErrorMessage::ExecutionError(s) => Self::ExecutionError(s),
+            ErrorMessage::Busy => Self::Busy,
+            ErrorMessage::RateLimited => Self::RateLimited,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        match err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<VersionMismatch>())
+        {
+            Some(mismatch) => Self::VersionMismatch(*mismatch),
+            None => Self::IoError(err),
         }
     }
 }
@@ -183,10 +294,24 @@ impl<T> From<actix::prelude::SendError<T>> for Error {
 #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
 impl From<tungstenite::Error> for crate::error::Error {
     fn from(err: tungstenite::Error) -> Self {
-        Self::IoError(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            err.to_string(),
-        ))
+        match err {
+            tungstenite::Error::Http(resp) => Self::WebSocketRejected {
+                status: resp.status().as_u16(),
+                headers: resp
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                        (name.as_str().to_string(), value)
+                    })
+                    .collect(),
+                body: resp.into_body(),
+            },
+            err => Self::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                err.to_string(),
+            )),
+        }
     }
 }
 
@@ -278,12 +403,127 @@ impl From<anyhow::Error> for Error {
 
 #[cfg(test)]
 mod tests {
-    #[cfg(feature = "anyhow")]
     use super::*;
 
     #[test]
     fn test_conversion_to_anyhow() {}
 
+    #[test]
+    fn every_variant_is_displayed() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let cases = vec![
+            (
+                Error::IoError(io),
+                "Custom { kind: ConnectionReset, error: \"reset\" }".to_string(),
+            ),
+            (
+                Error::ParseError("bad frame".into()),
+                "bad frame".to_string(),
+            ),
+            (
+                Error::VersionMismatch(VersionMismatch {
+                    expected: 1,
+                    found: 2,
+                }),
+                "Frame version mismatch. Expecting version 1, found version 2".to_string(),
+            ),
+            (
+                Error::Internal("broken".into()),
+                "InternalError: broken".to_string(),
+            ),
+            (Error::InvalidArgument, "InvalidArgument".to_string()),
+            (Error::ServiceNotFound, "ServiceNotFound".to_string()),
+            (Error::MethodNotFound, "MethodNotFound".to_string()),
+            (Error::ExecutionError("failed".into()), "failed".to_string()),
+            (Error::Canceled(1), "Request (1) is canceled".to_string()),
+            (Error::Timeout(2), "Request (2) reached timeout".to_string()),
+            (
+                Error::ConnectTimeout {
+                    step: "TLS handshake".into(),
+                    timeout: Duration::from_millis(200),
+                },
+                "TLS handshake timed out after 200ms".to_string(),
+            ),
+            (
+                Error::MaxRetriesReached(3),
+                "Maximum number of retries is reached for message 3".to_string(),
+            ),
+            (
+                Error::MissedHeartbeats(2),
+                "Connection is lost after 2 missed heartbeats".to_string(),
+            ),
+            (Error::Busy, "Busy".to_string()),
+            (Error::RateLimited, "RateLimited".to_string()),
+            (
+                Error::RetriesExhausted {
+                    attempts: 3,
+                    last: Box::new(Error::Timeout(4)),
+                },
+                "Call failed after 3 attempts: Request (4) reached timeout".to_string(),
+            ),
+            (
+                Error::CodecMismatch {
+                    expected: "bincode".into(),
+                    found: "json".into(),
+                },
+                "Codec mismatch. Expecting bincode, found json".to_string(),
+            ),
+            (
+                Error::ProxyError("refused".into()),
+                "ProxyError: refused".to_string(),
+            ),
+            (
+                Error::IncompatibleServer("json".into()),
+                "IncompatibleServer: json".to_string(),
+            ),
+            (
+                Error::WebSocketRejected {
+                    status: 401,
+                    headers: Vec::new(),
+                    body: None,
+                },
+                "WebSocket handshake is rejected with status 401".to_string(),
+            ),
+        ];
+        for (err, expected) in cases {
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn version_mismatch_is_recovered_from_io_error() {
+        let mismatch = VersionMismatch {
+            expected: 1,
+            found: 2,
+        };
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, mismatch);
+        assert!(matches!(
+            Error::from(CodecError::IoError(io)),
+            Error::VersionMismatch(m) if m == mismatch
+        ));
+
+        let io = std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid");
+        assert!(matches!(Error::from(io), Error::IoError(_)));
+    }
+
+    #[test]
+    fn timeout_and_cancellation_are_told_apart() {
+        assert!(Error::Timeout(1).is_timeout());
+        assert!(!Error::Timeout(1).is_cancelled());
+        assert!(Error::Canceled(1).is_cancelled());
+        assert!(!Error::Canceled(1).is_timeout());
+        let connect = Error::ConnectTimeout {
+            step: "Connection".into(),
+            timeout: Duration::from_secs(3),
+        };
+        assert!(connect.is_timeout());
+        assert!(!connect.is_cancelled());
+
+        let broken = Error::IoError(std::io::ErrorKind::ConnectionReset.into());
+        assert!(!broken.is_timeout());
+        assert!(!broken.is_cancelled());
+    }
+
     #[cfg(feature = "anyhow")]
     fn return_std_result() -> Result<(), Error> {
         let a: anyhow::Result<u32> = Ok(1);
@@ -0,0 +1,91 @@
+//! Built-in health-check service
+//!
+//! A server built with `ServerBuilder::enable_health_check` serves the reserved
+//! `__health` service, whose `ping` method takes `()` and returns a `HealthStatus`.
+//! The request goes through the same codec, transport and broker as any other call,
+//! so a successful ping means that the server is reading and answering requests.
+//!
+//! # Example
+//!
+//! ```rust
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .enable_health_check()
+//!     .build();
+//!
+//! // on the client
+//! client.health_check().await?;
+//! let status: HealthStatus = client.call("__health.ping", ()).await?;
+//! ```
+//!
+//! Every server also answers the heartbeats of the clients (see
+//! `toy_rpc::client::heartbeat`), which are requests to `HEARTBEAT_METHOD`. These are
+//! answered by the connection itself with `()`, without going through the registered
+//! services, the interceptors or the limits of the connection, and they are answered
+//! whether or not the health check is enabled.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Name of the health-check service
+pub const HEALTH_SERVICE: &str = "__health";
+
+/// Service and method of the heartbeats sent by the clients
+pub const HEARTBEAT_METHOD: &str = "__heartbeat.ping";
+
+/// Timeout of `Client::health_check`
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Response of `__health.ping`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// Time since the server is built
+    pub uptime: Duration,
+    /// Number of connections that the server is serving
+    pub num_connections: usize,
+}
+
+#[cfg(feature = "server")]
+pub(crate) use service::health_service;
+
+#[cfg(feature = "server")]
+mod service {
+    use erased_serde as erased;
+    use std::{sync::Arc, time::Instant};
+
+    use super::HealthStatus;
+    use crate::{
+        error::Error,
+        protocol::OutboundBody,
+        server::SharedState,
+        service::{HandlerResultFut, ServiceEntry},
+    };
+
+    /// Builds the health-check service of a server
+    pub(crate) fn health_service(shared: Arc<SharedState>) -> ServiceEntry {
+        let started_at = Instant::now();
+        let call = Arc::new(
+            move |method: String,
+                  _: Box<dyn erased::Deserializer<'static> + Send>|
+                  -> HandlerResultFut {
+                let result = match method.as_str() {
+                    "ping" => {
+                        let num_connections = shared
+                            .connections
+                            .lock()
+                            .map(|connections| connections.len())
+                            .unwrap_or_default();
+                        let status = HealthStatus {
+                            uptime: started_at.elapsed(),
+                            num_connections,
+                        };
+                        Ok(Box::new(status) as Box<OutboundBody>)
+                    }
+                    _ => Err(Error::MethodNotFound),
+                };
+                Box::pin(async move { result })
+            },
+        );
+        ServiceEntry::with_methods(call, &["ping"])
+    }
+}
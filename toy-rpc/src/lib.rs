@@ -71,6 +71,12 @@
 //!
 //! - `server`: enables RPC server
 //! - `client`: enables RPC client
+//! - `blocking`: enables `client::BlockingClient`, which makes the calls without an async runtime.
+//! This also enables `client`
+//! - `testing`: enables `codec::connect_in_memory`, which connects a client to a server
+//! without binding a port. This is meant for tests
+//! - `metrics`: records the number of calls to each service method by outcome and their
+//! latency on the client, which is returned by `Client::call_metrics`. This also enables `client`
 //!
 //! Choice of serialization/deserialzation (only one should be enabled at a time)
 //!
@@ -82,6 +88,8 @@
 //!     for serialization/deserialization
 //! - `serde_rmp`: the default codec will use `rmp-serde`
 //!     for serialization/deserialization
+//! - `content_type_json`: enables `codec::ContentType::Json` for `Client::call_with_codec`
+//!     without changing the codec of the connection. This can be combined with any codec
 //!
 //! WebSocket support (HTTP integration is implementd with WebSocket)
 //!
@@ -93,6 +101,23 @@
 //! TLS support
 //!
 //! - `tls`: enables TLS support
+//! - `quic`: enables the QUIC transport with `quinn` (`Server::accept_quic` and `Client::dial_quic`),
+//! which requires `tokio_runtime`
+//!
+//! Compatibility
+//!
+//! - `legacy_frame`: reads the frames of toy-rpc 0.8.x peers, which do not write the version
+//! byte after the magic byte and carry a `u16` message id. Frames are always written with the
+//! version byte
+//!
+//! Instrumentation
+//!
+//! - `tracing`: emits `tracing` spans (carrying `message_id` and `service_method`) for writing,
+//! reading and handling requests, and events for cancellations and errors
+//!
+//! Development tools
+//!
+//! - `discovery`: advertises servers and discovers them on the local network with mDNS/DNS-SD
 //!
 //! Other trivial feature flags are listed below, and they are likely of no actual usage for you.
 //! - `docs`
@@ -100,6 +125,8 @@
 //!
 //! By default, only `serde_bincode` feature is enabled.
 //! You must enable at least one runtime feature flag and the `server` and/or `client` to have something usable.
+//! Unsupported combinations of feature flags (eg. no codec, multiple codecs, or both runtimes) are
+//! rejected at compile time with a message naming the feature flags to change.
 //!
 //! ## Default features
 //!
@@ -118,13 +145,20 @@
 //! A quickstart example with `tokio` runtime is provided in the [Book/Quickstart](https://minghuaw.github.io/toy-rpc/02_quickstart.html).
 //!
 
+mod cfg_check;
 pub mod codec;
+#[cfg(feature = "discovery")]
+pub mod discovery;
 pub mod error;
+pub mod health;
 pub mod macros;
 pub mod message;
 pub mod protocol;
 pub mod pubsub;
 pub mod service;
+pub mod stats;
+mod trace;
+pub mod trace_id;
 pub mod transport;
 pub mod util;
 
@@ -156,3 +190,5 @@ pub use error::Error;
 // re-export
 pub use erased_serde;
 pub use serde;
+#[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+pub use tungstenite::http;
@@ -1,13 +1,13 @@
 //! ErrorMessage from server to client
 use cfg_if::cfg_if;
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::AtomicU16;
+use std::sync::atomic::AtomicU64;
 
-/// Type of message id is u16
-pub type MessageId = u16;
+/// Type of message id is u64, which doesn't wrap around in the lifetime of a connection
+pub type MessageId = u64;
 
 /// Atomic type of MessageId
-pub type AtomicMessageId = AtomicU16;
+pub type AtomicMessageId = AtomicU64;
 
 /// Returning the metadata
 pub trait Metadata {
@@ -22,6 +22,8 @@ pub(crate) enum ErrorMessage {
     ServiceNotFound,
     MethodNotFound,
     ExecutionError(String),
+    Busy,
+    RateLimited,
 }
 
 cfg_if! {
@@ -46,14 +48,62 @@ cfg_if! {
                     Error::ServiceNotFound => Ok(Self::ServiceNotFound),
                     Error::MethodNotFound => Ok(Self::MethodNotFound),
                     Error::ExecutionError(s) => Ok(Self::ExecutionError(s)),
+                    Error::Busy => Ok(Self::Busy),
+                    Error::RateLimited => Ok(Self::RateLimited),
                     e @ Error::IoError(_) => Err(e),
                     e @ Error::ParseError(_) => Err(e),
+                    e @ Error::VersionMismatch(_) => Err(e),
                     e @ Error::Internal(_) => Err(e),
                     e @ Error::Canceled(_) => Err(e),
                     e @ Error::Timeout(_) => Err(e),
+                    e @ Error::ConnectTimeout { .. } => Err(e),
                     e @ Error::MaxRetriesReached(_) => Err(e),
+                    e @ Error::MissedHeartbeats(_) => Err(e),
+                    e @ Error::RetriesExhausted { .. } => Err(e),
+                    e @ Error::CodecMismatch { .. } => Err(e),
+                    e @ Error::ProxyError(_) => Err(e),
+                    e @ Error::IncompatibleServer(_) => Err(e),
+                    e @ Error::WebSocketRejected { .. } => Err(e),
                 }
             }
         }
     }
 }
+
+#[cfg(all(
+    test,
+    feature = "server",
+    any(feature = "async_std_runtime", feature = "tokio_runtime")
+))]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::{DefaultCodec, Marshal, Unmarshal},
+        error::Error,
+    };
+
+    /// Runs with whichever codec is enabled (bincode, json, cbor or rmp)
+    #[test]
+    fn error_variants_survive_the_round_trip_to_the_client() {
+        type C = DefaultCodec<(), (), ()>;
+
+        let errors = vec![
+            Error::InvalidArgument,
+            Error::ServiceNotFound,
+            Error::MethodNotFound,
+            Error::ExecutionError("bad input".into()),
+            Error::Busy,
+            Error::RateLimited,
+        ];
+        for err in errors {
+            let variant = std::mem::discriminant(&err);
+            let expected = err.to_string();
+            let msg = ErrorMessage::from_err(err).unwrap();
+            let bytes = C::marshal(&msg).unwrap();
+            let msg: ErrorMessage = C::unmarshal(&bytes).unwrap();
+            let de = Error::from_err_msg(msg);
+            assert_eq!(std::mem::discriminant(&de), variant);
+            assert_eq!(de.to_string(), expected);
+        }
+    }
+}
@@ -1,9 +1,69 @@
 //! Message protocol between server and client
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use crate::message::{MessageId, Metadata};
 
+/// Key-value metadata attached to a request, ie. an auth token or a trace id
+pub type RequestMetadata = HashMap<String, String>;
+
+/// Hard limits a peer declares during the handshake when a connection is set up.
+/// `None` means there is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionLimits {
+    /// Max number of calls that can be outstanding at the same time
+    pub max_concurrent_calls: Option<u32>,
+    /// Max number of topics a client can be subscribed to at the same time
+    pub max_subscriptions: Option<u32>,
+}
+
+impl ConnectionLimits {
+    /// Creates limits without any limit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the max number of calls that can be outstanding at the same time
+    pub fn set_max_concurrent_calls(self, val: u32) -> Self {
+        Self {
+            max_concurrent_calls: Some(val),
+            ..self
+        }
+    }
+
+    /// Sets the max number of topics a client can be subscribed to at the same time
+    pub fn set_max_subscriptions(self, val: u32) -> Self {
+        Self {
+            max_subscriptions: Some(val),
+            ..self
+        }
+    }
+
+    /// Returns the limits that satisfy both `self` and `other`, which is the smaller
+    /// of the two in each dimension
+    pub fn min(&self, other: &Self) -> Self {
+        fn min(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, None) => a,
+                (None, b) => b,
+            }
+        }
+        Self {
+            max_concurrent_calls: min(self.max_concurrent_calls, other.max_concurrent_calls),
+            max_subscriptions: min(self.max_subscriptions, other.max_subscriptions),
+        }
+    }
+}
+
+/// Information about a connection that both peers agreed on during the handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The effective limits of the connection, ie. the smaller of the limits declared
+    /// by the two peers
+    pub limits: ConnectionLimits,
+}
+
 /// Header of a message
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Header {
@@ -15,7 +75,11 @@ pub enum Header {
         id: MessageId,
         /// RPC service and method in the format of "{Service}.{method}"
         service_method: String,
-        /// RPC timeout, all requests will have timeouts
+        /// RPC timeout, which is the time the client has left when the request is
+        /// written rather than a point in time, so it does not depend on the clocks of
+        /// the peers. The server doesn't run the handler if it is zero, and a timeout too
+        /// large for the server to turn into a deadline (ie. `Duration::MAX`) means the
+        /// request has no deadline.
         timeout: Duration,
     },
 
@@ -75,7 +139,7 @@ pub enum Header {
     //     topic: String,
     // },
     /// Acknowledge of the following type of messages
-    /// - Cancel?
+    /// - Cancel (sent by the server once the canceled request is no longer executing)
     /// - Publish
     /// - Subscribe
     ///
@@ -111,6 +175,89 @@ pub enum Header {
         /// Reserved for some numerical/enum content
         marker: u32,
     },
+
+    /// Header of a request that carries metadata
+    ///
+    /// This is only used when the metadata is not empty, so requests without metadata
+    /// are still understood by peers that don't know about metadata. The body is the same
+    /// as that of `Request`.
+    RequestWithMetadata {
+        /// Message id
+        id: MessageId,
+        /// RPC service and method in the format of "{Service}.{method}"
+        service_method: String,
+        /// RPC timeout, the same as that of `Request`
+        timeout: Duration,
+        /// Metadata of the request
+        metadata: RequestMetadata,
+    },
+
+    /// Header of a handshake message, which carries the limits declared by the sender
+    ///
+    /// The client sends it as the first message if it declares limits, and the server
+    /// replies with its own limits. The body is an unit type ie. `()`. If the codec is
+    /// negotiated, both peers announce their codec before this (see
+    /// `ServerBuilder::negotiate_codec`).
+    Handshake(ConnectionLimits),
+
+    /// Header of an item of a stream
+    ///
+    /// A handler can send any number of items before it returns. The body is the item,
+    /// and the `Response` with the same message id terminates the stream. Only clients
+    /// that made the request with `Client::call_stream` or `Client::open_stream` expect
+    /// these. On a bidirectional stream, the client sends its items with the same header.
+    StreamItem {
+        /// Message id of the request
+        id: MessageId,
+    },
+
+    /// Header of a request that opens a bidirectional stream
+    ///
+    /// The body is the same as that of `Request`. After this, the client sends its items
+    /// as `StreamItem`s with the same message id, and `StreamEnd` once it has no more
+    /// items. The server sends its items as `StreamItem`s, and the `Response` closes
+    /// the stream in both directions.
+    StreamOpen {
+        /// Message id
+        id: MessageId,
+        /// RPC service and method in the format of "{Service}.{method}"
+        service_method: String,
+        /// RPC timeout, which applies to the whole stream
+        timeout: Duration,
+        /// Metadata of the request
+        metadata: RequestMetadata,
+    },
+
+    /// The client has no more items to send on the stream with the message id
+    ///
+    /// The body is an unit type ie. `()`
+    StreamEnd(MessageId),
+}
+
+impl Header {
+    /// Creates the header of a request, which is a `Header::RequestWithMetadata` if
+    /// `metadata` is not empty and a `Header::Request` otherwise
+    pub fn request(
+        id: MessageId,
+        service_method: String,
+        timeout: Duration,
+        metadata: RequestMetadata,
+    ) -> Self {
+        if metadata.is_empty() {
+            Self::Request {
+                id,
+                service_method,
+                timeout,
+            }
+        } else {
+            Self::RequestWithMetadata {
+                id,
+                service_method,
+                timeout,
+                metadata,
+            }
+        }
+    }
 }
 
 impl Metadata for Header {
@@ -127,6 +274,11 @@ impl Metadata for Header {
             Self::Produce { id, .. } => id.clone(),
             Self::Consume { id, .. } => id.clone(),
             Self::Ext { id, .. } => id.clone(),
+            Self::RequestWithMetadata { id, .. } => id.clone(),
+            Self::Handshake(_) => 0,
+            Self::StreamItem { id } => id.clone(),
+            Self::StreamOpen { id, .. } => id.clone(),
+            Self::StreamEnd(id) => id.clone(),
         }
     }
 }
@@ -134,6 +286,30 @@ impl Metadata for Header {
 pub(crate) type OutboundBody = dyn erased_serde::Serialize + Send + Sync;
 pub(crate) type InboundBody = dyn erased_serde::Deserializer<'static> + Send;
 
+/// Prefix of the message with which a peer announces its codec when the codec is
+/// negotiated (see `ServerBuilder::negotiate_codec`). The announcement is written in a
+/// header frame as is instead of being marshaled, so that a peer built with another
+/// codec can still read it.
+const CODEC_ANNOUNCEMENT: &[u8] = b"toy-rpc/codec/";
+
+/// The announcement of `codec`, which ends with a newline so that the line delimited
+/// `serde_json` codec reads it as one message
+#[cfg_attr(not(any(feature = "server", feature = "client")), allow(dead_code))]
+pub(crate) fn codec_announcement(codec: &str) -> Vec<u8> {
+    let mut buf = CODEC_ANNOUNCEMENT.to_vec();
+    buf.extend_from_slice(codec.as_bytes());
+    buf.push(b'\n');
+    buf
+}
+
+/// The codec announced by `payload`, or `None` if `payload` is not an announcement,
+/// ie. a header of the peer that doesn't negotiate the codec
+#[cfg_attr(not(any(feature = "server", feature = "client")), allow(dead_code))]
+pub(crate) fn announced_codec(payload: &[u8]) -> Option<&str> {
+    let codec = payload.strip_prefix(CODEC_ANNOUNCEMENT)?;
+    std::str::from_utf8(codec).ok().map(str::trim_end)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -187,4 +363,103 @@ mod tests {
         let size = bincode_opt.serialized_size(&opt).unwrap();
         println!("size: {:?}", size);
     }
+
+    #[test]
+    fn request_metadata_is_only_sent_when_not_empty() {
+        let bincode_opt = bincode::DefaultOptions::new().with_varint_encoding();
+        let timeout = Duration::from_secs(10);
+
+        let header = Header::request(1, "Foo.bar".into(), timeout, RequestMetadata::new());
+        let plain = Header::Request {
+            id: 1,
+            service_method: "Foo.bar".into(),
+            timeout,
+        };
+        assert_eq!(
+            bincode_opt.serialize(&header).unwrap(),
+            bincode_opt.serialize(&plain).unwrap()
+        );
+
+        let mut metadata = RequestMetadata::new();
+        metadata.insert("token".into(), "secret".into());
+        let header = Header::request(1, "Foo.bar".into(), timeout, metadata.clone());
+        let bytes = bincode_opt.serialize(&header).unwrap();
+        match bincode_opt.deserialize(&bytes).unwrap() {
+            Header::RequestWithMetadata {
+                id,
+                service_method,
+                metadata: de,
+                ..
+            } => {
+                assert_eq!(id, 1);
+                assert_eq!(service_method, "Foo.bar");
+                assert_eq!(de, metadata);
+            }
+            header => panic!("Unexpected header {:?}", header),
+        }
+    }
+
+    /// Runs with whichever codec is enabled (bincode, json, cbor or rmp)
+    #[cfg(any(feature = "async_std_runtime", feature = "tokio_runtime"))]
+    #[test]
+    fn request_metadata_round_trips_with_the_codec() {
+        use crate::codec::{DefaultCodec, Marshal, Unmarshal};
+        type C = DefaultCodec<(), (), ()>;
+
+        let mut metadata = RequestMetadata::new();
+        metadata.insert("token".into(), "secret".into());
+        metadata.insert("tenant".into(), "".into());
+        let timeout = Duration::from_millis(1500);
+        let header = Header::request(7, "Foo.bar".into(), timeout, metadata.clone());
+        let bytes = C::marshal(&header).unwrap();
+        match C::unmarshal::<Header>(&bytes).unwrap() {
+            Header::RequestWithMetadata {
+                id,
+                service_method,
+                timeout: de_timeout,
+                metadata: de,
+            } => {
+                assert_eq!(id, 7);
+                assert_eq!(service_method, "Foo.bar");
+                assert_eq!(de_timeout, timeout);
+                assert_eq!(de, metadata);
+            }
+            header => panic!("Unexpected header {:?}", header),
+        }
+
+        let header = Header::request(8, "Foo.bar".into(), timeout, RequestMetadata::new());
+        let bytes = C::marshal(&header).unwrap();
+        match C::unmarshal::<Header>(&bytes).unwrap() {
+            Header::Request { id, .. } => assert_eq!(id, 8),
+            header => panic!("Unexpected header {:?}", header),
+        }
+    }
+
+    #[test]
+    fn codec_announcement_is_not_a_header() {
+        let bincode_opt = bincode::DefaultOptions::new().with_varint_encoding();
+
+        let announcement = codec_announcement("cbor");
+        assert_eq!(announced_codec(&announcement), Some("cbor"));
+        assert!(bincode_opt.deserialize::<Header>(&announcement).is_err());
+
+        let header = bincode_opt
+            .serialize(&Header::Handshake(ConnectionLimits::new()))
+            .unwrap();
+        assert_eq!(announced_codec(&header), None);
+    }
+
+    #[test]
+    fn effective_limits_are_the_smaller_of_both_sides() {
+        let client = ConnectionLimits::new()
+            .set_max_concurrent_calls(4)
+            .set_max_subscriptions(1);
+        let server = ConnectionLimits::new().set_max_concurrent_calls(2);
+
+        let limits = client.min(&server);
+        assert_eq!(limits.max_concurrent_calls, Some(2));
+        assert_eq!(limits.max_subscriptions, Some(1));
+        assert_eq!(limits, server.min(&client));
+        assert_eq!(ConnectionLimits::new().min(&server), server);
+    }
 }
@@ -2,27 +2,36 @@
 
 use std::future::Future;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::protocol::InboundBody;
+use crate::protocol::{ConnectionLimits, InboundBody, OutboundBody, RequestMetadata};
 use crate::pubsub::SeqId;
 use crate::service::{ArcAsyncServiceCall, HandlerResult};
 
-use crate::{error::Error, message::MessageId};
+use crate::trace::error_event;
+use crate::{codec::ContentType, error::Error, message::MessageId};
 
 cfg_if::cfg_if! {
     if #[cfg(not(feature = "http_actix_web"))] {
-        use std::collections::HashMap;
+        use std::collections::{HashMap, HashSet};
+        use std::net::SocketAddr;
         use std::marker::PhantomData;
 
         use flume::Sender;
         use brw::{Running, Broker};
         use futures::sink::{Sink, SinkExt};
+        use futures::FutureExt;
+        use std::panic::AssertUnwindSafe;
+
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
         use crate::server::pubsub::PubSubResponder;
+        use crate::stats::WriterStats;
+        use crate::util::{panic_message, Semaphore};
         use crate::pubsub::{AckModeNone, AckModeAuto};
 
-        use super::ClientId;
+        use super::{interceptor::{self, Interceptors, RequestHeader}, rate_limit::RateLimiter, ClientId, Context, SharedState};
+        use crate::trace::{debug_event, debug_span, Instrument};
         use super::pubsub::PubSubItem;
         use super::writer::ServerWriterItem;
     }
@@ -40,20 +49,34 @@ use ::async_std::task::JoinHandle;
 ))]
 use ::tokio::task::JoinHandle;
 
+/// Message of the error returned to the client when a handler exceeds the handler timeout
+#[cfg(not(feature = "http_actix_web"))]
+const HANDLER_TIMED_OUT: &str = "handler timed out";
+
+/// Start of the message of the error returned to the client when a handler panics
+#[cfg(not(feature = "http_actix_web"))]
+const HANDLER_PANICKED: &str = "handler panicked";
+
 #[cfg_attr(feature = "http_actix_web", derive(actix::Message))]
 #[cfg_attr(feature = "http_actix_web", rtype(result = "()"))]
 pub(crate) enum ServerBrokerItem {
-    Request {
-        call: ArcAsyncServiceCall,
-        id: MessageId,
-        method: String,
-        duration: Duration,
-        deserializer: Box<InboundBody>,
-    },
+    Request(IncomingRequest),
     Response {
         id: MessageId,
         result: HandlerResult,
     },
+    /// An item of a streaming response, sent by the handler
+    StreamItem {
+        id: MessageId,
+        body: Box<OutboundBody>,
+    },
+    /// An item sent by the client on a bidirectional stream
+    IncomingItem {
+        id: MessageId,
+        item: Box<InboundBody>,
+    },
+    /// The client has no more items to send on the stream
+    IncomingEnd(MessageId),
     Cancel(MessageId),
     // A new publish from the client publisher
     Publish {
@@ -80,43 +103,302 @@ pub(crate) enum ServerBrokerItem {
     InboundAck {
         seq_id: SeqId,
     },
+    /// Handshake from the client with the limits declared by the client
+    Handshake(ConnectionLimits),
+    /// The client announced its codec
+    AnnounceCodec,
+    /// Lets the writer schedule the chunks of large responses and the flushes through
+    /// the broker
+    AttachWriter,
+    /// The writer has more chunks of large responses to write
+    WriteChunks,
+    /// The writer has messages in its write buffer to flush
+    Flush,
+    /// The server is shutting down and the reader has stopped reading requests. The
+    /// connection is closed once the requests that are being handled are finished.
+    Draining,
+    /// The requests that are still being handled are canceled after the shutdown timeout
+    DrainTimeout,
     Stopping,
     Stop,
 }
 
+/// A request read from the client, with the handler it is dispatched to
+pub(crate) struct IncomingRequest {
+    pub call: ArcAsyncServiceCall,
+    pub id: MessageId,
+    pub service: String,
+    pub method: String,
+    pub duration: Duration,
+    pub metadata: RequestMetadata,
+    /// Whether the request opens a bidirectional stream
+    pub opens_stream: bool,
+    /// Content type of the body if it is not the codec of the connection
+    pub content_type: Option<ContentType>,
+    pub deserializer: Box<InboundBody>,
+}
+
+/// Settings of the server that apply to each connection
+#[cfg(not(feature = "http_actix_web"))]
+pub(crate) struct BrokerConfig {
+    /// Max number of concurrently executing requests on the connection
+    pub max_concurrent_requests: Option<usize>,
+    /// Max number of requests on the connection that wait for a slot
+    pub max_queued_requests: Option<usize>,
+    /// Max time a handler may run before it is abandoned
+    pub handler_timeout: Option<Duration>,
+    /// Whether a panic of a handler is turned into an error response
+    pub catch_panics: bool,
+    /// Max time the requests are waited for when the server is shutting down
+    pub shutdown_timeout: Option<Duration>,
+    pub num_in_flight: Arc<AtomicUsize>,
+    /// Interceptors wrapped around the handlers
+    pub interceptors: Interceptors,
+    /// Limits declared by the server
+    pub limits: ConnectionLimits,
+    /// Rate limiter of the connection
+    pub rate_limiter: Option<Box<dyn RateLimiter>>,
+}
+
+#[cfg(not(feature = "http_actix_web"))]
+impl BrokerConfig {
+    pub fn from_shared(shared: &SharedState) -> Self {
+        Self {
+            max_concurrent_requests: shared.max_concurrent_requests,
+            max_queued_requests: shared.max_queued_requests,
+            handler_timeout: shared.handler_timeout,
+            catch_panics: shared.catch_panics,
+            shutdown_timeout: shared.shutdown_timeout,
+            num_in_flight: shared.num_in_flight.clone(),
+            interceptors: shared.interceptors.clone(),
+            limits: shared.connection_limits,
+            rate_limiter: shared
+                .rate_limiter
+                .as_ref()
+                .map(|new_limiter| new_limiter()),
+        }
+    }
+}
+
 #[cfg(not(feature = "http_actix_web"))]
 pub(crate) struct ServerBroker<AckMode> {
     pub client_id: ClientId,
-    pub executions: HashMap<MessageId, JoinHandle<()>>,
+    pub executions: HashMap<MessageId, Execution>,
+    /// Forwards the items sent by the client to the handlers of bidirectional streams
+    pub incoming: HashMap<MessageId, Sender<Box<InboundBody>>>,
+    /// Canceled requests whose handler finished before it could be aborted. Their
+    /// responses are already on the way to the broker and are discarded.
+    pub discarded: HashSet<MessageId>,
     pub pubsub_broker: Sender<PubSubItem>,
+    /// Limits the number of concurrently executing requests on this connection
+    pub semaphore: Option<Arc<Semaphore>>,
+    /// Max number of requests that are executing or waiting for a slot of the semaphore
+    pub max_queued_requests: Option<usize>,
+    /// Max time a handler may run before it is abandoned
+    pub handler_timeout: Option<Duration>,
+    /// Whether a panic of a handler is turned into an error response
+    pub catch_panics: bool,
+    /// Max time the requests are waited for when the server is shutting down
+    pub shutdown_timeout: Option<Duration>,
+    /// Whether the connection is closed once the requests being handled are finished
+    pub draining: bool,
+    pub num_in_flight: Arc<AtomicUsize>,
+    /// Interceptors wrapped around the handlers
+    pub interceptors: Interceptors,
+    /// Address of the connected client, if the transport has one
+    pub peer_addr: Option<SocketAddr>,
+    /// Limits declared by the server
+    pub declared_limits: ConnectionLimits,
+    /// Limits in effect on this connection
+    pub limits: ConnectionLimits,
+    /// Topics the client is subscribed to
+    pub topics: HashSet<String>,
+    /// Rate limiter of this connection
+    pub rate_limiter: Option<Box<dyn RateLimiter>>,
+    pub stats: Arc<WriterStats>,
 
     ack_mode: PhantomData<AckMode>,
 }
 
 #[cfg(not(feature = "http_actix_web"))]
 impl<AckMode> ServerBroker<AckMode> {
-    pub fn new(client_id: ClientId, pubsub_broker: Sender<PubSubItem>) -> Self {
+    pub fn new(
+        client_id: ClientId,
+        pubsub_broker: Sender<PubSubItem>,
+        peer_addr: Option<SocketAddr>,
+        stats: Arc<WriterStats>,
+        config: BrokerConfig,
+    ) -> Self {
         Self {
             client_id,
             executions: HashMap::new(),
+            incoming: HashMap::new(),
+            discarded: HashSet::new(),
             pubsub_broker,
+            semaphore: config
+                .max_concurrent_requests
+                .map(|n| Arc::new(Semaphore::new(n))),
+            max_queued_requests: config
+                .max_concurrent_requests
+                .zip(config.max_queued_requests)
+                .map(|(executing, queued)| executing.saturating_add(queued)),
+            handler_timeout: config.handler_timeout,
+            catch_panics: config.catch_panics,
+            shutdown_timeout: config.shutdown_timeout,
+            draining: false,
+            num_in_flight: config.num_in_flight,
+            interceptors: config.interceptors,
+            peer_addr,
+            declared_limits: config.limits,
+            limits: config.limits,
+            topics: HashSet::new(),
+            rate_limiter: config.rate_limiter,
+            stats,
             ack_mode: PhantomData,
         }
     }
 
-    fn handle_request<'a>(
-        &'a mut self,
-        ctx: &'a Arc<brw::Context<ServerBrokerItem>>,
-        call: ArcAsyncServiceCall,
-        id: MessageId,
-        method: String,
-        duration: Duration,
-        deserializer: Box<InboundBody>,
-    ) -> Result<(), Error> {
-        let fut = call(method, deserializer);
+    async fn handle_request<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        ctx: &'w Arc<brw::Context<ServerBrokerItem>>,
+        request: IncomingRequest,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ServerWriterItem, Error = flume::SendError<ServerWriterItem>> + Send + Unpin,
+    {
+        let IncomingRequest {
+            call,
+            id,
+            service,
+            method,
+            duration,
+            metadata,
+            opens_stream,
+            content_type,
+            deserializer,
+        } = request;
+        if self.executions.contains_key(&id) {
+            // a response sent through the broker would end the execution that uses the id
+            log::error!("Message id {} is still used by a request being handled", id);
+            self.stats.add_protocol_violation();
+            let msg = ServerWriterItem::Response {
+                id,
+                result: Err(Error::ExecutionError(format!(
+                    "Message id {} is still in use",
+                    id
+                ))),
+                content_type: None,
+                enqueued_at: Instant::now(),
+            };
+            return writer.send(msg).await.map_err(|err| err.into());
+        }
+        if let Some(max) = self.limits.max_concurrent_calls {
+            if self.executions.len() >= max as usize {
+                debug_event!(
+                    client_id = self.client_id,
+                    message_id = id,
+                    "Max number of concurrent calls exceeded"
+                );
+                self.stats.add_protocol_violation();
+                let result = Err(Error::Busy);
+                return ctx
+                    .broker
+                    .send(ServerBrokerItem::Response { id, result })
+                    .map_err(Into::into);
+            }
+        }
+        if let Some(max) = self.max_queued_requests {
+            if self.executions.len() >= max {
+                debug_event!(
+                    client_id = self.client_id,
+                    message_id = id,
+                    "Too many requests waiting for a slot"
+                );
+                let result = Err(Error::Busy);
+                return ctx
+                    .broker
+                    .send(ServerBrokerItem::Response { id, result })
+                    .map_err(Into::into);
+            }
+        }
+        let header = || RequestHeader {
+            id,
+            service: service.clone(),
+            method: method.clone(),
+        };
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            if !rate_limiter.try_acquire(&header()) {
+                debug_event!(
+                    client_id = self.client_id,
+                    message_id = id,
+                    "Request is rate limited"
+                );
+                let result = Err(Error::RateLimited);
+                return ctx
+                    .broker
+                    .send(ServerBrokerItem::Response { id, result })
+                    .map_err(Into::into);
+            }
+        }
+        if duration.is_zero() {
+            // the client has already given up on the request
+            debug_event!(
+                client_id = self.client_id,
+                message_id = id,
+                "Deadline of request has passed"
+            );
+            let result = Err(Error::Timeout(id));
+            return ctx
+                .broker
+                .send(ServerBrokerItem::Response { id, result })
+                .map_err(Into::into);
+        }
+        let mut context = Context::new(id, self.peer_addr, metadata)
+            .with_items(ctx.broker.clone())
+            .with_deadline(duration);
+        let span = debug_span!("handle_request", client_id = self.client_id, message_id = id, method = %method, trace_id = ?context.trace_id());
+        if opens_stream {
+            let (tx, rx) = flume::unbounded();
+            self.incoming.insert(id, tx);
+            context = context.with_incoming(rx);
+        }
+        let handler = interceptor::wrap(
+            &self.interceptors,
+            header,
+            call(method.clone(), deserializer),
+        );
+        let fut = context.clone().scope(handler);
+        let semaphore = self.semaphore.clone();
+        let num_in_flight = self.num_in_flight.clone();
+        let handler_timeout = self.handler_timeout;
+        let handler_context = context.clone();
+        let catch_panics = self.catch_panics;
+        let service_method = format!("{}.{}", service, method);
+        let fut = async move {
+            // The broker keeps processing other items (ie. cancellation) while waiting
+            let _permit = match &semaphore {
+                Some(semaphore) => Some(semaphore.acquire().await),
+                None => None,
+            };
+            let _in_flight = InFlightGuard::new(num_in_flight);
+            let fut = execute_catching_panic(id, &service_method, catch_panics, fut);
+            match handler_timeout {
+                Some(limit) => execute_capped_call(id, limit, handler_context, fut).await,
+                None => fut.await,
+            }
+        }
+        .instrument(span);
         let _broker = ctx.broker.clone();
         let handle = spawn_timed_request_execution(_broker, duration, id, fut);
-        self.executions.insert(id, handle);
+        self.executions.insert(
+            id,
+            Execution {
+                handle,
+                context,
+                content_type,
+            },
+        );
         Ok(())
     }
 
@@ -129,21 +411,155 @@ impl<AckMode> ServerBroker<AckMode> {
     where
         W: Sink<ServerWriterItem, Error = flume::SendError<ServerWriterItem>> + Send + Unpin,
     {
-        self.executions.remove(&id);
-        let msg = ServerWriterItem::Response { id, result };
+        let content_type = self
+            .executions
+            .remove(&id)
+            .and_then(|exec| exec.content_type);
+        self.incoming.remove(&id);
+        if self.discarded.remove(&id) {
+            // the client is already told that the request is canceled
+            debug_event!(
+                client_id = self.client_id,
+                message_id = id,
+                "Discarding response of canceled request"
+            );
+            return Ok(());
+        }
+        let msg = ServerWriterItem::Response {
+            id,
+            result,
+            content_type,
+            enqueued_at: Instant::now(),
+        };
         writer.send(msg).await.map_err(|err| err.into())
     }
 
-    async fn handle_cancel(&mut self, id: MessageId) -> Result<(), Error> {
-        if let Some(handle) = self.executions.remove(&id) {
-            #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
-            handle.abort();
-            #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
-            handle.cancel().await;
+    /// Starts closing the connection as the server is shutting down. The requests that
+    /// are being handled are waited for, up to the shutdown timeout.
+    async fn handle_draining(
+        &mut self,
+        ctx: &Arc<brw::Context<ServerBrokerItem>>,
+    ) -> Result<(), Error> {
+        self.draining = true;
+        // nothing more is read from the client, so the incoming streams end here
+        self.incoming.clear();
+        if let Some(timeout) = self.shutdown_timeout {
+            if !self.executions.is_empty() {
+                let broker = ctx.broker.clone();
+                spawn_after(timeout, async move {
+                    let _ = broker.send_async(ServerBrokerItem::DrainTimeout).await;
+                });
+            }
+        }
+        self.stop_if_drained(ctx).await
+    }
+
+    /// Closes the connection if it is draining and no request is being handled
+    async fn stop_if_drained(
+        &mut self,
+        ctx: &Arc<brw::Context<ServerBrokerItem>>,
+    ) -> Result<(), Error> {
+        if !self.draining || !self.executions.is_empty() {
+            return Ok(());
+        }
+        self.draining = false;
+        log::debug!("All requests are finished, closing the connection");
+        ctx.broker
+            .send_async(ServerBrokerItem::Stopping)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Forwards an item of a streaming response to the writer unless the request is
+    /// already finished or canceled
+    async fn handle_stream_item<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        id: MessageId,
+        body: Box<OutboundBody>,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ServerWriterItem, Error = flume::SendError<ServerWriterItem>> + Send + Unpin,
+    {
+        if !self.executions.contains_key(&id) {
+            debug_event!(
+                client_id = self.client_id,
+                message_id = id,
+                "Discarding stream item of finished request"
+            );
+            return Ok(());
+        }
+        writer
+            .send(ServerWriterItem::StreamItem { id, body })
+            .await
+            .map_err(|err| err.into())
+    }
+
+    /// Forwards an item sent by the client to the handler of the stream
+    fn handle_incoming_item(&mut self, id: MessageId, item: Box<InboundBody>) -> Result<(), Error> {
+        match self.incoming.get(&id) {
+            Some(tx) => {
+                // the handler may have stopped reading the stream
+                let _ = tx.send(item);
+            }
+            None => {
+                debug_event!(
+                    client_id = self.client_id,
+                    message_id = id,
+                    "Discarding item of unknown stream"
+                );
+            }
         }
         Ok(())
     }
 
+    /// Cancels the execution and acknowledges the cancellation, which tells the client
+    /// that the handler is no longer running
+    async fn handle_cancel<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        id: MessageId,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ServerWriterItem, Error = flume::SendError<ServerWriterItem>> + Send + Unpin,
+    {
+        self.incoming.remove(&id);
+        if let Some(execution) = self.executions.remove(&id) {
+            debug_event!(
+                client_id = self.client_id,
+                message_id = id,
+                "Canceling request"
+            );
+            if execution.cancel().await {
+                self.discarded.insert(id);
+            }
+        }
+        writer
+            .send(ServerWriterItem::Ack { id })
+            .await
+            .map_err(|err| err.into())
+    }
+
+    /// Settles the limits of the connection and replies with the limits of the server
+    async fn handle_handshake<'w, W>(
+        &'w mut self,
+        writer: &'w mut W,
+        client_limits: ConnectionLimits,
+    ) -> Result<(), Error>
+    where
+        W: Sink<ServerWriterItem, Error = flume::SendError<ServerWriterItem>> + Send + Unpin,
+    {
+        self.limits = self.declared_limits.min(&client_limits);
+        debug_event!(client_id = self.client_id, limits = ?self.limits, "Handshake");
+        if let Some(max) = self.limits.max_concurrent_calls {
+            self.executions.reserve(max as usize);
+        }
+        writer
+            .send(ServerWriterItem::Handshake(self.declared_limits))
+            .await
+            .map_err(|err| err.into())
+    }
+
     async fn handle_publish_inner(
         &mut self,
         id: MessageId,
@@ -170,6 +586,13 @@ impl<AckMode> ServerBroker<AckMode> {
         topic: String,
     ) -> Result<(), Error> {
         log::debug!("Message ID: {}, Subscribe to topic: {}", &id, &topic);
+        if let Some(max) = self.limits.max_subscriptions {
+            if !self.topics.contains(&topic) && self.topics.len() >= max as usize {
+                self.stats.add_protocol_violation();
+                return Err(Error::Busy);
+            }
+        }
+        self.topics.insert(topic.clone());
         let sender = PubSubResponder::Sender(ctx.broker.clone());
         let msg = PubSubItem::Subscribe {
             client_id: self.client_id,
@@ -185,6 +608,7 @@ impl<AckMode> ServerBroker<AckMode> {
 
     async fn handle_unsubscribe(&mut self, id: MessageId, topic: String) -> Result<(), Error> {
         log::debug!("Message ID: {}, Unsubscribe from topic: {}", &id, &topic);
+        self.topics.remove(&topic);
         let msg = PubSubItem::Unsubscribe {
             client_id: self.client_id,
             topic,
@@ -272,6 +696,55 @@ impl ServerBroker<AckModeAuto> {
     }
 }
 
+/// A request that is being executed
+#[cfg(not(feature = "http_actix_web"))]
+pub(crate) struct Execution {
+    handle: JoinHandle<()>,
+    context: Context,
+    /// Content type of the response if it is not the codec of the connection
+    content_type: Option<ContentType>,
+}
+
+#[cfg(not(feature = "http_actix_web"))]
+impl Execution {
+    /// Cancels the request context (and thus the tokens bound to downstream calls)
+    /// and waits for the execution to stop.
+    ///
+    /// Returns `true` if the execution had already finished, in which case its response
+    /// is already sent to the broker.
+    async fn cancel(self) -> bool {
+        self.context.cancel();
+        #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+        {
+            self.handle.abort();
+            self.handle.await.is_ok()
+        }
+        #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+        {
+            self.handle.cancel().await.is_some()
+        }
+    }
+}
+
+/// Keeps track of the number of executing requests
+#[cfg(not(feature = "http_actix_web"))]
+struct InFlightGuard(Arc<AtomicUsize>);
+
+#[cfg(not(feature = "http_actix_web"))]
+impl InFlightGuard {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+#[cfg(not(feature = "http_actix_web"))]
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 macro_rules! impl_server_broker_for_ack_modes {
     ($($ack_mode:ty),*) => {
         $(
@@ -293,20 +766,27 @@ macro_rules! impl_server_broker_for_ack_modes {
                     W: Sink<Self::WriterItem, Error = flume::SendError<Self::WriterItem>> + Send + Unpin,
                 {
                     let result = match item {
-                        ServerBrokerItem::Request {
-                            call,
-                            id,
-                            method,
-                            duration,
-                            deserializer,
-                        } => {
-                            self.handle_request(ctx, call, id, method, duration, deserializer)
+                        ServerBrokerItem::Request(request) => {
+                            self.handle_request(&mut writer, ctx, request).await
                         },
                         ServerBrokerItem::Response { id, result } => {
-                           self.handle_response(&mut writer, id, result).await
+                            let result = self.handle_response(&mut writer, id, result).await;
+                            self.stop_if_drained(ctx).await.and(result)
+                        },
+                        ServerBrokerItem::StreamItem { id, body } => {
+                            self.handle_stream_item(&mut writer, id, body).await
+                        },
+                        ServerBrokerItem::IncomingItem { id, item } => {
+                            self.handle_incoming_item(id, item)
+                        },
+                        ServerBrokerItem::IncomingEnd(id) => {
+                            // the stream of the handler ends once the sender is dropped
+                            self.incoming.remove(&id);
+                            Ok(())
                         },
                         ServerBrokerItem::Cancel(id) => {
-                            self.handle_cancel(id).await
+                            let result = self.handle_cancel(&mut writer, id).await;
+                            self.stop_if_drained(ctx).await.and(result)
                         },
                         ServerBrokerItem::Publish { id, topic, content } => {
                             self.handle_publish(&mut writer, id, topic, content).await
@@ -323,13 +803,46 @@ macro_rules! impl_server_broker_for_ack_modes {
                         ServerBrokerItem::InboundAck {seq_id} => {
                             self.handle_inbound_ack(seq_id).await
                         },
+                        ServerBrokerItem::Handshake(limits) => {
+                            self.handle_handshake(&mut writer, limits).await
+                        },
+                        ServerBrokerItem::AnnounceCodec => {
+                            writer.send(ServerWriterItem::AnnounceCodec).await
+                                .map_err(Into::into)
+                        },
+                        ServerBrokerItem::AttachWriter => {
+                            writer.send(ServerWriterItem::Attach(ctx.broker.clone())).await
+                                .map_err(Into::into)
+                        },
+                        ServerBrokerItem::WriteChunks => {
+                            writer.send(ServerWriterItem::WriteChunks).await
+                                .map_err(Into::into)
+                        },
+                        ServerBrokerItem::Flush => {
+                            writer.send(ServerWriterItem::Flush).await
+                                .map_err(Into::into)
+                        },
+                        ServerBrokerItem::Draining => {
+                            self.handle_draining(ctx).await
+                        },
+                        ServerBrokerItem::DrainTimeout => {
+                            if self.draining {
+                                log::warn!(
+                                    "Canceling {} requests after the shutdown timeout",
+                                    self.executions.len()
+                                );
+                                self.draining = false;
+                                ctx.broker.send_async(ServerBrokerItem::Stopping).await
+                                    .map_err(Into::into)
+                            } else {
+                                Ok(())
+                            }
+                        },
                         ServerBrokerItem::Stopping => {
-                            for (_, handle) in self.executions.drain() {
+                            self.incoming.clear();
+                            for (_, execution) in self.executions.drain() {
                                 log::debug!("Stopping execution as client is disconnected");
-                                #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
-                                handle.abort();
-                                #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
-                                handle.cancel().await;
+                                execution.cancel().await;
                             }
 
                             let result = writer.send(ServerWriterItem::Stopping).await
@@ -357,6 +870,28 @@ macro_rules! impl_server_broker_for_ack_modes {
 
 impl_server_broker_for_ack_modes!(AckModeNone, AckModeAuto);
 
+/// Runs `fut` in a new task after `delay`
+#[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+fn spawn_after(delay: Duration, fut: impl Future<Output = ()> + Send + 'static) {
+    ::async_std::task::spawn(async move {
+        ::async_std::task::sleep(delay).await;
+        fut.await
+    });
+}
+
+/// Runs `fut` in a new task after `delay`
+#[cfg(all(
+    feature = "tokio_runtime",
+    not(feature = "async_std_runtime"),
+    not(feature = "http_actix_web")
+))]
+fn spawn_after(delay: Duration, fut: impl Future<Output = ()> + Send + 'static) {
+    ::tokio::task::spawn(async move {
+        ::tokio::time::sleep(delay).await;
+        fut.await
+    });
+}
+
 /// Spawn the execution in a async_std task and return the JoinHandle
 #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
 fn spawn_timed_request_execution(
@@ -400,6 +935,7 @@ pub(crate) async fn execute_call(
     fut: impl Future<Output = HandlerResult>,
 ) -> HandlerResult {
     let result: HandlerResult = fut.await.map_err(|err| {
+        error_event!(message_id = id, error = %err, "Error executing request");
         log::error!(
             "Error found executing request id: {}, error msg: {}",
             &id,
@@ -417,6 +953,59 @@ pub(crate) async fn execute_call(
     result
 }
 
+/// Turns a panic of the handler into an error response if `catch` is true, so that
+/// only the request of the handler fails
+#[cfg(not(feature = "http_actix_web"))]
+async fn execute_catching_panic(
+    id: MessageId,
+    service_method: &str,
+    catch: bool,
+    fut: impl Future<Output = HandlerResult>,
+) -> HandlerResult {
+    if !catch {
+        return fut.await;
+    }
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(result) => result,
+        Err(panic) => {
+            let msg = panic_message(&*panic);
+            error_event!(message_id = id, service_method = %service_method, "Handler panicked");
+            log::error!(
+                "Handler of request {} ({}) panicked: {}",
+                id,
+                service_method,
+                msg
+            );
+            Err(Error::ExecutionError(format!(
+                "{}: {}",
+                HANDLER_PANICKED, msg
+            )))
+        }
+    }
+}
+
+/// Abandons the handler once it runs longer than `limit`. The context is canceled so
+/// that the calls made by the handler are canceled as well
+#[cfg(not(feature = "http_actix_web"))]
+async fn execute_capped_call(
+    id: MessageId,
+    limit: Duration,
+    context: Context,
+    fut: impl Future<Output = HandlerResult>,
+) -> HandlerResult {
+    #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+    let result = ::async_std::future::timeout(limit, fut).await.ok();
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    let result = ::tokio::time::timeout(limit, fut).await.ok();
+
+    result.unwrap_or_else(|| {
+        debug_event!(message_id = id, "Handler timed out");
+        log::error!("Handler of request {} timed out after {:?}", id, limit);
+        context.cancel();
+        Err(Error::ExecutionError(HANDLER_TIMED_OUT.into()))
+    })
+}
+
 #[cfg(not(feature = "http_actix_web"))]
 pub(crate) async fn execute_timed_call(
     id: MessageId,
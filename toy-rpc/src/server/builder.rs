@@ -1,6 +1,5 @@
 //! Builder of the Server
 
-use erased_serde as erased;
 use std::{collections::HashMap, marker::PhantomData, sync::Arc, time::Duration};
 
 #[cfg(any(
@@ -10,9 +9,19 @@ use std::{collections::HashMap, marker::PhantomData, sync::Arc, time::Duration};
 ))]
 use super::Server;
 
+use super::{
+    introspect::{introspection_service, MethodNames},
+    rate_limit::{NewRateLimiter, RateLimit, RateLimiter, TokenBucket},
+    ConnectionLimitMode, ErrorMode, Interceptor, DEFAULT_MAX_CONCURRENT_REQUESTS,
+    INTROSPECTION_SERVICE,
+};
+#[cfg(feature = "discovery")]
+use crate::discovery::ServiceAdvert;
 use crate::{
+    health::{health_service, HEALTH_SERVICE},
+    protocol::ConnectionLimits,
     pubsub::{AckModeAuto, AckModeNone, DEFAULT_PUB_RETRIES, DEFAULT_PUB_RETRY_TIMEOUT},
-    service::{build_service, AsyncServiceMap, HandleService, HandlerResultFut, Service},
+    service::{build_service, AsyncServiceMap, Service, ServiceEntry},
     util::RegisterService,
 };
 
@@ -24,6 +33,46 @@ pub struct ServerBuilder<AckMode> {
     pub pub_retry_timeout: Duration,
     /// Max number of retries for publishing
     pub max_num_retries: u32,
+    /// Max number of request handlers that can execute concurrently on one connection
+    pub max_concurrent_requests: Option<usize>,
+    /// Max number of requests of one connection that wait for a slot of
+    /// `max_concurrent_requests`
+    pub max_queued_requests: Option<usize>,
+    /// Timeout after which a connection sending a partial frame is closed
+    pub frame_read_timeout: Option<Duration>,
+    /// Max time a request handler may run before it is abandoned
+    pub handler_timeout: Option<Duration>,
+    /// Whether a panic of a handler is turned into an error response
+    pub catch_panics: bool,
+    /// Max time the requests are waited for when the server is shutting down
+    pub shutdown_timeout: Option<Duration>,
+    /// Response bodies larger than this are written in chunks
+    pub response_chunk_size: Option<usize>,
+    /// How much detail of the handler errors is sent to the clients
+    pub error_mode: ErrorMode,
+    /// Capacities of the read and write buffers of each connection
+    pub buffer_capacity: Option<(usize, usize)>,
+    /// Max number of connections that are served at the same time
+    pub max_connections: Option<usize>,
+    /// What the server does with a new connection when it is at `max_connections`
+    pub connection_limit_mode: ConnectionLimitMode,
+    /// Limits declared to the clients during the handshake
+    pub connection_limits: ConnectionLimits,
+    /// Whether the codec announced by the clients is checked
+    pub negotiate_codec: bool,
+    /// Interceptors wrapped around the handlers, outermost first
+    pub interceptors: Vec<Box<dyn Interceptor>>,
+    /// Advertisement of the server on the local network
+    #[cfg(feature = "discovery")]
+    pub advert: Option<ServiceAdvert>,
+    /// Whether the built-in introspection service is registered
+    pub introspection: bool,
+    /// Whether the built-in health-check service is registered
+    pub health_check: bool,
+    /// Creates the rate limiter of each connection
+    pub(crate) rate_limiter: Option<NewRateLimiter>,
+    /// Names of the methods of each registered service
+    pub(crate) method_names: MethodNames,
     ack_mode: PhantomData<AckMode>,
 }
 
@@ -34,6 +83,26 @@ impl<AckMode> ServerBuilder<AckMode> {
             services: HashMap::new(),
             pub_retry_timeout: DEFAULT_PUB_RETRY_TIMEOUT,
             max_num_retries: DEFAULT_PUB_RETRIES,
+            max_concurrent_requests: Some(DEFAULT_MAX_CONCURRENT_REQUESTS),
+            max_queued_requests: None,
+            frame_read_timeout: None,
+            handler_timeout: None,
+            catch_panics: true,
+            shutdown_timeout: None,
+            response_chunk_size: None,
+            error_mode: ErrorMode::default(),
+            buffer_capacity: None,
+            max_connections: None,
+            connection_limit_mode: ConnectionLimitMode::default(),
+            connection_limits: ConnectionLimits::default(),
+            negotiate_codec: false,
+            interceptors: Vec::new(),
+            #[cfg(feature = "discovery")]
+            advert: None,
+            introspection: false,
+            health_check: false,
+            rate_limiter: None,
+            method_names: MethodNames::new(),
             ack_mode: PhantomData,
         }
     }
@@ -44,6 +113,26 @@ impl<AckMode> ServerBuilder<AckMode> {
             services: self.services,
             pub_retry_timeout: self.pub_retry_timeout,
             max_num_retries: self.max_num_retries,
+            max_concurrent_requests: self.max_concurrent_requests,
+            max_queued_requests: self.max_queued_requests,
+            frame_read_timeout: self.frame_read_timeout,
+            handler_timeout: self.handler_timeout,
+            catch_panics: self.catch_panics,
+            shutdown_timeout: self.shutdown_timeout,
+            response_chunk_size: self.response_chunk_size,
+            error_mode: self.error_mode,
+            buffer_capacity: self.buffer_capacity,
+            max_connections: self.max_connections,
+            connection_limit_mode: self.connection_limit_mode,
+            connection_limits: self.connection_limits,
+            negotiate_codec: self.negotiate_codec,
+            interceptors: self.interceptors,
+            #[cfg(feature = "discovery")]
+            advert: self.advert,
+            introspection: self.introspection,
+            health_check: self.health_check,
+            rate_limiter: self.rate_limiter,
+            method_names: self.method_names,
             ack_mode: PhantomData,
         }
     }
@@ -54,10 +143,366 @@ impl<AckMode> ServerBuilder<AckMode> {
             services: self.services,
             pub_retry_timeout: self.pub_retry_timeout,
             max_num_retries: self.max_num_retries,
+            max_concurrent_requests: self.max_concurrent_requests,
+            max_queued_requests: self.max_queued_requests,
+            frame_read_timeout: self.frame_read_timeout,
+            handler_timeout: self.handler_timeout,
+            catch_panics: self.catch_panics,
+            shutdown_timeout: self.shutdown_timeout,
+            response_chunk_size: self.response_chunk_size,
+            error_mode: self.error_mode,
+            buffer_capacity: self.buffer_capacity,
+            max_connections: self.max_connections,
+            connection_limit_mode: self.connection_limit_mode,
+            connection_limits: self.connection_limits,
+            negotiate_codec: self.negotiate_codec,
+            interceptors: self.interceptors,
+            #[cfg(feature = "discovery")]
+            advert: self.advert,
+            introspection: self.introspection,
+            health_check: self.health_check,
+            rate_limiter: self.rate_limiter,
+            method_names: self.method_names,
             ack_mode: PhantomData,
         }
     }
 
+    /// Sets the maximum number of request handlers that can execute concurrently on
+    /// a single connection. Defaults to `DEFAULT_MAX_CONCURRENT_REQUESTS`.
+    ///
+    /// The requests of a connection are handled concurrently and their responses are
+    /// written as soon as they are ready, so a slow handler does not hold up the other
+    /// calls of the same client. Requests beyond the limit wait for a running handler to
+    /// finish before they are executed. The connection keeps reading while requests are
+    /// waiting, so cancellations are still processed. Any number of requests may wait
+    /// unless `set_max_queued_requests` is set. A value of 0 is treated as 1.
+    ///
+    /// Streaming responses (`Client::call_stream`) and bidirectional streams
+    /// (`Client::open_stream`) hold their slot until the handler returns, so as many
+    /// long-running streams as the limit hold back every other request of the connection.
+    pub fn set_max_concurrent_requests(self, val: usize) -> Self {
+        Self {
+            max_concurrent_requests: Some(val.max(1)),
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of requests of a single connection that wait for a slot of
+    /// `set_max_concurrent_requests`. The requests beyond that are rejected right away
+    /// with `Error::Busy`. There is no limit by default.
+    ///
+    /// This has no effect when the number of concurrently executing requests is unlimited.
+    pub fn set_max_queued_requests(self, val: usize) -> Self {
+        Self {
+            max_queued_requests: Some(val),
+            ..self
+        }
+    }
+
+    /// Removes the limit on the number of request handlers that can execute concurrently
+    /// on a single connection. See `set_max_concurrent_requests`
+    pub fn set_unlimited_concurrent_requests(self) -> Self {
+        Self {
+            max_concurrent_requests: None,
+            ..self
+        }
+    }
+
+    /// Sets the timeout for receiving the rest of a frame once it has started to arrive.
+    /// There is no timeout by default.
+    ///
+    /// The timeout is reset whenever more bytes of the frame arrive, so large messages
+    /// are not affected as long as they keep making progress. The body frame of a request
+    /// must also start arriving within the timeout after its header frame. A connection
+    /// that stalls in the middle of a frame is closed.
+    ///
+    /// This only applies to the framed transport used by `accept`, `accept_with_tls_config`
+    /// and `serve_stream`, and not to WebSocket connections or the `serde_json` codec.
+    pub fn set_frame_read_timeout(self, duration: Duration) -> Self {
+        Self {
+            frame_read_timeout: Some(duration),
+            ..self
+        }
+    }
+
+    /// Caps the time a request handler may run. There is no limit by default.
+    ///
+    /// A handler that is still running after `duration` is abandoned, and the client
+    /// receives `Error::ExecutionError("handler timed out")` for the request. The time
+    /// spent waiting for a slot under `set_max_concurrent_requests` doesn't count.
+    ///
+    /// This is independent of the deadline set by the client, and whichever is reached
+    /// first ends the request. This is not enforced by the `actix-web` integration.
+    pub fn handler_timeout(self, duration: Duration) -> Self {
+        Self {
+            handler_timeout: Some(duration),
+            ..self
+        }
+    }
+
+    /// Sets whether a panic of a request handler is caught. Panics are caught by default.
+    ///
+    /// A caught panic only fails its own request: the client receives
+    /// `Error::ExecutionError("handler panicked: ..")` with the message of the panic, and
+    /// the panic is logged with the method and the message id. The connection and the
+    /// other requests are not affected. With `catch_panics(false)`, the panic unwinds the
+    /// task of the handler like any other panic, and the request is never responded, which
+    /// suits a server that is meant to fail fast, ie. with `panic = "abort"`.
+    ///
+    /// The shared state of the service is not restored after a panic. This is not
+    /// enforced by the `actix-web` integration.
+    pub fn catch_panics(self, catch: bool) -> Self {
+        Self {
+            catch_panics: catch,
+            ..self
+        }
+    }
+
+    /// Caps the time the requests that are being handled are waited for when the server
+    /// shuts down with `Server::accept_with_shutdown`. There is no limit by default.
+    ///
+    /// The requests that are still running after `duration` are canceled, and their
+    /// clients receive an error, as if the connection were closed by the client.
+    pub fn shutdown_timeout(self, duration: Duration) -> Self {
+        Self {
+            shutdown_timeout: Some(duration),
+            ..self
+        }
+    }
+
+    /// Writes response bodies larger than `size` bytes in chunks of `size` bytes.
+    /// Responses are written in one piece by default.
+    ///
+    /// The chunks of large responses on a connection are written in turns with each other
+    /// and with the other messages, so a single huge response does not hold up the small
+    /// responses that become ready after it. The client only sees a response once all its
+    /// chunks have arrived. A value of 0 is treated as 1.
+    ///
+    /// This only applies to the framed transport used by `accept`, `accept_with_tls_config`
+    /// and `serve_stream`, and not to WebSocket connections or the `serde_json` codec.
+    /// The client must be of a version that understands chunked responses.
+    pub fn set_response_chunk_size(self, size: usize) -> Self {
+        Self {
+            response_chunk_size: Some(size.max(1)),
+            ..self
+        }
+    }
+
+    /// Sets how much detail of the errors returned by the handlers is sent to the
+    /// clients. The default is `ErrorMode::Verbose`, which sends the text of the errors
+    /// as is.
+    ///
+    /// With `ErrorMode::Redacted`, the text of `Error::ExecutionError` is replaced with a
+    /// generic message and an error id that is logged on the server along with the
+    /// original text. See `toy_rpc::server::error_mode` for details.
+    pub fn error_mode(self, mode: ErrorMode) -> Self {
+        Self {
+            error_mode: mode,
+            ..self
+        }
+    }
+
+    /// Sets the max number of connections that are served at the same time. There is
+    /// no limit by default.
+    ///
+    /// When the limit is reached, the server stops accepting until a connection is
+    /// closed, or rejects the new connections with `ConnectionLimitMode::Reject`. This
+    /// applies to the connections accepted by `accept`, `accept_with_shutdown`,
+    /// `accept_with_tls_config`, `accept_websocket` and `connection_stream`. A value of 0
+    /// is treated as 1.
+    /// The number of connections being served is returned by `Server::num_connections`.
+    pub fn max_connections(self, val: usize) -> Self {
+        Self {
+            max_connections: Some(val.max(1)),
+            ..self
+        }
+    }
+
+    /// Sets what the server does with a new connection when it is serving
+    /// `max_connections` connections. The default is `ConnectionLimitMode::Wait`.
+    /// See `toy_rpc::server::connection_limit` for details.
+    pub fn connection_limit_mode(self, mode: ConnectionLimitMode) -> Self {
+        Self {
+            connection_limit_mode: mode,
+            ..self
+        }
+    }
+
+    /// Sets the capacities in bytes of the read and write buffers of each connection.
+    /// The buffers are 8 KiB each by default.
+    ///
+    /// The capacities don't limit the size of the messages. Frames larger than the
+    /// buffers are still read and written, only with more system calls, so larger
+    /// buffers help deployments that move large messages. See `Codec::with_capacity`.
+    ///
+    /// This only applies to the framed transport used by `accept`, `accept_with_tls_config`
+    /// and `serve_stream`, and not to WebSocket connections.
+    pub fn set_buffer_capacity(self, read_capacity: usize, write_capacity: usize) -> Self {
+        Self {
+            buffer_capacity: Some((read_capacity, write_capacity)),
+            ..self
+        }
+    }
+
+    /// Sets the limits that apply to every connection. There is no limit by default.
+    ///
+    /// The limits are declared to the clients that perform the handshake (see
+    /// `ClientBuilder::set_connection_limits`), and the effective limits of such a connection
+    /// are the smaller of the limits declared by both sides. A compliant client never
+    /// exceeds them. As a backstop, requests beyond the max number of concurrent calls are
+    /// rejected with `Error::Busy`, subscriptions beyond the max number of subscriptions are
+    /// ignored, and both are counted in `ConnectionStats::protocol_violations`.
+    ///
+    /// Unlike `set_max_concurrent_requests`, which makes the excess requests wait, the
+    /// limits here are hard limits.
+    pub fn set_connection_limits(self, limits: ConnectionLimits) -> Self {
+        Self {
+            connection_limits: limits,
+            ..self
+        }
+    }
+
+    /// Checks that the clients use the same codec as the server. Nothing is checked by
+    /// default.
+    ///
+    /// The codec is still selected by the feature flags when the crate is built, so
+    /// this doesn't let a client pick another codec. It only turns a mismatch into a
+    /// clear error instead of a parse error on every message. A client built with
+    /// `ClientBuilder::negotiate_codec(true)` announces its codec before its first
+    /// message and the server replies with its own. If they don't match, the server
+    /// closes the connection and the calls of the client fail with
+    /// `Error::CodecMismatch`. Clients that don't announce their codec are served as
+    /// usual.
+    ///
+    /// The announcement is only readable if both peers use the same transport, ie. a
+    /// `serde_json` peer over raw TCP cannot talk with a peer of another codec at all,
+    /// while WebSocket connections carry the announcement with any codec.
+    pub fn negotiate_codec(self, enabled: bool) -> Self {
+        Self {
+            negotiate_codec: enabled,
+            ..self
+        }
+    }
+
+    /// Limits the rate of the requests on each connection with a token bucket. There
+    /// is no limit by default.
+    ///
+    /// Every connection has its own bucket, which holds up to `limit.burst` requests
+    /// and refills at `limit.per_second` requests per second. A request that finds
+    /// the bucket empty is answered with `Error::RateLimited` without running the
+    /// handler. See `toy_rpc::server::rate_limit` for details.
+    ///
+    /// This is not enforced by the `actix-web` integration.
+    pub fn rate_limit(self, limit: RateLimit) -> Self {
+        self.rate_limit_with(move || TokenBucket::new(limit))
+    }
+
+    /// Limits the rate of the requests on each connection with the rate limiters
+    /// created by `new_limiter`, which is called once for every connection
+    pub fn rate_limit_with<F, L>(self, new_limiter: F) -> Self
+    where
+        F: Fn() -> L + Send + Sync + 'static,
+        L: RateLimiter,
+    {
+        let new_limiter: NewRateLimiter =
+            Arc::new(move || Box::new(new_limiter()) as Box<dyn RateLimiter>);
+        Self {
+            rate_limiter: Some(new_limiter),
+            ..self
+        }
+    }
+
+    /// Adds an interceptor around the dispatch of requests to the handlers.
+    ///
+    /// Interceptors are applied in the order they are added, so the first one added is
+    /// the outermost and sees the request first and the result last.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let server = Server::builder()
+    ///     .register(foo)
+    ///     .layer(RequireAuth) // runs first
+    ///     .layer(LogRequests) // runs second, right before the handler
+    ///     .build();
+    /// ```
+    pub fn layer<I>(mut self, interceptor: I) -> Self
+    where
+        I: Interceptor,
+    {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Advertises the server on the local network with mDNS/DNS-SD while it is accepting
+    /// connections, so that clients can find it with `Client::discover`.
+    ///
+    /// The advertisement starts when `accept` or `accept_with_shutdown` is called and
+    /// is withdrawn when the server stops accepting connections. A listener bound to an
+    /// unspecified address is advertised with the IPv4 and IPv6 addresses of all the
+    /// interfaces, and one bound to a loopback address fails to start accepting.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let server = Server::builder()
+    ///     .register(foo)
+    ///     .advertise_mdns(ServiceAdvert::new("my-dev-server"))
+    ///     .build();
+    /// ```
+    #[cfg(feature = "discovery")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "discovery")))]
+    pub fn advertise_mdns(self, advert: ServiceAdvert) -> Self {
+        Self {
+            advert: Some(advert),
+            ..self
+        }
+    }
+
+    /// Registers the built-in `__introspect` service, which lists the registered
+    /// services and their methods. See `toy_rpc::server::introspect` for details.
+    ///
+    /// `build` panics if another service is registered as `__introspect`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let server = Server::builder()
+    ///     .register(foo)
+    ///     .enable_introspection()
+    ///     .build();
+    ///
+    /// let services: Vec<String> = client.call("__introspect.list", ()).await?;
+    /// ```
+    pub fn enable_introspection(self) -> Self {
+        Self {
+            introspection: true,
+            ..self
+        }
+    }
+
+    /// Registers the built-in `__health` service, whose `ping` method answers with a
+    /// `HealthStatus`. See `toy_rpc::health` for details.
+    ///
+    /// `build` panics if another service is registered as `__health`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let server = Server::builder()
+    ///     .register(foo)
+    ///     .enable_health_check()
+    ///     .build();
+    ///
+    /// client.health_check().await?;
+    /// ```
+    pub fn enable_health_check(self) -> Self {
+        Self {
+            health_check: true,
+            ..self
+        }
+    }
+
     /// Registers a new service to the `Server` with the default name.
     ///
     /// Internally the `Service` object will be built using the supplied `service`
@@ -82,51 +527,72 @@ impl<AckMode> ServerBuilder<AckMode> {
     /// Register a a service with a name. This allows registering multiple instances
     /// of the same type on the server.
     ///
+    /// # Panics
+    ///
+    /// Panics if a service is already registered under `name`.
+    ///
     /// # Example
     ///
     /// ```rust
-    /// let foo1 = Arc::new(Foo { });
-    /// let foo2 = Arc::new(Foo { });
+    /// let users = Arc::new(KvService::new());
+    /// let sessions = Arc::new(KvService::new());
     /// // construct server
     /// let server = Server::builder()
-    ///     .register(foo1) // this will register `foo1` with the default service name `Foo`
-    ///     .register_with_name("Foo2", foo2) // this will register `foo2` with the service name `Foo2`
+    ///     .register_with_name("users", users) // requests are addressed to "users.get"
+    ///     .register_with_name("sessions", sessions) // and to "sessions.get"
     ///     .build();
     /// ```
-    pub fn register_with_name<S>(self, name: &'static str, service: Arc<S>) -> Self
+    pub fn register_with_name<S>(self, name: impl Into<String>, service: Arc<S>) -> Self
     where
         S: RegisterService + Send + Sync + 'static,
     {
         let service = build_service(service, S::handlers());
-        self.register_service(name, service)
+        self.register_service(vec![name.into()], service)
     }
 
-    /// Register a `Service` instance. This allows registering multiple instances
-    /// of the same type on the server.
+    /// Registers the same instance of a service under each of the `names`, ie. to
+    /// serve a versioned API under both `Foo` and `FooV1`. The service is only built
+    /// once, and all the names share its state.
+    ///
+    /// # Panics
+    ///
+    /// As with `register_with_name`, panics if a name is already taken or is repeated
+    /// in `names`.
     ///
     /// # Example
     ///
     /// ```rust
-    /// let foo1 = Arc::new(Foo { });
-    /// let foo2 = Arc::new(Foo { });
-    ///
-    /// // construct server
+    /// let foo = Arc::new(Foo { });
     /// let server = Server::builder()
-    ///     .register(foo1) // this will register `foo1` with the default service name `Foo`
-    ///     .register_service("Foo2", foo2) // this will register `foo2` with the service name `Foo2`
+    ///     .register_as(&["Foo", "FooV1"], foo)
     ///     .build();
     /// ```
-    fn register_service<S>(self, name: &'static str, service: Service<S>) -> Self
+    pub fn register_as<S>(self, names: &[&str], service: Arc<S>) -> Self
+    where
+        S: RegisterService + Send + Sync + 'static,
+    {
+        let service = build_service(service, S::handlers());
+        let names = names.iter().map(|name| name.to_string()).collect();
+        self.register_service(names, service)
+    }
+
+    /// Registers a `Service` instance under each of the `names`
+    fn register_service<S>(self, names: Vec<String>, service: Service<S>) -> Self
     where
         S: Send + Sync + 'static,
     {
-        let call = move |method_name: String,
-                         _deserializer: Box<(dyn erased::Deserializer<'static> + Send)>|
-              -> HandlerResultFut { service.call(&method_name, _deserializer) };
+        let method_names = service.method_names();
+        let entry = ServiceEntry::from_service(service);
 
-        log::debug!("Registering service: {}", name);
         let mut builder = self;
-        builder.services.insert(name, Arc::new(call));
+        for name in names {
+            log::debug!("Registering service: {}", name);
+            if builder.services.contains_key(&name) {
+                panic!("Service name `{}` is registered more than once", name);
+            }
+            builder.services.insert(name.clone(), entry.clone());
+            builder.method_names.insert(name, method_names.clone());
+        }
         builder
     }
 }
@@ -159,6 +625,20 @@ impl ServerBuilder<AckModeAuto> {
     }
 }
 
+/// Adds a built-in service under its reserved `name`. As with `register_service`,
+/// panics if a service is already registered under that name.
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+))]
+fn insert_builtin_service(services: &mut AsyncServiceMap, name: &str, entry: ServiceEntry) {
+    if services.contains_key(name) {
+        panic!("Service name `{}` is registered more than once", name);
+    }
+    services.insert(name.to_string(), entry);
+}
+
 macro_rules! impl_server_builder_for_ack_modes {
     ($($ack_mode:ty),*) => {
         $(
@@ -179,16 +659,47 @@ macro_rules! impl_server_builder_for_ack_modes {
                 /// let server: Server = builder.build();
                 /// ```
                 pub fn build(self) -> Server<$ack_mode> {
-                    use super::{AtomicClientId, RESERVED_CLIENT_ID, PubSubBroker};
+                    use super::{AtomicClientId, RESERVED_CLIENT_ID, PubSubBroker, PubSubStop, SharedState, connection_limit::ConnectionLimit};
+
+                    let shared = Arc::new(SharedState {
+                        max_concurrent_requests: self.max_concurrent_requests,
+                        max_queued_requests: self.max_queued_requests,
+                        frame_read_timeout: self.frame_read_timeout,
+                        handler_timeout: self.handler_timeout,
+                        catch_panics: self.catch_panics,
+                        shutdown_timeout: self.shutdown_timeout,
+                        response_chunk_size: self.response_chunk_size,
+                        error_mode: self.error_mode,
+                        buffer_capacity: self.buffer_capacity,
+                        connection_limit: self.max_connections
+                            .map(|max| ConnectionLimit::new(max, self.connection_limit_mode)),
+                        connection_limits: self.connection_limits,
+                        negotiate_codec: self.negotiate_codec,
+                        interceptors: Arc::new(self.interceptors),
+                        rate_limiter: self.rate_limiter,
+                        method_names: std::sync::RwLock::new(self.method_names),
+                        #[cfg(feature = "discovery")]
+                        advert: self.advert,
+                        ..Default::default()
+                    });
 
-                    let services = Arc::new(self.services);
+                    let mut services = self.services;
+                    if self.introspection {
+                        insert_builtin_service(&mut services, INTROSPECTION_SERVICE, introspection_service(shared.clone()));
+                    }
+                    if self.health_check {
+                        insert_builtin_service(&mut services, HEALTH_SERVICE, health_service(shared.clone()));
+                    }
+                    let services = Arc::new(std::sync::RwLock::new(services));
 
                     let (pubsub_broker, pubsub_tx) = PubSubBroker::<$ack_mode>::new(self.pub_retry_timeout, self.max_num_retries);
                     pubsub_broker.spawn();
 
                     Server::<$ack_mode> {
                         client_counter: Arc::new(AtomicClientId::new(RESERVED_CLIENT_ID + 1)),
+                        shared,
                         services,
+                        _pubsub_stop: Arc::new(PubSubStop(pubsub_tx.clone())),
                         pubsub_tx,
                         ack_mode: PhantomData,
                     }
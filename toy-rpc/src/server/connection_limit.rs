@@ -0,0 +1,81 @@
+//! Limit of the number of connections that are served at the same time
+//!
+//! With `ServerBuilder::max_connections`, the accept loops of the server (ie.
+//! `Server::accept` and `Server::connection_stream`) hold a permit for each connection
+//! until it is closed.
+//! What happens to a new connection when all the permits are taken is decided by
+//! `ConnectionLimitMode`.
+//!
+//! # Example
+//!
+//! ```rust
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .max_connections(1024)
+//!     .connection_limit_mode(ConnectionLimitMode::Reject)
+//!     .build();
+//! ```
+
+use crate::util::{Semaphore, SemaphorePermit};
+
+/// What the server does with a new connection when it is serving the max number of
+/// connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitMode {
+    /// Stops accepting until a connection is closed. The new connections wait in the
+    /// backlog of the listener. This is the default.
+    Wait,
+    /// Accepts the new connection and closes it immediately
+    Reject,
+}
+
+impl Default for ConnectionLimitMode {
+    fn default() -> Self {
+        ConnectionLimitMode::Wait
+    }
+}
+
+/// Permits of the connections that are being served
+pub(crate) struct ConnectionLimit {
+    semaphore: Semaphore,
+    mode: ConnectionLimitMode,
+}
+
+impl ConnectionLimit {
+    pub fn new(max_connections: usize, mode: ConnectionLimitMode) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_connections),
+            mode,
+        }
+    }
+
+    /// Waits for a permit before accepting in `ConnectionLimitMode::Wait`, and returns
+    /// immediately with `None` in `ConnectionLimitMode::Reject`
+    pub async fn wait(&self) -> Option<SemaphorePermit> {
+        match self.mode {
+            ConnectionLimitMode::Wait => Some(self.semaphore.acquire().await),
+            ConnectionLimitMode::Reject => None,
+        }
+    }
+
+    /// Takes a permit for an accepted connection, `None` if the connection has to be
+    /// rejected
+    pub fn admit(&self, permit: Option<SemaphorePermit>) -> Option<SemaphorePermit> {
+        permit.or_else(|| self.semaphore.try_acquire())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_when_full() {
+        let limit = ConnectionLimit::new(1, ConnectionLimitMode::Reject);
+        let first = limit.admit(None);
+        assert!(first.is_some());
+        assert!(limit.admit(None).is_none());
+        drop(first);
+        assert!(limit.admit(None).is_some());
+    }
+}
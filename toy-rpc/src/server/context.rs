@@ -0,0 +1,315 @@
+//! Context of the request that is being handled
+//!
+//! The context is made available to the RPC method while the handler future is being
+//! polled, so handlers that don't need it don't have to take an extra argument.
+//!
+//! # Example
+//!
+//! ```rust
+//! #[export_impl]
+//! impl Frontend {
+//!     #[export_method]
+//!     async fn fan_out(&self, args: i32) -> Result<i32, String> {
+//!         let ctx = Context::current().expect("Called outside of a request");
+//!         // the downstream call is canceled when the request to `Frontend` is canceled
+//!         self.downstream
+//!             .call("Backend.compute", args)
+//!             .bind_cancellation(ctx.child_token())
+//!             .await
+//!             .map_err(|err| err.to_string())
+//!     }
+//!
+//!     // a streaming response, which the client reads with `Client::call_stream`
+//!     #[export_method]
+//!     async fn count(&self, n: u32) -> Result<(), String> {
+//!         let ctx = Context::current().expect("Called outside of a request");
+//!         for i in 0..n {
+//!             ctx.send_item(i).map_err(|err| err.to_string())?;
+//!         }
+//!         Ok(())
+//!     }
+//!
+//!     // a bidirectional stream, which the client opens with `Client::open_stream`
+//!     #[export_method]
+//!     async fn double(&self, _: ()) -> Result<(), String> {
+//!         let ctx = Context::current().expect("Called outside of a request");
+//!         let mut incoming = ctx.incoming::<u32>().ok_or("Not a stream")?;
+//!         while let Some(item) = incoming.next().await {
+//!             let item = item.map_err(|err| err.to_string())?;
+//!             ctx.send_item(item * 2).map_err(|err| err.to_string())?;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use flume::{r#async::RecvStream, Receiver, Sender};
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::Error,
+    message::MessageId,
+    protocol::{InboundBody, RequestMetadata},
+    trace_id::TraceId,
+    util::CancellationToken,
+};
+
+use super::broker::ServerBrokerItem;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Context>> = RefCell::new(None);
+}
+
+/// Context of a request
+#[derive(Debug, Clone)]
+pub struct Context {
+    id: MessageId,
+    peer_addr: Option<SocketAddr>,
+    metadata: Arc<RequestMetadata>,
+    /// Trace id sent in the metadata
+    trace_id: Option<TraceId>,
+    token: CancellationToken,
+    /// Sends the items of a streaming response to the broker of the connection
+    items: Option<Sender<ServerBrokerItem>>,
+    /// Items sent by the client on a bidirectional stream, until they are taken by the
+    /// handler
+    incoming: Option<Arc<Mutex<Option<Receiver<Box<InboundBody>>>>>>,
+    /// When the client stops waiting for the response
+    deadline: Option<Instant>,
+}
+
+impl Context {
+    pub(crate) fn new(
+        id: MessageId,
+        peer_addr: Option<SocketAddr>,
+        metadata: RequestMetadata,
+    ) -> Self {
+        Self {
+            id,
+            peer_addr,
+            trace_id: TraceId::from_metadata(&metadata),
+            metadata: Arc::new(metadata),
+            token: CancellationToken::new(),
+            items: None,
+            incoming: None,
+            deadline: None,
+        }
+    }
+
+    /// Lets the handler send the items of a streaming response through `broker`
+    #[cfg_attr(feature = "http_actix_web", allow(dead_code))]
+    pub(crate) fn with_items(mut self, broker: Sender<ServerBrokerItem>) -> Self {
+        self.items = Some(broker);
+        self
+    }
+
+    /// Sets the deadline to `timeout` from now, where `timeout` is the time the client
+    /// has left when the request is written. A timeout too large to be represented as an
+    /// `Instant` leaves the request without a deadline.
+    pub(crate) fn with_deadline(mut self, timeout: Duration) -> Self {
+        self.deadline = Instant::now().checked_add(timeout);
+        self
+    }
+
+    /// Returns the context of the request that is being handled on the current task.
+    ///
+    /// Returns `None` if not called from within an RPC method
+    pub fn current() -> Option<Context> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Message id of the request
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+
+    /// Address of the client that sent the request.
+    ///
+    /// Returns `None` if the connection is not served from a socket that the server
+    /// accepted itself (ie. `serve_codec`, `serve_stream`, or the HTTP integrations)
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Metadata attached to the request by the client, ie. by a client interceptor.
+    ///
+    /// The map is empty if the client did not attach any metadata
+    pub fn metadata(&self) -> &RequestMetadata {
+        &self.metadata
+    }
+
+    /// Trace id of the request, which correlates the request with the call on the client
+    /// and the downstream calls made by the handler. See `toy_rpc::trace_id`
+    ///
+    /// Returns `None` if the client did not send a trace id or it is malformed
+    pub fn trace_id(&self) -> Option<TraceId> {
+        self.trace_id
+    }
+
+    /// When the client stops waiting for the response, according to the clock of the
+    /// server.
+    ///
+    /// The client sends the time it has left rather than a point in time, so the clocks
+    /// of the client and the server don't have to agree, but the time spent on the
+    /// network is not accounted for. Returns `None` if the request has no deadline.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left until the deadline, which is zero once the deadline has passed.
+    ///
+    /// Returns `None` if the request has no deadline
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns `true` if the request has been canceled by the client or the client
+    /// is disconnected
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Waits until the request is canceled by the client or the client is disconnected
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Returns a token that is cancelled when the request is canceled by the client
+    /// or the client is disconnected.
+    ///
+    /// The token can be bound to downstream calls with `Call::bind_cancellation`.
+    pub fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Lets the handler receive the items sent by the client on a bidirectional stream
+    #[cfg_attr(feature = "http_actix_web", allow(dead_code))]
+    pub(crate) fn with_incoming(mut self, rx: Receiver<Box<InboundBody>>) -> Self {
+        self.incoming = Some(Arc::new(Mutex::new(Some(rx))));
+        self
+    }
+
+    /// Takes the stream of items sent by the client if the request is a bidirectional
+    /// stream opened with `Client::open_stream`.
+    ///
+    /// The stream ends when the client closes its side of the stream. Returns `None` if
+    /// the request is not a bidirectional stream, or if the stream is already taken.
+    pub fn incoming<T: DeserializeOwned>(&self) -> Option<IncomingStream<T>> {
+        let incoming = self.incoming.as_ref()?;
+        let rx = match incoming.lock() {
+            Ok(mut guard) => guard.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }?;
+        Some(IncomingStream {
+            items: rx.into_stream(),
+            marker: PhantomData,
+        })
+    }
+
+    /// Sends an item of a streaming response to the client.
+    ///
+    /// The items are written in the order they are sent, and the value returned by the
+    /// handler terminates the stream. Items sent after the request is canceled are
+    /// discarded. Returns an error if the transport does not support streaming
+    /// responses, which is the case with the `actix-web` integration.
+    pub fn send_item<T>(&self, item: T) -> Result<(), Error>
+    where
+        T: serde::Serialize + Send + Sync + 'static,
+    {
+        let broker = self.items.as_ref().ok_or_else(|| {
+            Error::Internal("Streaming responses are not supported by this transport".into())
+        })?;
+        let item = ServerBrokerItem::StreamItem {
+            id: self.id,
+            body: Box::new(item),
+        };
+        broker.send(item).map_err(|_| {
+            Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Connection is closed",
+            ))
+        })
+    }
+
+    /// Sends every item of `stream` with `send_item`
+    pub async fn send_stream<S>(&self, stream: S) -> Result<(), Error>
+    where
+        S: Stream,
+        S::Item: serde::Serialize + Send + Sync + 'static,
+    {
+        futures::pin_mut!(stream);
+        while let Some(item) = stream.next().await {
+            self.send_item(item)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.token.cancel()
+    }
+
+    /// Makes the context available to `fut` through `Context::current()`
+    pub(crate) fn scope<F: Future>(self, fut: F) -> Scoped<F> {
+        Scoped { ctx: self, fut }
+    }
+}
+
+/// Items sent by the client on a bidirectional stream, returned by `Context::incoming`
+pub struct IncomingStream<T> {
+    items: RecvStream<'static, Box<InboundBody>>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Stream for IncomingStream<T> {
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.items.poll_next_unpin(cx).map(|item| {
+            item.map(|mut item| {
+                erased_serde::deserialize(&mut item).map_err(|err| Error::ParseError(Box::new(err)))
+            })
+        })
+    }
+}
+
+/// Future that sets the current context whenever the inner future is polled
+#[pin_project::pin_project]
+pub(crate) struct Scoped<F> {
+    ctx: Context,
+    #[pin]
+    fut: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let prev = CURRENT.with(|current| current.replace(Some(this.ctx.clone())));
+        // restores the previous context even if the inner future panics
+        let _guard = Restore(prev);
+        this.fut.poll(cx)
+    }
+}
+
+struct Restore(Option<Context>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = prev);
+    }
+}
@@ -0,0 +1,113 @@
+//! How much detail of the errors returned by the handlers is sent to the clients
+//!
+//! The error returned by a handler reaches the client as `Error::ExecutionError` with
+//! the text of the error, which may reveal internal details like file paths. With
+//! `ErrorMode::Redacted`, the text is replaced with a generic message and an opaque
+//! error id, and the original text is logged on the server together with the id, so
+//! that a report from a client can be matched with the log.
+//!
+//! The other variants, ie. `Error::InvalidArgument`, `Error::ServiceNotFound`,
+//! `Error::MethodNotFound`, `Error::Busy` and `Error::RateLimited`, carry no details
+//! and are always sent unchanged, including when a handler returns them explicitly.
+//!
+//! # Example
+//!
+//! ```rust
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .error_mode(ErrorMode::Redacted)
+//!     .build();
+//!
+//! // on the client, an error of the handler now reads like
+//! // "Internal server error (error id: 5f0c6a3e9d1b2c47)"
+//! ```
+
+use lazy_static::lazy_static;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::error::Error;
+
+/// The message that replaces the text of a redacted error
+pub const REDACTED_ERROR_MESSAGE: &str = "Internal server error";
+
+lazy_static! {
+    /// Keys that are random for each process, so the error ids are not predictable
+    static ref ERROR_ID_KEYS: RandomState = RandomState::new();
+}
+
+static ERROR_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// How much detail of the handler errors is sent to the clients. See the
+/// module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// The text of the errors is sent as is, which is meant for internal deployments.
+    /// This is the default.
+    Verbose,
+    /// The text of the errors is replaced with a generic message and an error id, and
+    /// is only logged on the server
+    Redacted,
+}
+
+impl Default for ErrorMode {
+    fn default() -> Self {
+        ErrorMode::Verbose
+    }
+}
+
+impl ErrorMode {
+    /// Returns the error that is sent to the client in place of `err`
+    pub(crate) fn apply(self, err: Error) -> Error {
+        match (self, err) {
+            (ErrorMode::Redacted, Error::ExecutionError(msg)) => {
+                let error_id = next_error_id();
+                log::error!("Error {}: {}", error_id, msg);
+                Error::ExecutionError(format!(
+                    "{} (error id: {})",
+                    REDACTED_ERROR_MESSAGE, error_id
+                ))
+            }
+            (_, err) => err,
+        }
+    }
+}
+
+fn next_error_id() -> String {
+    let mut hasher = ERROR_ID_KEYS.build_hasher();
+    hasher.write_u64(ERROR_ID_COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_mode_hides_execution_errors_only() {
+        let err = ErrorMode::Redacted.apply(Error::ExecutionError("/etc/secret".into()));
+        match err {
+            Error::ExecutionError(msg) => {
+                assert!(msg.starts_with(REDACTED_ERROR_MESSAGE));
+                assert!(!msg.contains("/etc/secret"));
+            }
+            err => panic!("Expecting Error::ExecutionError, found {:?}", err),
+        }
+        assert!(matches!(
+            ErrorMode::Redacted.apply(Error::MethodNotFound),
+            Error::MethodNotFound
+        ));
+        assert!(matches!(
+            ErrorMode::Verbose.apply(Error::ExecutionError("/etc/secret".into())),
+            Error::ExecutionError(msg) if msg == "/etc/secret"
+        ));
+    }
+
+    #[test]
+    fn error_ids_are_distinct() {
+        assert_ne!(next_error_id(), next_error_id());
+    }
+}
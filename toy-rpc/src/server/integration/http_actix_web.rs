@@ -12,23 +12,29 @@ use std::{
     marker::PhantomData,
     pin::Pin,
     sync::{atomic::Ordering, Arc},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    codec::{EraseDeserializer, Marshal, Unmarshal},
+    codec::{codec_name, EraseDeserializer, Marshal, Unmarshal},
     error::Error,
+    health::HEARTBEAT_METHOD,
     message::{ErrorMessage, MessageId},
-    protocol::{Header, InboundBody},
+    protocol::{
+        announced_codec, codec_announcement, ConnectionLimits, Header, OutboundBody,
+        RequestMetadata,
+    },
     pubsub::{AckModeAuto, AckModeNone, SeqId},
     server::{
-        broker::ServerBrokerItem,
+        broker::{IncomingRequest, ServerBrokerItem},
+        interceptor::{self, Interceptors, RequestHeader},
         pubsub::{PubSubItem, PubSubResponder},
         reader::{handle_cancel, service},
         writer::ServerWriterItem,
-        ClientId,
+        ClientId, ErrorMode,
     },
-    service::{ArcAsyncServiceCall, AsyncServiceMap, HandlerResult},
+    service::{HandlerResult, ServiceRegistry},
+    stats::MetricsCounters,
 };
 
 use crate::server::broker::execute_call;
@@ -44,9 +50,14 @@ use crate::server::broker::execute_call;
 pub struct WsMessageActor<C, AckMode> {
     client_id: ClientId,
     pubsub_broker: Sender<PubSubItem>,
-    services: Arc<AsyncServiceMap>,
+    services: Arc<ServiceRegistry>,
+    interceptors: Interceptors,
+    metrics: Arc<MetricsCounters>,
+    error_mode: ErrorMode,
     manager: Option<Recipient<ServerBrokerItem>>,
     req_header: Option<Header>,
+    /// Whether the first message may be the codec announcement of the client
+    negotiate_codec: bool,
     marker: PhantomData<C>,
     ack_mode: PhantomData<AckMode>,
 }
@@ -78,6 +89,7 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                         responder,
                         pubsub_broker: self.pubsub_broker.clone(),
                         executions: HashMap::new(),
+                        interceptors: self.interceptors.clone(),
 
                         ack_mode: PhantomData
                     };
@@ -97,14 +109,16 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                 C: Marshal + Unmarshal + EraseDeserializer + Unpin + 'static,
             {
                 fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+                    if let Ok(ws::Message::Binary(buf)) = &item {
+                        self.metrics.add_bytes_received(buf.len() as u64);
+                    }
                     match item {
                         Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
                         Ok(ws::Message::Pong(_)) => {}
+                        // a text message carries the same frame as a binary one
                         Ok(ws::Message::Text(text)) => {
-                            log::error!(
-                                "Received Text message: {} while expecting a binary message",
-                                text
-                            );
+                            let buf = web::Bytes::copy_from_slice(text.as_bytes());
+                            self.handle(Ok(ws::Message::Binary(buf)), ctx)
                         }
                         Ok(ws::Message::Continuation(_)) => {}
                         Ok(ws::Message::Nop) => {}
@@ -113,42 +127,26 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                             ctx.stop();
                         }
                         Ok(ws::Message::Binary(buf)) => match self.req_header.take() {
-                            None => match C::unmarshal(&buf) {
-                                Ok(h) => {
-                                    self.req_header.get_or_insert(h);
-                                }
-                                Err(err) => {
-                                    log::error!("Failed to unmarshal request header: {}", err);
-                                }
-                            },
+                            None if self.negotiate_codec => {
+                                self.negotiate_codec = false;
+                                self.handle_first_header(&buf, ctx);
+                            }
+                            None => self.handle_header(&buf),
                             Some(header) => match header {
                                 Header::Request {
                                     id,
                                     service_method,
                                     timeout,
                                 } => {
-                                    let deserializer = C::from_bytes(buf.to_vec());
-                                    match service(&self.services, service_method) {
-                                        Ok((call, method)) => {
-                                            let item = ServerBrokerItem::Request {
-                                                call,
-                                                id,
-                                                method,
-                                                duration: timeout,
-                                                deserializer,
-                                            };
-                                            self.send_to_manager(item);
-                                        }
-                                        Err(err) => {
-                                            log::error!("{}", &err);
-                                            let item = ServerWriterItem::Response {
-                                                id,
-                                                result: Err(err),
-                                            };
-                                            Self::send_via_context(item, ctx)
-                                                .unwrap_or_else(|err| log::error!("{}", err));
-                                        }
-                                    }
+                                    self.handle_request_body(id, service_method, timeout, RequestMetadata::new(), &buf, ctx);
+                                }
+                                Header::RequestWithMetadata {
+                                    id,
+                                    service_method,
+                                    timeout,
+                                    metadata,
+                                } => {
+                                    self.handle_request_body(id, service_method, timeout, metadata, &buf, ctx);
                                 }
                                 Header::Response { id, is_ok } => {
                                     log::error!("Server received Response {{id: {}, is_ok: {}}}", id, is_ok);
@@ -164,8 +162,10 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                                             let item = ServerWriterItem::Response {
                                                 id,
                                                 result: Err(err),
+                                                content_type: None,
+                                                enqueued_at: Instant::now(),
                                             };
-                                            Self::send_via_context(item, ctx)
+                                            self.send_via_context(item, ctx)
                                                 .unwrap_or_else(|err| log::error!("{}", err));
                                         }
                                     }
@@ -180,10 +180,28 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                                 Header::Unsubscribe { id, topic } => {
                                     self.send_to_manager(ServerBrokerItem::Unsubscribe { id, topic });
                                 }
+                                Header::Handshake(_) => {
+                                    // limits are not enforced by this integration
+                                    let item = ServerWriterItem::Handshake(ConnectionLimits::default());
+                                    self.send_via_context(item, ctx)
+                                        .unwrap_or_else(|err| log::error!("{}", err));
+                                }
                                 Header::Ack(_) => {}
                                 Header::Produce { .. } => {}
                                 Header::Consume { .. } => {}
                                 Header::Ext { .. } => {}
+                                // streams are not supported by this integration, the handler
+                                // runs without the items of the client
+                                Header::StreamOpen {
+                                    id,
+                                    service_method,
+                                    timeout,
+                                    metadata,
+                                } => {
+                                    self.handle_request_body(id, service_method, timeout, metadata, &buf, ctx);
+                                }
+                                Header::StreamItem { .. } => {}
+                                Header::StreamEnd(_) => {}
                             },
                         },
                         Err(err) => {
@@ -200,7 +218,94 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                 type Result = ();
 
                 fn handle(&mut self, msg: ServerWriterItem, ctx: &mut Self::Context) -> Self::Result {
-                    Self::send_via_context(msg, ctx).unwrap_or_else(|err| log::error!("{}", err));
+                    self.send_via_context(msg, ctx).unwrap_or_else(|err| log::error!("{}", err));
+                }
+            }
+
+            impl<C> WsMessageActor<C, $ack_mode>
+            where
+                C: Marshal + Unmarshal + EraseDeserializer + Unpin + 'static,
+            {
+                fn handle_header(&mut self, buf: &[u8]) {
+                    match C::unmarshal(buf) {
+                        Ok(h) => {
+                            self.req_header.get_or_insert(h);
+                        }
+                        Err(err) => {
+                            log::error!("Failed to unmarshal request header: {}", err);
+                        }
+                    }
+                }
+
+                /// Answers the codec announcement of the client, if any, and closes the
+                /// connection if the codec doesn't match
+                fn handle_first_header(&mut self, buf: &[u8], ctx: &mut <Self as Actor>::Context) {
+                    let codec = match announced_codec(buf) {
+                        Some(codec) => codec,
+                        None => return self.handle_header(buf),
+                    };
+                    Self::send_via_context(ServerWriterItem::AnnounceCodec, ctx)
+                        .unwrap_or_else(|err| log::error!("{}", err));
+                    if codec != codec_name() {
+                        let err = Error::CodecMismatch {
+                            expected: codec_name().into(),
+                            found: codec.into(),
+                        };
+                        log::error!("{}", err);
+                        ctx.close(None);
+                        ctx.stop();
+                    }
+                }
+
+                /// Looks up the service of a request and sends the request to the manager
+                fn handle_request_body(
+                    &mut self,
+                    id: MessageId,
+                    service_method: String,
+                    timeout: Duration,
+                    metadata: RequestMetadata,
+                    buf: &[u8],
+                    ctx: &mut <Self as Actor>::Context,
+                ) {
+                    self.metrics.add_request();
+                    if service_method == HEARTBEAT_METHOD {
+                        let item = ServerWriterItem::Response {
+                            id,
+                            result: Ok(Box::new(()) as Box<OutboundBody>),
+                            content_type: None,
+                            enqueued_at: Instant::now(),
+                        };
+                        return self.send_via_context(item, ctx)
+                            .unwrap_or_else(|err| log::error!("{}", err));
+                    }
+                    let deserializer = C::from_bytes(buf.to_vec());
+                    match service(&self.services, service_method) {
+                        Ok((call, service, method)) => {
+                            let item = ServerBrokerItem::Request(IncomingRequest {
+                                call,
+                                id,
+                                service,
+                                method,
+                                duration: timeout,
+                                metadata,
+                                opens_stream: false,
+                                content_type: None,
+                                deserializer,
+                            });
+                            self.send_to_manager(item);
+                        }
+                        Err(err) => {
+                            log::error!("{}", &err);
+                            let item = ServerWriterItem::Response {
+                                id,
+                                result: Err(err),
+                                content_type: None,
+                                enqueued_at: Instant::now(),
+                            };
+                            self.send_via_context(item, ctx)
+                                .unwrap_or_else(|err| log::error!("{}", err));
+                        }
+                    }
                 }
             }
 
@@ -208,32 +313,47 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
             where
                 C: Marshal + Unmarshal + Unpin + 'static,
             {
+                /// Text codecs are sent in text messages and the others in binary messages
+                fn write_frame(&self, ctx: &mut <Self as Actor>::Context, buf: Vec<u8>) {
+                    self.metrics.add_bytes_sent(buf.len());
+                    if C::IS_TEXT {
+                        match String::from_utf8(buf) {
+                            Ok(text) => ctx.text(text),
+                            Err(err) => ctx.binary(err.into_bytes()),
+                        }
+                    } else {
+                        ctx.binary(buf)
+                    }
+                }
+
                 fn send_via_context(
+                    &self,
                     item: ServerWriterItem,
                     ctx: &mut <Self as Actor>::Context,
                 ) -> Result<(), Error> {
                     match item {
-                        ServerWriterItem::Response { id, result } => {
+                        ServerWriterItem::Response { id, result, .. } => {
+                            self.metrics.add_response(result.is_ok());
                             match result {
                                 Ok(body) => {
                                     log::trace!("Message {} Success", &id);
                                     let header = Header::Response { id, is_ok: true };
                                     let buf = C::marshal(&header)?;
-                                    ctx.binary(buf);
+                                    self.write_frame(ctx, buf);
 
                                     let buf = C::marshal(&body)?;
-                                    ctx.binary(buf);
+                                    self.write_frame(ctx, buf);
                                 }
                                 Err(err) => {
                                     log::trace!("Message {} Error", id.clone());
                                     let header = Header::Response { id, is_ok: false };
-                                    let msg = ErrorMessage::from_err(err)?;
+                                    let msg = ErrorMessage::from_err(self.error_mode.apply(err))?;
 
                                     // compose error response header
                                     let buf = C::marshal(&header)?;
-                                    ctx.binary(buf);
+                                    self.write_frame(ctx, buf);
                                     let buf = C::marshal(&msg)?;
-                                    ctx.binary(buf);
+                                    self.write_frame(ctx, buf);
                                 }
                             };
                         }
@@ -245,15 +365,30 @@ macro_rules! impl_ws_message_actor_for_ack_modes {
                             let id = seq_id.0;
                             let header = Header::Publish { id, topic };
                             let buf = C::marshal(&header)?;
-                            ctx.binary(buf);
-                            ctx.binary(content.to_vec());
+                            self.write_frame(ctx, buf);
+                            self.write_frame(ctx, content.to_vec());
+                        }
+                        ServerWriterItem::Handshake(limits) => {
+                            let header = Header::Handshake(limits);
+                            let buf = C::marshal(&header)?;
+                            self.write_frame(ctx, buf);
+                            let buf = C::marshal(&())?;
+                            self.write_frame(ctx, buf);
+                        }
+                        ServerWriterItem::AnnounceCodec => {
+                            ctx.binary(codec_announcement(codec_name()));
                         }
                         ServerWriterItem::Ack { id } => {
                             let header = Header::Ack(id);
                             let buf = C::marshal(&header)?;
                             // There is no body frame for Ack message
-                            ctx.binary(buf);
+                            self.write_frame(ctx, buf);
                         }
+                        // Responses are not written in chunks or buffered over WebSocket
+                        ServerWriterItem::Attach(_)
+                        | ServerWriterItem::WriteChunks
+                        | ServerWriterItem::Flush => {}
+                        ServerWriterItem::StreamItem { .. } => {}
                         ServerWriterItem::Stopping => {
                             ctx.close(None);
                         },
@@ -283,23 +418,37 @@ struct ExecutionBroker<AckMode> {
     client_id: ClientId,
     responder: Recipient<ServerWriterItem>,
     pubsub_broker: Sender<PubSubItem>,
-    executions: HashMap<MessageId, Sender<()>>,
+    executions: HashMap<MessageId, crate::server::Context>,
+    interceptors: Interceptors,
 
     ack_mode: PhantomData<AckMode>,
 }
 
 impl<AckMode: Unpin + 'static> ExecutionBroker<AckMode> {
     fn handle_response(&mut self, id: MessageId, result: HandlerResult) -> Result<(), Error> {
-        self.executions.remove(&id);
-        let msg = ServerWriterItem::Response { id, result };
+        // all responses that go through the broker come from an execution, so the
+        // request is canceled if its execution is no longer tracked
+        if self.executions.remove(&id).is_none() {
+            log::debug!("Discarding response of canceled request {}", id);
+            return Ok(());
+        }
+        let msg = ServerWriterItem::Response {
+            id,
+            result,
+            content_type: None,
+            enqueued_at: Instant::now(),
+        };
         self.responder.do_send(msg).map_err(|err| err.into())
     }
 
     fn handle_cancel(&mut self, id: MessageId) -> Result<(), Error> {
-        if let Some(exec) = self.executions.remove(&id) {
-            exec.send(())?
+        if let Some(context) = self.executions.remove(&id) {
+            context.cancel();
         }
-        Ok(())
+        // acknowledges the cancellation
+        self.responder
+            .do_send(ServerWriterItem::Ack { id })
+            .map_err(|err| err.into())
     }
 
     fn handle_publish_inner(
@@ -385,8 +534,8 @@ impl<AckMode: Unpin + 'static> Actor for ExecutionBroker<AckMode> {
     type Context = Context<Self>;
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        for (_, tx) in self.executions.drain() {
-            tx.send(()).unwrap_or_else(|err| log::error!("{}", err));
+        for (_, context) in self.executions.drain() {
+            context.cancel();
         }
 
         Running::Stop
@@ -400,13 +549,28 @@ macro_rules! impl_execution_broker_for_ack_modes {
                 fn handle_request(
                     &mut self,
                     ctx: &mut actix::Context<Self>,
-                    call: ArcAsyncServiceCall,
-                    id: MessageId,
-                    method: String,
-                    duration: Duration,
-                    deserializer: Box<InboundBody>,
+                    request: IncomingRequest,
                 ) -> Result<(), Error> {
-                    let call_fut = call(method, deserializer);
+                    let IncomingRequest { call, id, service, method, duration, metadata, deserializer, .. } = request;
+                    if self.executions.contains_key(&id) {
+                        // a response sent through the broker would end the execution that uses the id
+                        log::error!("Message id {} is still used by a request being handled", id);
+                        let msg = ServerWriterItem::Response {
+                            id,
+                            result: Err(Error::ExecutionError(format!("Message id {} is still in use", id))),
+                            content_type: None,
+                            enqueued_at: Instant::now(),
+                        };
+                        return self.responder.do_send(msg).map_err(|err| err.into());
+                    }
+                    let context = crate::server::Context::new(id, None, metadata).with_deadline(duration);
+                    let header = || RequestHeader {
+                        id,
+                        service: service.clone(),
+                        method: method.clone(),
+                    };
+                    let handler = interceptor::wrap(&self.interceptors, header, call(method.clone(), deserializer));
+                    let call_fut = context.clone().scope(handler);
                     let broker = ctx.address().recipient();
 
                     let fut: Pin<Box<dyn Future<Output = ()>>> = Box::pin(async move {
@@ -415,12 +579,11 @@ macro_rules! impl_execution_broker_for_ack_modes {
                         broker.do_send(item)
                             .unwrap_or_else(|e| log::error!("{}", e));
                     });
-                    let (tx, rx) = flume::bounded(1);
-                    self.executions.insert(id, tx);
+                    self.executions.insert(id, context.clone());
 
                     actix::spawn(async move {
                         futures::select! {
-                            _ = rx.recv_async().fuse() => {
+                            _ = context.cancelled().fuse() => {
                                 // log::debug!("Future is canceled")
                             },
                             _ = fut.fuse() => {
@@ -455,18 +618,14 @@ macro_rules! impl_execution_broker_for_ack_modes {
 
                 fn handle(&mut self, msg: ServerBrokerItem, ctx: &mut Self::Context) -> Self::Result {
                     let result = match msg {
-                        ServerBrokerItem::Request {
-                            call,
-                            id,
-                            method,
-                            duration,
-                            deserializer,
-                        } => {
-                            self.handle_request(ctx, call, id, method, duration, deserializer)
-                        }
+                        ServerBrokerItem::Request(request) => self.handle_request(ctx, request),
                         ServerBrokerItem::Response { id, result } => {
                             self.handle_response(id, result)
                         }
+                        // handlers can't send or receive stream items over this integration
+                        ServerBrokerItem::StreamItem { .. }
+                        | ServerBrokerItem::IncomingItem { .. }
+                        | ServerBrokerItem::IncomingEnd(_) => Ok(()),
                         ServerBrokerItem::Cancel(id) => {
                             self.handle_cancel(id)
                         }
@@ -489,6 +648,13 @@ macro_rules! impl_execution_broker_for_ack_modes {
                         ServerBrokerItem::InboundAck{seq_id} => {
                             self.handle_inbound_ack(seq_id)
                         },
+                        ServerBrokerItem::Handshake(_)
+                        | ServerBrokerItem::AnnounceCodec
+                        | ServerBrokerItem::AttachWriter
+                        | ServerBrokerItem::WriteChunks
+                        | ServerBrokerItem::Flush
+                        | ServerBrokerItem::Draining
+                        | ServerBrokerItem::DrainTimeout => Ok(()),
                         ServerBrokerItem::Stopping => {
                             let msg = ServerWriterItem::Stopping;
                             self.responder.do_send(msg).map_err(Into::into)
@@ -573,6 +739,9 @@ cfg_if! {
                             stream: web::Payload,
                         ) -> Result<HttpResponse, actix_web::Error> {
                             let services = state.services.clone();
+                            let interceptors = state.shared.interceptors.clone();
+                            let metrics = state.shared.metrics.clone();
+                            let error_mode = state.shared.error_mode;
                             let client_id = state.client_counter.fetch_add(1, Ordering::Relaxed);
                             let pubsub_broker = state.pubsub_tx.clone();
                             let ws_actor: WsMessageActor<DefaultCodec<Vec<u8>, Vec<u8>, ConnTypePayload>, $ack_mode>
@@ -580,8 +749,12 @@ cfg_if! {
                                     client_id,
                                     pubsub_broker,
                                     services,
+                                    interceptors,
+                                    metrics,
+                                    error_mode,
                                     manager: None,
                                     req_header: None,
+                                    negotiate_codec: state.shared.negotiate_codec,
                                     marker: PhantomData,
                                     ack_mode: PhantomData,
                                 };
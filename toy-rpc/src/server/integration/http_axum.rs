@@ -27,11 +27,14 @@ macro_rules! impl_http_axum_for_ack_modes {
                 ) {
                     let codec = DefaultCodec::with_axum_websocket(ws);
                     let services = state.services.clone();
+                    let shared = state.shared.clone();
                     let client_id = state.client_counter.fetch_add(1, Ordering::Relaxed);
                     let pubsub_broker = state.pubsub_tx.clone();
 
-                    let fut = Self::start_broker_reader_writer(codec, services, client_id, pubsub_broker);
-                    fut.await.unwrap_or_else(|e| log::error!("{}", e));
+                    let fut = Self::start_broker_reader_writer(codec, services, shared, client_id, pubsub_broker, None);
+                    if let Err(e) = fut.await {
+                        log::error!("{}", e);
+                    }
                 }
 
                 async fn on_websocket_upgrade(
@@ -90,10 +90,11 @@ cfg_if! {
                                         let ws_stream = WebSocketConn::new_without_sink(ws_stream);
                                         let codec = DefaultCodec::with_tide_websocket(ws_stream);
                                         let services = req.state().services.clone();
+                                        let shared = req.state().shared.clone();
                                         let client_id = req.state().client_counter.fetch_add(1, Ordering::Relaxed);
                                         let pubsub_broker = req.state().pubsub_tx.clone();
 
-                                        let fut = Self::start_broker_reader_writer(codec, services, client_id, pubsub_broker);
+                                        let fut = Self::start_broker_reader_writer(codec, services, shared, client_id, pubsub_broker, None);
                                         log::trace!("Client disconnected.");
                                         fut.await?;
                                         Ok(())
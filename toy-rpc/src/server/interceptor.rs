@@ -0,0 +1,106 @@
+//! Interceptors that wrap the dispatch of requests to the handlers
+//!
+//! Interceptors are registered on the server with `ServerBuilder::layer` and are
+//! applied in the order they are registered, with the first one being the outermost.
+//! Each interceptor decides whether to pass the request on by calling `Next::run`,
+//! so it can short-circuit with an error before the handler runs, or inspect the
+//! result after the handler has finished.
+//!
+//! The interceptors run within the `Context` of the request, so `Context::current()`
+//! is available to them as well.
+//!
+//! # Example
+//!
+//! ```rust
+//! struct RequireAuth;
+//!
+//! #[async_trait]
+//! impl Interceptor for RequireAuth {
+//!     async fn intercept(&self, header: &RequestHeader, next: Next) -> HandlerResult {
+//!         if header.service == "Admin" {
+//!             return Err(Error::ExecutionError("Unauthorized".into()));
+//!         }
+//!         next.run(header).await
+//!     }
+//! }
+//!
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .layer(RequireAuth)
+//!     .build();
+//! ```
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::{
+    message::MessageId,
+    service::{HandlerResult, HandlerResultFut},
+};
+
+/// Information about the request that is being dispatched
+#[derive(Debug, Clone)]
+pub struct RequestHeader {
+    /// Message id of the request
+    pub id: MessageId,
+    /// Name of the service
+    pub service: String,
+    /// Name of the method
+    pub method: String,
+}
+
+/// A layer around the dispatch of requests to the handlers
+#[async_trait]
+pub trait Interceptor: Send + Sync + 'static {
+    /// Intercepts a request.
+    ///
+    /// Call `next.run(header)` to pass the request on to the next interceptor, or to the
+    /// handler if this is the innermost interceptor. Returning without calling `next`
+    /// rejects the request and the handler is never executed.
+    async fn intercept(&self, header: &RequestHeader, next: Next) -> HandlerResult;
+}
+
+/// Interceptors registered on a server, in the order they are applied
+pub(crate) type Interceptors = Arc<Vec<Box<dyn Interceptor>>>;
+
+/// The rest of the interceptor chain, followed by the handler
+pub struct Next {
+    interceptors: Interceptors,
+    index: usize,
+    handler: HandlerResultFut,
+}
+
+impl Next {
+    /// Runs the rest of the chain
+    pub async fn run(self, header: &RequestHeader) -> HandlerResult {
+        match self.interceptors.clone().get(self.index) {
+            Some(interceptor) => {
+                let next = Next {
+                    index: self.index + 1,
+                    ..self
+                };
+                interceptor.intercept(header, next).await
+            }
+            None => self.handler.await,
+        }
+    }
+}
+
+/// Wraps the handler future with the interceptors
+pub(crate) fn wrap(
+    interceptors: &Interceptors,
+    header: impl FnOnce() -> RequestHeader,
+    handler: HandlerResultFut,
+) -> HandlerResultFut {
+    if interceptors.is_empty() {
+        return handler;
+    }
+
+    let header = header();
+    let next = Next {
+        interceptors: interceptors.clone(),
+        index: 0,
+        handler,
+    };
+    Box::pin(async move { next.run(&header).await })
+}
@@ -0,0 +1,74 @@
+//! Built-in service that lists the registered services and their methods
+//!
+//! The service is registered under the name `__introspect` when the server is built
+//! with `ServerBuilder::enable_introspection`. It has two methods:
+//!
+//! - `__introspect.list` takes `()` and returns the names of the registered services
+//! - `__introspect.methods` takes the name of a service and returns the names of its
+//!   methods, or `Error::ServiceNotFound` if there is no such service
+//!
+//! The names are sorted, and the introspection service itself is not listed. The
+//! services registered or deregistered on the running server with `Server::register`
+//! and `Server::deregister` are reflected.
+//!
+//! # Example
+//!
+//! ```rust
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .enable_introspection()
+//!     .build();
+//!
+//! let services: Vec<String> = client.call("__introspect.list", ()).await?;
+//! let methods: Vec<String> = client.call("__introspect.methods", "Foo").await?;
+//! ```
+
+use erased_serde as erased;
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    error::Error,
+    protocol::OutboundBody,
+    service::{HandlerResultFut, ServiceEntry},
+};
+
+use super::SharedState;
+
+/// Name of the introspection service
+pub const INTROSPECTION_SERVICE: &str = "__introspect";
+
+/// Names of the methods of each registered service
+pub(crate) type MethodNames = BTreeMap<String, Vec<&'static str>>;
+
+/// Builds the introspection service over the names of the registered services
+pub(crate) fn introspection_service(shared: Arc<SharedState>) -> ServiceEntry {
+    let call = Arc::new(
+        move |method: String,
+              mut deserializer: Box<dyn erased::Deserializer<'static> + Send>|
+              -> HandlerResultFut {
+            let method_names = match shared.method_names.read() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let result = match method.as_str() {
+                "list" => {
+                    let services: Vec<String> = method_names.keys().cloned().collect();
+                    Ok(Box::new(services) as Box<OutboundBody>)
+                }
+                "methods" => erased::deserialize::<String>(&mut deserializer)
+                    .map_err(|_| Error::InvalidArgument)
+                    .and_then(|service| {
+                        let methods = method_names
+                            .get(service.as_str())
+                            .ok_or(Error::ServiceNotFound)?;
+                        let methods: Vec<String> =
+                            methods.iter().map(|name| name.to_string()).collect();
+                        Ok(Box::new(methods) as Box<OutboundBody>)
+                    }),
+                _ => Err(Error::MethodNotFound),
+            };
+            Box::pin(async move { result })
+        },
+    );
+    ServiceEntry::with_methods(call, &["list", "methods"])
+}
@@ -4,11 +4,21 @@
 
 use cfg_if::cfg_if;
 use std::{
+    collections::HashMap,
     marker::PhantomData,
-    sync::{atomic::AtomicU64, Arc},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use crate::{pubsub::AckModeNone, service::AsyncServiceMap};
+use crate::{
+    pubsub::AckModeNone,
+    service::{build_service, HandleService, ServiceEntry, ServiceRegistry},
+    stats::{ConnectionStats, Metrics, MetricsCounters, WriterStats},
+    util::RegisterService,
+};
 
 #[cfg(any(feature = "docs", not(feature = "http_actix_web")))]
 use crate::pubsub::AckModeAuto;
@@ -33,22 +43,116 @@ cfg_if! {
 pub mod builder;
 use builder::ServerBuilder;
 
-pub(crate) type ClientId = u64;
+pub mod connection_limit;
+pub use connection_limit::ConnectionLimitMode;
+
+pub mod context;
+pub use context::{Context, IncomingStream};
+
+pub mod error_mode;
+pub use error_mode::ErrorMode;
+
+pub mod interceptor;
+pub use interceptor::{Interceptor, Next, RequestHeader};
+
+pub mod introspect;
+pub use introspect::INTROSPECTION_SERVICE;
+
+pub mod rate_limit;
+pub use rate_limit::{RateLimit, RateLimiter, TokenBucket};
+
+/// Default maximum number of request handlers that can execute concurrently on one
+/// connection. See `ServerBuilder::set_max_concurrent_requests`
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// Type of the id assigned to each connected client
+pub type ClientId = u64;
 pub(crate) type AtomicClientId = AtomicU64;
 
+/// Statistics of the connections that are currently being served
+pub(crate) type ConnectionMap = Mutex<HashMap<ClientId, Arc<WriterStats>>>;
+
+/// How a connection served by the server has ended, returned by `Server::serve_one`
+#[derive(Debug)]
+pub struct ConnectionOutcome {
+    /// Id assigned to the client
+    pub client_id: ClientId,
+    /// Address of the client, if the transport has one
+    pub peer_addr: Option<std::net::SocketAddr>,
+    /// Time from when the connection is accepted until it is closed
+    pub duration: Duration,
+    /// Statistics of the connection when it is closed
+    pub stats: ConnectionStats,
+    /// Error that ended the connection. This is `None` if the client closed the
+    /// connection
+    pub error: Option<crate::error::Error>,
+}
+
+/// Server-wide settings and states that are shared with every connection
+#[derive(Default)]
+pub(crate) struct SharedState {
+    pub connections: ConnectionMap,
+    /// Traffic of all the connections, including the closed ones
+    pub metrics: Arc<MetricsCounters>,
+    /// Number of request handlers that are currently executing
+    pub num_in_flight: Arc<AtomicUsize>,
+    /// Max number of concurrently executing request handlers per connection
+    pub max_concurrent_requests: Option<usize>,
+    /// Max number of requests per connection that wait for a slot
+    pub max_queued_requests: Option<usize>,
+    /// Idle-progress timeout of reading a frame
+    pub frame_read_timeout: Option<Duration>,
+    /// Max time a request handler may run
+    pub handler_timeout: Option<Duration>,
+    /// Whether a panic of a handler is turned into an error response
+    pub catch_panics: bool,
+    /// Response bodies larger than this are written in chunks
+    pub response_chunk_size: Option<usize>,
+    /// How much detail of the handler errors is sent to the clients
+    pub error_mode: ErrorMode,
+    /// Capacities of the read and write buffers of each connection
+    pub buffer_capacity: Option<(usize, usize)>,
+    /// Permits of the connections that are being served
+    pub connection_limit: Option<connection_limit::ConnectionLimit>,
+    /// Limits declared to the clients during the handshake
+    pub connection_limits: crate::protocol::ConnectionLimits,
+    /// Whether the codec announced by the clients is checked
+    pub negotiate_codec: bool,
+    /// Interceptors wrapped around the handlers
+    pub interceptors: interceptor::Interceptors,
+    /// Creates the rate limiter of each connection
+    pub rate_limiter: Option<rate_limit::NewRateLimiter>,
+    /// Cancelled when the server starts shutting down
+    pub shutdown: crate::util::CancellationToken,
+    /// Max time the requests are waited for when the server is shutting down
+    pub shutdown_timeout: Option<Duration>,
+    /// Names of the methods of each registered service, which are listed by the
+    /// introspection service
+    pub method_names: std::sync::RwLock<introspect::MethodNames>,
+    /// Advertisement of the server on the local network
+    #[cfg(feature = "discovery")]
+    pub advert: Option<crate::discovery::ServiceAdvert>,
+}
+
 /// Client ID 0 is reserved for publisher and subscriber on the server side.
 /// Remote client have their ID starting from `RESERVED_CLIENT_ID + 1`
 pub const RESERVED_CLIENT_ID: ClientId = 0;
 
 /// RPC Server
 ///
+/// Cloning a `Server` is cheap, and the clones share the registered services, the
+/// connection statistics and the PubSub broker. A clone can be served over several
+/// transports at once, ie. put into the state of a web framework while another clone
+/// accepts raw TCP connections.
+///
 /// ```
 /// const DEFAULT_RPC_PATH: &str = "_rpc_";
 /// ```
 #[derive(Clone)]
 pub struct Server<AckMode> {
-    services: Arc<AsyncServiceMap>,
+    services: Arc<ServiceRegistry>,
     client_counter: Arc<AtomicClientId>, // monotomically increase counter
+    shared: Arc<SharedState>,
 
     #[cfg(any(
         feature = "docs",
@@ -56,25 +160,164 @@ pub struct Server<AckMode> {
         all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
     ))]
     pubsub_tx: Sender<PubSubItem>,
+    /// Stops the PubSub broker when the last clone of the server is dropped
+    #[cfg(any(
+        feature = "docs",
+        all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+        all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+    ))]
+    _pubsub_stop: Arc<PubSubStop>,
 
     ack_mode: PhantomData<AckMode>,
 }
 
-// Drop is implemented here because only **ONE** PubSub broker is available
-// on one server
+/// Stops the PubSub broker when dropped
+///
+/// Only **ONE** PubSub broker is available on one server, and it is shared by all the
+/// clones of the server, so it is stopped when the last clone is dropped.
 #[cfg(any(
     feature = "docs",
     all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
     all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
 ))]
-impl<AckMode> Drop for Server<AckMode> {
+pub(crate) struct PubSubStop(pub Sender<PubSubItem>);
+
+#[cfg(any(
+    feature = "docs",
+    all(feature = "async_std_runtime", not(feature = "tokio_runtime")),
+    all(feature = "tokio_runtime", not(feature = "async_std_runtime")),
+))]
+impl Drop for PubSubStop {
     fn drop(&mut self) {
-        if let Err(err) = self.pubsub_tx.send(PubSubItem::Stop) {
+        if let Err(err) = self.0.send(PubSubItem::Stop) {
             log::error!("{}", err);
         }
     }
 }
 
+impl<AckMode> Server<AckMode> {
+    /// Registers a service on the running server with the default name. The service is
+    /// available to new requests right away, including on the connections that are
+    /// already open, and it is shared by all the clones of the server.
+    ///
+    /// Returns an error if a service is already registered under the name.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// server.register(Arc::new(Plugin::new()))?;
+    /// // ...
+    /// server.deregister("Plugin");
+    /// ```
+    pub fn register<S>(&self, service: Arc<S>) -> Result<(), crate::error::Error>
+    where
+        S: RegisterService + Send + Sync + 'static,
+    {
+        self.register_with_name(S::default_name(), service)
+    }
+
+    /// Registers a service on the running server with a name. See `register`.
+    pub fn register_with_name<S>(
+        &self,
+        name: impl Into<String>,
+        service: Arc<S>,
+    ) -> Result<(), crate::error::Error>
+    where
+        S: RegisterService + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let service = build_service(service, S::handlers());
+        let method_names = service.method_names();
+
+        let mut services = match self.services.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if services.contains_key(&name) {
+            return Err(crate::error::Error::Internal(
+                format!("Service name `{}` is already registered", name).into(),
+            ));
+        }
+        log::debug!("Registering service: {}", name);
+        services.insert(name.clone(), ServiceEntry::from_service(service));
+        drop(services);
+
+        if let Ok(mut names) = self.shared.method_names.write() {
+            names.insert(name, method_names);
+        }
+        Ok(())
+    }
+
+    /// Deregisters the service `name` from the running server. Returns `false` if there
+    /// is no such service.
+    ///
+    /// The requests to the service that have already started are completed, and the
+    /// later requests are answered with `Error::ServiceNotFound`, the same as for a
+    /// service that has never been registered.
+    pub fn deregister(&self, name: &str) -> bool {
+        let removed = match self.services.write() {
+            Ok(mut guard) => guard.remove(name).is_some(),
+            Err(poisoned) => poisoned.into_inner().remove(name).is_some(),
+        };
+        if let Ok(mut names) = self.shared.method_names.write() {
+            names.remove(name);
+        }
+        if removed {
+            log::debug!("Deregistered service: {}", name);
+        }
+        removed
+    }
+
+    /// Returns the timing statistics of the connections that are currently being served,
+    /// keyed by the client id.
+    ///
+    /// The counters are cumulative, so calling `connections()` has no side effect. The
+    /// rates over an interval are computed from two snapshots with
+    /// `ConnectionStats::since`.
+    pub fn connections(&self) -> HashMap<ClientId, ConnectionStats> {
+        let connections = match self.shared.connections.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        connections
+            .iter()
+            .map(|(id, stats)| (*id, stats.snapshot()))
+            .collect()
+    }
+
+    /// Returns the number of calls, bytes and errors across all the connections since
+    /// the server is built, including the connections that are closed
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let metrics = server.metrics();
+    /// println!("{} requests, {} errors", metrics.requests, metrics.errors);
+    /// ```
+    pub fn metrics(&self) -> Metrics {
+        self.shared.metrics.snapshot()
+    }
+
+    /// Returns the number of connections that are currently being served
+    pub fn num_connections(&self) -> usize {
+        match self.shared.connections.lock() {
+            Ok(guard) => guard.len(),
+            Err(poisoned) => poisoned.into_inner().len(),
+        }
+    }
+
+    /// Returns the number of request handlers that are currently executing across all
+    /// connections.
+    ///
+    /// Requests that are waiting for a permit because of `max_concurrent_requests`
+    /// are not counted.
+    pub fn num_in_flight(&self) -> usize {
+        self.shared
+            .num_in_flight
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
 impl Server<AckModeNone> {
     /// Creates a `ServerBuilder`
     ///
@@ -144,16 +387,79 @@ cfg_if! {
         use rustls::ServerConfig;
 
         use futures::{StreamExt};
-        use std::sync::atomic::Ordering;
+        use std::{net::SocketAddr, sync::atomic::Ordering};
 
-        use crate::{error::Error, codec::{split::SplittableCodec, DefaultCodec}};
+        use crate::{error::Error, codec::{split::SplittableCodec, CodecRead, CodecWrite, DefaultCodec}};
 
         #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
         use crate::{transport::ws::WebSocketConn};
 
+        /// Accepts a single connection, waiting at most `accept_timeout` if it is `Some`
+        async fn accept_one(
+            listener: &TcpListener,
+            accept_timeout: Option<Duration>,
+        ) -> Result<(TcpStream, SocketAddr), Error> {
+            let accept = listener.accept();
+            let accepted = match accept_timeout {
+                #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+                Some(duration) => ::tokio::time::timeout(duration, accept).await.ok(),
+                #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+                Some(duration) => ::async_std::future::timeout(duration, accept).await.ok(),
+                None => Some(accept.await),
+            };
+            match accepted {
+                Some(res) => res.map_err(Into::into),
+                None => Err(Error::IoError(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "No connection is accepted before timeout",
+                ))),
+            }
+        }
+
+        /// A connection accepted by `Server::connection_stream`, which is served by
+        /// awaiting `serve`
+        ///
+        /// Dropping the handler without serving it closes the connection.
+        pub struct ConnectionHandler<AckMode> {
+            stream: TcpStream,
+            peer_addr: SocketAddr,
+            client_id: ClientId,
+            server: Server<AckMode>,
+            // held until the connection is closed
+            permit: Option<crate::util::SemaphorePermit>,
+        }
+
+        impl<AckMode> ConnectionHandler<AckMode> {
+            /// Address of the client
+            pub fn peer_addr(&self) -> SocketAddr {
+                self.peer_addr
+            }
+
+            /// Id assigned to the client
+            pub fn client_id(&self) -> ClientId {
+                self.client_id
+            }
+        }
+
         macro_rules! impl_server_for_ack_modes {
             ($($ack_mode:ty),*) => {
                 $(
+                    impl ConnectionHandler<$ack_mode> {
+                        /// Serves the connection with the default codec until the client
+                        /// disconnects, like a connection accepted by `Server::accept`
+                        pub async fn serve(self) -> Result<ConnectionOutcome, Error> {
+                            let server = self.server;
+                            let _permit = self.permit;
+                            Server::<$ack_mode>::serve_tcp_connection(
+                                self.stream,
+                                server.services.clone(),
+                                server.shared.clone(),
+                                self.client_id,
+                                server.pubsub_tx.clone()
+                            ).await
+                        }
+                    }
+
                     /// The following impl block is controlled by feature flag. It is enabled
                     /// if and only if **exactly one** of the the following feature flag is turned on
                     /// - `serde_bincode`
@@ -184,22 +490,163 @@ cfg_if! {
                         ///
                         /// See `toy-rpc/examples/tokio_tcp/` for the example
                         pub async fn accept(&self, listener: TcpListener) -> Result<(), Error> {
-                            #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
-                            let mut incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
-                            #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
-                            let mut incoming = listener.incoming();
+                            self.accept_until(listener, futures::future::pending()).await
+                        }
 
-                            while let Some(conn) = incoming.next().await {
-                                let stream = conn?;
-                                log::info!("Accepting incoming connection from {}", stream.peer_addr()?);
+                        /// Similar to `accept`, but shuts the server down gracefully once the `shutdown`
+                        /// future completes, ie. `tokio::signal::ctrl_c()` or a oneshot receiver
+                        /// (which also completes when its sender is dropped).
+                        ///
+                        /// On shutdown, the server stops accepting new connections and stops
+                        /// reading new requests on the existing connections. The requests that are
+                        /// already being handled are answered, and each connection is closed once
+                        /// its last response is written. Requests that are still running after
+                        /// `ServerBuilder::shutdown_timeout` are canceled. This returns `Ok(())` once
+                        /// all the connections are closed.
+                        ///
+                        /// The shutdown applies to all the connections of the server and its clones,
+                        /// including those served by other transports.
+                        ///
+                        /// # Example
+                        ///
+                        /// ```rust
+                        /// let server = Server::builder()
+                        ///     .register(foo)
+                        ///     .shutdown_timeout(Duration::from_secs(10))
+                        ///     .build();
+                        /// server.accept_with_shutdown(listener, async {
+                        ///     tokio::signal::ctrl_c().await.ok();
+                        /// }).await?;
+                        /// ```
+                        pub async fn accept_with_shutdown<F>(
+                            &self,
+                            listener: TcpListener,
+                            shutdown: F,
+                        ) -> Result<(), Error>
+                        where
+                            F: std::future::Future,
+                        {
+                            self.accept_until(listener, async move {
+                                let _ = shutdown.await;
+                            }).await
+                        }
 
-                                let client_id = self.client_counter.fetch_add(1, Ordering::Relaxed);
-                                let pubsub_broker = self.pubsub_tx.clone();
-                                task::spawn(
-                                    Self::serve_tcp_connection(stream, self.services.clone(), client_id, pubsub_broker)
-                                );
+                        /// Returns the connections accepted on `listener` as a stream, which
+                        /// allows a custom accept loop, ie. to reject or count the connections
+                        /// before serving them. `accept` is the same as spawning a task that
+                        /// serves each of the connections.
+                        ///
+                        /// An error of accepting a connection is yielded by the stream, and the
+                        /// stream can still be polled for the next connection. With
+                        /// `ServerBuilder::max_connections`, the stream waits for, or rejects, the
+                        /// connections beyond the limit before they are yielded.
+                        ///
+                        /// # Example
+                        ///
+                        /// ```rust
+                        /// let mut connections = server.connection_stream(listener);
+                        /// while let Some(conn) = connections.next().await {
+                        ///     let conn = conn?;
+                        ///     if !admit(conn.peer_addr()) {
+                        ///         continue // the connection is closed when it is dropped
+                        ///     }
+                        ///     task::spawn(conn.serve());
+                        /// }
+                        /// ```
+                        pub fn connection_stream(
+                            &self,
+                            listener: TcpListener,
+                        ) -> impl futures::Stream<Item = Result<ConnectionHandler<$ack_mode>, Error>> + Send + 'static {
+                            futures::stream::unfold((listener, self.clone()), |(listener, server)| async move {
+                                let handler = loop {
+                                    let (stream, peer_addr, permit) = match server.accept_within_limit(&listener).await {
+                                        Ok(Some(accepted)) => accepted,
+                                        // the connection is closed when the stream is dropped
+                                        Ok(None) => continue,
+                                        Err(err) => break Err(err),
+                                    };
+                                    log::info!("Accepting incoming connection from {}", peer_addr);
+                                    break Ok(ConnectionHandler {
+                                        stream,
+                                        peer_addr,
+                                        client_id: server.client_counter.fetch_add(1, Ordering::Relaxed),
+                                        server: server.clone(),
+                                        permit,
+                                    })
+                                };
+                                Some((handler, (listener, server)))
+                            })
+                        }
+
+                        /// Accepts a connection if the server is below `max_connections`.
+                        /// Returns `Ok(None)` if the connection is rejected.
+                        async fn accept_within_limit(
+                            &self,
+                            listener: &TcpListener,
+                        ) -> Result<Option<(TcpStream, SocketAddr, Option<crate::util::SemaphorePermit>)>, Error> {
+                            let limit = match &self.shared.connection_limit {
+                                Some(limit) => limit,
+                                None => {
+                                    let (stream, peer_addr) = listener.accept().await?;
+                                    return Ok(Some((stream, peer_addr, None)))
+                                }
+                            };
+                            let permit = limit.wait().await;
+                            let (stream, peer_addr) = listener.accept().await?;
+                            match limit.admit(permit) {
+                                Some(permit) => Ok(Some((stream, peer_addr, Some(permit)))),
+                                None => {
+                                    log::warn!("Rejecting connection from {}, max number of connections is reached", peer_addr);
+                                    Ok(None)
+                                }
                             }
+                        }
+
+                        async fn accept_until(
+                            &self,
+                            listener: TcpListener,
+                            shutdown: impl std::future::Future<Output = ()>,
+                        ) -> Result<(), Error> {
+                            use futures::{FutureExt, stream::FuturesUnordered};
+
+                            #[cfg(feature = "discovery")]
+                            let advertisement = match &self.shared.advert {
+                                Some(advert) => Some(crate::discovery::advertise(advert, listener.local_addr()?)?),
+                                None => None,
+                            };
+
+                            let incoming = self.connection_stream(listener);
+                            futures::pin_mut!(incoming);
+
+                            let shutdown = shutdown.fuse();
+                            futures::pin_mut!(shutdown);
+                            let mut connections = FuturesUnordered::new();
+
+                            loop {
+                                futures::select! {
+                                    _ = shutdown => {
+                                        log::info!("Shutting down, stop accepting new connections");
+                                        self.shared.shutdown.cancel();
+                                        break
+                                    },
+                                    conn = incoming.next().fuse() => {
+                                        let conn = match conn {
+                                            Some(conn) => conn?,
+                                            None => break,
+                                        };
+                                        connections.push(task::spawn(conn.serve()));
+                                    },
+                                    // reap finished connections
+                                    _ = connections.select_next_some() => { },
+                                }
+                            }
+
+                            // withdraw the advertisement before draining the connections
+                            #[cfg(feature = "discovery")]
+                            drop(advertisement);
 
+                            // wait for existing connections to finish their requests and close
+                            while connections.next().await.is_some() { }
                             Ok(())
                         }
 
@@ -210,22 +657,52 @@ cfg_if! {
                         #[cfg(feature = "tls")]
                         #[cfg_attr(feature = "docs",doc(cfg(all(feature ="tls"))))]
                         pub async fn accept_with_tls_config(&self, listener: TcpListener, config: ServerConfig) -> Result<(), Error> {
-                            #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
-                            let mut incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
-                            #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
-                            let mut incoming = listener.incoming();
-
                             let acceptor = TlsAcceptor::from(Arc::new(config));
 
-                            while let Some(conn) = incoming.next().await {
-                                let stream = conn?;
+                            loop {
+                                let (stream, _, permit) = match self.accept_within_limit(&listener).await? {
+                                    Some(accepted) => accepted,
+                                    None => continue,
+                                };
                                 let acceptor = acceptor.clone();
 
                                 let client_id = self.client_counter.fetch_add(1, Ordering::Relaxed);
                                 let pubsub_broker = self.pubsub_tx.clone();
-                                task::spawn(
-                                    Self::serve_tls_connection(stream, acceptor, self.services.clone(), client_id, pubsub_broker)
-                                );
+                                let serve = Self::serve_tls_connection(stream, acceptor, self.services.clone(), self.shared.clone(), client_id, pubsub_broker);
+                                task::spawn(async move {
+                                    let _permit = permit;
+                                    serve.await
+                                });
+                            }
+                        }
+
+                        /// Accepts QUIC connections on a `quinn::Endpoint`
+                        ///
+                        /// Every bidirectional stream that a client opens on a QUIC connection is
+                        /// served as a separate connection with the default codec. The endpoint
+                        /// must be created with a `quinn::ServerConfig`.
+                        ///
+                        /// # Example
+                        ///
+                        /// ```rust
+                        /// let server_config = quinn::ServerConfig::with_single_cert(certs, key)?;
+                        /// let endpoint = quinn::Endpoint::server(server_config, addr)?;
+                        /// server.accept_quic(endpoint).await.unwrap();
+                        /// ```
+                        #[cfg(feature = "quic")]
+                        #[cfg_attr(feature = "docs",doc(cfg(all(feature ="quic"))))]
+                        pub async fn accept_quic(&self, endpoint: quinn::Endpoint) -> Result<(), Error> {
+                            while let Some(connecting) = endpoint.accept().await {
+                                let services = self.services.clone();
+                                let shared = self.shared.clone();
+                                let client_counter = self.client_counter.clone();
+                                let pubsub_broker = self.pubsub_tx.clone();
+                                task::spawn(async move {
+                                    match connecting.await {
+                                        Ok(connection) => Self::serve_quic_connection(connection, services, shared, client_counter, pubsub_broker).await,
+                                        Err(err) => log::error!("Failed to establish QUIC connection: {}", err),
+                                    }
+                                });
                             }
 
                             Ok(())
@@ -254,24 +731,22 @@ cfg_if! {
                         #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
                         #[cfg_attr(feature = "docs", doc(cfg(any(feature = "ws_tokio", feature = "ws_async_std"))))]
                         pub async fn accept_websocket(&self, listener: TcpListener) -> Result<(), Error> {
-                            #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
-                            let mut incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
-                            #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
-                            let mut incoming = listener.incoming();
-
-                            while let Some(conn) = incoming.next().await {
-                                let stream = conn?;
-                                log::info!("Accepting incoming connection from {}", stream.peer_addr()?);
+                            loop {
+                                let (stream, peer_addr, permit) = match self.accept_within_limit(&listener).await? {
+                                    Some(accepted) => accepted,
+                                    None => continue,
+                                };
+                                log::info!("Accepting incoming connection from {}", peer_addr);
 
                                 let client_id = self.client_counter.fetch_add(1, Ordering::Relaxed);
                                 let pubsub_broker = self.pubsub_tx.clone();
                                 let ws_stream = accept_async(stream).await?;
-                                task::spawn(
-                                    Self::serve_ws_connection(ws_stream, self.services.clone(), client_id, pubsub_broker)
-                                );
+                                let serve = Self::serve_ws_connection(ws_stream, self.services.clone(), self.shared.clone(), client_id, pubsub_broker, peer_addr);
+                                task::spawn(async move {
+                                    let _permit = permit;
+                                    serve.await
+                                });
                             }
-
-                            Ok(())
                         }
 
                         /// Serves a single connection using the default codec
@@ -308,7 +783,7 @@ cfg_if! {
                             T: AsyncRead + AsyncWrite + Send + Unpin + 'static
                         {
                             // let ret = serve_readwrite_stream(stream, self.services.clone()).await;
-                            let codec = DefaultCodec::new(stream);
+                            let codec = DefaultCodec::with_buffer_capacity(stream, self.shared.buffer_capacity);
                             let ret = self.serve_codec(codec).await;
                             log::info!("Client disconnected from stream");
                             ret
@@ -332,77 +807,200 @@ cfg_if! {
                         {
                             let client_id = self.client_counter.fetch_add(1, Ordering::Relaxed);
                             let pubsub_broker = self.pubsub_tx.clone();
-                            Self::start_broker_reader_writer(codec, self.services.clone(), client_id, pubsub_broker).await
+                            Self::start_broker_reader_writer(codec, self.services.clone(), self.shared.clone(), client_id, pubsub_broker, None)
+                                .await
+                                .map(|_| ())
+                        }
+
+                        /// Accepts a single connection on the listener and serves it until it is
+                        /// closed, then returns how the connection has ended.
+                        ///
+                        /// The connection is served on the current task, so no task is left running
+                        /// once this returns. Returns an error with `ErrorKind::TimedOut` if no client
+                        /// connects within `accept_timeout`, or waits indefinitely if it is `None`.
+                        /// This is handy for tests and command line tools.
+                        ///
+                        /// # Example
+                        ///
+                        /// ```rust
+                        /// let listener = TcpListener::bind(addr).await.unwrap();
+                        /// let outcome = server.serve_one(listener, Some(Duration::from_secs(5))).await?;
+                        /// println!("Client {} disconnected", outcome.client_id);
+                        /// ```
+                        pub async fn serve_one(
+                            &self,
+                            listener: TcpListener,
+                            accept_timeout: Option<Duration>,
+                        ) -> Result<ConnectionOutcome, Error> {
+                            let (stream, peer_addr) = accept_one(&listener, accept_timeout).await?;
+                            log::info!("Accepting incoming connection from {}", peer_addr);
+
+                            let client_id = self.client_counter.fetch_add(1, Ordering::Relaxed);
+                            let pubsub_broker = self.pubsub_tx.clone();
+                            Self::serve_tcp_connection(stream, self.services.clone(), self.shared.clone(), client_id, pubsub_broker).await
+                        }
+
+                        /// Similar to `serve_one`, but serves the connection using the WebSocket
+                        /// transport protocol. The WebSocket handshake is part of serving the
+                        /// connection, and is not limited by `accept_timeout`.
+                        #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
+                        #[cfg_attr(feature = "docs", doc(cfg(any(feature = "ws_tokio", feature = "ws_async_std"))))]
+                        pub async fn serve_one_ws(
+                            &self,
+                            listener: TcpListener,
+                            accept_timeout: Option<Duration>,
+                        ) -> Result<ConnectionOutcome, Error> {
+                            let (stream, peer_addr) = accept_one(&listener, accept_timeout).await?;
+                            log::info!("Accepting incoming connection from {}", peer_addr);
+
+                            let client_id = self.client_counter.fetch_add(1, Ordering::Relaxed);
+                            let pubsub_broker = self.pubsub_tx.clone();
+                            let ws_stream = accept_async(stream).await?;
+                            Self::serve_ws_connection(ws_stream, self.services.clone(), self.shared.clone(), client_id, pubsub_broker, peer_addr).await
                         }
                     }
 
                     impl Server<$ack_mode> {
                         pub(crate) async fn start_broker_reader_writer(
                             codec: impl crate::codec::split::SplittableCodec + 'static,
-                            services: Arc<AsyncServiceMap>,
+                            services: Arc<ServiceRegistry>,
+                            shared: Arc<SharedState>,
                             client_id: ClientId,
                             pubsub_tx: Sender<PubSubItem>,
-                        ) -> Result<(), crate::Error> {
-                            let (writer, reader) = codec.split();
-
-                            let reader = reader::ServerReader::new(reader, services);
-                            let writer = writer::ServerWriter::new(writer);
-                            let broker = broker::ServerBroker::<$ack_mode>::new(client_id, pubsub_tx);
-
-                            let (broker_handle, _) = brw::spawn(broker, reader, writer);
-                            let _ = broker_handle.await;
-                            Ok(())
+                            peer_addr: Option<SocketAddr>,
+                        ) -> Result<ConnectionOutcome, crate::Error> {
+                            let started_at = std::time::Instant::now();
+                            let (writer, mut reader) = codec.split();
+                            reader.set_frame_read_timeout(shared.frame_read_timeout);
+                            let buffered = writer.flush_policy().is_buffered();
+
+                            let stats = Arc::new(WriterStats::with_metrics(shared.metrics.clone()));
+                            let reader = reader::ServerReader::new(reader, services, stats.clone())
+                                .with_codec_negotiation(shared.negotiate_codec)
+                                .with_shutdown(shared.shutdown.clone());
+                            let writer = writer::ServerWriter::new(
+                                writer,
+                                stats.clone(),
+                                shared.response_chunk_size,
+                                shared.error_mode,
+                            );
+                            let broker = broker::ServerBroker::<$ack_mode>::new(
+                                client_id,
+                                pubsub_tx,
+                                peer_addr,
+                                stats.clone(),
+                                broker::BrokerConfig::from_shared(&shared),
+                            );
+
+                            if let Ok(mut map) = shared.connections.lock() {
+                                map.insert(client_id, stats.clone());
+                            }
+                            let (broker_handle, broker_tx) = brw::spawn(broker, reader, writer);
+                            if shared.response_chunk_size.is_some() || buffered {
+                                broker_tx.send_async(broker::ServerBrokerItem::AttachWriter).await?;
+                            }
+                            #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+                            let result = broker_handle
+                                .await
+                                .unwrap_or_else(|err| Err(Error::Internal(err.into())));
+                            #[cfg(all(feature = "async_std_runtime", not(feature = "tokio_runtime")))]
+                            let result = broker_handle.await;
+                            if let Ok(mut map) = shared.connections.lock() {
+                                map.remove(&client_id);
+                            }
+                            Ok(ConnectionOutcome {
+                                client_id,
+                                peer_addr,
+                                duration: started_at.elapsed(),
+                                stats: stats.snapshot(),
+                                error: result.err(),
+                            })
                         }
 
                         #[cfg(feature = "tls")]
                         async fn serve_tls_connection(
                             stream: TcpStream,
                             acceptor: TlsAcceptor,
-                            services: Arc<AsyncServiceMap>,
+                            services: Arc<ServiceRegistry>,
+                            shared: Arc<SharedState>,
                             client_id: ClientId,
                             pubsub_broker: Sender<PubSubItem>
-                        ) -> Result<(), Error> {
+                        ) -> Result<ConnectionOutcome, Error> {
                             let peer_addr = stream.peer_addr()?;
                             let tls_stream = acceptor.accept(stream).await?;
                             // let ret = serve_readwrite_stream(tls_stream, services).await;
-                            let codec = DefaultCodec::new(tls_stream);
-                            let ret = Self::start_broker_reader_writer(codec, services, client_id, pubsub_broker).await;
+                            let codec = DefaultCodec::with_buffer_capacity(tls_stream, shared.buffer_capacity);
+                            let ret = Self::start_broker_reader_writer(codec, services, shared, client_id, pubsub_broker, Some(peer_addr)).await;
                             log::info!("Client disconnected from {}", peer_addr);
                             ret
                         }
 
+                        /// Serves every bidirectional stream of a QUIC connection
+                        #[cfg(feature = "quic")]
+                        async fn serve_quic_connection(
+                            connection: quinn::Connection,
+                            services: Arc<ServiceRegistry>,
+                            shared: Arc<SharedState>,
+                            client_counter: Arc<AtomicClientId>,
+                            pubsub_broker: Sender<PubSubItem>
+                        ) {
+                            let peer_addr = connection.remote_address();
+                            log::info!("Accepting incoming QUIC connection from {}", peer_addr);
+                            loop {
+                                let (send, recv) = match connection.accept_bi().await {
+                                    Ok(stream) => stream,
+                                    Err(err) => {
+                                        log::debug!("QUIC connection from {} is closed: {}", peer_addr, err);
+                                        break
+                                    }
+                                };
+
+                                let stream = crate::transport::quic::QuicStream::new(send, recv);
+                                let codec = DefaultCodec::with_buffer_capacity(stream, shared.buffer_capacity);
+                                let client_id = client_counter.fetch_add(1, Ordering::Relaxed);
+                                task::spawn(
+                                    Self::start_broker_reader_writer(codec, services.clone(), shared.clone(), client_id, pubsub_broker.clone(), Some(peer_addr))
+                                );
+                            }
+                        }
+
                         /// Serves a single connection
                         async fn serve_tcp_connection(
                             stream: TcpStream,
-                            services: Arc<AsyncServiceMap>,
+                            services: Arc<ServiceRegistry>,
+                            shared: Arc<SharedState>,
                             client_id: ClientId,
                             pubsub_broker: Sender<PubSubItem>
-                        ) -> Result<(), Error> {
-                            let _peer_addr = stream.peer_addr()?;
+                        ) -> Result<ConnectionOutcome, Error> {
+                            let peer_addr = stream.peer_addr()?;
                             // let ret = serve_readwrite_stream(stream, services, client_id, pubsub_broker);
-                            let codec = DefaultCodec::new(stream);
-                            let ret = Self::start_broker_reader_writer(codec, services, client_id, pubsub_broker).await;
-                            log::info!("Client disconnected from {}", _peer_addr);
+                            let codec = DefaultCodec::with_buffer_capacity(stream, shared.buffer_capacity);
+                            let ret = Self::start_broker_reader_writer(codec, services, shared, client_id, pubsub_broker, Some(peer_addr)).await;
+                            log::info!("Client disconnected from {}", peer_addr);
                             ret
                         }
 
                         #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
                         async fn serve_ws_connection<T>(
                             ws_stream: WebSocketStream<T>,
-                            services: Arc<AsyncServiceMap>,
+                            services: Arc<ServiceRegistry>,
+                            shared: Arc<SharedState>,
                             client_id: ClientId,
-                            pubsub_broker: Sender<PubSubItem>
-                        )
+                            pubsub_broker: Sender<PubSubItem>,
+                            peer_addr: SocketAddr,
+                        ) -> Result<ConnectionOutcome, Error>
                         where
                             T: futures::AsyncRead + futures::AsyncWrite + Send + Sync + Unpin + 'static,
                         {
                             let ws_stream = WebSocketConn::new(ws_stream);
                             let codec = DefaultCodec::with_websocket(ws_stream);
 
-                            if let Err(err) = Self::start_broker_reader_writer(codec, services, client_id, pubsub_broker).await {
+                            let ret = Self::start_broker_reader_writer(codec, services, shared, client_id, pubsub_broker, Some(peer_addr)).await;
+                            if let Err(err) = &ret {
                                 log::error!("{}", err);
                             }
                             log::info!("Client disconnected from WebSocket connection");
+                            ret
                         }
                     }
                 )*
@@ -0,0 +1,131 @@
+//! Rate limiting of the requests on each connection
+//!
+//! A rate limiter is created for every connection when it is set up, and it is
+//! dropped with the connection, so a client that reconnects starts over with a fresh
+//! limiter. The limiter is consulted before each request is dispatched, and a request
+//! that is not allowed is answered with `Error::RateLimited` without running the
+//! handler.
+//!
+//! `ServerBuilder::rate_limit` sets up a token bucket on each connection. Other
+//! policies implement `RateLimiter` and are set with `ServerBuilder::rate_limit_with`.
+//!
+//! # Example
+//!
+//! ```rust
+//! // 100 requests per second, with bursts of up to 20 requests
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .rate_limit(RateLimit { per_second: 100, burst: 20 })
+//!     .build();
+//!
+//! // Only limits the calls to the `Search` service
+//! struct LimitSearch(TokenBucket);
+//!
+//! impl RateLimiter for LimitSearch {
+//!     fn try_acquire(&mut self, header: &RequestHeader) -> bool {
+//!         header.service != "Search" || self.0.try_acquire(header)
+//!     }
+//! }
+//!
+//! let server = Server::builder()
+//!     .register(foo)
+//!     .rate_limit_with(|| LimitSearch(TokenBucket::new(RateLimit { per_second: 1, burst: 1 })))
+//!     .build();
+//! ```
+
+use std::{sync::Arc, time::Instant};
+
+use super::RequestHeader;
+
+/// Decides whether a request on a connection is dispatched
+pub trait RateLimiter: Send + Sync + 'static {
+    /// Returns `true` if the request may be dispatched now
+    fn try_acquire(&mut self, header: &RequestHeader) -> bool;
+}
+
+/// Creates the rate limiter of a new connection
+pub(crate) type NewRateLimiter = Arc<dyn Fn() -> Box<dyn RateLimiter> + Send + Sync>;
+
+/// Rate of a token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Number of requests allowed per second on average
+    pub per_second: u32,
+    /// Number of requests that can be made at once after the connection is idle. A
+    /// value of 0 is treated as 1.
+    pub burst: u32,
+}
+
+/// A token bucket that holds up to `burst` tokens and refills at `per_second`
+/// tokens per second. Each request takes a token, and it starts full.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.max(1) as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token at `now`, returns `false` if the bucket is empty
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        let capacity = self.limit.burst.max(1) as f64;
+        self.tokens = (self.tokens + elapsed * self.limit.per_second as f64).min(capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl RateLimiter for TokenBucket {
+    fn try_acquire(&mut self, _: &RequestHeader) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn token_bucket_allows_bursts_and_refills() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 10,
+            burst: 3,
+        });
+        let start = bucket.refilled_at;
+        for _ in 0..3 {
+            assert!(bucket.try_acquire_at(start));
+        }
+        assert!(!bucket.try_acquire_at(start));
+
+        // one token every 100ms
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(50)));
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(100)));
+
+        // never more than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire_at(later));
+        }
+        assert!(!bucket.try_acquire_at(later));
+    }
+}
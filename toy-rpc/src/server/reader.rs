@@ -1,92 +1,234 @@
 use brw::{Reader, Running};
 use futures::sink::{Sink, SinkExt};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
-    codec::CodecRead,
-    error::Error,
+    codec::{codec_name, CodecRead, ContentType},
+    error::{CodecError, Error},
+    health::HEARTBEAT_METHOD,
     message::{MessageId, CANCELLATION_TOKEN, CANCELLATION_TOKEN_DELIM},
     pubsub::SeqId,
-    service::{ArcAsyncServiceCall, AsyncServiceMap},
+    service::{ArcAsyncServiceCall, ServiceRegistry},
+    stats::WriterStats,
+    util::CancellationToken,
 };
 
-use super::broker::ServerBrokerItem;
-use crate::protocol::{Header, InboundBody};
+use super::broker::{IncomingRequest, ServerBrokerItem};
+use crate::protocol::{announced_codec, Header, InboundBody, OutboundBody, RequestMetadata};
+use crate::trace::{debug_event, debug_span, Instrument};
 
 pub(crate) struct ServerReader<T> {
     reader: T,
-    services: Arc<AsyncServiceMap>,
+    services: Arc<ServiceRegistry>,
+    stats: Arc<WriterStats>,
+    /// Whether the first message may be the codec announcement of the client
+    negotiate_codec: bool,
+    /// Stops reading new requests once the server is shutting down
+    shutdown: Option<CancellationToken>,
 }
 
 impl<T: CodecRead> ServerReader<T> {
     #[cfg(not(feature = "http_actix_web"))]
-    pub fn new(reader: T, services: Arc<AsyncServiceMap>) -> Self {
-        Self { reader, services }
+    pub fn new(reader: T, services: Arc<ServiceRegistry>, stats: Arc<WriterStats>) -> Self {
+        Self {
+            reader,
+            services,
+            stats,
+            negotiate_codec: false,
+            shutdown: None,
+        }
     }
-}
 
-pub(crate) fn service(
-    services: &Arc<AsyncServiceMap>,
-    service_method: String,
-) -> Result<(ArcAsyncServiceCall, String), Error> {
-    // split service and method
-    let args: Vec<&str> = service_method.split('.').collect();
-    let (service, method) = match args[..] {
-        [s, m] => (s, m),
-        _ => {
-            // Method not found
-            return Err(Error::MethodNotFound);
+    /// Stops reading new requests when `shutdown` is cancelled, and lets the broker
+    /// drain the requests that are being handled
+    #[cfg(not(feature = "http_actix_web"))]
+    pub fn with_shutdown(self, shutdown: CancellationToken) -> Self {
+        Self {
+            shutdown: Some(shutdown),
+            ..self
         }
-    };
+    }
 
-    // look up the service
-    match services.get(service) {
-        Some(call) => Ok((call.clone(), method.into())),
-        None => Err(Error::ServiceNotFound),
+    /// Reads the next header unless the server starts shutting down first, in which
+    /// case `Err(())` is returned
+    async fn read_header_until_shutdown<B>(
+        &mut self,
+        broker: &mut B,
+    ) -> Result<Option<Result<Header, CodecError>>, ()>
+    where
+        B: Sink<ServerBrokerItem, Error = flume::SendError<ServerBrokerItem>> + Send + Unpin,
+    {
+        let shutdown = match &self.shutdown {
+            Some(shutdown) => shutdown.clone(),
+            None => return Ok(self.read_header(broker).await),
+        };
+        let read_header = self.read_header(broker);
+        let cancelled = shutdown.cancelled();
+        futures::pin_mut!(read_header, cancelled);
+        match futures::future::select(read_header, cancelled).await {
+            futures::future::Either::Left((header, _)) => Ok(header),
+            futures::future::Either::Right(_) => Err(()),
+        }
     }
-}
 
-pub(crate) fn handle_cancel(
-    id: MessageId,
-    mut deserializer: Box<InboundBody>,
-) -> Result<(), Error> {
-    let token: String = erased_serde::deserialize(&mut deserializer)?;
-    if is_correct_cancellation_token(id, &token) {
-        Ok(())
-    } else {
-        Err(Error::InvalidArgument)
+    /// Checks the codec announced by the client, if any, before the first message
+    #[cfg(not(feature = "http_actix_web"))]
+    pub fn with_codec_negotiation(self, enabled: bool) -> Self {
+        Self {
+            negotiate_codec: enabled,
+            ..self
+        }
     }
-}
 
-fn is_correct_cancellation_token(id: MessageId, token: &str) -> bool {
-    match token.find(CANCELLATION_TOKEN_DELIM) {
-        Some(ind) => {
-            let base = &token[..ind];
-            let id_str = &token[ind + 1..];
-            let _id: MessageId = match id_str.parse() {
-                Ok(num) => num,
-                Err(_) => return false,
+    /// Reads the header of the next message. The codec announcement of the client, if
+    /// any, is answered with the codec of the server. `None` is returned if the codec
+    /// doesn't match
+    async fn read_header<B>(&mut self, broker: &mut B) -> Option<Result<Header, CodecError>>
+    where
+        B: Sink<ServerBrokerItem, Error = flume::SendError<ServerBrokerItem>> + Send + Unpin,
+    {
+        if !self.negotiate_codec {
+            return self.reader.read_header().await;
+        }
+        self.negotiate_codec = false;
+        let payload = match self.reader.read_header_bytes().await? {
+            Ok(payload) => payload,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let codec = match announced_codec(&payload) {
+            Some(codec) => codec,
+            None => return Some(T::unmarshal(&payload).map_err(Into::into)),
+        };
+        if let Err(err) = broker.send(ServerBrokerItem::AnnounceCodec).await {
+            log::error!("{}", err);
+        }
+        if codec != codec_name() {
+            let err = Error::CodecMismatch {
+                expected: codec_name().into(),
+                found: codec.into(),
             };
-            base == CANCELLATION_TOKEN && _id == id
+            log::error!("{}", err);
+            return None;
         }
-        None => false,
+        self.reader.read_header().await
     }
-}
-
-#[async_trait::async_trait]
-impl<T: CodecRead> Reader for ServerReader<T> {
-    type BrokerItem = ServerBrokerItem;
-    type Ok = ();
-    type Error = Error;
 
-    async fn op<B>(
+    /// Reads the body of a request and sends the request to the broker
+    async fn read_request<B>(
         &mut self,
         mut broker: B,
-    ) -> Running<Result<Self::Ok, Self::Error>, Option<Self::Error>>
+        id: MessageId,
+        service_method: String,
+        timeout: Duration,
+        metadata: RequestMetadata,
+        opens_stream: bool,
+    ) -> Running<Result<(), Error>, Option<Error>>
     where
-        B: Sink<Self::BrokerItem, Error = flume::SendError<Self::BrokerItem>> + Send + Unpin,
+        B: Sink<ServerBrokerItem, Error = flume::SendError<ServerBrokerItem>> + Send + Unpin,
+    {
+        let span = debug_span!("read_request", message_id = id, service_method = %service_method);
+        let content_type = ContentType::from_metadata(&metadata);
+        let deserializer = match content_type {
+            Ok(Some(content_type)) => match self.reader.read_bytes().instrument(span).await {
+                Some(Ok(bytes)) => Ok((content_type.from_bytes_fn())(bytes)),
+                Some(Err(err)) => Err(CodecError::from(err)),
+                None => {
+                    log::debug!(
+                        "Connection closed before the body of request {} is received",
+                        id
+                    );
+                    return stop_broker(broker).await;
+                }
+            },
+            _ => match self.reader.read_body().instrument(span).await {
+                Some(res) => res,
+                None => {
+                    log::debug!(
+                        "Connection closed before the body of request {} is received",
+                        id
+                    );
+                    return stop_broker(broker).await;
+                }
+            },
+        };
+        let deserializer = match deserializer {
+            Ok(de) => de,
+            Err(err) => {
+                // the client is still waiting for a response to the request
+                let msg = ServerBrokerItem::Response {
+                    id,
+                    result: Err(Error::InvalidArgument),
+                };
+                if let Err(send_err) = broker.send(msg).await {
+                    return Running::Continue(Err(send_err.into()));
+                }
+                return Running::Continue(Err(err.into()));
+            }
+        };
+        self.stats.metrics().add_request();
+        let content_type = match content_type {
+            Ok(content_type) => content_type,
+            Err(err) => {
+                log::error!("Request {} has an unsupported content type", id);
+                let msg = ServerBrokerItem::Response {
+                    id,
+                    result: Err(err),
+                };
+                return Running::Continue(broker.send(msg).await.map_err(|err| err.into()));
+            }
+        };
+        if service_method == HEARTBEAT_METHOD {
+            debug_event!(message_id = id, "Received heartbeat");
+            let msg = ServerBrokerItem::Response {
+                id,
+                result: Ok(Box::new(()) as Box<OutboundBody>),
+            };
+            return Running::Continue(broker.send(msg).await.map_err(|err| err.into()));
+        }
+        match service(&self.services, service_method) {
+            Ok((call, service, method)) => {
+                let msg = ServerBrokerItem::Request(IncomingRequest {
+                    call,
+                    id,
+                    service,
+                    method,
+                    duration: timeout,
+                    metadata,
+                    opens_stream,
+                    content_type,
+                    deserializer,
+                });
+                Running::Continue(broker.send(msg).await.map_err(|err| err.into()))
+            }
+            Err(err) => {
+                // the body is read already, so the connection stays in sync and only
+                // this call fails
+                log::error!("{}", &err);
+                let msg = ServerBrokerItem::Response {
+                    id,
+                    result: Err(err),
+                };
+                Running::Continue(broker.send(msg).await.map_err(|err| err.into()))
+            }
+        }
+    }
+
+    /// Reads the next message and sends it to the broker
+    async fn read_message<B>(&mut self, mut broker: B) -> Running<Result<(), Error>, Option<Error>>
+    where
+        B: Sink<ServerBrokerItem, Error = flume::SendError<ServerBrokerItem>> + Send + Unpin,
     {
-        if let Some(header) = self.reader.read_header().await {
+        let header = match self.read_header_until_shutdown(&mut broker).await {
+            Ok(header) => header,
+            Err(()) => {
+                log::debug!("Server is shutting down, stop reading requests");
+                if let Err(err) = broker.send(ServerBrokerItem::Draining).await {
+                    log::error!("{}", err)
+                }
+                return Running::Stop(None);
+            }
+        };
+        if let Some(header) = header {
             let header: Header = match header {
                 Ok(header) => header,
                 Err(err) => return Running::Continue(Err(err.into())),
@@ -99,33 +241,33 @@ impl<T: CodecRead> Reader for ServerReader<T> {
                     service_method,
                     timeout,
                 } => {
-                    let deserializer = match self.reader.read_body().await {
-                        Some(res) => match res {
-                            Ok(de) => de,
-                            Err(err) => return Running::Continue(Err(err.into())),
-                        },
-                        None => return Running::Stop(None),
-                    };
-                    match service(&self.services, service_method) {
-                        Ok((call, method)) => {
-                            let msg = ServerBrokerItem::Request {
-                                call,
-                                id,
-                                method,
-                                duration: timeout,
-                                deserializer,
-                            };
-                            Running::Continue(broker.send(msg).await.map_err(|err| err.into()))
-                        }
-                        Err(err) => {
-                            log::error!("{}", &err);
-                            let msg = ServerBrokerItem::Response {
-                                id,
-                                result: Err(err),
-                            };
-                            Running::Continue(broker.send(msg).await.map_err(|err| err.into()))
-                        }
-                    }
+                    self.read_request(
+                        broker,
+                        id,
+                        service_method,
+                        timeout,
+                        RequestMetadata::new(),
+                        false,
+                    )
+                    .await
+                }
+                Header::RequestWithMetadata {
+                    id,
+                    service_method,
+                    timeout,
+                    metadata,
+                } => {
+                    self.read_request(broker, id, service_method, timeout, metadata, false)
+                        .await
+                }
+                Header::StreamOpen {
+                    id,
+                    service_method,
+                    timeout,
+                    metadata,
+                } => {
+                    self.read_request(broker, id, service_method, timeout, metadata, true)
+                        .await
                 }
                 Header::Response { id, is_ok } => {
                     let _ = match self.reader.read_body().await {
@@ -133,7 +275,7 @@ impl<T: CodecRead> Reader for ServerReader<T> {
                             Ok(de) => de,
                             Err(err) => return Running::Continue(Err(err.into())),
                         },
-                        None => return Running::Stop(None),
+                        None => return stop_broker(broker).await,
                     };
                     Running::Continue(Err(Error::Internal(
                         format!("Server received Response {{id: {}, is_ok: {}}}", id, is_ok).into(),
@@ -145,10 +287,11 @@ impl<T: CodecRead> Reader for ServerReader<T> {
                             Ok(de) => de,
                             Err(err) => return Running::Continue(Err(err.into())),
                         },
-                        None => return Running::Stop(None),
+                        None => return stop_broker(broker).await,
                     };
                     match handle_cancel(id, deserializer) {
                         Ok(_) => {
+                            debug_event!(message_id = id, "Received cancellation");
                             let msg = ServerBrokerItem::Cancel(id);
                             Running::Continue(broker.send(msg).await.map_err(|err| err.into()))
                         }
@@ -167,7 +310,7 @@ impl<T: CodecRead> Reader for ServerReader<T> {
                             Ok(b) => b,
                             Err(err) => return Running::Continue(Err(err.into())),
                         },
-                        None => return Running::Stop(None),
+                        None => return stop_broker(broker).await,
                     };
                     Running::Continue(
                         broker
@@ -176,6 +319,15 @@ impl<T: CodecRead> Reader for ServerReader<T> {
                             .map_err(|err| err.into()),
                     )
                 }
+                Header::Handshake(limits) => {
+                    let _ = self.reader.read_bytes().await;
+                    Running::Continue(
+                        broker
+                            .send(ServerBrokerItem::Handshake(limits))
+                            .await
+                            .map_err(|err| err.into()),
+                    )
+                }
                 Header::Subscribe { id, topic } => {
                     let _ = self.reader.read_bytes().await;
                     Running::Continue(
@@ -221,15 +373,126 @@ impl<T: CodecRead> Reader for ServerReader<T> {
                 } => Running::Continue(Err(Error::Internal(
                     "Unexpected Header type (Header::Ext)".into(),
                 ))),
+                Header::StreamItem { id } => {
+                    let item = match self.reader.read_body().await {
+                        Some(res) => match res {
+                            Ok(de) => de,
+                            Err(err) => return Running::Continue(Err(err.into())),
+                        },
+                        None => return stop_broker(broker).await,
+                    };
+                    Running::Continue(
+                        broker
+                            .send(ServerBrokerItem::IncomingItem { id, item })
+                            .await
+                            .map_err(|err| err.into()),
+                    )
+                }
+                Header::StreamEnd(id) => {
+                    let _ = self.reader.read_bytes().await;
+                    Running::Continue(
+                        broker
+                            .send(ServerBrokerItem::IncomingEnd(id))
+                            .await
+                            .map_err(|err| err.into()),
+                    )
+                }
             }
         } else {
-            // Stop is not needed on the server because server broker will send a stop to itself after stopping
-            if let Err(err) = broker.send(ServerBrokerItem::Stopping).await {
-                log::error!("{}", err)
-            }
+            stop_broker(broker).await
+        }
+    }
+}
+
+/// Tells the broker to stop once the connection is closed, including when it is closed
+/// in the middle of a message
+async fn stop_broker<B>(mut broker: B) -> Running<Result<(), Error>, Option<Error>>
+where
+    B: Sink<ServerBrokerItem, Error = flume::SendError<ServerBrokerItem>> + Send + Unpin,
+{
+    // Stop is not needed on the server because server broker will send a stop to itself after stopping
+    if let Err(err) = broker.send(ServerBrokerItem::Stopping).await {
+        log::error!("{}", err)
+    }
+
+    Running::Stop(None)
+}
+
+pub(crate) fn service(
+    services: &Arc<ServiceRegistry>,
+    service_method: String,
+) -> Result<(ArcAsyncServiceCall, String, String), Error> {
+    // split service and method
+    let args: Vec<&str> = service_method.split('.').collect();
+    let (service, method) = match args[..] {
+        [s, m] => (s, m),
+        _ => {
+            // Method not found
+            return Err(Error::MethodNotFound);
+        }
+    };
+
+    // look up the service, and then ask the service for the method. The lock is only
+    // held to clone the entry
+    let services = match services.read() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    match services.get_key_value(service) {
+        Some((service, entry)) => match entry.has_method(method) {
+            true => Ok((entry.call.clone(), service.clone(), method.into())),
+            false => Err(Error::MethodNotFound),
+        },
+        None => Err(Error::ServiceNotFound),
+    }
+}
+
+pub(crate) fn handle_cancel(
+    id: MessageId,
+    mut deserializer: Box<InboundBody>,
+) -> Result<(), Error> {
+    let token: String = erased_serde::deserialize(&mut deserializer)?;
+    if is_correct_cancellation_token(id, &token) {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument)
+    }
+}
 
-            Running::Stop(None)
+fn is_correct_cancellation_token(id: MessageId, token: &str) -> bool {
+    match token.find(CANCELLATION_TOKEN_DELIM) {
+        Some(ind) => {
+            let base = &token[..ind];
+            let id_str = &token[ind + 1..];
+            let _id: MessageId = match id_str.parse() {
+                Ok(num) => num,
+                Err(_) => return false,
+            };
+            base == CANCELLATION_TOKEN && _id == id
         }
+        None => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: CodecRead> Reader for ServerReader<T> {
+    type BrokerItem = ServerBrokerItem;
+    type Ok = ();
+    type Error = Error;
+
+    async fn op<B>(
+        &mut self,
+        broker: B,
+    ) -> Running<Result<Self::Ok, Self::Error>, Option<Self::Error>>
+    where
+        B: Sink<Self::BrokerItem, Error = flume::SendError<Self::BrokerItem>> + Send + Unpin,
+    {
+        let running = self.read_message(broker).await;
+        self.stats.set_bytes_read(self.reader.bytes_read());
+        if let Running::Continue(Err(_)) = &running {
+            self.stats.metrics().add_error();
+        }
+        running
     }
 
     async fn handle_result(res: Result<Self::Ok, Self::Error>) -> Running<(), Option<Self::Error>> {
@@ -239,3 +502,41 @@ impl<T: CodecRead> Reader for ServerReader<T> {
         Running::Continue(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancellation_token_carries_wide_id() {
+        let id = u32::MAX as MessageId + 7;
+        let token = format!("{}{}{}", CANCELLATION_TOKEN, CANCELLATION_TOKEN_DELIM, id);
+        assert!(is_correct_cancellation_token(id, &token));
+        assert!(!is_correct_cancellation_token(7, &token));
+    }
+
+    #[test]
+    fn service_not_found_and_method_not_found() {
+        use crate::service::ServiceEntry;
+
+        let call: ArcAsyncServiceCall = Arc::new(
+            |_: String, _: Box<InboundBody>| -> crate::service::HandlerResultFut {
+                Box::pin(async { Err(Error::MethodNotFound) })
+            },
+        );
+        let mut services = crate::service::AsyncServiceMap::new();
+        services.insert("Foo".into(), ServiceEntry::with_methods(call, &["bar"]));
+        let services = Arc::new(ServiceRegistry::new(services));
+
+        let (_, service_name, method) = service(&services, "Foo.bar".into()).unwrap();
+        assert_eq!((service_name.as_str(), method.as_str()), ("Foo", "bar"));
+        assert!(matches!(
+            service(&services, "Baz.bar".into()),
+            Err(Error::ServiceNotFound)
+        ));
+        assert!(matches!(
+            service(&services, "Foo.baz".into()),
+            Err(Error::MethodNotFound)
+        ));
+    }
+}
@@ -1,17 +1,21 @@
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc, time::Instant};
 
 use brw::{Running, Writer};
+use flume::Sender;
 
 use crate::{
-    codec::CodecWrite,
+    codec::{codec_name, CodecWrite, ContentType, Marshal},
     error::Error,
     message::{ErrorMessage, MessageId},
     pubsub::SeqId,
+    server::{broker::ServerBrokerItem, ErrorMode},
     service::HandlerResult,
+    stats::{WriterStats, WriterTimer},
+    trace::{debug_span, Instrument},
     util::GracefulShutdown,
 };
 
-use crate::protocol::Header;
+use crate::protocol::{codec_announcement, ConnectionLimits, Header, OutboundBody};
 
 #[cfg_attr(feature = "http_actix_web", derive(actix::Message))]
 #[cfg_attr(feature = "http_actix_web", rtype(result = "()"))]
@@ -19,6 +23,10 @@ pub(crate) enum ServerWriterItem {
     Response {
         id: MessageId,
         result: HandlerResult,
+        /// Content type of the `Ok` body if it is not the codec of the connection
+        content_type: Option<ContentType>,
+        /// The time at which the response is put into the writer channel
+        enqueued_at: Instant,
     },
     /// Publish subscription item to client
     Publication {
@@ -31,63 +39,285 @@ pub(crate) enum ServerWriterItem {
         // Thus should reply with the MessageId that came from the client
         id: MessageId,
     },
+    /// An item of a streaming response
+    StreamItem {
+        id: MessageId,
+        body: Box<OutboundBody>,
+    },
+    /// Reply to the handshake of the client with the limits of the server
+    Handshake(ConnectionLimits),
+    /// Reply to the codec announcement of the client with the codec of the server
+    AnnounceCodec,
+    /// Gives the writer a way to schedule the next chunks of the large responses
+    Attach(Sender<ServerBrokerItem>),
+    /// Writes the next chunk of every large response that is being written
+    WriteChunks,
+    /// Flushes the messages that are buffered by the flush policy of the codec
+    Flush,
     Stopping,
     Stop,
 }
 
+/// Marshals the body of a response with `content_type`, or with the codec of the
+/// connection if it is `None`
+fn marshal_body<W: Marshal>(
+    body: &OutboundBody,
+    content_type: Option<ContentType>,
+) -> Result<Vec<u8>, Error> {
+    let res = match content_type {
+        Some(content_type) => content_type.marshal(body),
+        None => W::marshal(&body),
+    };
+    res.map_err(Error::ParseError)
+}
+
+/// A response body that is written in chunks, interleaved with other messages
+struct ChunkedResponse {
+    id: MessageId,
+    is_ok: bool,
+    body: Vec<u8>,
+    /// Index of the next chunk to write
+    index: usize,
+}
+
 pub(crate) struct ServerWriter<W> {
     writer: W,
+    timer: WriterTimer,
+    /// Response bodies larger than this are written in chunks
+    chunk_size: Option<usize>,
+    /// Large responses that are being written, in round-robin order
+    chunked: VecDeque<ChunkedResponse>,
+    broker: Option<Sender<ServerBrokerItem>>,
+    /// Whether a `WriteChunks` is on its way back from the broker
+    chunks_scheduled: bool,
+    /// Whether a `Flush` is on its way back from the broker
+    flush_scheduled: bool,
+    /// How much detail of the handler errors is sent to the client
+    error_mode: ErrorMode,
 }
 
 impl<W: CodecWrite> ServerWriter<W> {
     #[cfg(not(feature = "http_actix_web"))]
-    pub fn new(writer: W) -> Self {
-        Self { writer }
+    pub fn new(
+        writer: W,
+        stats: Arc<WriterStats>,
+        chunk_size: Option<usize>,
+        error_mode: ErrorMode,
+    ) -> Self {
+        let chunk_size = chunk_size.filter(|_| writer.supports_body_chunks());
+        Self {
+            writer,
+            timer: WriterTimer::new(stats),
+            chunk_size,
+            chunked: VecDeque::new(),
+            broker: None,
+            chunks_scheduled: false,
+            flush_scheduled: false,
+            error_mode,
+        }
     }
 
-    async fn write_response(&mut self, id: MessageId, result: HandlerResult) -> Result<(), Error> {
+    /// Writes a response and returns the number of bytes written
+    async fn write_response(
+        &mut self,
+        id: MessageId,
+        result: HandlerResult,
+        content_type: Option<ContentType>,
+    ) -> Result<usize, Error> {
+        if let Some(chunk_size) = self.chunk_size {
+            return self
+                .write_response_in_chunks(id, result, content_type, chunk_size)
+                .await;
+        }
         match result {
+            Ok(body) if content_type.is_some() => {
+                log::trace!("Message {} Success", &id);
+                let body = marshal_body::<W>(&body, content_type)?;
+                let header = Header::Response { id, is_ok: true };
+                let n = self.writer.write_header(header).await?;
+                let m = self.writer.write_body_bytes(id, &body).await?;
+                Ok(n + m)
+            }
             Ok(body) => {
                 log::trace!("Message {} Success", &id);
                 let header = Header::Response { id, is_ok: true };
-                self.writer.write_header(header).await?;
-                self.writer.write_body(id, &body).await?;
-                Ok(())
+                let n = self.writer.write_header(header).await?;
+                let m = self.writer.write_body(id, &body).await?;
+                Ok(n + m)
             }
             Err(err) => {
                 log::trace!("Message {} Error", &id);
                 let header = Header::Response { id, is_ok: false };
-                let msg = match ErrorMessage::from_err(err) {
+                let msg = match ErrorMessage::from_err(self.error_mode.apply(err)) {
                     Ok(m) => m,
                     Err(err) => {
                         log::debug!("Non-sendable error: {}", err);
-                        return Ok(());
+                        return Ok(0);
                     }
                 };
-                self.writer.write_header(header).await?;
-                self.writer.write_body(id, &msg).await?;
-                Ok(())
+                let n = self.writer.write_header(header).await?;
+                let m = self.writer.write_body(id, &msg).await?;
+                Ok(n + m)
             }
         }
     }
 
+    /// Writes a response whose body is no larger than `chunk_size` right away. Larger
+    /// bodies are queued and written one chunk at a time, taking turns with the other
+    /// large responses, so that they don't hold up the small ones.
+    async fn write_response_in_chunks(
+        &mut self,
+        id: MessageId,
+        result: HandlerResult,
+        content_type: Option<ContentType>,
+        chunk_size: usize,
+    ) -> Result<usize, Error> {
+        let (is_ok, body) = match result {
+            Ok(body) => (true, marshal_body::<W>(&body, content_type)?),
+            Err(err) => match ErrorMessage::from_err(self.error_mode.apply(err)) {
+                Ok(msg) => (false, W::marshal(&msg)?),
+                Err(err) => {
+                    log::debug!("Non-sendable error: {}", err);
+                    return Ok(0);
+                }
+            },
+        };
+        if body.len() <= chunk_size {
+            let header = Header::Response { id, is_ok };
+            let n = self.writer.write_header(header).await?;
+            let m = self.writer.write_body_bytes(id, &body).await?;
+            return Ok(n + m);
+        }
+        log::trace!("Message {} is written in chunks", &id);
+        self.chunked.push_back(ChunkedResponse {
+            id,
+            is_ok,
+            body,
+            index: 0,
+        });
+        self.schedule_chunks().await
+    }
+
+    /// Writes the next chunk of every large response. The header of a response is
+    /// written right before its last chunk, so the client sees a complete message as
+    /// soon as it reads the header.
+    async fn write_chunks(&mut self) -> Result<usize, Error> {
+        let chunk_size = match self.chunk_size {
+            Some(size) => size,
+            None => return Ok(0),
+        };
+        let mut num_bytes = 0;
+        for _ in 0..self.chunked.len() {
+            let mut resp = match self.chunked.pop_front() {
+                Some(resp) => resp,
+                None => break,
+            };
+            let start = resp.index * chunk_size;
+            let end = start + chunk_size;
+            if end < resp.body.len() {
+                num_bytes += self
+                    .writer
+                    .write_body_chunk(resp.id, resp.index, &resp.body[start..end], false)
+                    .await?;
+                resp.index += 1;
+                self.chunked.push_back(resp);
+            } else {
+                let header = Header::Response {
+                    id: resp.id,
+                    is_ok: resp.is_ok,
+                };
+                num_bytes += self.writer.write_header(header).await?;
+                num_bytes += self
+                    .writer
+                    .write_body_chunk(resp.id, resp.index, &resp.body[start..], true)
+                    .await?;
+            }
+        }
+        Ok(num_bytes)
+    }
+
+    /// Makes sure the remaining chunks are written after the items that are already
+    /// waiting for the writer. If the writer is not attached to the broker, the chunks
+    /// are written right away.
+    async fn schedule_chunks(&mut self) -> Result<usize, Error> {
+        if self.chunks_scheduled || self.chunked.is_empty() {
+            return Ok(0);
+        }
+        if let Some(broker) = &self.broker {
+            if broker
+                .send_async(ServerBrokerItem::WriteChunks)
+                .await
+                .is_ok()
+            {
+                self.chunks_scheduled = true;
+                return Ok(0);
+            }
+        }
+        self.flush_chunks().await
+    }
+
+    /// Makes sure the messages left in the write buffer are flushed once the messages
+    /// that are already queued are written.
+    ///
+    /// The `Flush` goes through the broker, so it reaches the writer behind everything
+    /// that is queued at this point.
+    async fn schedule_flush(&mut self) -> Result<(), Error> {
+        if self.flush_scheduled {
+            return Ok(());
+        }
+        if let Some(broker) = &self.broker {
+            if broker.send_async(ServerBrokerItem::Flush).await.is_ok() {
+                self.flush_scheduled = true;
+                return Ok(());
+            }
+        }
+        self.writer.flush().await.map_err(Into::into)
+    }
+
+    /// Writes all the remaining chunks
+    async fn flush_chunks(&mut self) -> Result<usize, Error> {
+        let mut num_bytes = 0;
+        while !self.chunked.is_empty() {
+            num_bytes += self.write_chunks().await?;
+        }
+        Ok(num_bytes)
+    }
+
     async fn write_publication(
         &mut self,
         id: MessageId,
         topic: String,
         content: &[u8],
-    ) -> Result<(), Error> {
+    ) -> Result<usize, Error> {
         let header = Header::Publish { id, topic };
-        self.writer.write_header(header).await?;
-        self.writer.write_body_bytes(id, &content).await?;
-        Ok(())
+        let n = self.writer.write_header(header).await?;
+        let m = self.writer.write_body_bytes(id, &content).await?;
+        Ok(n + m)
+    }
+
+    async fn write_stream_item(
+        &mut self,
+        id: MessageId,
+        body: &OutboundBody,
+    ) -> Result<usize, Error> {
+        let header = Header::StreamItem { id };
+        let n = self.writer.write_header(header).await?;
+        let m = self.writer.write_body(id, body).await?;
+        Ok(n + m)
+    }
+
+    async fn write_handshake(&mut self, limits: ConnectionLimits) -> Result<usize, Error> {
+        let header = Header::Handshake(limits);
+        let n = self.writer.write_header(header).await?;
+        let m = self.writer.write_body(0, &()).await?;
+        Ok(n + m)
     }
 
     // Ack message
-    async fn write_ack(&mut self, id: MessageId) -> Result<(), Error> {
+    async fn write_ack(&mut self, id: MessageId) -> Result<usize, Error> {
         let header = Header::Ack(id);
-        self.writer.write_header(header).await?;
-        Ok(())
+        let n = self.writer.write_header(header).await?;
+        Ok(n)
     }
 }
 
@@ -101,8 +331,29 @@ impl<W: CodecWrite + GracefulShutdown> Writer for ServerWriter<W> {
         &mut self,
         item: Self::Item,
     ) -> Running<Result<Self::Ok, Self::Error>, Option<Self::Error>> {
+        let enqueued_at = match &item {
+            ServerWriterItem::Response { enqueued_at, .. } => Some(*enqueued_at),
+            _ => None,
+        };
+        let start = self.timer.start(enqueued_at);
         let res = match item {
-            ServerWriterItem::Response { id, result } => self.write_response(id, result).await,
+            ServerWriterItem::Response {
+                id,
+                result,
+                content_type,
+                ..
+            } => {
+                let is_ok = result.is_ok();
+                let span = debug_span!("write_response", message_id = id, is_ok);
+                let res = self
+                    .write_response(id, result, content_type)
+                    .instrument(span)
+                    .await;
+                if res.is_ok() {
+                    self.timer.stats().metrics().add_response(is_ok);
+                }
+                res
+            }
             ServerWriterItem::Publication {
                 seq_id,
                 topic,
@@ -112,10 +363,50 @@ impl<W: CodecWrite + GracefulShutdown> Writer for ServerWriter<W> {
                 self.write_publication(id, topic, &content).await
             }
             ServerWriterItem::Ack { id } => self.write_ack(id).await,
-            ServerWriterItem::Stopping => Ok(self.writer.close().await),
+            ServerWriterItem::StreamItem { id, body } => self.write_stream_item(id, &body).await,
+            ServerWriterItem::Handshake(limits) => self.write_handshake(limits).await,
+            ServerWriterItem::AnnounceCodec => self
+                .writer
+                .write_header_bytes(0, &codec_announcement(codec_name()))
+                .await
+                .map_err(Into::into),
+            ServerWriterItem::Attach(broker) => {
+                self.broker = Some(broker);
+                Ok(0)
+            }
+            ServerWriterItem::WriteChunks => {
+                self.chunks_scheduled = false;
+                match self.write_chunks().await {
+                    Ok(n) => self.schedule_chunks().await.map(|m| n + m),
+                    Err(err) => Err(err),
+                }
+            }
+            ServerWriterItem::Flush => {
+                self.flush_scheduled = false;
+                self.writer.flush().await.map(|_| 0).map_err(Into::into)
+            }
+            ServerWriterItem::Stopping => {
+                let res = self.flush_chunks().await;
+                self.writer.close().await;
+                res
+            }
             ServerWriterItem::Stop => return Running::Stop(None),
         };
-        Running::Continue(res)
+        let res = match res {
+            Ok(n) if n > 0 && self.writer.flush_policy().is_buffered() => {
+                self.schedule_flush().await.map(|_| n)
+            }
+            res => res,
+        };
+        let num_bytes = match &res {
+            Ok(n) => *n,
+            Err(_) => {
+                self.timer.stats().metrics().add_error();
+                0
+            }
+        };
+        self.timer.end(start, num_bytes);
+        Running::Continue(res.map(|_| ()))
     }
 
     async fn handle_result(res: Result<Self::Ok, Self::Error>) -> Running<(), Option<Self::Error>> {
@@ -6,7 +6,7 @@ use futures::future::Future;
 use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use crate::error::Error;
 use crate::protocol::OutboundBody;
@@ -34,10 +34,68 @@ pub type AsyncServiceCall = dyn Fn(String, Box<dyn erased::Deserializer<'static>
 /// Arc wrapper of `AsyncServiceCall`
 pub type ArcAsyncServiceCall = Arc<AsyncServiceCall>;
 
+/// Trait objects to check whether a service handles a method
+pub type ServiceHasMethod = dyn Fn(&str) -> bool + Send + Sync + 'static;
+
+/// A registered service
+///
+/// The server asks the service whether it handles the requested method before the
+/// method is invoked, so that a missing method can be told apart from a missing service.
+#[derive(Clone)]
+pub struct ServiceEntry {
+    /// Invokes a method of the service
+    pub call: ArcAsyncServiceCall,
+    /// Returns whether the service handles a method
+    pub has_method: Arc<ServiceHasMethod>,
+}
+
+impl ServiceEntry {
+    /// Creates an entry of a service that handles a fixed set of methods
+    pub fn with_methods(call: ArcAsyncServiceCall, methods: &'static [&'static str]) -> Self {
+        Self {
+            call,
+            has_method: Arc::new(move |name| methods.contains(&name)),
+        }
+    }
+
+    /// Returns whether the service handles the method
+    pub fn has_method(&self, name: &str) -> bool {
+        (self.has_method)(name)
+    }
+
+    /// Creates the entry of a `Service`
+    pub(crate) fn from_service<S>(service: Service<S>) -> Self
+    where
+        S: Send + Sync + 'static,
+    {
+        let service = Arc::new(service);
+        let has_method = {
+            let service = service.clone();
+            move |method_name: &str| service.has_method(method_name)
+        };
+        let call = move |method_name: String,
+                         _deserializer: Box<(dyn erased::Deserializer<'static> + Send)>|
+              -> HandlerResultFut { service.call(&method_name, _deserializer) };
+        Self {
+            call: Arc::new(call),
+            has_method: Arc::new(has_method),
+        }
+    }
+}
+
 /// Hashmap of services.
 ///
-/// The keys are service names and the values are function trait objects `ArcAsyncServiceCall`
-pub type AsyncServiceMap = HashMap<&'static str, ArcAsyncServiceCall>;
+/// The keys are service names and the values are the registered services `ServiceEntry`
+pub type AsyncServiceMap = HashMap<String, ServiceEntry>;
+
+/// Services of a running server, which can be registered and deregistered while the
+/// server is running.
+///
+/// Each request takes the read lock once to clone the `Arc`s of the entry of its service,
+/// and the lock is never held across an `.await`, so the lookup is not contended unless a
+/// service is being registered or deregistered. A call that has been looked up keeps its
+/// entry, so it completes even if the service is deregistered in the meantime.
+pub(crate) type ServiceRegistry = RwLock<AsyncServiceMap>;
 
 /// A RPC service that can hold an internal state
 pub struct Service<State>
@@ -71,6 +129,18 @@ where
     /// Returns a function pointer to the requested method
     fn method(&self, name: &str) -> Option<AsyncHandler<State>>;
 
+    /// Returns whether the service handles the requested method, which is checked
+    /// before the method is invoked
+    fn has_method(&self, name: &str) -> bool {
+        self.method(name).is_some()
+    }
+
+    /// Returns the names of the methods of the service, which are listed by the
+    /// introspection service. The default implementation returns no names.
+    fn method_names(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
     /// Returns a future that will execute the RPC method when `.await`ed.
     /// Returns `Error::MethodNotFound` if the requested method is not registered.
     fn call(
@@ -98,6 +168,16 @@ where
         // self.handlers.get(name).map(|m| m.clone())
         self.handlers.get(name).cloned()
     }
+
+    fn has_method(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+
+    fn method_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.handlers.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
 }
 
 /// Type state for the `ServiceBuilder` when the builder is NOT ready to build a `Service`
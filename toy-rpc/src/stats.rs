@@ -0,0 +1,643 @@
+//! Per-connection statistics
+//!
+//! The writer of each connection keeps track of where the outbound messages spend their time:
+//!
+//! - `queue_wait`: time a request (client) or a response (server) spent waiting in the
+//! internal outbound channel before the writer picked it up
+//! - `write`: time spent inside the writer writing to and flushing the underlying transport
+//! - `idle`: time the writer spent waiting for new items
+//!
+//! Time is only sampled at the boundaries of each writer operation, so the overhead is a few
+//! calls to `Instant::now()` per message.
+//!
+//! Byte counts are the number of bytes on the wire. For the framed transport used over TCP
+//! and TLS, this includes the magic and version bytes and the frame header of every frame
+//! (but not the TLS records). For WebSocket, this is the size of the binary messages, since the WebSocket
+//! framing is done by the WebSocket library. The same counts are available per call on the
+//! client with `Call::stats()`.
+//!
+//! The `liveness` of a connection tells when a frame was last sent and received. The
+//! timestamps are stored with a millisecond resolution.
+//!
+//! All the counters are cumulative, so taking a snapshot has no side effect. The activity
+//! over an interval is the difference of two snapshots, which `ConnectionStats::since`
+//! computes together with the rates of the writer.
+//!
+//! The `Metrics` of a client or a server count the calls and the bytes across the
+//! connections, and the counters of a server keep the traffic of the connections that
+//! are closed.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Accumulated timings of the writer of a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WriterTimings {
+    /// Total time outbound items spent waiting in the outbound channel
+    pub queue_wait: Duration,
+    /// Total time spent writing to the transport
+    pub write: Duration,
+    /// Total time the writer spent waiting for new items
+    pub idle: Duration,
+    /// Number of items written
+    pub num_items: u64,
+    /// Number of bytes written
+    pub num_bytes: u64,
+}
+
+impl WriterTimings {
+    /// Timings accumulated since the `earlier` totals
+    pub fn since(&self, earlier: &WriterTimings) -> WriterTimings {
+        WriterTimings {
+            queue_wait: self.queue_wait.saturating_sub(earlier.queue_wait),
+            write: self.write.saturating_sub(earlier.write),
+            idle: self.idle.saturating_sub(earlier.idle),
+            num_items: self.num_items.saturating_sub(earlier.num_items),
+            num_bytes: self.num_bytes.saturating_sub(earlier.num_bytes),
+        }
+    }
+}
+
+/// Snapshot of the statistics of a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionStats {
+    /// Rolling totals since the connection is established
+    pub total: WriterTimings,
+    /// When the snapshot is taken
+    pub taken_at: Instant,
+    /// Number of bytes read since the connection is established
+    pub bytes_read: u64,
+    /// Number of messages from the peer that violated the limits declared during the
+    /// handshake. See `toy_rpc::protocol::ConnectionLimits`
+    pub protocol_violations: u64,
+    /// When the connection was last active
+    pub liveness: Liveness,
+}
+
+/// Activity of a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Liveness {
+    /// Time at which the connection is established
+    pub connected_at: Instant,
+    /// Time at which the last frame is received, `None` if nothing is received yet
+    pub last_frame_received_at: Option<Instant>,
+    /// Time at which the last frame is sent, `None` if nothing is sent yet
+    pub last_frame_sent_at: Option<Instant>,
+    /// Round trip time of the last answered heartbeat, `None` if no heartbeat is
+    /// answered yet. Only the client sends heartbeats, see `toy_rpc::client::Heartbeat`
+    pub last_ping_rtt: Option<Duration>,
+    /// Number of heartbeats in a row that are not answered in time
+    pub missed_pongs: u32,
+    /// Time between two heartbeats, `None` if no heartbeat is set
+    pub heartbeat_interval: Option<Duration>,
+    /// Time a heartbeat is waited for, `None` if no heartbeat is set
+    pub heartbeat_timeout: Option<Duration>,
+}
+
+impl Liveness {
+    /// Time at which the last frame is sent or received
+    pub fn last_activity(&self) -> Option<Instant> {
+        self.last_frame_received_at.max(self.last_frame_sent_at)
+    }
+
+    /// Time since the last frame is sent or received, or since the connection is
+    /// established if there is no frame yet
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity().unwrap_or(self.connected_at).elapsed()
+    }
+}
+
+impl ConnectionStats {
+    /// Activity of the writer between the `earlier` snapshot of the same connection and
+    /// this one
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let before = client.stats();
+    /// // ...
+    /// let interval = client.stats().since(&before);
+    /// println!("writing {:.0}% of the time", interval.write_rate() * 100.0);
+    /// ```
+    pub fn since(&self, earlier: &ConnectionStats) -> IntervalStats {
+        IntervalStats {
+            timings: self.total.since(&earlier.total),
+            duration: self.taken_at.saturating_duration_since(earlier.taken_at),
+        }
+    }
+
+    /// Time since the last frame is sent or received. See `Liveness::idle_for`
+    pub fn idle_for(&self) -> Duration {
+        self.liveness.idle_for()
+    }
+}
+
+/// Activity of the writer of a connection between two snapshots, which is returned by
+/// `ConnectionStats::since`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IntervalStats {
+    /// Timings accumulated during the interval
+    pub timings: WriterTimings,
+    /// Length of the interval
+    pub duration: Duration,
+}
+
+impl IntervalStats {
+    /// Fraction of the interval spent waiting in the outbound channel
+    pub fn queue_wait_rate(&self) -> f64 {
+        Self::rate(self.timings.queue_wait, self.duration)
+    }
+
+    /// Fraction of the interval spent writing to the transport
+    pub fn write_rate(&self) -> f64 {
+        Self::rate(self.timings.write, self.duration)
+    }
+
+    /// Fraction of the interval the writer was idle
+    pub fn idle_rate(&self) -> f64 {
+        Self::rate(self.timings.idle, self.duration)
+    }
+
+    fn rate(part: Duration, interval: Duration) -> f64 {
+        if interval.as_nanos() == 0 {
+            return 0.0;
+        }
+        part.as_secs_f64() / interval.as_secs_f64()
+    }
+}
+
+/// Number of bytes transferred for a single call
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CallStats {
+    /// Number of bytes of the request written, including the header. This is 0 until the
+    /// request is written.
+    pub request_bytes: u64,
+    /// Number of bytes of the response read, including the header. This is 0 until the
+    /// response is received.
+    pub response_bytes: u64,
+}
+
+/// Byte counters of a call shared by the `Call`, the broker and the writer
+#[derive(Debug, Default)]
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+pub(crate) struct CallCounters {
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+}
+
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+impl CallCounters {
+    pub fn set_request_bytes(&self, num_bytes: usize) {
+        self.request_bytes
+            .store(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_response_bytes(&self, num_bytes: usize) {
+        self.response_bytes
+            .store(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CallStats {
+        CallStats {
+            request_bytes: self.request_bytes.load(Ordering::Relaxed),
+            response_bytes: self.response_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of the traffic of a client or a server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of requests sent by a client or received by a server
+    pub requests: u64,
+    /// Number of responses received by a client or sent by a server
+    pub responses: u64,
+    /// Number of bytes written to the connections
+    pub bytes_sent: u64,
+    /// Number of bytes read from the connections
+    pub bytes_received: u64,
+    /// Number of error responses, and of messages that could not be read or written
+    pub errors: u64,
+}
+
+/// Counters of `Metrics` shared by the connections of a client or a server
+#[derive(Debug, Default)]
+pub(crate) struct MetricsCounters {
+    requests: AtomicU64,
+    responses: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl MetricsCounters {
+    pub fn add_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_response(&self, is_ok: bool) {
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        if !is_ok {
+            self.add_error();
+        }
+    }
+
+    pub fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, num_bytes: usize) {
+        self.bytes_sent
+            .fetch_add(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_received(&self, num_bytes: u64) {
+        self.bytes_received.fetch_add(num_bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Metrics {
+        Metrics {
+            requests: self.requests.load(Ordering::Relaxed),
+            responses: self.responses.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Shared accumulators updated by the writer of a connection. The number of bytes read is
+/// updated by the reader.
+#[derive(Debug)]
+pub(crate) struct WriterStats {
+    queue_wait: AtomicU64,
+    write: AtomicU64,
+    idle: AtomicU64,
+    num_items: AtomicU64,
+    num_bytes: AtomicU64,
+    bytes_read: AtomicU64,
+    protocol_violations: AtomicU64,
+    created_at: Instant,
+    /// Milliseconds from `created_at` to the last frame received plus one, 0 if none
+    last_received: AtomicU64,
+    /// Milliseconds from `created_at` to the last frame sent plus one, 0 if none
+    last_sent: AtomicU64,
+    /// Round trip time of the last heartbeat in microseconds plus one, 0 if none
+    last_ping_rtt: AtomicU64,
+    missed_pongs: AtomicU64,
+    /// Heartbeat interval and timeout in milliseconds, 0 if no heartbeat is set
+    heartbeat_interval: AtomicU64,
+    heartbeat_timeout: AtomicU64,
+    /// Shared with the other connections of the same client or server
+    metrics: Arc<MetricsCounters>,
+}
+
+impl Default for WriterStats {
+    fn default() -> Self {
+        Self {
+            queue_wait: AtomicU64::new(0),
+            write: AtomicU64::new(0),
+            idle: AtomicU64::new(0),
+            num_items: AtomicU64::new(0),
+            num_bytes: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            protocol_violations: AtomicU64::new(0),
+            created_at: Instant::now(),
+            last_received: AtomicU64::new(0),
+            last_sent: AtomicU64::new(0),
+            last_ping_rtt: AtomicU64::new(0),
+            missed_pongs: AtomicU64::new(0),
+            heartbeat_interval: AtomicU64::new(0),
+            heartbeat_timeout: AtomicU64::new(0),
+            metrics: Arc::new(MetricsCounters::default()),
+        }
+    }
+}
+
+fn as_nanos(dur: Duration) -> u64 {
+    dur.as_nanos() as u64
+}
+
+fn load_millis(millis: &AtomicU64) -> Option<Duration> {
+    match millis.load(Ordering::Relaxed) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+impl WriterStats {
+    /// Adds the traffic of the connection to `metrics`
+    pub fn with_metrics(metrics: Arc<MetricsCounters>) -> Self {
+        Self {
+            metrics,
+            ..Default::default()
+        }
+    }
+
+    pub fn metrics(&self) -> &Arc<MetricsCounters> {
+        &self.metrics
+    }
+
+    pub fn add_queue_wait(&self, dur: Duration) {
+        self.queue_wait.fetch_add(as_nanos(dur), Ordering::Relaxed);
+    }
+
+    pub fn add_write(&self, dur: Duration, num_bytes: usize) {
+        self.write.fetch_add(as_nanos(dur), Ordering::Relaxed);
+        self.num_items.fetch_add(1, Ordering::Relaxed);
+        self.num_bytes
+            .fetch_add(num_bytes as u64, Ordering::Relaxed);
+        self.metrics.add_bytes_sent(num_bytes);
+        if num_bytes > 0 {
+            self.last_sent.store(self.timestamp(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_idle(&self, dur: Duration) {
+        self.idle.fetch_add(as_nanos(dur), Ordering::Relaxed);
+    }
+
+    /// Sets the total number of bytes read from the connection
+    pub fn set_bytes_read(&self, total: u64) {
+        let prev = self.bytes_read.swap(total, Ordering::Relaxed);
+        if prev < total {
+            self.metrics.add_bytes_received(total - prev);
+            self.last_received
+                .store(self.timestamp(), Ordering::Relaxed);
+        }
+    }
+
+    /// Coarse monotonic timestamp of now
+    fn timestamp(&self) -> u64 {
+        self.created_at.elapsed().as_millis() as u64 + 1
+    }
+
+    fn instant(&self, timestamp: &AtomicU64) -> Option<Instant> {
+        match timestamp.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(self.created_at + Duration::from_millis(millis - 1)),
+        }
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        Liveness {
+            connected_at: self.created_at,
+            last_frame_received_at: self.instant(&self.last_received),
+            last_frame_sent_at: self.instant(&self.last_sent),
+            last_ping_rtt: match self.last_ping_rtt.load(Ordering::Relaxed) {
+                0 => None,
+                micros => Some(Duration::from_micros(micros - 1)),
+            },
+            missed_pongs: self.missed_pongs.load(Ordering::Relaxed) as u32,
+            heartbeat_interval: load_millis(&self.heartbeat_interval),
+            heartbeat_timeout: load_millis(&self.heartbeat_timeout),
+        }
+    }
+
+    /// Records the interval and the timeout of the heartbeat of the connection
+    pub fn set_heartbeat(&self, interval: Duration, timeout: Duration) {
+        self.heartbeat_interval
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+        self.heartbeat_timeout
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Records an answered heartbeat, which resets the missed count
+    pub fn set_ping_rtt(&self, rtt: Duration) {
+        self.last_ping_rtt
+            .store(rtt.as_micros() as u64 + 1, Ordering::Relaxed);
+        self.missed_pongs.store(0, Ordering::Relaxed);
+    }
+
+    /// Records a heartbeat that is not answered in time
+    pub fn set_missed_pongs(&self, missed: u32) {
+        self.missed_pongs.store(missed as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a message that violated the declared limits
+    pub fn add_protocol_violation(&self) {
+        self.protocol_violations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> WriterTimings {
+        WriterTimings {
+            queue_wait: Duration::from_nanos(self.queue_wait.load(Ordering::Relaxed)),
+            write: Duration::from_nanos(self.write.load(Ordering::Relaxed)),
+            idle: Duration::from_nanos(self.idle.load(Ordering::Relaxed)),
+            num_items: self.num_items.load(Ordering::Relaxed),
+            num_bytes: self.num_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Takes a snapshot of the counters, which are left untouched
+    pub fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            total: self.totals(),
+            taken_at: Instant::now(),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            protocol_violations: self.protocol_violations.load(Ordering::Relaxed),
+            liveness: self.liveness(),
+        }
+    }
+}
+
+/// Samples the time spent by a writer
+#[derive(Debug)]
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+pub(crate) struct WriterTimer {
+    stats: std::sync::Arc<WriterStats>,
+    last_op_end: Instant,
+}
+
+#[cfg_attr(
+    all(not(feature = "tokio_runtime"), not(feature = "async_std_runtime")),
+    allow(dead_code)
+)]
+impl WriterTimer {
+    pub fn new(stats: std::sync::Arc<WriterStats>) -> Self {
+        Self {
+            stats,
+            last_op_end: Instant::now(),
+        }
+    }
+
+    pub fn stats(&self) -> &WriterStats {
+        &self.stats
+    }
+
+    /// Marks the start of a writer operation and returns the start time
+    pub fn start(&mut self, enqueued_at: Option<Instant>) -> Instant {
+        let now = Instant::now();
+        self.stats
+            .add_idle(now.saturating_duration_since(self.last_op_end));
+        if let Some(enqueued_at) = enqueued_at {
+            self.stats
+                .add_queue_wait(now.saturating_duration_since(enqueued_at));
+        }
+        now
+    }
+
+    /// Marks the end of a writer operation that wrote `num_bytes`
+    pub fn end(&mut self, start: Instant, num_bytes: usize) {
+        let now = Instant::now();
+        self.stats
+            .add_write(now.saturating_duration_since(start), num_bytes);
+        self.last_op_end = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread::sleep};
+
+    use super::*;
+
+    #[test]
+    fn stalled_transport_counts_as_write_time() {
+        let stats = Arc::new(WriterStats::default());
+        let mut timer = WriterTimer::new(stats.clone());
+
+        let start = timer.start(Some(Instant::now()));
+        // the transport is stalled
+        sleep(Duration::from_millis(50));
+        timer.end(start, 42);
+
+        let snapshot = stats.snapshot();
+        assert!(snapshot.total.write >= Duration::from_millis(50));
+        assert!(snapshot.total.queue_wait < Duration::from_millis(50));
+        assert_eq!(snapshot.total.num_items, 1);
+        assert_eq!(snapshot.total.num_bytes, 42);
+    }
+
+    #[test]
+    fn flooded_channel_counts_as_queue_wait() {
+        let stats = Arc::new(WriterStats::default());
+        let mut timer = WriterTimer::new(stats.clone());
+
+        // items are enqueued faster than the writer picks them up
+        let enqueued_at = Instant::now();
+        sleep(Duration::from_millis(50));
+        for _ in 0..3 {
+            let start = timer.start(Some(enqueued_at));
+            timer.end(start, 10);
+        }
+
+        let snapshot = stats.snapshot();
+        assert!(snapshot.total.queue_wait >= Duration::from_millis(150));
+        assert!(snapshot.total.write < Duration::from_millis(50));
+        assert_eq!(snapshot.total.num_items, 3);
+        assert_eq!(snapshot.total.num_bytes, 30);
+
+        // taking a snapshot doesn't reset anything
+        let later = stats.snapshot();
+        assert_eq!(later.total, snapshot.total);
+        let interval = later.since(&snapshot);
+        assert_eq!(interval.timings.num_items, 0);
+        assert_eq!(interval.timings.num_bytes, 0);
+
+        let start = timer.start(Some(Instant::now()));
+        timer.end(start, 10);
+        let interval = stats.snapshot().since(&snapshot);
+        assert_eq!(interval.timings.num_items, 1);
+        assert_eq!(interval.timings.num_bytes, 10);
+        assert!(interval.duration > Duration::from_millis(0));
+    }
+
+    #[test]
+    fn metrics_are_shared_by_connections() {
+        let metrics = Arc::new(MetricsCounters::default());
+        let first = WriterStats::with_metrics(metrics.clone());
+        let second = WriterStats::with_metrics(metrics.clone());
+
+        first.metrics().add_request();
+        first.add_write(Duration::from_millis(1), 10);
+        first.set_bytes_read(20);
+        first.metrics().add_response(true);
+        second.metrics().add_request();
+        second.add_write(Duration::from_millis(1), 5);
+        second.set_bytes_read(7);
+        // only the bytes read since the last update are added
+        second.set_bytes_read(8);
+        second.metrics().add_response(false);
+
+        // a snapshot of a connection doesn't reset the metrics
+        first.snapshot();
+        assert_eq!(
+            metrics.snapshot(),
+            Metrics {
+                requests: 2,
+                responses: 2,
+                bytes_sent: 15,
+                bytes_received: 28,
+                errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn liveness_follows_frames_sent_and_received() {
+        let stats = WriterStats::default();
+        let liveness = stats.liveness();
+        assert_eq!(liveness.last_activity(), None);
+
+        sleep(Duration::from_millis(20));
+        stats.set_bytes_read(10);
+        let liveness = stats.liveness();
+        let received_at = liveness.last_frame_received_at.unwrap();
+        assert!(received_at >= liveness.connected_at + Duration::from_millis(20));
+        assert_eq!(liveness.last_frame_sent_at, None);
+
+        // nothing more is read
+        sleep(Duration::from_millis(20));
+        stats.set_bytes_read(10);
+        assert_eq!(stats.liveness().last_frame_received_at, Some(received_at));
+        assert!(stats.liveness().idle_for() >= Duration::from_millis(20));
+
+        stats.add_write(Duration::from_millis(1), 5);
+        let liveness = stats.liveness();
+        assert!(liveness.last_frame_sent_at.unwrap() >= received_at + Duration::from_millis(20));
+        assert_eq!(liveness.last_activity(), liveness.last_frame_sent_at);
+    }
+
+    #[test]
+    fn liveness_follows_heartbeats() {
+        let stats = WriterStats::default();
+        let liveness = stats.liveness();
+        assert_eq!(liveness.last_ping_rtt, None);
+        assert_eq!(liveness.missed_pongs, 0);
+        assert_eq!(liveness.heartbeat_interval, None);
+        assert_eq!(liveness.heartbeat_timeout, None);
+
+        stats.set_heartbeat(Duration::from_secs(15), Duration::from_secs(5));
+        stats.set_missed_pongs(2);
+        let liveness = stats.liveness();
+        assert_eq!(liveness.last_ping_rtt, None);
+        assert_eq!(liveness.missed_pongs, 2);
+        assert_eq!(liveness.heartbeat_interval, Some(Duration::from_secs(15)));
+        assert_eq!(liveness.heartbeat_timeout, Some(Duration::from_secs(5)));
+
+        // an answer resets the missed count
+        stats.set_ping_rtt(Duration::from_millis(3));
+        let liveness = stats.liveness();
+        assert_eq!(liveness.last_ping_rtt, Some(Duration::from_millis(3)));
+        assert_eq!(liveness.missed_pongs, 0);
+    }
+}
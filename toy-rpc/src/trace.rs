@@ -0,0 +1,64 @@
+//! Optional instrumentation with `tracing`
+//!
+//! Spans and events are only emitted with the `tracing` feature. Without it, the
+//! macros below expand to no-ops so that the call sites don't need to be feature gated.
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Instrument;
+
+/// Placeholder for `tracing::Span` when the `tracing` feature is not enabled
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+/// No-op replacement of `tracing::Instrument` when the `tracing` feature is not enabled
+#[cfg(not(feature = "tracing"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<T: Sized> Instrument for T {}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug_span {
+    ($($arg:tt)*) => {
+        tracing::debug_span!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_span {
+    ($($arg:tt)*) => {
+        $crate::trace::Span
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_event {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! error_event {
+    ($($arg:tt)*) => {
+        tracing::error!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! error_event {
+    ($($arg:tt)*) => {};
+}
+
+#[allow(unused_imports)]
+pub(crate) use {debug_event, debug_span, error_event};
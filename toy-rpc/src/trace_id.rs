@@ -0,0 +1,134 @@
+//! 128-bit trace ids that correlate a call across the client, the server and the
+//! downstream calls made by the handler
+//!
+//! The trace id of a call is sent in the request metadata under `TRACE_ID_KEY` as 32
+//! lowercase hex digits. It is optional on the wire: a request without it is served as
+//! usual, and a server that doesn't know about trace ids sees it as just another
+//! metadata entry. With `ClientBuilder::set_trace_ids`, the client attaches a trace id
+//! to every call that doesn't carry one. A call made from within a handler then reuses
+//! the trace id of the request that is being handled, and the other calls get a new one.
+//!
+//! On the server, the trace id is recorded on the `tracing` span of the request and is
+//! available to the handler through `Context::trace_id()`.
+//!
+//! # Example
+//!
+//! ```rust
+//! let client = Client::builder()
+//!     .set_trace_ids(true)
+//!     .dial(addr)
+//!     .await?;
+//!
+//! // or pick the trace id of a call
+//! let mut metadata = RequestMetadata::new();
+//! TraceId::new().insert_into(&mut metadata);
+//! let reply: i32 = client.call_with_metadata("Arith.add", (1, 2), metadata).await?;
+//! ```
+
+use lazy_static::lazy_static;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::protocol::RequestMetadata;
+
+/// Key of the request metadata that carries the trace id of a call
+pub const TRACE_ID_KEY: &str = "__trace_id";
+
+lazy_static! {
+    /// Keys that are random for each process, so the trace ids of different processes
+    /// don't collide
+    static ref TRACE_ID_KEYS: (RandomState, RandomState) = (RandomState::new(), RandomState::new());
+}
+
+static TRACE_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A 128-bit id that correlates a call end to end. See the module documentation
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TraceId(u128);
+
+impl TraceId {
+    /// Generates a new trace id, which is unpredictable and unique within the process
+    pub fn new() -> Self {
+        let count = TRACE_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut high = TRACE_ID_KEYS.0.build_hasher();
+        high.write_u64(count);
+        let mut low = TRACE_ID_KEYS.1.build_hasher();
+        low.write_u64(count);
+        Self((high.finish() as u128) << 64 | low.finish() as u128)
+    }
+
+    /// Creates a trace id from its value, ie. one that is received from another
+    /// tracing system
+    pub fn from_u128(value: u128) -> Self {
+        Self(value)
+    }
+
+    /// Value of the trace id
+    pub fn as_u128(&self) -> u128 {
+        self.0
+    }
+
+    /// Reads the trace id from the request metadata. Returns `None` if there is none
+    /// or if it is malformed
+    pub fn from_metadata(metadata: &RequestMetadata) -> Option<Self> {
+        metadata.get(TRACE_ID_KEY)?.parse().ok()
+    }
+
+    /// Inserts the trace id into the request metadata, replacing the previous one
+    pub fn insert_into(&self, metadata: &mut RequestMetadata) {
+        metadata.insert(TRACE_ID_KEY.into(), self.to_string());
+    }
+}
+
+impl Default for TraceId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for TraceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl std::fmt::Debug for TraceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TraceId({})", self)
+    }
+}
+
+impl std::str::FromStr for TraceId {
+    type Err = std::num::ParseIntError;
+
+    /// Parses the 32 hex digits of a trace id
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u128::from_str_radix(s, 16).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_metadata() {
+        let trace_id = TraceId::from_u128(0x0123_4567_89ab_cdef);
+        let mut metadata = RequestMetadata::new();
+        trace_id.insert_into(&mut metadata);
+        assert_eq!(metadata[TRACE_ID_KEY], "00000000000000000123456789abcdef");
+        assert_eq!(TraceId::from_metadata(&metadata), Some(trace_id));
+
+        metadata.insert(TRACE_ID_KEY.into(), "not a trace id".into());
+        assert_eq!(TraceId::from_metadata(&metadata), None);
+        assert_eq!(TraceId::from_metadata(&RequestMetadata::new()), None);
+    }
+
+    #[test]
+    fn trace_ids_are_distinct() {
+        assert_ne!(TraceId::new(), TraceId::new());
+    }
+}
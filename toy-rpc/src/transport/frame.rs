@@ -3,17 +3,22 @@
 use async_trait::async_trait;
 use bincode::{DefaultOptions, Options};
 use cfg_if::cfg_if;
+use futures::FutureExt;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::io::ErrorKind;
+use std::time::Duration;
 
-use crate::error::IoError;
+use crate::codec::FlushPolicy;
+use crate::error::{IoError, VersionMismatch};
 use crate::message::MessageId;
 use crate::{error::Error, util::GracefulShutdown};
 
 use super::as_io_err_other;
 
 const INVALID_PROTOCOL: &str = "Magic byte mismatch.\rClient may be using a different protocol or version.\rClient of version <0.5.0 is not compatible with Server of version >0.5.0";
+#[cfg(not(feature = "legacy_frame"))]
+const UNVERSIONED_PROTOCOL: &str = "Frame has no version byte.\rPeer is using the unversioned framing, which is only read with the `legacy_frame` feature";
 const END_FRAME_ID: FrameId = 131;
 
 cfg_if! {
@@ -22,26 +27,37 @@ cfg_if! {
         feature = "http_tide"
     ))] {
         use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+        use async_std::future::timeout;
     } else if #[cfg(any(
         feature = "tokio_runtime",
         feature = "http_warp",
         feature = "http_actix_web"
     ))] {
         use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+        use tokio::time::timeout;
     }
 }
 
 type FrameId = u8;
 type PayloadLen = u32;
-const MAGIC: u8 = 13;
-
-// const HEADER_LEN: usize = 8; // header length in bytes
+/// Magic byte of the frames that carry the version byte
+const MAGIC: u8 = 14;
+/// Magic byte of the frames written before the version byte is introduced
+const LEGACY_MAGIC: u8 = 13;
+/// Version of the framing, which is written after the magic byte of every frame
+pub(crate) const VERSION: u8 = 1;
+
+// const HEADER_LEN: usize = 14; // header length in bytes
 lazy_static! {
     static ref HEADER_LEN: usize =
         // default uses fixint size
         bincode::serialized_size(&FrameHeader::default()).unwrap() as usize;
 }
 
+/// Length of the header of a legacy frame, whose message id is a `u16`
+#[cfg(feature = "legacy_frame")]
+const LEGACY_HEADER_LEN: usize = 8;
+
 /// Trait for custom binary transport protocol
 ///
 /// `AsyncBufRead` or `AsyncRead` is required because `async_std::net::TcpStream`
@@ -51,6 +67,19 @@ lazy_static! {
 pub trait FrameRead {
     /// Reads a frame
     async fn read_frame(&mut self) -> Option<Result<Frame, IoError>>;
+
+    /// Reads a frame, failing with `ErrorKind::TimedOut` if reading the frame makes no
+    /// progress for longer than `timeout`.
+    ///
+    /// The timeout is reset whenever some bytes of the frame arrive, so a large frame that
+    /// is steadily transferred does not time out. The timeout only starts once the first
+    /// byte of the frame has arrived unless `continuation_of` is `Some(id)`, in which case
+    /// the frame is expected to follow the header frame of message `id` without delay.
+    async fn read_frame_with_timeout(
+        &mut self,
+        timeout: Duration,
+        continuation_of: Option<MessageId>,
+    ) -> Option<Result<Frame, IoError>>;
 }
 
 /// Trait for custom binary transport protocol
@@ -60,12 +89,33 @@ pub trait FrameRead {
 ///
 #[async_trait]
 pub trait FrameWrite {
-    /// Writes a frame
+    /// Writes and flushes a frame, and returns the number of bytes written, which
+    /// includes the magic and version bytes and the frame header
     async fn write_frame(
         &mut self,
         frame_header: FrameHeader,
         payload: &[u8],
-    ) -> Result<(), IoError>;
+    ) -> Result<usize, IoError> {
+        self.write_frame_with_policy(frame_header, payload, FlushPolicy::EveryFrame)
+            .await
+    }
+
+    /// Writes a frame that is flushed according to `policy`, and returns the number of
+    /// bytes written like `write_frame`
+    async fn write_frame_with_policy(
+        &mut self,
+        frame_header: FrameHeader,
+        payload: &[u8],
+        policy: FlushPolicy,
+    ) -> Result<usize, IoError>;
+
+    /// Flushes the frames that are left in the write buffer
+    async fn flush_frames(&mut self) -> Result<(), IoError>;
+}
+
+/// Number of bytes a frame with a payload of `payload_len` bytes takes on the wire
+pub fn frame_len(payload_len: usize) -> usize {
+    2 + *HEADER_LEN + payload_len
 }
 
 /// Header of a frame
@@ -98,7 +148,7 @@ impl FrameHeader {
         DefaultOptions::new()
             .with_fixint_encoding()
             .deserialize(&buf)
-            .map_err(|err| Error::ParseError(err))
+            .map_err(Error::from)
     }
 
     /// Convert a frame header to bytes
@@ -110,6 +160,44 @@ impl FrameHeader {
     }
 }
 
+/// Header of a frame written by toy-rpc 0.8.x and earlier, which is not preceded by
+/// the version byte and carries a `u16` message id
+#[cfg(feature = "legacy_frame")]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct LegacyFrameHeader {
+    message_id: u16,
+    frame_id: FrameId,
+    payload_type: u8,
+    payload_len: PayloadLen,
+}
+
+#[cfg(feature = "legacy_frame")]
+impl From<LegacyFrameHeader> for FrameHeader {
+    fn from(header: LegacyFrameHeader) -> Self {
+        Self {
+            message_id: header.message_id.into(),
+            frame_id: header.frame_id,
+            payload_type: header.payload_type,
+            payload_len: header.payload_len,
+        }
+    }
+}
+
+/// Decodes the header of a frame, which is a legacy one if the frame starts with
+/// `LEGACY_MAGIC`
+fn decode_header(buf: &[u8], legacy: bool) -> Result<FrameHeader, Error> {
+    #[cfg(feature = "legacy_frame")]
+    if legacy {
+        return DefaultOptions::new()
+            .with_fixint_encoding()
+            .deserialize::<LegacyFrameHeader>(buf)
+            .map(Into::into)
+            .map_err(Error::from);
+    }
+    debug_assert!(!legacy);
+    FrameHeader::from_slice(buf)
+}
+
 /// Type of payload carried by a frame
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PayloadType {
@@ -119,6 +207,9 @@ pub enum PayloadType {
     Data,
     /// Message trailer
     Trailer,
+    /// A chunk of a message body that is continued by the following frames of the same
+    /// message. The last chunk of the body is a `Data` frame
+    Partial,
 }
 
 impl Default for PayloadType {
@@ -133,6 +224,7 @@ impl From<u8> for PayloadType {
             0 => Self::Header,
             1 => Self::Data,
             2 => Self::Trailer,
+            3 => Self::Partial,
             _ => Self::Trailer,
         }
     }
@@ -144,6 +236,7 @@ impl From<PayloadType> for u8 {
             PayloadType::Header => 0,
             PayloadType::Data => 1,
             PayloadType::Trailer => 2,
+            PayloadType::Partial => 3,
         }
     }
 }
@@ -176,60 +269,206 @@ impl Frame {
             payload,
         }
     }
+
+    /// Number of bytes the frame takes on the wire, which includes the magic and version
+    /// bytes and the frame header
+    pub fn wire_len(&self) -> usize {
+        frame_len(self.payload.len())
+    }
 }
 
-#[async_trait]
-impl<R: AsyncRead + Unpin + Send> FrameRead for R {
-    async fn read_frame(&mut self) -> Option<Result<Frame, IoError>> {
-        // read magic first
-        let magic = &mut [0];
-        let _ = self.read_exact(magic).await.ok()?;
-        if magic[0] != MAGIC {
+/// Reason that a buffer is not filled
+enum PartialRead {
+    /// The underlying reader returned an error or reached EOF
+    Closed,
+    /// No bytes arrived within the timeout. Carries the number of bytes outstanding
+    Stalled(usize),
+}
+
+/// Fills `buf`, failing if no bytes arrive within `idle_timeout`
+async fn read_exact_within<R>(
+    reader: &mut R,
+    buf: &mut [u8],
+    idle_timeout: Option<Duration>,
+) -> Result<(), PartialRead>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let idle_timeout = match idle_timeout {
+        Some(duration) => duration,
+        None => {
+            return reader
+                .read_exact(buf)
+                .await
+                .map(|_| ())
+                .map_err(|_| PartialRead::Closed)
+        }
+    };
+
+    let mut filled = 0;
+    while filled < buf.len() {
+        match timeout(idle_timeout, reader.read(&mut buf[filled..])).await {
+            Ok(Ok(0)) => return Err(PartialRead::Closed),
+            Ok(Ok(n)) => filled += n,
+            Ok(Err(err)) if err.kind() == ErrorKind::Interrupted => {}
+            Ok(Err(_)) => return Err(PartialRead::Closed),
+            Err(_) => return Err(PartialRead::Stalled(buf.len() - filled)),
+        }
+    }
+    Ok(())
+}
+
+fn stalled_err(msg: String) -> Option<Result<Frame, IoError>> {
+    Some(Err(IoError::new(ErrorKind::TimedOut, msg)))
+}
+
+async fn read_frame_within<R>(
+    reader: &mut R,
+    idle_timeout: Option<Duration>,
+    continuation_of: Option<MessageId>,
+) -> Option<Result<Frame, IoError>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    // read magic first
+    let magic = &mut [0];
+    let magic_timeout = continuation_of.and(idle_timeout);
+    match read_exact_within(reader, magic, magic_timeout).await {
+        Ok(_) => {}
+        Err(PartialRead::Stalled(_)) => {
+            return stalled_err(format!(
+                "Timed out waiting for the next frame of message {}",
+                continuation_of.unwrap_or_default()
+            ))
+        }
+        Err(PartialRead::Closed) => return None,
+    }
+    let legacy = match magic[0] {
+        MAGIC => {
+            let version = &mut [0];
+            match read_exact_within(reader, version, idle_timeout).await {
+                Ok(_) => {}
+                Err(PartialRead::Stalled(_)) => {
+                    return stalled_err("Timed out reading frame version".into())
+                }
+                Err(PartialRead::Closed) => return None,
+            }
+            if version[0] != VERSION {
+                return Some(Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    VersionMismatch {
+                        expected: VERSION,
+                        found: version[0],
+                    },
+                )));
+            }
+            false
+        }
+        // the legacy header follows the magic byte right away
+        #[cfg(feature = "legacy_frame")]
+        LEGACY_MAGIC => true,
+        #[cfg(not(feature = "legacy_frame"))]
+        LEGACY_MAGIC => {
+            return Some(Err(IoError::new(
+                ErrorKind::InvalidData,
+                UNVERSIONED_PROTOCOL,
+            )))
+        }
+        _ => {
             return Some(Err(std::io::Error::new(
                 ErrorKind::InvalidData,
                 INVALID_PROTOCOL,
             )));
         }
-
-        // read header
-        let mut buf = vec![0; *HEADER_LEN];
-        let _ = self.read_exact(&mut buf).await.ok()?;
-        let header = match FrameHeader::from_slice(&buf) {
-            Ok(h) => h,
-            Err(e) => {
-                let err = as_io_err_other(&e);
-                return Some(Err(err));
-            }
-        };
-
-        // determine if end frame is received
-        if let PayloadType::Trailer = header.payload_type.into() {
-            if header.frame_id == END_FRAME_ID && header.message_id == 0 && header.payload_len == 0
-            {
-                return None;
-            }
+    };
+
+    // read header
+    #[cfg(feature = "legacy_frame")]
+    let header_len = if legacy {
+        LEGACY_HEADER_LEN
+    } else {
+        *HEADER_LEN
+    };
+    #[cfg(not(feature = "legacy_frame"))]
+    let header_len = *HEADER_LEN;
+    let mut buf = vec![0; header_len];
+    match read_exact_within(reader, &mut buf, idle_timeout).await {
+        Ok(_) => {}
+        Err(PartialRead::Stalled(n)) => {
+            return stalled_err(format!(
+                "Timed out reading frame header: {} of {} bytes outstanding",
+                n, header_len
+            ))
+        }
+        Err(PartialRead::Closed) => return None,
+    }
+    let header = match decode_header(&buf, legacy) {
+        Ok(h) => h,
+        Err(e) => {
+            let err = as_io_err_other(&e);
+            return Some(Err(err));
         }
+    };
 
-        // read frame payload
-        let mut payload = vec![0; header.payload_len as usize];
-        let _ = self.read_exact(&mut payload).await.ok()?;
+    // determine if end frame is received
+    if let PayloadType::Trailer = header.payload_type.into() {
+        if header.frame_id == END_FRAME_ID && header.message_id == 0 && header.payload_len == 0 {
+            return None;
+        }
+    }
 
-        Some(Ok(Frame::new(
+    // read frame payload, there is nothing left to read for an empty payload
+    if header.payload_len == 0 {
+        return Some(Ok(Frame::new(
             header.message_id,
             header.frame_id,
             header.payload_type.into(),
-            payload,
-        )))
+            Vec::new(),
+        )));
+    }
+    let mut payload = vec![0; header.payload_len as usize];
+    match read_exact_within(reader, &mut payload, idle_timeout).await {
+        Ok(_) => {}
+        Err(PartialRead::Stalled(n)) => {
+            return stalled_err(format!(
+                "Timed out reading frame of message {}: {} of {} bytes outstanding",
+                header.message_id, n, header.payload_len
+            ))
+        }
+        Err(PartialRead::Closed) => return None,
+    }
+
+    Some(Ok(Frame::new(
+        header.message_id,
+        header.frame_id,
+        header.payload_type.into(),
+        payload,
+    )))
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> FrameRead for R {
+    async fn read_frame(&mut self) -> Option<Result<Frame, IoError>> {
+        read_frame_within(self, None, None).await
+    }
+
+    async fn read_frame_with_timeout(
+        &mut self,
+        timeout: Duration,
+        continuation_of: Option<MessageId>,
+    ) -> Option<Result<Frame, IoError>> {
+        read_frame_within(self, Some(timeout), continuation_of).await
     }
 }
 
 #[async_trait]
 impl<W: AsyncWrite + Unpin + Send> FrameWrite for W {
-    async fn write_frame(
+    async fn write_frame_with_policy(
         &mut self,
         frame_header: FrameHeader,
         payload: &[u8],
-    ) -> Result<(), IoError> {
+        policy: FlushPolicy,
+    ) -> Result<usize, IoError> {
         // check if buf length exceeds maximum
         if payload.len() > PayloadLen::MAX as usize {
             return Err(std::io::Error::new(
@@ -242,17 +481,31 @@ impl<W: AsyncWrite + Unpin + Send> FrameWrite for W {
             ));
         }
 
-        // write magic first
-        self.write_all(&[MAGIC]).await?;
+        // write magic and version first
+        self.write_all(&[MAGIC, VERSION]).await?;
 
         // write header
         self.write_all(&frame_header.to_vec()?).await?;
 
         // write payload
         let _ = self.write_all(&payload).await?;
-        self.flush().await?;
+        match policy {
+            FlushPolicy::EveryFrame => self.flush().await?,
+            FlushPolicy::Manual => {}
+            FlushPolicy::OnIdle => {
+                // the buffered writer keeps track of what is flushed already, so the
+                // flush can be given up whenever the transport is not ready
+                if let Some(res) = self.flush().now_or_never() {
+                    res?
+                }
+            }
+        }
+
+        Ok(frame_len(payload.len()))
+    }
 
-        Ok(())
+    async fn flush_frames(&mut self) -> Result<(), IoError> {
+        self.flush().await
     }
 }
 
@@ -285,6 +538,185 @@ mod tests {
         println!("FrameHeader len: {}", fh);
         println!("ModifiedHeader len: {}", mh);
     }
+
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    async fn write_partial_frame<W: AsyncWrite + Unpin>(writer: &mut W, id: MessageId) {
+        let header = FrameHeader::new(id, 1, PayloadType::Data, 16);
+        writer.write_all(&[MAGIC, VERSION]).await.unwrap();
+        writer.write_all(&header.to_vec().unwrap()).await.unwrap();
+        writer.write_all(&[0; 4]).await.unwrap();
+    }
+
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    #[test]
+    fn stalled_payload_times_out() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut tx, mut rx) = tokio::io::duplex(64);
+            write_partial_frame(&mut tx, 7).await;
+
+            let err = rx
+                .read_frame_with_timeout(Duration::from_millis(50), None)
+                .await
+                .expect("Expecting a frame")
+                .expect_err("Expecting a timeout");
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(err
+                .to_string()
+                .contains("message 7: 12 of 16 bytes outstanding"));
+        });
+    }
+
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    #[test]
+    fn missing_continuation_frame_times_out() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (_tx, mut rx) = tokio::io::duplex(64);
+
+            let err = rx
+                .read_frame_with_timeout(Duration::from_millis(50), Some(3))
+                .await
+                .expect("Expecting a frame")
+                .expect_err("Expecting a timeout");
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(err.to_string().contains("next frame of message 3"));
+        });
+    }
+
+    #[cfg(all(feature = "tokio_runtime", not(feature = "async_std_runtime")))]
+    #[test]
+    fn slow_frame_making_progress_does_not_time_out() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut tx, mut rx) = tokio::io::duplex(64);
+            write_partial_frame(&mut tx, 7).await;
+            // the remaining 12 bytes take longer than the timeout in total
+            let writer = tokio::spawn(async move {
+                for _ in 0..6 {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    tx.write_all(&[0; 2]).await.unwrap();
+                }
+                tx
+            });
+
+            let frame = rx
+                .read_frame_with_timeout(Duration::from_millis(100), None)
+                .await
+                .expect("Expecting a frame")
+                .expect("Unexpected error reading frame");
+            assert_eq!(frame.message_id, 7);
+            assert_eq!(frame.payload.len(), 16);
+            writer.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn frame_byte_counts_include_magic_version_and_header() {
+        futures::executor::block_on(async {
+            let mut buf: Vec<u8> = Vec::new();
+            let header = FrameHeader::new(5, 1, PayloadType::Data, 10);
+            let written = buf.write_frame(header, &[7; 10]).await.unwrap();
+            // magic and version bytes + 14 bytes frame header + payload
+            assert_eq!(written, 26);
+            assert_eq!(written, buf.len());
+
+            let frame = (&buf[..]).read_frame().await.unwrap().unwrap();
+            assert_eq!(frame.payload, vec![7; 10]);
+            assert_eq!(frame.wire_len(), written);
+        });
+    }
+
+    #[test]
+    fn empty_payload_is_read_as_empty_frame() {
+        futures::executor::block_on(async {
+            let mut buf: Vec<u8> = Vec::new();
+            buf.write_frame(FrameHeader::new(5, 1, PayloadType::Data, 0), &[])
+                .await
+                .unwrap();
+            buf.write_frame(FrameHeader::new(6, 1, PayloadType::Data, 3), &[1, 2, 3])
+                .await
+                .unwrap();
+
+            let mut reader = &buf[..];
+            let frame = reader.read_frame().await.unwrap().unwrap();
+            assert_eq!(frame.message_id, 5);
+            assert!(frame.payload.is_empty());
+            assert_eq!(frame.wire_len(), frame_len(0));
+
+            // the next frame is not consumed by the empty one
+            let frame = reader.read_frame().await.unwrap().unwrap();
+            assert_eq!(frame.message_id, 6);
+            assert_eq!(frame.payload, vec![1, 2, 3]);
+            assert!(reader.read_frame().await.is_none());
+        });
+    }
+
+    /// A data frame of message 261 with the payload `[1, 2, 3]`, laid out as toy-rpc
+    /// 0.8.6 writes it: the magic byte 13 without a version byte, then the fixint
+    /// header with a `u16` message id, a `u8` frame id, a `u8` payload type and a `u32`
+    /// payload length, all little endian
+    const LEGACY_FRAME: [u8; 12] = [13, 0x05, 0x01, 1, 1, 3, 0, 0, 0, 1, 2, 3];
+
+    #[test]
+    fn version_mismatch_reports_both_versions() {
+        futures::executor::block_on(async {
+            let mut buf: Vec<u8> = Vec::new();
+            buf.write_frame(FrameHeader::new(5, 1, PayloadType::Data, 0), &[])
+                .await
+                .unwrap();
+            buf[1] = VERSION + 1;
+
+            let err = (&buf[..]).read_frame().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            let mismatch = match Error::from(err) {
+                Error::VersionMismatch(mismatch) => mismatch,
+                err => panic!("Expecting a VersionMismatch, found {:?}", err),
+            };
+            assert_eq!(
+                mismatch,
+                VersionMismatch {
+                    expected: VERSION,
+                    found: VERSION + 1
+                }
+            );
+        });
+    }
+
+    #[cfg(not(feature = "legacy_frame"))]
+    #[test]
+    fn legacy_frame_is_rejected() {
+        futures::executor::block_on(async {
+            let buf = LEGACY_FRAME.to_vec();
+            let err = (&buf[..]).read_frame().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("legacy_frame"));
+        });
+    }
+
+    #[cfg(feature = "legacy_frame")]
+    #[test]
+    fn legacy_frame_is_read() {
+        futures::executor::block_on(async {
+            assert_eq!(
+                bincode::serialized_size(&LegacyFrameHeader::default()).unwrap() as usize,
+                LEGACY_HEADER_LEN
+            );
+            let mut buf = LEGACY_FRAME.to_vec();
+            buf.write_frame(FrameHeader::new(6, 1, PayloadType::Data, 0), &[])
+                .await
+                .unwrap();
+
+            let mut reader = &buf[..];
+            let frame = reader.read_frame().await.unwrap().unwrap();
+            assert_eq!(frame.message_id, 261);
+            assert!(matches!(frame.payload_type, PayloadType::Data));
+            assert_eq!(frame.payload, vec![1, 2, 3]);
+            // versioned frames are still read on the same stream
+            let frame = reader.read_frame().await.unwrap().unwrap();
+            assert_eq!(frame.message_id, 6);
+        });
+    }
 }
 
 #[async_trait]
@@ -297,8 +729,8 @@ where
         // let end_frame = Frame::new(0, END_FRAME_ID, PayloadType::Trailer, Vec::with_capacity(0));
         let end_frame_header = FrameHeader::new(0, END_FRAME_ID, PayloadType::Trailer, 0);
         let payload = Vec::with_capacity(0);
-        self.write_frame(end_frame_header, &payload)
-            .await
-            .unwrap_or_else(|e| log::error!("{}", e));
+        if let Err(e) = self.write_frame(end_frame_header, &payload).await {
+            log::error!("{}", e);
+        }
     }
 }
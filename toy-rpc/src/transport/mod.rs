@@ -13,10 +13,15 @@ use crate::error::IoError;
     any(feature = "async_std_runtime", feature = "tokio_runtime",)
 ))]
 pub(crate) mod frame;
+pub use crate::error::VersionMismatch;
 
 #[cfg(any(feature = "ws_tokio", feature = "ws_async_std"))]
 pub(crate) mod ws;
 
+#[cfg(feature = "quic")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "quic")))]
+pub mod quic;
+
 #[cfg(any(
     all(
         any(
@@ -36,7 +41,8 @@ pub(crate) fn as_io_err_other(err: &impl std::fmt::Display) -> IoError {
 /// Reads bytes from transport protocols that carry payload (ie. WebSocket)
 #[async_trait]
 pub trait PayloadRead {
-    /// Reads bytes from the payload
+    /// Reads bytes from the payload. Text messages of WebSocket are read as their UTF-8
+    /// bytes, and control messages (ie. ping and pong) are skipped.
     async fn read_payload(&mut self) -> Option<Result<Vec<u8>, IoError>>;
 }
 
@@ -45,4 +51,13 @@ pub trait PayloadRead {
 pub trait PayloadWrite {
     /// Writes bytes to the payload
     async fn write_payload(&mut self, payload: &[u8]) -> Result<(), IoError>;
+
+    /// Writes bytes that are UTF-8 text to the payload. Transports that tell text from
+    /// binary payloads (ie. the text messages of WebSocket) send them as text.
+    async fn write_text_payload(&mut self, payload: &[u8]) -> Result<(), IoError>
+    where
+        Self: Send,
+    {
+        self.write_payload(payload).await
+    }
 }
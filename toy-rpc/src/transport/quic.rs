@@ -0,0 +1,82 @@
+//! QUIC transport built on `quinn`
+//!
+//! Every bidirectional stream of a QUIC connection is served as a separate RPC
+//! connection with the default codec, so a single QUIC connection can carry several
+//! clients without head-of-line blocking between them.
+
+use pin_project::pin_project;
+use std::{
+    io::IoSlice,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A bidirectional stream of a QUIC connection that the `DefaultCodec` can wrap
+///
+/// The stream keeps the QUIC connection alive until it is dropped.
+#[pin_project]
+#[derive(Debug)]
+pub struct QuicStream {
+    #[pin]
+    send: quinn::SendStream,
+    #[pin]
+    recv: quinn::RecvStream,
+}
+
+impl QuicStream {
+    /// Joins the two halves of a bidirectional stream, ie. the return of
+    /// `quinn::Connection::open_bi` or `quinn::Connection::accept_bi`
+    pub fn new(send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
+        Self { send, recv }
+    }
+
+    /// Splits the stream into the sending and receiving halves
+    pub fn into_inner(self) -> (quinn::SendStream, quinn::RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().recv.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().send.poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().send.poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().send.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().send.poll_shutdown(cx)
+    }
+}
+
+/// Converts the errors of `quinn` (ie. `ConnectError` and `ConnectionError`)
+pub(crate) fn as_io_err<E>(err: E) -> crate::error::IoError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    std::io::Error::new(std::io::ErrorKind::Other, err)
+}
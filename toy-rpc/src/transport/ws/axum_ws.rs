@@ -9,21 +9,22 @@ use axum::extract::ws::{Message, WebSocket};
 #[async_trait]
 impl PayloadRead for StreamHalf<SplitStream<WebSocket>, CanSink> {
     async fn read_payload(&mut self) -> Option<Result<Vec<u8>, IoError>> {
-        match self.next().await? {
-            Err(e) => {
-                return Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    e.to_string(),
-                )))
+        loop {
+            match self.next().await? {
+                Err(e) => {
+                    return Some(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        e.to_string(),
+                    )))
+                }
+                Ok(m) => match m {
+                    Message::Close(_) => return None,
+                    Message::Binary(bytes) => return Some(Ok(bytes)),
+                    Message::Text(text) => return Some(Ok(text.into_bytes())),
+                    // axum answers the pings by itself
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                },
             }
-            Ok(m) => match m {
-                Message::Close(_) => None,
-                Message::Binary(bytes) => Some(Ok(bytes)),
-                _ => Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Expecting WebSocket::Message::Binary, but found something else".to_string(),
-                ))),
-            },
         }
     }
 }
@@ -37,6 +38,11 @@ impl PayloadWrite for SinkHalf<SplitSink<WebSocket, Message>, CanSink> {
         // provide public API to retrieve the original error.
         self.send(msg).await.map_err(|e| as_io_err_other(&e))
     }
+
+    async fn write_text_payload(&mut self, payload: &[u8]) -> Result<(), IoError> {
+        let msg = Message::Text(into_text(payload)?);
+        self.send(msg).await.map_err(|e| as_io_err_other(&e))
+    }
 }
 
 #[async_trait]
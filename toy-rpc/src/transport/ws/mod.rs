@@ -133,24 +133,27 @@ where
     T: AsyncRead + AsyncWrite + Send + Unpin,
 {
     async fn read_payload(&mut self) -> Option<Result<Vec<u8>, IoError>> {
-        match self.next().await? {
-            Err(e) => {
-                return Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    e.to_string(),
-                )))
-            }
-            Ok(msg) => {
-                if let Message::Binary(bytes) = msg {
-                    return Some(Ok(bytes));
-                } else if let Message::Close(_) = msg {
-                    return None;
+        loop {
+            match self.next().await? {
+                Err(e) => {
+                    return Some(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        e.to_string(),
+                    )))
                 }
-
-                Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Expecting WebSocket::Message::Binary",
-                )))
+                Ok(msg) => match msg {
+                    Message::Binary(bytes) => return Some(Ok(bytes)),
+                    Message::Text(text) => return Some(Ok(text.into_bytes())),
+                    Message::Close(_) => return None,
+                    // tungstenite answers the pings by itself
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                    Message::Frame(_) => {
+                        return Some(Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "Expecting WebSocket::Message::Binary or WebSocket::Message::Text",
+                        )))
+                    }
+                },
             }
         }
     }
@@ -162,9 +165,22 @@ where
     T: AsyncRead + AsyncWrite + Send + Unpin,
 {
     async fn write_payload(&mut self, payload: &[u8]) -> Result<(), IoError> {
-        use tungstenite::error;
         let msg = Message::Binary(payload.to_owned());
+        self.send_message(msg).await
+    }
 
+    async fn write_text_payload(&mut self, payload: &[u8]) -> Result<(), IoError> {
+        let msg = Message::Text(into_text(payload)?);
+        self.send_message(msg).await
+    }
+}
+
+impl<T> SinkHalf<SplitSink<WebSocketStream<T>, Message>, CanSink>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    async fn send_message(&mut self, msg: Message) -> Result<(), IoError> {
+        use tungstenite::error;
         match self.send(msg).await {
             Ok(_) => Ok(()),
             Err(err) => match err {
@@ -175,6 +191,12 @@ where
     }
 }
 
+/// Text of a payload that is marshaled into UTF-8 text
+fn into_text(payload: &[u8]) -> Result<String, IoError> {
+    String::from_utf8(payload.to_owned())
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
 // GracefulShutdown is only required on the client side.
 #[async_trait]
 impl<T> GracefulShutdown for SinkHalf<SplitSink<WebSocketStream<T>, Message>, CanSink>
@@ -195,3 +217,44 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(
+        feature = "ws_tokio",
+        feature = "tokio_runtime",
+        not(feature = "async_std_runtime")
+    ))]
+    #[test]
+    fn read_text_and_skip_ping() {
+        use async_tungstenite::tokio::TokioAdapter;
+        use tungstenite::protocol::Role;
+
+        use super::*;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (local, remote) = tokio::io::duplex(1024);
+            let local =
+                WebSocketStream::from_raw_socket(TokioAdapter::new(local), Role::Server, None)
+                    .await;
+            let mut remote =
+                WebSocketStream::from_raw_socket(TokioAdapter::new(remote), Role::Client, None)
+                    .await;
+            let (_, mut reader) = WebSocketConn::new(local).split();
+
+            remote.send(Message::Ping(vec![1])).await.unwrap();
+            remote.send(Message::Text("hi".into())).await.unwrap();
+            remote.send(Message::Binary(vec![2, 3])).await.unwrap();
+
+            let payload = reader.read_payload().await.unwrap().unwrap();
+            assert_eq!(payload, b"hi");
+            let payload = reader.read_payload().await.unwrap().unwrap();
+            assert_eq!(payload, vec![2, 3]);
+
+            // the ping is answered without the reader seeing it
+            let msg = remote.next().await.unwrap().unwrap();
+            assert_eq!(msg, Message::Pong(vec![1]));
+        });
+    }
+}
@@ -33,24 +33,29 @@ impl WebSocketConn<tide_websockets::WebSocketConnection, CannotSink> {
 #[async_trait]
 impl PayloadRead for StreamHalf<tide_websockets::WebSocketConnection, CannotSink> {
     async fn read_payload(&mut self) -> Option<Result<Vec<u8>, IoError>> {
-        match self.inner.next().await? {
-            Err(e) => {
-                return Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    e.to_string(),
-                )))
-            }
-            Ok(msg) => {
-                if let tide_websockets::Message::Binary(bytes) = msg {
-                    return Some(Ok(bytes));
-                } else if let tide_websockets::Message::Close(_) = msg {
-                    return None;
+        loop {
+            match self.inner.next().await? {
+                Err(e) => {
+                    return Some(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        e.to_string(),
+                    )))
                 }
-
-                Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Expecting WebSocket::Message::Binary",
-                )))
+                Ok(msg) => match msg {
+                    tide_websockets::Message::Binary(bytes) => return Some(Ok(bytes)),
+                    tide_websockets::Message::Text(text) => return Some(Ok(text.into_bytes())),
+                    tide_websockets::Message::Close(_) => return None,
+                    // tungstenite answers the pings by itself
+                    tide_websockets::Message::Ping(_) | tide_websockets::Message::Pong(_) => {
+                        continue
+                    }
+                    _ => {
+                        return Some(Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "Expecting WebSocket::Message::Binary or WebSocket::Message::Text",
+                        )))
+                    }
+                },
             }
         }
     }
@@ -67,6 +72,16 @@ impl PayloadWrite for SinkHalf<tide_websockets::WebSocketConnection, CannotSink>
             },
         }
     }
+
+    async fn write_text_payload(&mut self, payload: &[u8]) -> Result<(), IoError> {
+        match self.inner.send_string(into_text(payload)?).await {
+            Ok(_) => Ok(()),
+            Err(err) => match err {
+                tungstenite::error::Error::Io(e) => Err(e),
+                _ => Err(as_io_err_other(&err)),
+            },
+        }
+    }
 }
 
 #[async_trait]
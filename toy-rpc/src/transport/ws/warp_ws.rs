@@ -6,24 +6,29 @@ use warp::ws::{Message, WebSocket};
 #[async_trait]
 impl PayloadRead for StreamHalf<SplitStream<WebSocket>, CanSink> {
     async fn read_payload(&mut self) -> Option<Result<Vec<u8>, IoError>> {
-        let msg = self.next().await?;
-        match msg {
-            Err(e) => {
-                return Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    e.to_string(),
-                )))
-            }
-            Ok(m) => {
-                if m.is_close() {
-                    return None;
-                } else if m.is_binary() {
-                    return Some(Ok(m.into_bytes()));
+        loop {
+            let msg = self.next().await?;
+            match msg {
+                Err(e) => {
+                    return Some(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        e.to_string(),
+                    )))
+                }
+                Ok(m) => {
+                    if m.is_close() {
+                        return None;
+                    } else if m.is_binary() || m.is_text() {
+                        return Some(Ok(m.into_bytes()));
+                    } else if m.is_ping() || m.is_pong() {
+                        // the pings are answered by the underlying tungstenite
+                        continue;
+                    }
+                    return Some(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Expecting WebSocket::Message::Binary or WebSocket::Message::Text",
+                    )));
                 }
-                Some(Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Expecting WebSocket::Message::Binary",
-                )))
             }
         }
     }
@@ -38,6 +43,11 @@ impl PayloadWrite for SinkHalf<SplitSink<WebSocket, Message>, CanSink> {
         // provide public API to retrieve the original error.
         self.send(msg).await.map_err(|e| as_io_err_other(&e))
     }
+
+    async fn write_text_payload(&mut self, payload: &[u8]) -> Result<(), IoError> {
+        let msg = Message::text(into_text(payload)?);
+        self.send(msg).await.map_err(|e| as_io_err_other(&e))
+    }
 }
 
 #[async_trait]
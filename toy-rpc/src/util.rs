@@ -1,7 +1,10 @@
 //! Utility traits and functions.
 
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+};
 
 use crate::service::AsyncHandler;
 
@@ -27,6 +30,159 @@ pub trait GracefulShutdown {
     async fn close(&mut self);
 }
 
+/// A runtime agnostic counting semaphore
+#[cfg_attr(
+    not(any(feature = "async_std_runtime", feature = "tokio_runtime")),
+    allow(dead_code)
+)]
+pub(crate) struct Semaphore {
+    permits_tx: flume::Sender<()>,
+    permits_rx: flume::Receiver<()>,
+}
+
+#[cfg_attr(
+    not(any(feature = "async_std_runtime", feature = "tokio_runtime")),
+    allow(dead_code)
+)]
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        let (permits_tx, permits_rx) = flume::bounded(permits);
+        for _ in 0..permits {
+            // cannot fail because the channel has exactly `permits` slots
+            let _ = permits_tx.try_send(());
+        }
+        Self {
+            permits_tx,
+            permits_rx,
+        }
+    }
+
+    /// Waits until a permit is available. The permit is returned when the
+    /// `SemaphorePermit` is dropped.
+    pub async fn acquire(&self) -> SemaphorePermit {
+        // the receiver cannot be disconnected while `self` holds a sender
+        let _ = self.permits_rx.recv_async().await;
+        SemaphorePermit {
+            permits_tx: self.permits_tx.clone(),
+        }
+    }
+
+    /// Takes a permit if one is available without waiting
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        self.permits_rx.try_recv().ok().map(|_| SemaphorePermit {
+            permits_tx: self.permits_tx.clone(),
+        })
+    }
+}
+
+/// A permit acquired from a `Semaphore`
+pub(crate) struct SemaphorePermit {
+    permits_tx: flume::Sender<()>,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        let _ = self.permits_tx.try_send(());
+    }
+}
+
+/// A runtime agnostic token to signal cancellation.
+///
+/// Cancelling a token also cancels all the child tokens created by `child_token()`.
+/// Cloned tokens share the same state.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+#[derive(Debug)]
+struct TokenInner {
+    // the token is cancelled once the sender is dropped
+    tx: Mutex<Option<flume::Sender<()>>>,
+    rx: flume::Receiver<()>,
+    children: Mutex<Vec<Weak<TokenInner>>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    /// Creates a new token that is not cancelled
+    pub fn new() -> Self {
+        let (tx, rx) = flume::bounded(0);
+        Self {
+            inner: Arc::new(TokenInner {
+                tx: Mutex::new(Some(tx)),
+                rx,
+                children: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Creates a child token that is cancelled when this token is cancelled.
+    ///
+    /// Cancelling the child token does not affect this token.
+    pub fn child_token(&self) -> Self {
+        let child = Self::new();
+        let mut children = lock(&self.inner.children);
+        if self.is_cancelled() {
+            drop(children);
+            child.cancel();
+        } else {
+            children.retain(|c| c.strong_count() > 0);
+            children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    /// Cancels this token and all of its children
+    pub fn cancel(&self) {
+        Self::cancel_inner(&self.inner);
+    }
+
+    fn cancel_inner(inner: &TokenInner) {
+        lock(&inner.tx).take();
+        let children: Vec<_> = lock(&inner.children).drain(..).collect();
+        for child in children.iter().filter_map(Weak::upgrade) {
+            Self::cancel_inner(&child);
+        }
+    }
+
+    /// Returns `true` if the token is cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.rx.is_disconnected()
+    }
+
+    /// Waits until the token is cancelled
+    pub async fn cancelled(&self) {
+        // nothing is ever sent, this only returns when the sender is dropped
+        let _ = self.inner.rx.recv_async().await;
+    }
+}
+
+/// Locks the mutex, ignoring the poisoning by a panicked thread
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Message of a panic, if the payload is a string
+#[cfg_attr(not(any(feature = "server", feature = "client")), allow(dead_code))]
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = panic.downcast_ref::<&'static str>() {
+        msg.to_string()
+    } else {
+        "Box<dyn Any>".into()
+    }
+}
+
 /// .await until the end of the task in a blocking manner
 pub(crate) trait Conclude {
     fn conclude(&mut self);
@@ -70,3 +226,40 @@ impl<T: Send> Terminate for tokio::task::JoinHandle<T> {
         self.abort();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CancellationToken, Semaphore};
+
+    #[test]
+    fn semaphore_returns_permit_on_drop() {
+        let semaphore = Semaphore::new(1);
+        futures::executor::block_on(async {
+            let permit = semaphore.acquire().await;
+            assert!(semaphore.permits_rx.is_empty());
+            drop(permit);
+            assert_eq!(semaphore.permits_rx.len(), 1);
+            let _permit = semaphore.acquire().await;
+        });
+    }
+
+    #[test]
+    fn cancelling_parent_cancels_children() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+
+        grandchild.cancel();
+        assert!(!child.is_cancelled());
+        assert!(!parent.is_cancelled());
+
+        let other = child.child_token();
+        parent.cancel();
+        assert!(child.is_cancelled());
+        assert!(other.is_cancelled());
+        futures::executor::block_on(other.cancelled());
+
+        // children created after cancellation are cancelled
+        assert!(parent.child_token().is_cancelled());
+    }
+}
@@ -0,0 +1,67 @@
+//! Behaviour of the client on the async-std runtime
+
+mod common;
+mod rpc;
+
+mod connect_timeout {
+    //! Timeout of dialing a server that doesn't respond
+
+    use async_std::task;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use toy_rpc::{client::Proxy, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    async fn run() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        // accepts connections and never responds
+        let (silent, silent_addr) = common::bind().await;
+        let silent_handle = task::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = silent.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let client = Client::dial_timeout(addr, TIMEOUT)
+            .await
+            .expect("Error dialing server");
+        rpc::test_get_magic_u32(&client).await;
+        client.close().await;
+
+        // the proxy never answers the SOCKS5 greeting
+        let started = Instant::now();
+        let result = Client::builder()
+            .set_proxy(Proxy::socks5(silent_addr))
+            .set_connect_timeout(TIMEOUT)
+            .dial(addr)
+            .await;
+        match result {
+            Err(Error::ConnectTimeout { step, timeout }) => {
+                assert_eq!(step, "Connection");
+                assert_eq!(timeout, TIMEOUT);
+            }
+            Err(err) => panic!("Unexpected error {:?}", err),
+            Ok(_) => panic!("Dialing is expected to time out"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        silent_handle.cancel().await;
+        server_handle.cancel().await;
+    }
+
+    #[test]
+    fn connect_timeout() {
+        task::block_on(run());
+    }
+}
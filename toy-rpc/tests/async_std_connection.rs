@@ -0,0 +1,159 @@
+//! Life of a connection on the async-std runtime: heartbeats and closing
+
+mod common;
+mod rpc;
+
+mod heartbeat {
+    //! Heartbeats keep a healthy connection open and detect a dead one
+
+    use async_std::{future, io::ReadExt, task};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use toy_rpc::{client::Heartbeat, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    async fn healthy() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let heartbeat = Heartbeat::new(Duration::from_millis(50), Duration::from_millis(200))
+            .with_max_missed(1);
+        let client = Client::builder()
+            .set_heartbeat(heartbeat)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+
+        common::sleep(Duration::from_millis(400)).await;
+        assert!(client.is_connected());
+        let liveness = client.stats().liveness;
+        assert!(liveness.last_frame_received_at.is_some());
+        assert!(liveness.last_ping_rtt.is_some());
+        assert_eq!(liveness.missed_pongs, 0);
+        assert_eq!(liveness.heartbeat_interval, Some(Duration::from_millis(50)));
+        assert_eq!(liveness.heartbeat_timeout, Some(Duration::from_millis(200)));
+        rpc::test_get_magic_i32(&client).await;
+
+        client.close().await;
+        server_handle.cancel().await;
+    }
+
+    async fn dead() {
+        // a peer that reads everything and never answers
+        let (listener, addr) = common::bind().await;
+        let peer_handle = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+
+        let heartbeat =
+            Heartbeat::new(Duration::from_millis(50), Duration::from_millis(50)).with_max_missed(2);
+        let mut client = Client::builder()
+            .set_heartbeat(heartbeat)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        client.set_default_timeout(Duration::from_secs(10));
+        let disconnected = client.on_disconnect();
+
+        let start = Instant::now();
+        let result = client.call::<_, i32>("CommonTest.get_magic_i32", ()).await;
+        assert!(
+            matches!(result, Err(Error::MissedHeartbeats(2))),
+            "{:?}",
+            result
+        );
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+        assert!(!client.is_connected());
+        assert_eq!(client.stats().liveness.missed_pongs, 2);
+        future::timeout(Duration::from_secs(1), disconnected)
+            .await
+            .expect("on_disconnect does not resolve");
+
+        client.close().await;
+        peer_handle.cancel().await;
+    }
+
+    #[test]
+    fn healthy_connection() {
+        task::block_on(healthy());
+    }
+
+    #[test]
+    fn dead_connection() {
+        task::block_on(dead());
+    }
+}
+
+mod graceful_close {
+    //! Closing the client waits for the pending requests, and closing twice is harmless
+
+    use async_std::task;
+    use std::sync::Arc;
+    use toy_rpc::{client::CloseSummary, Client, Server};
+
+    use super::common::{self, Sleeper};
+
+    async fn close_with_pending_call() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let call = client.call::<_, u64>("Sleeper.sleep", 300u64);
+        let (reply, summary) = futures::join!(call, client.close());
+        assert_eq!(reply.unwrap(), 300);
+        assert_eq!(
+            summary,
+            CloseSummary {
+                flushed: 1,
+                dropped: 0
+            }
+        );
+
+        server_handle.cancel().await;
+    }
+
+    async fn close_twice() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let clone = client.clone();
+        let survivor = client.clone();
+        let (first, second) = futures::join!(client.close(), clone.close());
+        assert_eq!(first, CloseSummary::default());
+        assert_eq!(second, CloseSummary::default());
+
+        // the connection is closed once `close` returns
+        let reply = survivor.call::<_, u64>("Sleeper.sleep", 0u64).await;
+        assert!(reply.is_err());
+        assert_eq!(survivor.close().await, CloseSummary::default());
+
+        server_handle.cancel().await;
+    }
+
+    #[test]
+    fn graceful_close() {
+        task::block_on(close_with_pending_call());
+    }
+
+    #[test]
+    fn close_is_idempotent() {
+        task::block_on(close_twice());
+    }
+}
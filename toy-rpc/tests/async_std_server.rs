@@ -0,0 +1,163 @@
+//! Behaviour of the server on the async-std runtime
+
+mod common;
+mod rpc;
+
+mod concurrent_requests {
+    //! The concurrent request limit holds back calls, and the optional wait queue rejects
+    //! the calls beyond it
+
+    use async_std::task;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use toy_rpc::{Client, Error, Server};
+
+    use super::common::{self, Sleeper};
+
+    async fn holds_back_calls() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .set_max_concurrent_requests(1)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let start = Instant::now();
+        let slow = task::spawn(client.call::<_, u64>("Sleeper.sleep", 300u64));
+        common::sleep(Duration::from_millis(50)).await;
+
+        // the fast call waits for the slow call to free the only slot
+        client
+            .call::<_, u64>("Sleeper.sleep", 0u64)
+            .await
+            .expect("Fast call failed");
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(slow.await.expect("Slow call failed"), 300);
+
+        client.close().await;
+        server_handle.cancel().await;
+    }
+
+    async fn busy_beyond_the_queue() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .set_max_concurrent_requests(1)
+            .set_max_queued_requests(1)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        // one call runs and one waits for the slot
+        let slow = task::spawn(client.call::<_, u64>("Sleeper.sleep", 500u64));
+        let queued = task::spawn(client.call::<_, u64>("Sleeper.sleep", 100u64));
+        common::sleep(Duration::from_millis(100)).await;
+
+        let reply = client.call::<_, u64>("Sleeper.sleep", 0u64).await;
+        assert!(matches!(reply, Err(Error::Busy)), "{:?}", reply);
+        assert_eq!(slow.await.expect("Slow call failed"), 500);
+        assert_eq!(queued.await.expect("Queued call failed"), 100);
+
+        client.close().await;
+        server_handle.cancel().await;
+    }
+
+    #[test]
+    fn max_concurrent_requests_holds_back_calls() {
+        task::block_on(holds_back_calls());
+    }
+
+    #[test]
+    fn calls_beyond_the_queue_are_busy() {
+        task::block_on(busy_beyond_the_queue());
+    }
+}
+
+mod rate_limit {
+    //! Rate limiting of the requests on each connection
+
+    use async_std::task;
+    use std::{sync::Arc, time::Duration};
+    use toy_rpc::{pubsub::AckModeNone, server::RateLimit, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    async fn get_magic(client: &Client<AckModeNone>) -> Result<u32, Error> {
+        client.call("CommonTest.get_magic_u32", ()).await
+    }
+
+    async fn run() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .rate_limit(RateLimit {
+                per_second: 1,
+                burst: 3,
+            })
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        for _ in 0..3 {
+            let reply = get_magic(&client).await.expect("Call failed");
+            assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U32);
+        }
+        match get_magic(&client).await {
+            Err(Error::RateLimited) => {}
+            res => panic!("Expecting Error::RateLimited, found {:?}", res),
+        }
+
+        // the bucket refills over time
+        common::sleep(Duration::from_millis(1100)).await;
+        get_magic(&client).await.expect("Call failed");
+        match get_magic(&client).await {
+            Err(Error::RateLimited) => {}
+            res => panic!("Expecting Error::RateLimited, found {:?}", res),
+        }
+
+        client.close().await;
+        server_handle.cancel().await;
+    }
+
+    #[test]
+    fn requests_are_rate_limited_per_connection() {
+        task::block_on(run());
+    }
+}
+
+mod serve_one {
+    //! Serving exactly one connection with `Server::serve_one`
+
+    use async_std::task;
+    use std::{sync::Arc, time::Duration};
+    use toy_rpc::{Client, Server};
+
+    use super::common;
+    use super::rpc;
+
+    async fn run() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (listener, addr) = common::bind().await;
+
+        let client = async {
+            let client = Client::dial(addr).await.expect("Error dialing server");
+            rpc::test_get_magic_u32(&client).await;
+            client.close().await;
+        };
+        let (outcome, _) = futures::join!(
+            server.serve_one(listener, Some(Duration::from_secs(5))),
+            client
+        );
+        let outcome = outcome.expect("Error serving the connection");
+        assert!(outcome.error.is_none(), "{:?}", outcome.error);
+        assert!(server.connections().is_empty());
+    }
+
+    #[test]
+    fn serve_one_tcp_connection() {
+        task::block_on(run());
+    }
+}
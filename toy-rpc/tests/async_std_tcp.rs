@@ -25,9 +25,26 @@ async fn test_client(addr: &'static str, mut ready: Receiver<()>) -> Result<()>
     rpc::test_get_magic_bool(&client).await;
     rpc::test_get_magic_str(&client).await;
     rpc::test_imcomplete_service_method(&client).await;
+    rpc::test_bad_calls_keep_connection(&client).await;
     rpc::test_service_not_found(&client).await;
     rpc::test_method_not_found(&client).await;
     rpc::test_execution_error(&client).await;
+    rpc::test_invalid_argument(&client).await;
+    rpc::test_cancel_ack(&client).await;
+    rpc::test_concurrent_calls(&client).await;
+    rpc::test_batch(&client).await;
+    rpc::test_call_raw(&client).await;
+    rpc::test_cancellation_handle(&client).await;
+    rpc::test_peer_addr(&client).await;
+
+    let limited_client = Client::builder()
+        .set_max_in_flight(2)
+        .dial(addr)
+        .await
+        .expect("Error dialing server");
+    rpc::test_concurrent_calls(&limited_client).await;
+    rpc::test_cancel_all(&limited_client).await;
+    limited_client.close().await;
 
     println!("Client received correct RPC result");
     client.close().await;
@@ -31,6 +31,9 @@ async fn test_client(base: &'static str, mut ready: Receiver<()>) -> Result<()>
     rpc::test_service_not_found(&client).await;
     rpc::test_method_not_found(&client).await;
     rpc::test_execution_error(&client).await;
+    rpc::test_invalid_argument(&client).await;
+    rpc::test_cancel_ack(&client).await;
+    rpc::test_peer_addr(&client).await;
 
     println!("Client received all correct RPC result");
     client.close().await;
@@ -0,0 +1,68 @@
+//! Clones of one server are served over axum and raw TCP at the same time
+
+use futures::{SinkExt, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::task;
+use toy_rpc::{pubsub::Topic, Client, Server};
+
+mod common;
+mod rpc;
+
+struct Count;
+
+impl Topic for Count {
+    type Item = u32;
+
+    fn topic() -> String {
+        String::from("Count")
+    }
+}
+
+#[tokio::test]
+async fn clones_serve_axum_and_tcp_together() {
+    use axum::routing::Router;
+
+    let common_test_service = Arc::new(rpc::CommonTest::new());
+    let server = Server::builder().register(common_test_service).build();
+
+    let app = Router::new().nest("/rpc", server.clone().into_route());
+    let (listener, http_addr) = common::bind().await;
+    let http_handle = task::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let (tcp_addr, tcp_handle) = common::serve(server.clone()).await;
+    // the clones keep serving after the original is dropped
+    drop(server);
+
+    let ws_client = Client::dial_http(&format!("ws://{}/rpc/", http_addr))
+        .await
+        .expect("Error dialing http server");
+    rpc::test_get_magic_u32(&ws_client).await;
+    // the state of the closed connection is dropped by axum
+    ws_client.close().await;
+
+    let mut tcp_client = Client::dial(tcp_addr).await.expect("Error dialing server");
+    rpc::test_get_magic_u32(&tcp_client).await;
+    let mut subscriber = tcp_client
+        .subscriber::<Count>(None)
+        .expect("Error creating subscriber");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // the PubSub broker is shared by the connections of both transports
+    let ws_client = Client::dial_http(&format!("ws://{}/rpc/", http_addr))
+        .await
+        .expect("Error dialing http server");
+    rpc::test_get_magic_u32(&ws_client).await;
+    let mut publisher = ws_client.publisher::<Count>();
+    publisher.send(7).await.expect("Error publishing");
+    let item = tokio::time::timeout(Duration::from_secs(5), subscriber.next())
+        .await
+        .expect("Timed out waiting for the publication")
+        .expect("Subscriber is closed")
+        .expect("Error receiving publication");
+    assert_eq!(item, 7);
+
+    ws_client.close().await;
+    tcp_client.close().await;
+    http_handle.abort();
+    tcp_handle.abort();
+}
@@ -0,0 +1,32 @@
+//! `Call` can be stored and moved across tasks whatever the runtime and the type of
+//! the response
+
+use futures::Future;
+use serde::Deserialize;
+use std::marker::PhantomData;
+use toy_rpc::client::{Call, CancellationHandle, FromResponse};
+
+/// A response type that is neither `Send` nor `Sync`
+#[derive(Deserialize)]
+struct NotSend {
+    #[serde(skip)]
+    _marker: PhantomData<*const ()>,
+}
+
+fn assert_storable<T: Send + Unpin + 'static>() {}
+
+fn assert_future<F: Future<Output = Result<Res, toy_rpc::Error>>, Res>() {}
+
+fn storable<Res: FromResponse + 'static>() {
+    assert_storable::<Call<Res>>();
+    assert_future::<Call<Res>, Res>();
+}
+
+#[test]
+fn call_is_storable() {
+    storable::<i32>();
+    storable::<String>();
+    // the response type is not required to be `Send`
+    storable::<NotSend>();
+    assert_storable::<CancellationHandle>();
+}
@@ -0,0 +1,93 @@
+//! Fixtures shared by the integration tests
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, time::Duration};
+use toy_rpc::{macros::export_impl, pubsub::AckModeNone, Error, Server};
+
+#[cfg(feature = "async_std_runtime")]
+use async_std::{
+    net::TcpListener,
+    task::{self, JoinHandle},
+};
+#[cfg(feature = "tokio_runtime")]
+use tokio::{
+    net::TcpListener,
+    task::{self, JoinHandle},
+};
+
+/// Binds a free port of the loopback interface, so the tests can run in parallel
+pub async fn bind() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Cannot bind to address");
+    let addr = listener.local_addr().expect("Listener has no address");
+    (listener, addr)
+}
+
+/// Serves `server` on a free port of the loopback interface, and returns the address to
+/// dial and the task accepting the connections, which is aborted to stop the server
+pub async fn serve(server: Server<AckModeNone>) -> (SocketAddr, JoinHandle<Result<(), Error>>) {
+    let (listener, addr) = bind().await;
+    let handle = task::spawn(async move { server.accept(listener).await });
+    (addr, handle)
+}
+
+/// Sleeps on the runtime of the test
+pub async fn sleep(duration: Duration) {
+    #[cfg(feature = "tokio_runtime")]
+    tokio::time::sleep(duration).await;
+    #[cfg(feature = "async_std_runtime")]
+    async_std::task::sleep(duration).await;
+}
+
+/// Service that replies after a delay chosen by the caller
+#[derive(Default)]
+pub struct Sleeper {}
+
+#[export_impl]
+impl Sleeper {
+    /// Sleeps and returns the number of milliseconds slept
+    #[export_method]
+    async fn sleep(&self, millis: u64) -> Result<u64, String> {
+        sleep(Duration::from_millis(millis)).await;
+        Ok(millis)
+    }
+
+    #[export_method]
+    async fn echo(&self, val: i32) -> Result<i32, String> {
+        Ok(val)
+    }
+}
+
+#[cfg(feature = "tokio_runtime")]
+pub use self::relay::{relay, Relays};
+
+#[cfg(feature = "tokio_runtime")]
+mod relay {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        task::{self, JoinHandle},
+    };
+
+    /// The tasks forwarding the connections accepted by `relay`
+    pub type Relays = Arc<Mutex<Vec<JoinHandle<()>>>>;
+
+    /// Forwards every connection accepted on `listener` to the server, so that the tests
+    /// can close the connections by aborting their relay
+    pub async fn relay(listener: TcpListener, server_addr: SocketAddr, relays: Relays) {
+        loop {
+            let (mut inbound, _) = listener.accept().await.expect("Error accepting");
+            let handle = task::spawn(async move {
+                let mut outbound = TcpStream::connect(server_addr)
+                    .await
+                    .expect("Error connecting to server");
+                let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+            });
+            relays.lock().unwrap().push(handle);
+        }
+    }
+}
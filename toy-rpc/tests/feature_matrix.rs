@@ -0,0 +1,116 @@
+//! Checks that unsupported combinations of feature flags fail with an actionable message
+//! and that the documented combinations still build.
+//!
+//! Each case invokes `cargo check` on this crate, so these tests are ignored by default.
+//! Run them with `cargo make test_feature_matrix`, which is part of `cargo make test` and of
+//! the CI workflow.
+
+use std::process::Command;
+
+fn cargo_check(features: &str) -> (bool, String) {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let output = Command::new(cargo)
+        .args(&[
+            "check",
+            "--lib",
+            "--no-default-features",
+            "--features",
+            features,
+        ])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env(
+            "CARGO_TARGET_DIR",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../target/feature_matrix"),
+        )
+        .output()
+        .expect("Failed to run cargo check");
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    (output.status.success(), stderr)
+}
+
+fn assert_fails_with(features: &str, expected: &str) {
+    let (success, stderr) = cargo_check(features);
+    assert!(!success, "Expecting features {:?} to fail", features);
+    assert!(
+        stderr.contains(expected),
+        "Expecting {:?} in the error message of features {:?}, found:\n{}",
+        expected,
+        features,
+        stderr
+    );
+}
+
+#[test]
+#[ignore]
+fn no_codec() {
+    assert_fails_with("server tokio_runtime", "require a codec");
+}
+
+#[test]
+#[ignore]
+fn multiple_codecs() {
+    assert_fails_with(
+        "server tokio_runtime serde_bincode serde_json",
+        "only one of `serde_bincode`",
+    );
+}
+
+#[test]
+#[ignore]
+fn no_runtime() {
+    assert_fails_with("client serde_bincode", "require a runtime");
+}
+
+#[test]
+#[ignore]
+fn both_runtimes() {
+    assert_fails_with(
+        "server serde_bincode tokio_runtime async_std_runtime",
+        "cannot be enabled at the same time",
+    );
+}
+
+#[test]
+#[ignore]
+fn tide_with_tokio() {
+    assert_fails_with(
+        "http_tide tokio_runtime serde_bincode",
+        "`http_tide` runs on",
+    );
+}
+
+#[test]
+#[ignore]
+fn tls_without_runtime() {
+    assert_fails_with("tls serde_bincode", "`tls` requires a runtime");
+}
+
+#[test]
+#[ignore]
+fn mismatched_websocket_runtime() {
+    assert_fails_with(
+        "client serde_bincode async_std_runtime ws_tokio",
+        "`ws_tokio` requires `tokio_runtime`",
+    );
+}
+
+#[test]
+#[ignore]
+fn supported_combinations() {
+    let combinations = [
+        "serde_bincode",
+        "server client serde_bincode tokio_runtime",
+        "server client serde_bincode async_std_runtime",
+        "server client serde_json tokio_runtime ws_tokio",
+        "server client serde_cbor async_std_runtime ws_async_std",
+        "server client serde_rmp tokio_runtime tls",
+    ];
+    for features in combinations.iter() {
+        let (success, stderr) = cargo_check(features);
+        assert!(
+            success,
+            "Features {:?} failed to build:\n{}",
+            features, stderr
+        );
+    }
+}
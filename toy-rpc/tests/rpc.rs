@@ -138,6 +138,24 @@ cfg_if::cfg_if! {
             async fn echo_error(&self, args: String) -> Result<(), String> {
                 Err(args)
             }
+
+            #[export_method]
+            async fn wait_forever(&self, _: ()) -> Result<(), String> {
+                futures::future::pending::<()>().await;
+                Ok(())
+            }
+
+            #[export_method]
+            async fn get_peer_addr(&self, _: ()) -> Result<Option<String>, String> {
+                let ctx = toy_rpc::server::Context::current().ok_or("Missing request context")?;
+                Ok(ctx.peer_addr().map(|addr| addr.to_string()))
+            }
+
+            #[export_method]
+            async fn get_metadata(&self, key: String) -> Result<Option<String>, String> {
+                let ctx = toy_rpc::server::Context::current().ok_or("Missing request context")?;
+                Ok(ctx.metadata().get(&key).cloned())
+            }
         }
 
         use toy_rpc::client::{Client};
@@ -245,12 +263,9 @@ cfg_if::cfg_if! {
 
         pub async fn test_service_not_found<AckMode>(client: &Client<AckMode>) {
             let reply: Result<(), toy_rpc::Error> = client.call("UndefinedService.method", ()).await;
-            let expected = toy_rpc::Error::ServiceNotFound;
             match reply {
-                Ok(_) => panic!("Expecting an error"),
-                Err(err) => {
-                    assert_eq!(err.to_string(), expected.to_string())
-                }
+                Err(toy_rpc::Error::ServiceNotFound) => {}
+                res => panic!("Expecting Error::ServiceNotFound, found {:?}", res),
             };
             println!("test_service_not_found() Passed")
         }
@@ -258,12 +273,9 @@ cfg_if::cfg_if! {
         pub async fn test_method_not_found<AckMode>(client: &Client<AckMode>) {
             let service_method = format!("{}.undefined_method", COMMON_TEST_SERVICE_NAME);
             let reply: Result<(), toy_rpc::Error> = client.call(service_method, ()).await;
-            let expected = toy_rpc::Error::MethodNotFound;
             match reply {
-                Ok(_) => panic!("Expecting an error"),
-                Err(err) => {
-                    assert_eq!(err.to_string(), expected.to_string())
-                }
+                Err(toy_rpc::Error::MethodNotFound) => {}
+                res => panic!("Expecting Error::MethodNotFound, found {:?}", res),
             };
             println!("test_method_not_found() Passed")
         }
@@ -271,29 +283,227 @@ cfg_if::cfg_if! {
         pub async fn test_imcomplete_service_method<AckMode>(client: &Client<AckMode>) {
             let service_method = format!("{}", COMMON_TEST_SERVICE_NAME);
             let reply: Result<(), toy_rpc::Error> = client.call(service_method, ()).await;
-            let expected = toy_rpc::Error::MethodNotFound;
             match reply {
-                Ok(_) => panic!("Expecting an error"),
-                Err(err) => {
-                    assert_eq!(err.to_string(), expected.to_string())
-                }
+                Err(toy_rpc::Error::MethodNotFound) => {}
+                res => panic!("Expecting Error::MethodNotFound, found {:?}", res),
             };
             println!("test_imcomplete_service_method() Passed")
         }
 
+        pub async fn test_bad_calls_keep_connection<AckMode>(client: &Client<AckMode>) {
+            // the bad calls are interleaved with good ones on the same connection
+            let service_methods = [
+                "CommonTest.get_magic_u32",
+                "UndefinedService.method",
+                "CommonTest.get_magic_u32",
+                "CommonTest.undefined_method",
+                "CommonTest",
+                "CommonTest.get_magic_u32",
+            ];
+            let calls: Vec<toy_rpc::client::Call<u32>> = service_methods
+                .iter()
+                .map(|service_method| client.call(*service_method, ()))
+                .collect();
+            let replies = futures::future::join_all(calls).await;
+            for (service_method, reply) in service_methods.iter().zip(replies) {
+                match (*service_method, reply) {
+                    ("CommonTest.get_magic_u32", Ok(reply)) => assert_eq!(COMMON_TEST_MAGIC_U32, reply),
+                    ("UndefinedService.method", Err(toy_rpc::Error::ServiceNotFound)) => {}
+                    ("CommonTest.undefined_method", Err(toy_rpc::Error::MethodNotFound)) => {}
+                    ("CommonTest", Err(toy_rpc::Error::MethodNotFound)) => {}
+                    (service_method, res) => panic!("Unexpected reply to {}: {:?}", service_method, res),
+                }
+            }
+
+            // and the connection is still served afterwards
+            test_get_magic_u32(client).await;
+            println!("test_bad_calls_keep_connection() Passed")
+        }
+
         pub async fn test_execution_error<AckMode>(client: &Client<AckMode>) {
             let val = "an error message".to_string();
             let reply = client.common_test().echo_error(val.clone()).await;
-            let expected = toy_rpc::Error::ExecutionError(val);
             match reply {
-                Ok(_) => panic!("Expecting an error"),
-                Err(err) => {
-                    assert_eq!(err.to_string(), expected.to_string())
-                }
+                Err(toy_rpc::Error::ExecutionError(msg)) => assert_eq!(msg, val),
+                res => panic!("Expecting Error::ExecutionError, found {:?}", res),
             };
             println!("test_execution_error() Passed")
         }
 
+        pub async fn test_borrowed_args<AckMode>(client: &Client<AckMode>) {
+            // neither owned nor 'static
+            let val = format!("an error message {}", 7);
+            let reply: Result<(), toy_rpc::Error> =
+                client.call("CommonTest.echo_error", val.as_str()).await;
+            match reply {
+                Err(toy_rpc::Error::ExecutionError(msg)) => assert_eq!(msg, val),
+                res => panic!("Expecting Error::ExecutionError, found {:?}", res),
+            };
+            let reply: Result<(), toy_rpc::Error> =
+                client.call("CommonTest.echo_error", &val).await;
+            assert!(matches!(reply, Err(toy_rpc::Error::ExecutionError(_))));
+            println!("test_borrowed_args() Passed")
+        }
+
+        pub async fn test_invalid_argument<AckMode>(client: &Client<AckMode>) {
+            let service_method = format!("{}.echo_error", COMMON_TEST_SERVICE_NAME);
+            let reply: Result<(), toy_rpc::Error> = client.call(service_method, ()).await;
+            match reply {
+                Err(toy_rpc::Error::InvalidArgument) => {}
+                res => panic!("Expecting Error::InvalidArgument, found {:?}", res),
+            };
+            println!("test_invalid_argument() Passed")
+        }
+
+        pub async fn test_concurrent_calls<AckMode>(client: &Client<AckMode>) {
+            let calls: Vec<toy_rpc::client::Call<u32>> = (0..16)
+                .map(|_| client.call("CommonTest.get_magic_u32", ()))
+                .collect();
+            for reply in futures::future::join_all(calls).await {
+                let reply = reply.expect("Unexpected error executing RPC");
+                assert_eq!(COMMON_TEST_MAGIC_U32, reply);
+            }
+            println!("test_concurrent_calls() Passed")
+        }
+
+        pub async fn test_batch<AckMode>(client: &Client<AckMode>) {
+            let replies: Vec<Result<u32, toy_rpc::Error>> = client
+                .batch()
+                .push("CommonTest.get_magic_u32", ())
+                .push("CommonTest.non_existent", ())
+                .push("CommonTest.get_magic_u32", ())
+                .send()
+                .await;
+            assert_eq!(replies.len(), 3);
+            assert_eq!(*replies[0].as_ref().unwrap(), COMMON_TEST_MAGIC_U32);
+            assert!(replies[1].is_err());
+            assert_eq!(*replies[2].as_ref().unwrap(), COMMON_TEST_MAGIC_U32);
+
+            let batch = client.batch::<u32>();
+            assert!(batch.is_empty());
+            assert!(batch.send().await.is_empty());
+            println!("test_batch() Passed")
+        }
+
+        pub async fn test_call_raw<AckMode>(client: &Client<AckMode>) {
+            let response: toy_rpc::client::RawResponse = client
+                .call_raw("CommonTest.get_magic_str", ())
+                .await
+                .expect("Unexpected error executing RPC");
+            assert!(!response.as_bytes().is_empty());
+            let reply: String = response.deserialize().unwrap();
+            assert_eq!(reply, COMMON_TEST_MAGIC_STR);
+
+            // an error response is still an error
+            let result = client
+                .call_raw("CommonTest.echo_error", "an error".to_string())
+                .await;
+            assert!(matches!(result, Err(Error::ExecutionError(_))), "{:?}", result);
+            println!("test_call_raw() Passed")
+        }
+
+        async fn yield_now() {
+            let mut yielded = false;
+            futures::future::poll_fn(|cx| {
+                if yielded {
+                    std::task::Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    std::task::Poll::Pending
+                }
+            })
+            .await
+        }
+
+        pub async fn test_cancellation_handle<AckMode>(client: &Client<AckMode>) {
+            let mut call: toy_rpc::client::Call<()> = client.call("CommonTest.wait_forever", ());
+            let handle = call.cancellation_handle();
+            assert_eq!(handle.id(), call.id());
+            assert!(!call.is_finished());
+            handle.cancel();
+            match call.await {
+                Err(toy_rpc::Error::Canceled(_)) => {}
+                res => panic!("Expecting Error::Canceled, found {:?}", res),
+            }
+
+            // cancelling a finished call has no effect
+            let mut call: toy_rpc::client::Call<u32> = client.call("CommonTest.get_magic_u32", ());
+            let handle = call.cancellation_handle();
+            while !call.is_finished() {
+                yield_now().await;
+            }
+            handle.cancel();
+            let reply = call.await.expect("Unexpected error executing RPC");
+            assert_eq!(COMMON_TEST_MAGIC_U32, reply);
+            println!("test_cancellation_handle() Passed")
+        }
+
+        pub async fn test_cancel_ack<AckMode>(client: &Client<AckMode>) {
+            let mut call: toy_rpc::client::Call<()> = client.call("CommonTest.wait_forever", ());
+            call.cancel()
+                .await
+                .expect("Cancellation is not acknowledged");
+            match call.await {
+                Err(toy_rpc::Error::Canceled(_)) => {}
+                res => panic!("Expecting Error::Canceled, found {:?}", res),
+            }
+            println!("test_cancel_ack() Passed")
+        }
+
+        /// Expects a client with `max_in_flight` smaller than 4 and no pending call
+        pub async fn test_cancel_all<AckMode>(client: &Client<AckMode>) {
+            let calls: Vec<toy_rpc::client::Call<()>> = (0..4)
+                .map(|_| client.call("CommonTest.wait_forever", ()))
+                .collect();
+            assert_eq!(client.cancel_all().await, 4);
+            assert_eq!(client.num_pending(), 0);
+            for call in calls {
+                match call.await {
+                    Err(toy_rpc::Error::Canceled(_)) => {}
+                    res => panic!("Expecting Error::Canceled, found {:?}", res),
+                }
+            }
+
+            // the in-flight slots are released
+            test_concurrent_calls(client).await;
+            assert_eq!(client.cancel_all().await, 0);
+            println!("test_cancel_all() Passed")
+        }
+
+        pub async fn test_peer_addr<AckMode>(client: &Client<AckMode>) {
+            let reply: Option<String> = client
+                .common_test()
+                .get_peer_addr(())
+                .await
+                .expect("Unexpected error executing RPC");
+            let peer_addr: std::net::SocketAddr = reply
+                .expect("Expecting peer address")
+                .parse()
+                .expect("Invalid peer address");
+            assert!(peer_addr.ip().is_loopback());
+            println!("test_peer_addr() Passed")
+        }
+
+        /// Expects a fresh client that has not made any call yet
+        pub async fn test_call_stats<AckMode>(client: &Client<AckMode>) {
+            let mut call: toy_rpc::client::Call<u32> = client.call("CommonTest.get_magic_u32", ());
+            let reply = (&mut call).await.expect("Unexpected error executing RPC");
+            assert_eq!(COMMON_TEST_MAGIC_U32, reply);
+
+            // every call is written as a header frame and a body frame, each of which
+            // carries the magic byte and the 8 bytes frame header
+            let stats = call.stats();
+            assert!(stats.request_bytes >= 2 * 9 + "CommonTest.get_magic_u32".len() as u64);
+            assert!(stats.response_bytes >= 2 * 9 + 4);
+
+            // the connection totals account for exactly the same bytes
+            let conn_stats = client.stats();
+            assert_eq!(conn_stats.total.num_bytes, stats.request_bytes);
+            assert_eq!(conn_stats.bytes_read, stats.response_bytes);
+            println!("test_call_stats() Passed")
+        }
+
         pub fn simply_panic() {
             panic!("just panics");
         }
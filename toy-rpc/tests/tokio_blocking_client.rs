@@ -0,0 +1,62 @@
+//! The blocking client makes calls without an async runtime on the calling thread
+
+use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
+use toy_rpc::{client::BlockingClient, util::CancellationToken, Error, Server};
+
+mod common;
+mod rpc;
+
+/// Serves on a runtime of its own, as the calling thread must not be in a runtime
+fn spawn_server() -> SocketAddr {
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let server = Server::builder()
+                .register(Arc::new(rpc::CommonTest::new()))
+                .build();
+            let (listener, addr) = common::bind().await;
+            tx.send(addr).unwrap();
+            server.accept(listener).await.unwrap();
+        });
+    });
+    rx.recv().unwrap()
+}
+
+#[test]
+fn blocking_client() {
+    let addr = spawn_server();
+
+    let client = BlockingClient::connect(addr).expect("Error connecting to server");
+    let reply: i32 = client
+        .call("CommonTest.get_magic_i32", ())
+        .expect("Unexpected error executing RPC");
+    assert_eq!(reply, rpc::COMMON_TEST_MAGIC_I32);
+    let reply: String = client
+        .call("CommonTest.get_magic_str", ())
+        .expect("Unexpected error executing RPC");
+    assert_eq!(reply, rpc::COMMON_TEST_MAGIC_STR);
+
+    let result: Result<(), Error> =
+        client.call_with_timeout("CommonTest.wait_forever", (), Duration::from_millis(100));
+    assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+
+    let token = CancellationToken::new();
+    let child = token.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        child.cancel();
+    });
+    let result: Result<(), Error> =
+        client.call_with_cancellation("CommonTest.wait_forever", (), token);
+    assert!(matches!(result, Err(Error::Canceled(_))), "{:?}", result);
+
+    // the connection is still usable
+    let reply: u8 = client
+        .call("CommonTest.get_magic_u8", ())
+        .expect("Unexpected error executing RPC");
+    assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U8);
+    assert!(client.is_connected());
+
+    client.close();
+}
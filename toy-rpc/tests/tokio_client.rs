@@ -0,0 +1,1317 @@
+//! Behaviour of the client: calls, cancellation, timeouts and the connections it dials
+
+use tokio::sync::mpsc::UnboundedSender;
+use toy_rpc::Error;
+
+mod common;
+mod rpc;
+
+fn expect_magic(result: Result<u32, Error>) {
+    assert_eq!(result.expect("Call failed"), rpc::COMMON_TEST_MAGIC_U32);
+}
+
+/// Sends a message when the handler stops
+struct NotifyOnDrop(UnboundedSender<&'static str>);
+
+impl Drop for NotifyOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.send("stopped");
+    }
+}
+
+mod cancellation {
+    //! Cancellation of requests
+    //!
+    //! Cancellation is propagated from the caller through a server to its downstream server
+    //!
+    //! caller -> Frontend (server A) -> Backend (server B)
+
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        task,
+    };
+    use toy_rpc::{
+        client::Call, macros::export_impl, pubsub::AckModeNone, server::Context, Client, Server,
+    };
+
+    use super::{common, NotifyOnDrop};
+
+    struct Backend {
+        events: UnboundedSender<&'static str>,
+    }
+
+    #[export_impl]
+    impl Backend {
+        #[export_method]
+        async fn wait_forever(&self, _: ()) -> Result<(), String> {
+            let _guard = NotifyOnDrop(self.events.clone());
+            self.events.send("started").map_err(|err| err.to_string())?;
+            futures::future::pending::<()>().await;
+            Ok(())
+        }
+
+        #[export_method]
+        async fn ping(&self, _: ()) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    /// Same as `Echo::finite_loop` of the tokio_tcp example, with shorter sleeps
+    struct Looper {
+        counter: Arc<AtomicUsize>,
+    }
+
+    #[export_impl]
+    impl Looper {
+        #[export_method]
+        async fn finite_loop(&self, _: ()) -> Result<(), String> {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                self.counter.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    struct Frontend {
+        downstream: Client<AckModeNone>,
+    }
+
+    #[export_impl]
+    impl Frontend {
+        #[export_method]
+        async fn fan_out(&self, _: ()) -> Result<(), String> {
+            let ctx = Context::current().ok_or("Missing request context")?;
+            // the downstream call is detached from this handler, so only the
+            // bound token can cancel it
+            let call: Call<()> = self
+                .downstream
+                .call("Backend.wait_forever", ())
+                .bind_cancellation(ctx.child_token());
+            task::spawn(call);
+            futures::future::pending::<()>().await;
+            Ok(())
+        }
+    }
+
+    async fn expect_event(events: &mut UnboundedReceiver<&'static str>, expected: &str) {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap_or_else(|_| panic!("Timed out waiting for {:?}", expected));
+        assert_eq!(event, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn cancellation_propagates_to_downstream_calls() {
+        let (tx, mut events) = unbounded_channel();
+        let backend = Arc::new(Backend { events: tx });
+        let (backend_addr, _) = common::serve(Server::builder().register(backend).build()).await;
+
+        let downstream = Client::dial(backend_addr)
+            .await
+            .expect("Error dialing backend");
+        let frontend = Arc::new(Frontend { downstream });
+        let (frontend_addr, _) = common::serve(Server::builder().register(frontend).build()).await;
+
+        let client = Client::dial(frontend_addr)
+            .await
+            .expect("Error dialing frontend");
+        let mut call: Call<()> = client.call("Frontend.fan_out", ());
+        expect_event(&mut events, "started").await;
+
+        call.cancel()
+            .await
+            .expect("Cancellation is not acknowledged");
+        expect_event(&mut events, "stopped").await;
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn dropping_call_cancels_only_pending_request() {
+        let (tx, mut events) = unbounded_channel();
+        let backend = Arc::new(Backend { events: tx });
+        let (addr, _) = common::serve(Server::builder().register(backend).build()).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing backend");
+
+        // dropping a pending call cancels the request
+        let call: Call<()> = client.call("Backend.wait_forever", ());
+        expect_event(&mut events, "started").await;
+        drop(call);
+        expect_event(&mut events, "stopped").await;
+
+        // dropping a completed call doesn't
+        let call: Call<()> = client.call("Backend.ping", ());
+        // the response reaches the broker but the call is never polled
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let num_items = client.stats().total.num_items;
+        drop(call);
+
+        // only the request of the next call is written
+        let _: () = client.call("Backend.ping", ()).await.unwrap();
+        assert_eq!(client.stats().total.num_items, num_items + 1);
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn canceled_and_timed_out_calls_are_not_left_pending() {
+        let (tx, _events) = unbounded_channel();
+        let backend = Arc::new(Backend { events: tx });
+        let (addr, _) = common::serve(Server::builder().register(backend).build()).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing backend");
+        let mut calls = Vec::new();
+        for i in 0..60 {
+            let call: Call<()> = match i % 3 {
+                0 => client.call("Backend.wait_forever", ()),
+                1 => client
+                    .set_next_timeout(Duration::from_millis(50))
+                    .call("Backend.wait_forever", ()),
+                _ => client.call("Backend.ping", ()),
+            };
+            calls.push(call);
+        }
+        for (i, mut call) in calls.into_iter().enumerate() {
+            match i % 6 {
+                0 => drop(call),
+                3 => {
+                    let _ = call.cancel().await;
+                }
+                _ => {
+                    let _ = call.await;
+                }
+            }
+        }
+
+        // none of the canceled or timed out requests is left behind
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(client.num_pending(), 0);
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn cancellation_aborts_running_handler() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let looper = Arc::new(Looper {
+            counter: counter.clone(),
+        });
+        let (addr, _) = common::serve(Server::builder().register(looper).build()).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let mut call: Call<()> = client.call("Looper.finite_loop", ());
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        call.cancel()
+            .await
+            .expect("Cancellation is not acknowledged");
+
+        // the handler is aborted once the cancellation is acknowledged
+        let count = counter.load(Ordering::SeqCst);
+        assert!(count < 10);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(counter.load(Ordering::SeqCst), count);
+        assert!(call.await.unwrap_err().is_cancelled());
+        client.close().await;
+    }
+}
+
+mod deadline {
+    //! Deadline of the request propagated from the client to the handler
+
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use toy_rpc::{macros::export_impl, server::Context, Client, Error, Server};
+
+    use super::common;
+
+    #[derive(Default)]
+    struct Deadline {
+        count: AtomicU32,
+    }
+
+    #[export_impl]
+    impl Deadline {
+        /// Milliseconds left until the deadline of the request
+        #[export_method]
+        async fn remaining(&self, _: ()) -> Result<u64, String> {
+            let ctx = Context::current().ok_or("No context")?;
+            let remaining = ctx.time_remaining().ok_or("No deadline")?;
+            Ok(remaining.as_millis() as u64)
+        }
+
+        #[export_method]
+        async fn sleep(&self, millis: u64) -> Result<(), String> {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            Ok(())
+        }
+
+        #[export_method]
+        async fn increment(&self, _: ()) -> Result<u32, String> {
+            Ok(self.count.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let server = Server::builder()
+            .register(Arc::new(Deadline::default()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::builder()
+            .set_max_in_flight(1)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+
+        // the handler sees the timeout of the call
+        client.set_next_timeout(Duration::from_secs(5));
+        let remaining: u64 = client.call("Deadline.remaining", ()).await.unwrap();
+        assert!(remaining > 4000 && remaining <= 5000, "{}", remaining);
+
+        // the time spent waiting for an in-flight slot is taken off the timeout
+        let slow = client.call::<_, ()>("Deadline.sleep", 300u64);
+        client.set_next_timeout(Duration::from_secs(1));
+        let queued = client.call::<_, u64>("Deadline.remaining", ());
+        let (slow, queued) = futures::join!(slow, queued);
+        slow.unwrap();
+        let remaining = queued.unwrap();
+        assert!(remaining <= 750, "{}", remaining);
+
+        // the handler is not run once the deadline has passed
+        client.set_next_timeout(Duration::ZERO);
+        let reply: Result<u32, Error> = client.call("Deadline.increment", ()).await;
+        assert!(matches!(reply, Err(Error::Timeout(_))));
+        let count: u32 = client.call("Deadline.increment", ()).await.unwrap();
+        assert_eq!(count, 1);
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod max_in_flight {
+    //! Calls beyond `max_in_flight` wait for a slot before they are sent
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        sync::{
+            mpsc::{unbounded_channel, UnboundedSender},
+            Semaphore,
+        },
+        task,
+        time::timeout,
+    };
+    use toy_rpc::{client::Call, macros::export_impl, Client, Error, Server};
+
+    use super::common;
+
+    const MAX_IN_FLIGHT: usize = 2;
+
+    struct Gate {
+        started: UnboundedSender<u32>,
+        release: Arc<Semaphore>,
+    }
+
+    #[export_impl]
+    impl Gate {
+        /// Returns `n` once the test releases the handler
+        #[export_method]
+        async fn wait(&self, n: u32) -> Result<u32, String> {
+            self.started.send(n).map_err(|err| err.to_string())?;
+            self.release
+                .acquire()
+                .await
+                .map_err(|err| err.to_string())?
+                .forget();
+            Ok(n)
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_beyond_the_limit_wait_for_a_slot() {
+        let (tx, mut started) = unbounded_channel();
+        let release = Arc::new(Semaphore::new(0));
+        let server = Server::builder()
+            .register(Arc::new(Gate {
+                started: tx,
+                release: release.clone(),
+            }))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::builder()
+            .set_max_in_flight(MAX_IN_FLIGHT)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        let first: Call<u32> = client.call("Gate.wait", 1u32);
+        let second: Call<u32> = client.call("Gate.wait", 2u32);
+        for _ in 0..MAX_IN_FLIGHT {
+            let n = timeout(Duration::from_secs(5), started.recv()).await;
+            assert!(matches!(n.unwrap(), Some(1) | Some(2)));
+        }
+
+        // the call is pending without reaching the server while the slots are taken
+        let mut third: Call<u32> = client.call("Gate.wait", 3u32);
+        assert!(timeout(Duration::from_millis(200), &mut third)
+            .await
+            .is_err());
+        assert!(timeout(Duration::from_millis(200), started.recv())
+            .await
+            .is_err());
+        assert_eq!(client.num_pending(), MAX_IN_FLIGHT);
+
+        // a call that is canceled while it waits is never sent
+        let mut canceled: Call<u32> = client.call("Gate.wait", 4u32);
+        assert!(timeout(Duration::from_millis(100), &mut canceled)
+            .await
+            .is_err());
+        canceled.cancel().await.unwrap();
+        assert!(matches!(canceled.await, Err(Error::Canceled(_))));
+
+        // finishing one of the calls lets the waiting one through
+        let third = task::spawn(third);
+        release.add_permits(1);
+        let n = timeout(Duration::from_secs(5), started.recv()).await;
+        assert_eq!(n.unwrap(), Some(3));
+
+        release.add_permits(MAX_IN_FLIGHT);
+        assert_eq!(first.await.unwrap(), 1);
+        assert_eq!(second.await.unwrap(), 2);
+        assert_eq!(third.await.unwrap().unwrap(), 3);
+        assert!(started.try_recv().is_err());
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod ordered_responses {
+    //! Responses are delivered in the order of the requests with ordered responses
+
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use toy_rpc::{client::Call, Client, Error, Server};
+
+    use super::common::{self, Sleeper};
+
+    /// Awaits the calls at the same time and returns the replies in the order they
+    /// are delivered
+    async fn delivery_order(calls: Vec<Call<u64>>) -> Vec<Result<u64, Error>> {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let calls = calls.into_iter().map(|call| {
+            let delivered = delivered.clone();
+            async move {
+                let reply = call.await;
+                delivered.lock().unwrap().push(reply);
+            }
+        });
+        futures::future::join_all(calls).await;
+        let mut delivered = delivered.lock().unwrap();
+        std::mem::take(&mut *delivered)
+    }
+
+    // the calls forward their responses in separate tasks, which run in the order they
+    // are woken on the single thread of the runtime of the test
+    #[tokio::test]
+    async fn ordered_responses() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper::default()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        // responses are delivered as soon as they arrive by default
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let calls = vec![
+            client.call("Sleeper.sleep", 200u64),
+            client.call("Sleeper.sleep", 10u64),
+        ];
+        let replies: Vec<u64> = delivery_order(calls)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(replies, vec![10, 200]);
+        client.close().await;
+
+        let client = Client::builder()
+            .set_ordered_responses(true)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        let calls = vec![
+            client.call("Sleeper.sleep", 200u64),
+            client.call("Sleeper.sleep", 10u64),
+            client.call("Sleeper.sleep", 100u64),
+        ];
+        let replies: Vec<u64> = delivery_order(calls)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(replies, vec![200, 10, 100]);
+
+        // a canceled request doesn't hold up the responses after it
+        let mut slow: Call<u64> = client.call("Sleeper.sleep", 10_000u64);
+        let fast: Call<u64> = client.call("Sleeper.sleep", 10u64);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        slow.cancel()
+            .await
+            .expect("Cancellation is not acknowledged");
+        assert!(matches!(slow.await, Err(Error::Canceled(_))));
+        assert_eq!(fast.await.unwrap(), 10);
+
+        // neither does a request that times out
+        let slow: Call<u64> = client
+            .set_next_timeout(Duration::from_millis(100))
+            .call("Sleeper.sleep", 10_000u64);
+        let fast: Call<u64> = client.call("Sleeper.sleep", 10u64);
+        let replies = delivery_order(vec![slow, fast]).await;
+        assert!(
+            matches!(replies[0], Err(Error::Timeout(_))),
+            "{:?}",
+            replies
+        );
+        assert_eq!(*replies[1].as_ref().unwrap(), 10);
+        assert_eq!(client.num_pending(), 0);
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod retry {
+    //! Retrying idempotent calls with a `RetryPolicy`
+
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use toy_rpc::{client::RetryPolicy, macros::export_impl, Client, Error, Server};
+
+    use super::common;
+
+    #[derive(Default)]
+    struct Flaky {
+        slow_calls: AtomicU32,
+        failed_calls: AtomicU32,
+    }
+
+    #[export_impl]
+    impl Flaky {
+        /// Takes longer than the timeout of the client until it is called `n` times
+        #[export_method]
+        async fn slow_then_ok(&self, n: u32) -> Result<u32, String> {
+            let count = self.slow_calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if count <= n {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(count)
+        }
+
+        #[export_method]
+        async fn fail(&self, _: ()) -> Result<(), String> {
+            self.failed_calls.fetch_add(1, Ordering::SeqCst);
+            Err("Always fails".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_policy_retries_transport_failures_only() {
+        let flaky = Arc::new(Flaky::default());
+        let server = Server::builder().register(flaky.clone()).build();
+        let (addr, _server_handle) = common::serve(server).await;
+
+        let mut client = Client::builder()
+            .set_retry_policy(RetryPolicy::fixed(2, Duration::from_millis(10)))
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        client.set_default_timeout(Duration::from_millis(200));
+
+        // timeouts are retried with a new request each time
+        let policy = RetryPolicy::exponential(3, Duration::from_millis(10));
+        let reply: u32 = client
+            .call_with_retry("Flaky.slow_then_ok", &2u32, policy)
+            .await
+            .expect("Call failed after retries");
+        assert_eq!(reply, 3);
+        assert_eq!(client.num_pending(), 0);
+
+        // errors returned by the handler are not retried
+        let policy = RetryPolicy::exponential(3, Duration::from_millis(10));
+        let res: Result<(), Error> = client.call_with_retry("Flaky.fail", &(), policy).await;
+        assert!(matches!(res, Err(Error::ExecutionError(_))), "{:?}", res);
+        assert_eq!(flaky.failed_calls.load(Ordering::SeqCst), 1);
+
+        // the default policy of the client allows two attempts
+        flaky.slow_calls.store(0, Ordering::SeqCst);
+        let res: Result<u32, Error> = client
+            .call_with_default_retry("Flaky.slow_then_ok", &100u32)
+            .await;
+        match res {
+            Err(Error::RetriesExhausted { attempts, last }) => {
+                assert_eq!(attempts, 2);
+                assert!(matches!(*last, Error::Timeout(_)), "{:?}", last);
+            }
+            res => panic!("Expecting Error::RetriesExhausted, found {:?}", res),
+        }
+        assert_eq!(flaky.slow_calls.load(Ordering::SeqCst), 2);
+        // the broker releases the timed out request after the call resolves
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.num_pending(), 0);
+
+        client.close().await;
+    }
+}
+
+mod spawn_task {
+    //! Calls that run in a task of their own
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+    use toy_rpc::{
+        client::{JoinError, Task},
+        macros::export_impl,
+        Client, Server,
+    };
+
+    use super::{common, NotifyOnDrop};
+
+    struct Service {
+        events: UnboundedSender<&'static str>,
+    }
+
+    #[export_impl]
+    impl Service {
+        #[export_method]
+        async fn echo(&self, val: i32) -> Result<i32, String> {
+            Ok(val)
+        }
+
+        #[export_method]
+        async fn wait_forever(&self, _: ()) -> Result<(), String> {
+            let _guard = NotifyOnDrop(self.events.clone());
+            self.events.send("started").map_err(|err| err.to_string())?;
+            futures::future::pending::<()>().await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn_task() {
+        let (tx, mut events) = unbounded_channel();
+        let server = Server::builder()
+            .register(Arc::new(Service { events: tx }))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let task: Task<i32> = client.spawn_task("Service.echo", 7i32);
+        assert_eq!(task.await.unwrap().unwrap(), 7);
+
+        // an error of the call is not an error of the task
+        let task: Task<i32> = client.spawn_task("Service.not_a_method", 7i32);
+        assert!(task.await.unwrap().is_err());
+
+        let task: Task<()> = client.spawn_task("Service.wait_forever", ());
+        let started = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+        assert_eq!(started.unwrap(), Some("started"));
+        task.cancel();
+        assert!(matches!(task.await, Err(JoinError::Canceled)));
+
+        // the call is canceled on the server as well
+        let stopped = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+        assert_eq!(stopped.unwrap(), Some("stopped"));
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod trace_id {
+    //! Trace ids carried from the client to the handlers and on to the downstream calls
+
+    use std::sync::Arc;
+    use toy_rpc::{
+        macros::export_impl,
+        protocol::RequestMetadata,
+        server::Context,
+        trace_id::{TraceId, TRACE_ID_KEY},
+        Client, Server,
+    };
+
+    use super::common;
+
+    fn current_trace_id() -> Option<TraceId> {
+        Context::current()
+            .expect("Called outside of a request")
+            .trace_id()
+    }
+
+    struct Backend {}
+
+    #[export_impl]
+    impl Backend {
+        #[export_method]
+        async fn trace_id(&self, _: ()) -> Result<Option<String>, String> {
+            Ok(current_trace_id().map(|trace_id| trace_id.to_string()))
+        }
+    }
+
+    struct Frontend {
+        backend: Client,
+    }
+
+    #[export_impl]
+    impl Frontend {
+        /// Returns the trace ids seen by this handler and by the downstream call
+        #[export_method]
+        async fn forward(&self, _: ()) -> Result<(Option<String>, Option<String>), String> {
+            let own = current_trace_id().map(|trace_id| trace_id.to_string());
+            let downstream = self
+                .backend
+                .call("Backend.trace_id", ())
+                .await
+                .map_err(|err| err.to_string())?;
+            Ok((own, downstream))
+        }
+    }
+
+    #[tokio::test]
+    async fn trace_id_end_to_end() {
+        let backend = Server::builder().register(Arc::new(Backend {})).build();
+        let (backend_addr, _backend_handle) = common::serve(backend).await;
+        let backend = Client::builder()
+            .set_trace_ids(true)
+            .dial(backend_addr)
+            .await
+            .expect("Error dialing");
+        let frontend = Server::builder()
+            .register(Arc::new(Frontend { backend }))
+            .build();
+        let (frontend_addr, _frontend_handle) = common::serve(frontend).await;
+
+        // the trace id is optional
+        let client = Client::dial(backend_addr).await.expect("Error dialing");
+        let reply: Option<String> = client.call("Backend.trace_id", ()).await.unwrap();
+        assert_eq!(reply, None);
+        client.close().await;
+
+        // a new trace id is generated for every call
+        let client = Client::builder()
+            .set_trace_ids(true)
+            .dial(backend_addr)
+            .await
+            .expect("Error dialing");
+        let first: Option<String> = client.call("Backend.trace_id", ()).await.unwrap();
+        let second: Option<String> = client.call("Backend.trace_id", ()).await.unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert_ne!(first, second);
+
+        // a trace id that is provided is kept
+        let trace_id = TraceId::new();
+        let mut metadata = RequestMetadata::new();
+        trace_id.insert_into(&mut metadata);
+        assert_eq!(metadata[TRACE_ID_KEY], trace_id.to_string());
+        let reply: Option<String> = client
+            .call_with_metadata("Backend.trace_id", (), metadata)
+            .await
+            .unwrap();
+        assert_eq!(reply, Some(trace_id.to_string()));
+        client.close().await;
+
+        // and carried on to the downstream calls of the handler
+        let client = Client::dial(frontend_addr).await.expect("Error dialing");
+        let mut metadata = RequestMetadata::new();
+        trace_id.insert_into(&mut metadata);
+        let reply: (Option<String>, Option<String>) = client
+            .call_with_metadata("Frontend.forward", (), metadata)
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            (Some(trace_id.to_string()), Some(trace_id.to_string()))
+        );
+        client.close().await;
+    }
+}
+
+mod shared_client {
+    //! Clones of a client share the connection across tasks
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::task;
+    use toy_rpc::{Client, Server};
+
+    use super::common;
+    use super::rpc;
+
+    const NUM_TASKS: usize = 100;
+
+    #[tokio::test]
+    async fn shared_client() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let disconnected = client.on_disconnect();
+
+        let handles: Vec<_> = (0..NUM_TASKS)
+            .map(|_| {
+                let client = client.clone();
+                task::spawn(async move {
+                    for _ in 0..10 {
+                        rpc::test_get_magic_i32(&client).await;
+                        rpc::test_get_magic_str(&client).await;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(client.num_pending(), 0);
+
+        // dropping a clone leaves the connection open
+        let clone = client.clone();
+        drop(client);
+        assert!(clone.is_connected());
+        rpc::test_get_magic_u8(&clone).await;
+
+        // dropping the last one closes it
+        drop(clone);
+        tokio::time::timeout(Duration::from_secs(1), disconnected)
+            .await
+            .expect("Connection is not closed when the last clone is dropped");
+
+        server_handle.abort();
+    }
+}
+
+mod metrics {
+    //! Metrics count the calls and the bytes on both ends of the connections
+
+    use std::{sync::Arc, time::Duration};
+    use toy_rpc::{Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    #[tokio::test]
+    async fn metrics() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server.clone()).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let metrics = client.clone();
+        for _ in 0..5 {
+            rpc::test_get_magic_i32(&client).await;
+        }
+        let result: Result<(), Error> = client
+            .call("CommonTest.echo_error", "an error".to_string())
+            .await;
+        assert!(result.is_err());
+        client.close().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client_metrics = metrics.metrics();
+        assert_eq!(client_metrics.requests, 6);
+        assert_eq!(client_metrics.responses, 6);
+        assert_eq!(client_metrics.errors, 1);
+
+        // the closed connection is still counted by the server
+        assert!(server.connections().is_empty());
+        let server_metrics = server.metrics();
+        assert_eq!(server_metrics.requests, 6);
+        assert_eq!(server_metrics.responses, 6);
+        assert_eq!(server_metrics.errors, 1);
+
+        assert!(client_metrics.bytes_sent > 0);
+        assert_eq!(client_metrics.bytes_sent, server_metrics.bytes_received);
+        assert_eq!(client_metrics.bytes_received, server_metrics.bytes_sent);
+
+        server_handle.abort();
+    }
+}
+
+mod disconnect {
+    //! Connection liveness of the client
+
+    use std::time::Duration;
+    use tokio::{sync::oneshot, task};
+    use toy_rpc::{Client, Error};
+
+    use super::common;
+
+    #[tokio::test]
+    async fn disconnect() {
+        // a server that accepts the connection and drops it when told to
+        let (listener, addr) = common::bind().await;
+        let (drop_tx, drop_rx) = oneshot::channel::<()>();
+        let server_handle = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = drop_rx.await;
+            drop(stream);
+        });
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        assert!(client.is_connected());
+        let disconnected = client.on_disconnect();
+
+        // never answered by the server
+        let call = client.call::<_, ()>("Echo.echo", ());
+        let watcher = task::spawn(disconnected);
+        drop_tx.send(()).unwrap();
+
+        let result = call.await;
+        assert!(result.is_err());
+        assert!(!matches!(result, Err(Error::Timeout(_))));
+        // the state is updated before the pending call fails
+        assert!(!client.is_connected());
+        tokio::time::timeout(Duration::from_secs(1), watcher)
+            .await
+            .expect("on_disconnect does not resolve")
+            .unwrap();
+
+        // resolves right away once disconnected
+        client.on_disconnect().await;
+        client.close().await;
+        server_handle.await.unwrap();
+    }
+}
+
+mod reconnect {
+    //! Message ids are reused after reconnecting
+    //!
+    //! Every client starts counting message ids from zero, so a client that replaces a
+    //! closed one sends requests with the same ids as the requests that were still
+    //! pending on the old connection. The late responses to those requests must not
+    //! complete the calls of the new client.
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::task;
+    use toy_rpc::{Client, Server};
+
+    use super::common::{self, Sleeper};
+
+    #[tokio::test]
+    async fn late_responses_do_not_reach_a_new_client() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, _server_handle) = common::serve(server).await;
+
+        // the response to this call arrives after the client is closed, as closing doesn't
+        // wait for it beyond the close timeout
+        let old = Client::builder()
+            .set_close_timeout(Duration::from_millis(50))
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        let straggler = old.call::<_, u64>("Sleeper.sleep", 200u64);
+        let id = straggler.id();
+        let straggler = task::spawn(straggler);
+        old.close().await;
+
+        let new = Client::dial(addr).await.expect("Error dialing server");
+        let call = new.call::<_, u64>("Sleeper.sleep", 400u64);
+        assert_eq!(call.id(), id);
+        let reply = call.await.expect("Call of the new client failed");
+        assert_eq!(reply, 400);
+
+        let res = straggler.await.unwrap();
+        assert!(
+            res.is_err(),
+            "Call of the closed client is completed: {:?}",
+            res
+        );
+
+        // the connection of the new client is not disturbed by the late response
+        let reply: u64 = new
+            .call("Sleeper.sleep", 0u64)
+            .await
+            .expect("Call of the new client failed");
+        assert_eq!(reply, 0);
+        new.close().await;
+    }
+}
+
+mod connect_timeout {
+    //! Timeout of dialing a server that doesn't respond
+
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::task;
+    use toy_rpc::{client::Proxy, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn assert_timed_out<T>(result: Result<T, Error>, started: Instant) {
+        match result {
+            Err(Error::ConnectTimeout { timeout, .. }) => assert_eq!(timeout, TIMEOUT),
+            Err(err) => panic!("Unexpected error {:?}", err),
+            Ok(_) => panic!("Dialing is expected to time out"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        // accepts connections and never responds
+        let (silent, silent_addr) = common::bind().await;
+        let silent_handle = task::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = silent.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let client = Client::dial_timeout(addr, TIMEOUT)
+            .await
+            .expect("Error dialing server");
+        rpc::test_get_magic_u32(&client).await;
+        client.close().await;
+
+        // the proxy never answers the SOCKS5 greeting
+        let started = Instant::now();
+        let result = Client::builder()
+            .set_proxy(Proxy::socks5(silent_addr))
+            .set_connect_timeout(TIMEOUT)
+            .dial(addr)
+            .await;
+        assert_timed_out(result, started);
+
+        // the server never answers the WebSocket upgrade
+        #[cfg(feature = "ws_tokio")]
+        {
+            let started = Instant::now();
+            let result = Client::builder()
+                .set_connect_timeout(TIMEOUT)
+                .dial_websocket(&format!("ws://{}/", silent_addr))
+                .await;
+            assert_timed_out(result, started);
+        }
+
+        silent_handle.abort();
+        server_handle.abort();
+    }
+}
+
+mod proxy {
+    //! Connecting through a SOCKS5 proxy
+
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        task,
+    };
+    use toy_rpc::{client::Proxy, Client, Error, Server};
+
+    use super::common::{self, Sleeper};
+
+    const USERNAME: &str = "user";
+    const PASSWORD: &str = "secret";
+
+    /// Just enough of SOCKS5 with username/password authentication to forward a connection
+    async fn socks5(mut client: TcpStream) -> std::io::Result<()> {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; header[1] as usize];
+        client.read_exact(&mut methods).await?;
+        assert!(methods.contains(&0x02));
+        client.write_all(&[0x05, 0x02]).await?;
+
+        let mut len = [0u8; 2];
+        client.read_exact(&mut len).await?;
+        let mut username = vec![0u8; len[1] as usize];
+        client.read_exact(&mut username).await?;
+        client.read_exact(&mut len[..1]).await?;
+        let mut password = vec![0u8; len[0] as usize];
+        client.read_exact(&mut password).await?;
+        if username != USERNAME.as_bytes() || password != PASSWORD.as_bytes() {
+            return client.write_all(&[0x01, 0x01]).await;
+        }
+        client.write_all(&[0x01, 0x00]).await?;
+
+        let mut request = [0u8; 4];
+        client.read_exact(&mut request).await?;
+        assert_eq!(request[..3], [0x05, 0x01, 0x00]);
+        assert_eq!(request[3], 0x01);
+        let mut addr = [0u8; 6];
+        client.read_exact(&mut addr).await?;
+        let ip = std::net::Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+        let port = u16::from_be_bytes([addr[4], addr[5]]);
+        let mut server = TcpStream::connect((ip, port)).await?;
+        client
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await?;
+        tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let (proxy_listener, proxy_addr) = common::bind().await;
+        let proxy_handle = task::spawn(async move {
+            while let Ok((stream, _)) = proxy_listener.accept().await {
+                task::spawn(socks5(stream));
+            }
+        });
+
+        let client = Client::builder()
+            .set_proxy(Proxy::socks5(proxy_addr).with_auth(USERNAME, PASSWORD))
+            .dial(addr)
+            .await
+            .expect("Error dialing server through the proxy");
+        assert_eq!(client.peer_addr(), Some(addr));
+        let reply: i32 = client.call("Sleeper.echo", 7).await.unwrap();
+        assert_eq!(reply, 7);
+        client.close().await;
+
+        let result = Client::builder()
+            .set_proxy(Proxy::socks5(proxy_addr).with_auth(USERNAME, "wrong"))
+            .dial(addr)
+            .await;
+        assert!(matches!(result, Err(Error::ProxyError(_))));
+
+        proxy_handle.abort();
+        server_handle.abort();
+    }
+}
+
+mod failover {
+    //! Dialing several addresses with failover
+    //!
+    //! The pool first connects through a relay, which is then shut down so that redialing
+    //! has to move on to the server itself
+
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{
+        net::TcpListener,
+        task::{self, JoinHandle},
+    };
+    use toy_rpc::{Client, Error, Server};
+
+    use super::common::{self, relay, Relays};
+    use super::{expect_magic, rpc};
+
+    #[tokio::test]
+    async fn dial_fails_over_to_the_next_address() {
+        // nothing listens on this address once the listener is dropped
+        let (listener, dead_addr) = common::bind().await;
+        drop(listener);
+
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (server_addr, server_handle) = common::serve(server).await;
+
+        // the addresses are tried in order
+        let client = Client::dial(&[dead_addr, server_addr][..])
+            .await
+            .expect("Error dialing");
+        assert_eq!(client.peer_addr(), Some(server_addr));
+        expect_magic(client.call("CommonTest.get_magic_u32", ()).await);
+        client.close().await;
+
+        match Client::dial(&[dead_addr][..]).await {
+            Err(Error::IoError(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused)
+            }
+            Err(err) => panic!("Expecting Error::IoError, found {:?}", err),
+            Ok(_) => panic!("Expecting an error"),
+        }
+
+        // the pool keeps to the first address that accepts the connection
+        let (listener, relay_addr) = common::bind().await;
+        let relays = Relays::default();
+        let relay_handle = task::spawn(relay(listener, server_addr, relays.clone()));
+        let mut pool = Client::dial_pool(&[dead_addr, relay_addr, server_addr][..], 2)
+            .await
+            .expect("Error dialing pool");
+        assert_eq!(pool.addr(), relay_addr);
+        assert_eq!(pool.addrs(), &[dead_addr, relay_addr, server_addr]);
+        expect_magic(pool.call("CommonTest.get_magic_u32", ()).await);
+
+        // and moves on to the next address once it stops accepting connections
+        relay_handle.abort();
+        let _ = relay_handle.await;
+        for handle in relays.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(pool.num_connected(), 0);
+        assert_eq!(pool.redial().await.expect("Error redialing"), 2);
+        assert_eq!(pool.addr(), server_addr);
+        expect_magic(pool.call("CommonTest.get_magic_u32", ()).await);
+
+        pool.close().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server_handle.abort();
+    }
+
+    async fn serve_on(addr: SocketAddr) -> Option<(SocketAddr, JoinHandle<Result<(), Error>>)> {
+        // the host may not have the address family
+        let listener = TcpListener::bind(addr).await.ok()?;
+        let addr = listener.local_addr().ok()?;
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let handle = task::spawn(async move { server.accept(listener).await });
+        Some((addr, handle))
+    }
+
+    #[tokio::test]
+    async fn dial_resolves_hostname() {
+        // `localhost` may resolve to both `::1` and `127.0.0.1`, in either order, and
+        // only the IPv4 address is listened on
+        let (v4_addr, v4_handle) = serve_on(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Cannot bind to address");
+        let port = v4_addr.port();
+        let v6_addr = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port));
+        let hostname = format!("localhost:{}", port);
+        let resolved: Vec<_> = tokio::net::lookup_host(hostname.as_str())
+            .await
+            .expect("Cannot resolve localhost")
+            .collect();
+        assert!(resolved.contains(&v4_addr));
+        let client = Client::dial(hostname.as_str())
+            .await
+            .expect("Error dialing");
+        assert_eq!(client.peer_addr(), Some(v4_addr));
+        expect_magic(client.call("CommonTest.get_magic_u32", ()).await);
+        client.close().await;
+
+        // an IPv6 literal, on the port that is free for IPv4
+        if let Some((_, v6_handle)) = serve_on(v6_addr).await {
+            let client = Client::dial(format!("[::1]:{}", port).as_str())
+                .await
+                .expect("Error dialing");
+            assert_eq!(client.peer_addr(), Some(v6_addr));
+            expect_magic(client.call("CommonTest.get_magic_u32", ()).await);
+            client.close().await;
+
+            // with both families listened on, the first resolved address is used
+            let client = Client::dial(hostname.as_str())
+                .await
+                .expect("Error dialing");
+            assert_eq!(client.peer_addr(), Some(resolved[0]));
+            client.close().await;
+            v6_handle.abort();
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        v4_handle.abort();
+    }
+}
+
+mod pool {
+    //! Spreading calls over a pool of connections
+    //!
+    //! The connections go through a relay, so that they can be closed one at a time
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::task;
+    use toy_rpc::{
+        client::{Balance, Call},
+        Client, Error, Server,
+    };
+
+    use super::common::{self, relay, Relays};
+    use super::{expect_magic, rpc};
+
+    fn close_relays(relays: &Relays, n: usize) {
+        for handle in relays.lock().unwrap().drain(..n) {
+            handle.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_spreads_calls_over_connections() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (server_addr, server_handle) = common::serve(server.clone()).await;
+        let relays = Relays::default();
+        let (listener, relay_addr) = common::bind().await;
+        let relay_handle = task::spawn(relay(listener, server_addr, relays.clone()));
+
+        let mut pool = Client::dial_pool(relay_addr, 3)
+            .await
+            .expect("Error dialing pool");
+        assert_eq!(pool.size(), 3);
+
+        // every connection takes a share of the calls
+        for _ in 0..6 {
+            expect_magic(pool.call("CommonTest.get_magic_u32", ()).await);
+        }
+        assert_eq!(server.connections().len(), 3);
+
+        // the cancellation goes to the connection of the request
+        let mut call: Call<()> = pool.call("CommonTest.wait_forever", ());
+        call.cancel()
+            .await
+            .expect("Cancellation is not acknowledged");
+
+        // a closed connection is taken out of the rotation
+        close_relays(&relays, 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(pool.num_connected(), 2);
+        pool.set_balance(Balance::LeastPending);
+        for _ in 0..6 {
+            expect_magic(pool.call("CommonTest.get_magic_u32", ()).await);
+        }
+
+        // calls fail right away without any open connection
+        close_relays(&relays, 2);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(pool.num_connected(), 0);
+        match pool.call::<_, u32>("CommonTest.get_magic_u32", ()).await {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotConnected),
+            res => panic!("Expecting Error::IoError, found {:?}", res),
+        }
+
+        assert_eq!(pool.redial().await.expect("Error redialing"), 3);
+        assert_eq!(pool.num_connected(), 3);
+        expect_magic(pool.call("CommonTest.get_magic_u32", ()).await);
+
+        pool.close().await;
+        relay_handle.abort();
+        server_handle.abort();
+    }
+}
@@ -0,0 +1,720 @@
+//! Life of a connection: handshake, heartbeats, closing, and how frames are read and written
+
+mod common;
+mod rpc;
+
+mod handshake {
+    //! Limits declared during the handshake are enforced on both sides, and the codec
+    //! announced before the handshake is checked
+
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::task::JoinHandle;
+    use toy_rpc::{
+        macros::export_impl,
+        protocol::ConnectionLimits,
+        pubsub::{AckModeNone, Topic},
+        Client, Error, Server,
+    };
+
+    use super::common;
+
+    /// Keeps track of the max number of concurrently executing calls
+    #[derive(Default)]
+    struct Slow {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    #[export_impl]
+    impl Slow {
+        #[export_method]
+        async fn wait(&self, millis: u64) -> Result<(), String> {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    macro_rules! topics {
+        ($($name:ident),*) => {
+            $(
+                struct $name;
+
+                impl Topic for $name {
+                    type Item = String;
+
+                    fn topic() -> String {
+                        String::from(stringify!($name))
+                    }
+                }
+            )*
+        };
+    }
+
+    topics!(TopicA, TopicB, TopicC, TopicD);
+
+    fn server_limits() -> ConnectionLimits {
+        ConnectionLimits::new()
+            .set_max_concurrent_calls(2)
+            .set_max_subscriptions(3)
+    }
+
+    async fn serve(
+        service: Arc<Slow>,
+    ) -> (
+        Server<AckModeNone>,
+        SocketAddr,
+        JoinHandle<Result<(), Error>>,
+    ) {
+        let server = Server::builder()
+            .register(service)
+            .set_connection_limits(server_limits())
+            .build();
+        let (addr, handle) = common::serve(server.clone()).await;
+        (server, addr, handle)
+    }
+
+    fn num_protocol_violations(server: &Server<AckModeNone>) -> u64 {
+        server
+            .connections()
+            .values()
+            .map(|stats| stats.protocol_violations)
+            .sum()
+    }
+
+    async fn five_concurrent_calls(client: &Client<AckModeNone>) -> Vec<Result<(), Error>> {
+        let calls = (0..5).map(|_| client.call("Slow.wait", 200u64));
+        futures::future::join_all(calls).await
+    }
+
+    #[tokio::test]
+    async fn negotiated_limits_are_enforced_by_the_client() {
+        let service = Arc::new(Slow::default());
+        let (server, addr, handle) = serve(service.clone()).await;
+
+        let client_limits = ConnectionLimits::new()
+            .set_max_concurrent_calls(4)
+            .set_max_subscriptions(1);
+        let mut client = Client::builder()
+            .set_connection_limits(client_limits)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+
+        // the client never has more than 2 calls outstanding
+        let replies = five_concurrent_calls(&client).await;
+        assert!(replies.iter().all(|reply| reply.is_ok()));
+        assert_eq!(service.max.load(Ordering::SeqCst), 2);
+
+        let info = client.connection_info().expect("Handshake is not complete");
+        assert_eq!(info.limits.max_concurrent_calls, Some(2));
+        assert_eq!(info.limits.max_subscriptions, Some(1));
+
+        // the client refuses to subscribe beyond its own limit
+        let _sub = client.subscriber::<TopicA>(None).unwrap();
+        match client.subscriber::<TopicB>(None) {
+            Err(Error::Busy) => {}
+            Err(err) => panic!("Unexpected error {:?}", err),
+            Ok(_) => panic!("Expecting Error::Busy"),
+        }
+
+        assert_eq!(num_protocol_violations(&server), 0);
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn server_rejects_violations_of_its_limits() {
+        let service = Arc::new(Slow::default());
+        let (server, addr, handle) = serve(service.clone()).await;
+
+        // a client that skips the handshake
+        let mut client = Client::dial(addr).await.expect("Error dialing server");
+        assert!(client.connection_info().is_none());
+
+        let replies = five_concurrent_calls(&client).await;
+        assert_eq!(replies.iter().filter(|reply| reply.is_ok()).count(), 2);
+        assert!(replies
+            .iter()
+            .filter_map(|reply| reply.as_ref().err())
+            .all(|err| matches!(err, Error::Busy)));
+        assert_eq!(service.max.load(Ordering::SeqCst), 2);
+        assert_eq!(num_protocol_violations(&server), 3);
+
+        // the server ignores the subscription beyond its limit
+        let _a = client.subscriber::<TopicA>(None).unwrap();
+        let _b = client.subscriber::<TopicB>(None).unwrap();
+        let _c = client.subscriber::<TopicC>(None).unwrap();
+        let _d = client.subscriber::<TopicD>(None).unwrap();
+        let mut violations = 0;
+        for _ in 0..50 {
+            violations = num_protocol_violations(&server);
+            if violations == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(violations, 4);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn calls_go_through_once_the_codec_is_negotiated() {
+        let server = Server::builder()
+            .register(Arc::new(Slow::default()))
+            .negotiate_codec(true)
+            .build();
+        let (addr, handle) = common::serve(server).await;
+
+        let client = Client::builder()
+            .negotiate_codec(true)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        let reply: Result<(), Error> = client.call("Slow.wait", 10u64).await;
+        assert!(reply.is_ok());
+        assert!(client.connection_info().is_some());
+
+        // a client that doesn't announce its codec is served as usual
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let reply: Result<(), Error> = client.call("Slow.wait", 10u64).await;
+        assert!(reply.is_ok());
+
+        handle.abort();
+    }
+
+    /// A header frame of message 0 carrying `payload`
+    #[cfg(not(feature = "serde_json"))]
+    fn header_frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![14, 1]; // magic and version
+        bytes.extend_from_slice(&0u64.to_le_bytes()); // message id
+        bytes.push(0); // frame id
+        bytes.push(0); // payload type
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes()); // payload length
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    #[tokio::test]
+    async fn calls_fail_when_the_server_announces_another_codec() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a server that announces another codec and hangs up
+        let (listener, addr) = common::bind().await;
+        let handle = tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let _ = stream.read(&mut buf).await;
+            // the call of the client is held back in the meantime
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = stream
+                .write_all(&header_frame(b"toy-rpc/codec/other\n"))
+                .await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+
+        let client = Client::builder()
+            .negotiate_codec(true)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        let reply: Result<(), Error> = client.call("Slow.wait", 10u64).await;
+        match reply {
+            Err(Error::CodecMismatch { found, .. }) => assert_eq!(found, "other"),
+            other => panic!("Expecting Error::CodecMismatch, found {:?}", other),
+        }
+
+        handle.abort();
+    }
+}
+
+mod heartbeat {
+    //! Heartbeats keep a healthy connection open and detect a dead one
+
+    use async_trait::async_trait;
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+    use tokio::{io::AsyncReadExt, task};
+    use toy_rpc::{
+        client::Heartbeat,
+        server::{Interceptor, Next, RequestHeader},
+        service::HandlerResult,
+        Client, Error, Server,
+    };
+
+    use super::common;
+    use super::rpc;
+
+    /// Counts the requests that are dispatched to the handlers
+    struct Count(Arc<AtomicU32>);
+
+    #[async_trait]
+    impl Interceptor for Count {
+        async fn intercept(&self, header: &RequestHeader, next: Next) -> HandlerResult {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            next.run(header).await
+        }
+    }
+
+    #[tokio::test]
+    async fn healthy_connection() {
+        let count = Arc::new(AtomicU32::new(0));
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .layer(Count(count.clone()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let heartbeat = Heartbeat::new(Duration::from_millis(50), Duration::from_millis(200))
+            .with_max_missed(1);
+        let client = Client::builder()
+            .set_heartbeat(heartbeat)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(client.is_connected());
+        let liveness = client.stats().liveness;
+        assert!(liveness.last_frame_received_at.is_some());
+        assert!(liveness.last_ping_rtt.is_some());
+        assert_eq!(liveness.missed_pongs, 0);
+        assert_eq!(liveness.heartbeat_interval, Some(Duration::from_millis(50)));
+        assert_eq!(liveness.heartbeat_timeout, Some(Duration::from_millis(200)));
+        // the heartbeats are answered without running any handler
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        rpc::test_get_magic_i32(&client).await;
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn dead_connection() {
+        // a peer that reads everything and never answers, like a connection that is
+        // silently dropped on the way
+        let (listener, addr) = common::bind().await;
+        let server_handle = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+
+        let heartbeat =
+            Heartbeat::new(Duration::from_millis(50), Duration::from_millis(50)).with_max_missed(2);
+        let mut client = Client::builder()
+            .set_heartbeat(heartbeat)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        client.set_default_timeout(Duration::from_secs(10));
+        let disconnected = client.on_disconnect();
+
+        let start = Instant::now();
+        let result = client.call::<_, i32>("CommonTest.get_magic_i32", ()).await;
+        assert!(
+            matches!(result, Err(Error::MissedHeartbeats(2))),
+            "{:?}",
+            result
+        );
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+        assert!(!client.is_connected());
+        assert_eq!(client.stats().liveness.missed_pongs, 2);
+        tokio::time::timeout(Duration::from_secs(1), disconnected)
+            .await
+            .expect("on_disconnect does not resolve");
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod graceful_close {
+    //! Closing the client waits for the pending requests and the server, and reports
+    //! the requests that are dropped
+
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::task;
+    use toy_rpc::{client::CloseSummary, Client, Error, Server};
+
+    use super::common::{self, Sleeper};
+
+    async fn close_with_pending_call<AckMode>(client: Client<AckMode>) {
+        let call = client.call::<_, u64>("Sleeper.sleep", 300u64);
+        let (reply, summary) = futures::join!(call, client.close());
+        assert_eq!(reply.unwrap(), 300);
+        assert_eq!(
+            summary,
+            CloseSummary {
+                flushed: 1,
+                dropped: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn graceful_close() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server.clone()).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        close_with_pending_call(client).await;
+
+        #[cfg(feature = "ws_tokio")]
+        {
+            let (ws_listener, ws_addr) = common::bind().await;
+            let ws_handle = task::spawn(async move { server.accept_websocket(ws_listener).await });
+            let client = Client::dial_websocket(&format!("ws://{}", ws_addr))
+                .await
+                .expect("Error dialing server");
+            close_with_pending_call(client).await;
+            ws_handle.abort();
+        }
+
+        // the close timeout bounds the wait for a server that doesn't respond
+        // accepts connections and never responds or closes them
+        let (silent, silent_addr) = common::bind().await;
+        let silent_handle = task::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = silent.accept().await {
+                streams.push(stream);
+            }
+        });
+        let client = Client::builder()
+            .set_close_timeout(Duration::from_millis(200))
+            .dial(silent_addr)
+            .await
+            .expect("Error dialing server");
+        let call = client.call::<_, u64>("Sleeper.sleep", 0u64);
+        let started = Instant::now();
+        let (reply, summary) = futures::join!(call, client.close());
+        assert!(matches!(reply, Err(Error::Canceled(_))));
+        assert_eq!(
+            summary,
+            CloseSummary {
+                flushed: 0,
+                dropped: 1
+            }
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        silent_handle.abort();
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn close_is_idempotent() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let clone = client.clone();
+        let survivor = client.clone();
+        let (first, second) = futures::join!(client.close(), clone.close());
+        assert_eq!(first, CloseSummary::default());
+        assert_eq!(second, CloseSummary::default());
+
+        // the connection is closed once `close` returns
+        let reply = survivor.call::<_, u64>("Sleeper.sleep", 0u64).await;
+        assert!(reply.is_err());
+        assert_eq!(survivor.close().await, CloseSummary::default());
+
+        server_handle.abort();
+    }
+}
+
+mod frame_timeout {
+    //! Connections that stall in the middle of a frame are closed
+
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        task,
+    };
+    use toy_rpc::{Client, Server};
+
+    use super::common;
+    use super::rpc;
+
+    const FRAME_READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// Writes the magic and version bytes, a frame header announcing a 64 bytes payload,
+    /// and only the first 8 bytes of the payload
+    async fn write_partial_frame(stream: &mut TcpStream) {
+        let mut bytes = vec![14, 1]; // magic and version
+        bytes.extend_from_slice(&1u64.to_le_bytes()); // message id
+        bytes.push(0); // frame id
+        bytes.push(0); // payload type
+        bytes.extend_from_slice(&64u32.to_le_bytes()); // payload length
+        bytes.extend_from_slice(&[0; 8]);
+        stream.write_all(&bytes).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_closes_stalled_connection() {
+        let common_test_service = Arc::new(rpc::CommonTest::new());
+        let server = Server::builder()
+            .register(common_test_service)
+            .set_frame_read_timeout(FRAME_READ_TIMEOUT)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        // requests that keep making progress are not affected
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        rpc::test_get_magic_u32(&client).await;
+        client.close().await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        write_partial_frame(&mut stream).await;
+
+        // the server closes the connection once the frame stalls
+        let mut buf = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .expect("Server did not close the stalled connection");
+        assert!(start.elapsed() >= FRAME_READ_TIMEOUT);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn client_closes_stalled_connection() {
+        let (listener, addr) = common::bind().await;
+        // a fake server that starts a response frame but never finishes it
+        let fake_server = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            write_partial_frame(&mut stream).await;
+            futures::future::pending::<()>().await;
+            drop(stream);
+        });
+
+        let client = Client::builder()
+            .set_frame_read_timeout(FRAME_READ_TIMEOUT)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        let reply: Result<u32, toy_rpc::Error> = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.call("CommonTest.get_magic_u32", ()),
+        )
+        .await
+        .expect("Client did not close the stalled connection");
+        assert!(reply.is_err());
+
+        fake_server.abort();
+    }
+}
+
+mod flush_policy {
+    //! Buffered flush policies don't hold back the last message of a burst
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        task,
+    };
+    use toy_rpc::{
+        codec::{DefaultCodec, FlushPolicy},
+        Client, Server,
+    };
+
+    use super::common::{self, Sleeper};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn run_with_policy(listener: &TcpListener, server: &Server, policy: FlushPolicy) {
+        let server = server.clone();
+        let addr = listener.local_addr().unwrap();
+        let (client_stream, server_stream) =
+            tokio::join!(TcpStream::connect(addr), listener.accept());
+        let server_codec = DefaultCodec::new(server_stream.unwrap().0).with_flush_policy(policy);
+        let server_handle = task::spawn(async move { server.serve_codec(server_codec).await });
+        let client_codec = DefaultCodec::new(client_stream.unwrap()).with_flush_policy(policy);
+        let client = Client::with_codec(client_codec);
+
+        // a burst of pipelined calls
+        let calls = (0..100).map(|i| client.call("Sleeper.echo", i));
+        let replies: Vec<Result<i32, _>> =
+            tokio::time::timeout(TIMEOUT, futures::future::join_all(calls))
+                .await
+                .expect("Calls stalled in the write buffer");
+        for (i, reply) in replies.into_iter().enumerate() {
+            assert_eq!(reply.unwrap(), i as i32);
+        }
+
+        // a lone call with nothing queued after it
+        let reply: i32 = tokio::time::timeout(TIMEOUT, client.call("Sleeper.echo", 7i32))
+            .await
+            .expect("Call stalled in the write buffer")
+            .unwrap();
+        assert_eq!(reply, 7);
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn flush_policy() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (listener, _) = common::bind().await;
+
+        for policy in [FlushPolicy::Manual, FlushPolicy::OnIdle] {
+            run_with_policy(&listener, &server, policy).await;
+        }
+    }
+}
+
+mod buffer_capacity {
+    //! Read and write buffers of custom capacities
+
+    use std::sync::Arc;
+    use toy_rpc::{macros::export_impl, Client, Server};
+
+    use super::common;
+
+    const LARGE_LEN: usize = 1024 * 1024;
+
+    struct Payload {}
+
+    #[export_impl]
+    impl Payload {
+        #[export_method]
+        async fn echo(&self, val: String) -> Result<String, String> {
+            Ok(val)
+        }
+    }
+
+    #[tokio::test]
+    async fn buffer_capacity() {
+        // buffers much smaller than the frames on the server
+        let server = Server::builder()
+            .register(Arc::new(Payload {}))
+            .set_buffer_capacity(64, 64)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        // buffers larger than the frames on the client
+        let client = Client::builder()
+            .set_buffer_capacity(4 * LARGE_LEN, 4 * LARGE_LEN)
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+
+        for len in [0, 10, 64, 1000, LARGE_LEN] {
+            let val = "a".repeat(len);
+            let reply: String = client.call("Payload.echo", val.clone()).await.unwrap();
+            assert_eq!(reply, val);
+        }
+
+        let calls = (0..10).map(|i| client.call("Payload.echo", i.to_string()));
+        let replies: Vec<Result<String, _>> = futures::future::join_all(calls).await;
+        for (i, reply) in replies.into_iter().enumerate() {
+            assert_eq!(reply.unwrap(), i.to_string());
+        }
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod chunked_response {
+    //! Large responses are written in chunks and don't hold up the small ones
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::task;
+    use toy_rpc::{macros::export_impl, Client, Server};
+
+    use super::common;
+
+    const CHUNK_SIZE: usize = 16 * 1024;
+    const HUGE_LEN: usize = 32 * 1024 * 1024;
+
+    struct Payload {}
+
+    #[export_impl]
+    impl Payload {
+        #[export_method]
+        async fn huge(&self, len: usize) -> Result<String, String> {
+            Ok("a".repeat(len))
+        }
+
+        #[export_method]
+        async fn tiny(&self, val: u32) -> Result<u32, String> {
+            Ok(val)
+        }
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_interleaved() {
+        let server = Server::builder()
+            .register(Arc::new(Payload {}))
+            .set_response_chunk_size(CHUNK_SIZE)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Arc::new(Client::dial(addr).await.expect("Error dialing server"));
+
+        // responses below the chunk size are not affected
+        let small: String = client.call("Payload.huge", 100usize).await.unwrap();
+        assert_eq!(small.len(), 100);
+
+        let huge = {
+            let client = client.clone();
+            task::spawn(async move {
+                let reply: Result<String, _> = client.call("Payload.huge", HUGE_LEN).await;
+                reply
+            })
+        };
+        // wait until the huge response is being written
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let tiny = (0..10u32).map(|i| client.call("Payload.tiny", i));
+        let replies: Vec<Result<u32, _>> = futures::future::join_all(tiny).await;
+        for (i, reply) in replies.into_iter().enumerate() {
+            assert_eq!(reply.unwrap(), i as u32);
+        }
+        assert!(
+            !huge.is_finished(),
+            "The small responses waited for the huge response"
+        );
+
+        let reply = huge.await.unwrap().unwrap();
+        assert_eq!(reply.len(), HUGE_LEN);
+        assert!(reply.bytes().all(|b| b == b'a'));
+
+        server_handle.abort();
+    }
+}
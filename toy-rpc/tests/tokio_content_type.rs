@@ -0,0 +1,67 @@
+//! Calls with a per-call content type share one connection with the other calls
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use toy_rpc::{codec::ContentType, macros::export_impl, Client, Error, Server};
+
+mod common;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Blob {
+    name: String,
+    bytes: Vec<u8>,
+}
+
+struct Storage {}
+
+#[export_impl]
+impl Storage {
+    #[export_method]
+    async fn echo(&self, blob: Blob) -> Result<Blob, String> {
+        Ok(blob)
+    }
+
+    #[export_method]
+    async fn reject(&self, name: String) -> Result<(), String> {
+        Err(format!("{} is rejected", name))
+    }
+}
+
+#[tokio::test]
+async fn content_type() {
+    let server = Server::builder().register(Arc::new(Storage {})).build();
+    let (addr, server_handle) = common::serve(server).await;
+
+    let client = Client::dial(addr).await.expect("Error dialing server");
+    let blob = Blob {
+        name: "blob".into(),
+        bytes: (0..=255).collect(),
+    };
+
+    // json and bincode bodies are interleaved on the same connection
+    let calls = (0..10).map(|i| {
+        let content_type = match i % 2 {
+            0 => ContentType::Json,
+            _ => ContentType::Bincode,
+        };
+        client.call_with_codec::<_, Blob>("Storage.echo", &blob, content_type)
+    });
+    let plain = client.call::<_, Blob>("Storage.echo", &blob);
+    let (replies, plain) = futures::join!(futures::future::join_all(calls), plain);
+    for reply in replies {
+        assert_eq!(reply.unwrap(), blob);
+    }
+    assert_eq!(plain.unwrap(), blob);
+
+    // errors are serialized with the codec of the connection
+    let reply: Result<(), Error> = client
+        .call_with_codec("Storage.reject", "blob", ContentType::Json)
+        .await;
+    match reply {
+        Err(Error::ExecutionError(msg)) => assert_eq!(msg, "blob is rejected"),
+        other => panic!("Unexpected reply {:?}", other),
+    }
+
+    client.close().await;
+    server_handle.abort();
+}
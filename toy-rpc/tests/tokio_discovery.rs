@@ -0,0 +1,59 @@
+//! Servers advertised with mDNS can be discovered and dialed
+
+use std::sync::Arc;
+use tokio::{net::TcpListener, task};
+use toy_rpc::{
+    discovery::{ServiceAdvert, DEFAULT_SERVICE_TYPE},
+    Client, Server,
+};
+
+mod rpc;
+
+const INSTANCE_NAME: &str = "toy-rpc-discovery-test";
+
+async fn discover() -> Vec<toy_rpc::discovery::DiscoveredEndpoint> {
+    Client::discover(DEFAULT_SERVICE_TYPE, |endpoint| {
+        endpoint.instance_name == INSTANCE_NAME
+    })
+    .await
+    .expect("Error browsing for servers")
+}
+
+#[tokio::test]
+async fn discover_advertised_server() {
+    let common_test_service = Arc::new(rpc::CommonTest::new());
+    let server = Server::builder()
+        .register(common_test_service)
+        .advertise_mdns(ServiceAdvert::new(INSTANCE_NAME).set_txt("owner", "alice"))
+        .build();
+    // loopback addresses are not advertised, so the server listens on all interfaces
+    let listener = TcpListener::bind("0.0.0.0:0")
+        .await
+        .expect("Cannot bind to address");
+    let port = listener.local_addr().unwrap().port();
+    let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel();
+    let server_handle =
+        task::spawn(async move { server.accept_with_shutdown(listener, shutdown_rx).await });
+
+    let endpoints = discover().await;
+    assert_eq!(endpoints.len(), 1);
+    let endpoint = &endpoints[0];
+    assert_eq!(endpoint.addr.port(), port);
+    assert_eq!(endpoint.codec(), Some("bincode"));
+    assert_eq!(endpoint.version(), Some(env!("CARGO_PKG_VERSION")));
+    assert_eq!(endpoint.transport(), Some("tcp"));
+    assert_eq!(endpoint.txt.get("owner").map(String::as_str), Some("alice"));
+
+    let client = Client::dial_discovered(endpoint)
+        .await
+        .expect("Error dialing discovered server");
+    rpc::test_get_magic_u32(&client).await;
+
+    // the advertisement is withdrawn as soon as the server stops accepting
+    shutdown_tx.send(()).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(discover().await.is_empty());
+
+    client.close().await;
+    server_handle.await.unwrap().unwrap();
+}
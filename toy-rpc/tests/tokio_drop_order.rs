@@ -0,0 +1,127 @@
+//! Dropping the client in any order with its background tasks and the runtime
+//!
+//! The client is created over an in-memory stream whose other end never replies, so
+//! the calls stay pending. Errors logged by the client are counted by a test logger.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Once,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{io::DuplexStream, runtime::Runtime, task};
+use toy_rpc::{client::Call, Client};
+
+static NUM_ERRORS: AtomicUsize = AtomicUsize::new(0);
+static INIT_LOGGER: Once = Once::new();
+
+struct CountErrors;
+
+impl log::Log for CountErrors {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Error
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.target(), record.args());
+            NUM_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn init_logger() {
+    INIT_LOGGER.call_once(|| {
+        log::set_logger(&CountErrors).expect("Error setting logger");
+        log::set_max_level(log::LevelFilter::Error);
+    });
+}
+
+/// Runs `f` on another thread, and fails if it panics or does not return in time
+fn run_within(timeout: Duration, f: impl FnOnce() + Send + 'static) {
+    init_logger();
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        f();
+        let _ = tx.send(());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(()) => handle.join().expect("Test thread panicked"),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            handle.join().expect("Test thread panicked");
+        }
+        Err(mpsc::RecvTimeoutError::Timeout) => panic!("Deadlock"),
+    }
+    assert_eq!(NUM_ERRORS.load(Ordering::Relaxed), 0);
+}
+
+/// A client whose server never replies, and the other end of its stream
+fn client() -> (Client<toy_rpc::pubsub::AckModeNone>, DuplexStream) {
+    let (stream, peer) = tokio::io::duplex(1024);
+    (Client::with_stream(stream), peer)
+}
+
+#[test]
+fn drop_runtime_before_client() {
+    run_within(Duration::from_secs(10), || {
+        let rt = Runtime::new().unwrap();
+        let (client, peer, call) = rt.block_on(async {
+            let (client, peer) = client();
+            let call: Call<()> = client.call("Echo.wait", ());
+            // let the background tasks start and send the request
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            (client, peer, call)
+        });
+        drop(rt);
+        drop(call);
+        drop(client);
+        drop(peer);
+    });
+}
+
+#[test]
+fn drop_client_in_task_during_runtime_shutdown() {
+    run_within(Duration::from_secs(10), || {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            for _ in 0..10 {
+                let (client, peer) = client();
+                task::spawn(async move {
+                    let _peer = peer;
+                    let call: Call<()> = client.call("Echo.wait", ());
+                    let _ = call.await;
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+        rt.shutdown_timeout(Duration::from_secs(1));
+    });
+}
+
+#[test]
+fn create_and_drop_clients() {
+    run_within(Duration::from_secs(60), || {
+        let rt = Runtime::new().unwrap();
+        // the other ends stay open until the background tasks are gone
+        let peers = rt.block_on(async {
+            let mut peers = Vec::new();
+            for i in 0..5000 {
+                let (client, peer) = client();
+                if i % 2 == 0 {
+                    // the reader may not have run before the call is dropped
+                    drop(client.call::<_, ()>("Echo.wait", ()));
+                }
+                drop(client);
+                peers.push(peer);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            peers
+        });
+        drop(rt);
+        drop(peers);
+    });
+}
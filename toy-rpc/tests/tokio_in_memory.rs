@@ -0,0 +1,87 @@
+//! A client connected to the server in memory, without binding a port
+
+use std::{sync::Arc, time::Duration};
+use tokio::task;
+use toy_rpc::{client::Call, codec::connect_in_memory, Error, Server};
+
+mod rpc;
+
+#[tokio::test]
+async fn in_memory_connection() {
+    let server = Server::builder()
+        .register(Arc::new(rpc::CommonTest::new()))
+        .build();
+    let (client, serving) = connect_in_memory(&server);
+    let serving = task::spawn(serving);
+
+    rpc::test_get_magic_i32(&client).await;
+    let result: Result<(), Error> = client
+        .call("CommonTest.echo_error", "an error".to_string())
+        .await;
+    assert!(result.is_err());
+
+    let mut call: Call<()> = client.call("CommonTest.wait_forever", ());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    call.cancel()
+        .await
+        .expect("Cancellation is not acknowledged");
+    assert!(matches!(call.await, Err(Error::Canceled(_))));
+    assert_eq!(client.num_pending(), 0);
+
+    // the connection is served until the client is closed
+    client.close().await;
+    tokio::time::timeout(Duration::from_secs(1), serving)
+        .await
+        .expect("The connection is still served")
+        .unwrap()
+        .unwrap();
+}
+
+/// The per-method metrics of a client count the calls by outcome
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn call_metrics() {
+    let server = Server::builder()
+        .register(Arc::new(rpc::CommonTest::new()))
+        .build();
+    let (client, serving) = connect_in_memory(&server);
+    task::spawn(serving);
+
+    for _ in 0..3 {
+        rpc::test_get_magic_i32(&client).await;
+    }
+    let result: Result<(), Error> = client
+        .call("CommonTest.echo_error", "an error".to_string())
+        .await;
+    assert!(result.is_err());
+
+    let result: Result<(), Error> = client
+        .set_next_timeout(Duration::from_millis(50))
+        .call("CommonTest.wait_forever", ())
+        .await;
+    assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+
+    let mut call: Call<()> = client.call("CommonTest.wait_forever", ());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    call.cancel()
+        .await
+        .expect("Cancellation is not acknowledged");
+    assert!(matches!(call.await, Err(Error::Canceled(_))));
+
+    let snapshot = client.call_metrics();
+    let magic = snapshot.method("CommonTest.get_magic_i32").unwrap();
+    assert_eq!((magic.issued, magic.succeeded), (3, 3));
+    assert_eq!(magic.latency.count, 3);
+    assert!(magic.latency.quantile(0.99).is_some());
+
+    let echo_error = snapshot.method("CommonTest.echo_error").unwrap();
+    assert_eq!((echo_error.issued, echo_error.failed), (1, 1));
+
+    let wait_forever = snapshot.method("CommonTest.wait_forever").unwrap();
+    assert_eq!(wait_forever.issued, 2);
+    assert_eq!((wait_forever.timed_out, wait_forever.canceled), (1, 1));
+    assert_eq!(wait_forever.in_flight(), 0);
+    assert_eq!(wait_forever.latency.count, 0);
+
+    client.close().await;
+}
@@ -0,0 +1,64 @@
+//! RPC over QUIC, with every bidirectional stream served as a connection
+
+use rustls::{Certificate, PrivateKey};
+use std::sync::Arc;
+use tokio::task;
+use toy_rpc::{client::tls::TlsOptions, transport::quic::QuicStream, Client, Server};
+
+mod rpc;
+
+const CERT: &[u8] = include_bytes!("certs/self_signed.der");
+const KEY: &[u8] = include_bytes!("certs/self_signed.key.der");
+
+fn client_config() -> quinn::ClientConfig {
+    // the certificate is self-signed, so it is trusted by its key
+    let pin = toy_rpc::client::tls::spki_sha256(CERT).unwrap();
+    let config = TlsOptions::new().pin_sha256(&pin).client_config();
+    quinn::ClientConfig::new(Arc::new(config))
+}
+
+#[tokio::test]
+async fn quic() {
+    let server_config = quinn::ServerConfig::with_single_cert(
+        vec![Certificate(CERT.to_vec())],
+        PrivateKey(KEY.to_vec()),
+    )
+    .unwrap();
+    // a free UDP port of the loopback interface
+    let endpoint = quinn::Endpoint::server(server_config, ([127, 0, 0, 1], 0).into())
+        .expect("Cannot bind to address");
+    let addr = endpoint.local_addr().unwrap();
+    let server = Server::builder()
+        .register(Arc::new(rpc::CommonTest::new()))
+        .build();
+    let server_handle = task::spawn(async move { server.accept_quic(endpoint).await });
+
+    let client = Client::dial_quic(addr, "localhost", client_config())
+        .await
+        .expect("Error dialing over QUIC");
+    assert_eq!(client.peer_addr(), Some(addr));
+    rpc::test_get_magic_u32(&client).await;
+    rpc::test_get_magic_str(&client).await;
+    client.close().await;
+
+    // two clients on the streams of the same QUIC connection
+    let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+    endpoint.set_default_client_config(client_config());
+    let connection = endpoint
+        .connect(addr, "localhost")
+        .unwrap()
+        .await
+        .expect("Error connecting over QUIC");
+    let (send, recv) = connection.open_bi().await.unwrap();
+    let first = Client::builder().with_stream(QuicStream::new(send, recv));
+    let (send, recv) = connection.open_bi().await.unwrap();
+    let second = Client::builder().with_stream(QuicStream::new(send, recv));
+    rpc::test_get_magic_u32(&first).await;
+    rpc::test_get_magic_u32(&second).await;
+    first.close().await;
+    // closing one stream doesn't affect the other
+    rpc::test_get_magic_str(&second).await;
+    second.close().await;
+
+    server_handle.abort();
+}
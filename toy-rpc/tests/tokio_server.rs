@@ -0,0 +1,1539 @@
+//! Behaviour of the server: services, handlers, limits and the connections it accepts
+
+use toy_rpc::{pubsub::AckModeNone, Client, Error};
+
+mod common;
+mod rpc;
+
+async fn get_magic(client: &Client<AckModeNone>) -> Result<u32, Error> {
+    client.call("CommonTest.get_magic_u32", ()).await
+}
+
+mod concurrent_requests {
+    //! Requests on one connection are handled concurrently
+
+    use futures::{SinkExt, StreamExt};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::{task, time};
+    use toy_rpc::{
+        client::{CallStream, StreamSender},
+        macros::export_impl,
+        server::Context,
+        Client, Error, Server,
+    };
+
+    use super::common::{self, Sleeper};
+
+    struct Streams {}
+
+    #[export_impl]
+    impl Streams {
+        /// Sends `n` items, one every 100 milliseconds
+        #[export_method]
+        async fn ticks(&self, n: u32) -> Result<(), String> {
+            let ctx = Context::current().ok_or("Missing request context")?;
+            for i in 0..n {
+                time::sleep(Duration::from_millis(100)).await;
+                ctx.send_item(i).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+
+        /// Sends back every item of the client until the client is done
+        #[export_method]
+        async fn echo(&self, _: ()) -> Result<(), String> {
+            let ctx = Context::current().ok_or("Missing request context")?;
+            let mut incoming = ctx.incoming::<u32>().ok_or("Not a stream")?;
+            while let Some(item) = incoming.next().await {
+                let item = item.map_err(|err| err.to_string())?;
+                ctx.send_item(item).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn fast_call_is_not_held_up_by_slow_call() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let slow = task::spawn(client.call::<_, u64>("Sleeper.sleep", 2000u64));
+        time::sleep(Duration::from_millis(100)).await;
+
+        // the fast call is answered while the slow call is still running
+        time::timeout(
+            Duration::from_millis(500),
+            client.call::<_, u64>("Sleeper.sleep", 0u64),
+        )
+        .await
+        .expect("Fast call is held up by the slow call")
+        .expect("Fast call failed");
+        assert_eq!(slow.await.unwrap().expect("Slow call failed"), 2000);
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_requests_holds_back_calls() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .set_max_concurrent_requests(1)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let start = Instant::now();
+        let slow = task::spawn(client.call::<_, u64>("Sleeper.sleep", 300u64));
+        time::sleep(Duration::from_millis(50)).await;
+
+        // the fast call waits for the slow call to free the only slot
+        client
+            .call::<_, u64>("Sleeper.sleep", 0u64)
+            .await
+            .expect("Fast call failed");
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(slow.await.unwrap().expect("Slow call failed"), 300);
+
+        // without a queue limit, any number of calls wait for the slot
+        let calls = (0..16).map(|_| client.call::<_, u64>("Sleeper.sleep", 10u64));
+        for reply in futures::future::join_all(calls).await {
+            assert_eq!(reply.expect("Queued call failed"), 10);
+        }
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn bidirectional_stream_holds_its_slot() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .register(Arc::new(Streams {}))
+            .set_max_concurrent_requests(1)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let (mut tx, mut rx): (StreamSender<u32>, CallStream<u32>) =
+            client.open_stream("Streams.echo", ());
+        tx.send(1).await.expect("Error sending item");
+        let item = rx.next().await.expect("Stream ended early");
+        assert_eq!(item.expect("Unexpected error in stream"), 1);
+
+        // the handler of the stream is still running, so the call waits for the stream to end
+        let mut fast = task::spawn(client.call::<_, u64>("Sleeper.sleep", 0u64));
+        assert!(time::timeout(Duration::from_millis(300), &mut fast)
+            .await
+            .is_err());
+
+        tx.close().await.expect("Error closing stream");
+        assert!(rx.next().await.is_none());
+        fast.await.unwrap().expect("Fast call failed");
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn calls_beyond_the_queue_are_busy() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .set_max_concurrent_requests(1)
+            .set_max_queued_requests(1)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        // one call runs and one waits for the slot
+        let slow = task::spawn(client.call::<_, u64>("Sleeper.sleep", 500u64));
+        let queued = task::spawn(client.call::<_, u64>("Sleeper.sleep", 100u64));
+        time::sleep(Duration::from_millis(100)).await;
+
+        let reply = client.call::<_, u64>("Sleeper.sleep", 0u64).await;
+        assert!(matches!(reply, Err(Error::Busy)), "{:?}", reply);
+        assert_eq!(slow.await.unwrap().expect("Slow call failed"), 500);
+        assert_eq!(queued.await.unwrap().expect("Queued call failed"), 100);
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn streaming_response_holds_its_slot() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .register(Arc::new(Streams {}))
+            .set_max_concurrent_requests(1)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let start = Instant::now();
+        let stream: CallStream<u32> = client.call_stream("Streams.ticks", 3u32);
+        let fast = async {
+            time::sleep(Duration::from_millis(50)).await;
+            client.call::<_, u64>("Sleeper.sleep", 0u64).await
+        };
+        let (items, reply) = tokio::join!(stream.collect::<Vec<_>>(), fast);
+
+        // the fast call waits for the handler of the stream to return
+        reply.expect("Fast call failed");
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(items.len(), 3);
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod rate_limit {
+    //! Rate limiting of the requests on each connection
+
+    use std::sync::Arc;
+    use toy_rpc::{server::RateLimit, Client, Error, Server};
+
+    use super::{common, get_magic, rpc};
+
+    #[tokio::test]
+    async fn requests_are_rate_limited_per_connection() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .rate_limit(RateLimit {
+                per_second: 1,
+                burst: 3,
+            })
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        for _ in 0..3 {
+            let reply = get_magic(&client).await.expect("Call failed");
+            assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U32);
+        }
+        match get_magic(&client).await {
+            Err(Error::RateLimited) => {}
+            res => panic!("Expecting Error::RateLimited, found {:?}", res),
+        }
+
+        // every connection has its own bucket
+        let other = Client::dial(addr).await.expect("Error dialing");
+        get_magic(&other).await.expect("Call failed");
+        other.close().await;
+
+        // the bucket refills over time
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        get_magic(&client).await.expect("Call failed");
+        match get_magic(&client).await {
+            Err(Error::RateLimited) => {}
+            res => panic!("Expecting Error::RateLimited, found {:?}", res),
+        }
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod handler_timeout {
+    //! Handlers running longer than the handler timeout of the server are abandoned
+
+    use std::{sync::Arc, time::Duration};
+    use toy_rpc::{client::Call, Client, Error, Server};
+
+    use super::common::{self, Sleeper};
+
+    #[tokio::test]
+    async fn handler_timeout() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper::default()))
+            .handler_timeout(Duration::from_millis(100))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+        let client = Client::dial(addr).await.expect("Error dialing");
+
+        let reply: Result<u64, Error> = client.call("Sleeper.sleep", 10u64).await;
+        reply.expect("Handler within the timeout");
+
+        // the handler sleeps past the timeout
+        let reply: Result<u64, Error> = client.call("Sleeper.sleep", 5_000u64).await;
+        match reply {
+            Err(Error::ExecutionError(msg)) => assert_eq!(msg, "handler timed out"),
+            other => panic!("Expecting the handler to time out, found {:?}", other),
+        }
+
+        // the shorter deadline of the client is reached first
+        let reply: Result<u64, Error> = client
+            .set_next_timeout(Duration::from_millis(20))
+            .call("Sleeper.sleep", 5_000u64)
+            .await;
+        assert!(matches!(reply, Err(Error::Timeout(_))), "{:?}", reply);
+
+        // cancellation still stops the handler before the timeout
+        let mut call: Call<u64> = client.call("Sleeper.sleep", 5_000u64);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        call.cancel()
+            .await
+            .expect("Cancellation is not acknowledged");
+        assert!(matches!(call.await, Err(Error::Canceled(_))));
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod catch_panics {
+    //! A panic of a handler only fails its own request
+
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::task::JoinHandle;
+    use toy_rpc::{macros::export_impl, Client, Error, Server};
+
+    use super::common;
+
+    struct Buggy {
+        items: Vec<u32>,
+    }
+
+    #[export_impl]
+    impl Buggy {
+        #[export_method]
+        async fn get(&self, index: usize) -> Result<u32, String> {
+            // panics if the index is out of bounds
+            Ok(self.items[index])
+        }
+
+        #[export_method]
+        async fn sleep(&self, millis: u64) -> Result<u64, String> {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            Ok(millis)
+        }
+    }
+
+    async fn serve(catch_panics: bool) -> (SocketAddr, JoinHandle<Result<(), Error>>) {
+        let server = Server::builder()
+            .register(Arc::new(Buggy {
+                items: vec![1, 2, 3],
+            }))
+            .catch_panics(catch_panics)
+            .build();
+        common::serve(server).await
+    }
+
+    #[tokio::test]
+    async fn panic_becomes_error_response() {
+        let (addr, server_handle) = serve(true).await;
+        let client = Client::dial(addr).await.expect("Error dialing server");
+
+        // a call that is pending on the same connection
+        let pending = client.call::<_, u64>("Buggy.sleep", 200u64);
+        let reply: Result<u32, Error> = client.call("Buggy.get", 10usize).await;
+        match reply {
+            Err(Error::ExecutionError(msg)) => {
+                assert!(msg.starts_with("handler panicked"), "{}", msg);
+                assert!(msg.contains("index out of bounds"), "{}", msg);
+            }
+            res => panic!("Expecting Error::ExecutionError, found {:?}", res),
+        }
+        assert_eq!(pending.await.unwrap(), 200);
+
+        // the connection is still served
+        let reply: u32 = client.call("Buggy.get", 1usize).await.unwrap();
+        assert_eq!(reply, 2);
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn panic_is_not_caught() {
+        let (addr, server_handle) = serve(false).await;
+        let client = Client::dial(addr).await.expect("Error dialing server");
+
+        // the request is never responded
+        let reply: Result<u32, Error> = client
+            .set_next_timeout(Duration::from_millis(300))
+            .call("Buggy.get", 10usize)
+            .await;
+        assert!(reply.unwrap_err().is_timeout());
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod error_mode {
+    //! Redacting the errors of the handlers before they are sent to the client
+
+    use std::sync::Arc;
+    use toy_rpc::{
+        macros::export_impl,
+        server::{error_mode::REDACTED_ERROR_MESSAGE, ErrorMode},
+        Client, Error, Server,
+    };
+
+    use super::common;
+
+    struct Files;
+
+    #[export_impl]
+    impl Files {
+        #[export_method]
+        async fn read(&self, path: String) -> Result<String, String> {
+            Err(format!("Cannot open {}", path))
+        }
+
+        #[export_method]
+        async fn check(&self, _: ()) -> Result<(), Error> {
+            Err(Error::InvalidArgument)
+        }
+    }
+
+    #[tokio::test]
+    async fn error_mode_redacted() {
+        let server = Server::builder()
+            .register(Arc::new(Files))
+            .error_mode(ErrorMode::Redacted)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let reply: Result<String, Error> =
+            client.call("Files.read", "/srv/secret".to_string()).await;
+        match reply {
+            Err(Error::ExecutionError(msg)) => {
+                assert!(msg.starts_with(REDACTED_ERROR_MESSAGE), "{}", msg);
+                assert!(msg.contains("error id"), "{}", msg);
+                assert!(!msg.contains("/srv/secret"), "{}", msg);
+            }
+            res => panic!("Expecting Error::ExecutionError, found {:?}", res),
+        }
+
+        // explicit variants are not redacted
+        let reply: Result<(), Error> = client.call("Files.check", ()).await;
+        match reply {
+            Err(Error::InvalidArgument) => {}
+            res => panic!("Expecting Error::InvalidArgument, found {:?}", res),
+        }
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod interceptor {
+    //! Interceptors wrap the dispatch of requests in the order they are added, and client
+    //! interceptors attach metadata to outgoing requests and observe their outcome
+
+    use async_trait::async_trait;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use toy_rpc::{
+        client::interceptor::{ClientInterceptor, RequestHeader as ClientRequestHeader},
+        protocol::RequestMetadata,
+        server::{Context, Interceptor, Next, RequestHeader},
+        service::HandlerResult,
+        Client, Error, Server,
+    };
+
+    use super::common;
+    use super::rpc;
+
+    const REJECTED_METHOD: &str = "get_magic_u64";
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Rejects one method and records the requests it lets through
+    struct Reject {
+        log: Log,
+    }
+
+    #[async_trait]
+    impl Interceptor for Reject {
+        async fn intercept(&self, header: &RequestHeader, next: Next) -> HandlerResult {
+            if header.method == REJECTED_METHOD {
+                return Err(Error::ExecutionError("Rejected".into()));
+            }
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("reject:{}", header.method));
+            let result = next.run(header).await;
+            self.log.lock().unwrap().push("reject:done".into());
+            result
+        }
+    }
+
+    /// Records every request that reaches it
+    struct Record {
+        log: Log,
+    }
+
+    #[async_trait]
+    impl Interceptor for Record {
+        async fn intercept(&self, header: &RequestHeader, next: Next) -> HandlerResult {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("record:{}.{}", header.service, header.method));
+            let result = next.run(header).await;
+            self.log.lock().unwrap().push("record:done".into());
+            result
+        }
+    }
+
+    /// Rejects requests that don't carry the token in the metadata
+    struct RequireToken;
+
+    #[async_trait]
+    impl Interceptor for RequireToken {
+        async fn intercept(&self, header: &RequestHeader, next: Next) -> HandlerResult {
+            let ctx = Context::current().expect("Missing request context");
+            match ctx.metadata().get("token").map(|s| s.as_str()) {
+                Some("secret") => next.run(header).await,
+                _ => Err(Error::ExecutionError("Unauthorized".into())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn interceptors_wrap_handlers_in_order() {
+        let log = Log::default();
+        let common_test_service = Arc::new(rpc::CommonTest::new());
+        let server = Server::builder()
+            .register(common_test_service)
+            .layer(Reject { log: log.clone() })
+            .layer(Record { log: log.clone() })
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        rpc::test_get_magic_u32(&client).await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "reject:get_magic_u32",
+                "record:CommonTest.get_magic_u32",
+                "record:done",
+                "reject:done",
+            ]
+        );
+
+        // the outer interceptor short-circuits before the inner one and the handler run
+        log.lock().unwrap().clear();
+        let reply: Result<u64, Error> = client
+            .call(format!("CommonTest.{}", REJECTED_METHOD), ())
+            .await;
+        match reply {
+            Err(Error::ExecutionError(msg)) => assert_eq!(msg, "Rejected"),
+            other => panic!("Expected the request to be rejected, got {:?}", other),
+        }
+        assert!(log.lock().unwrap().is_empty());
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn client_interceptors_attach_metadata() {
+        let common_test_service = Arc::new(rpc::CommonTest::new());
+        let server = Server::builder()
+            .register(common_test_service)
+            .layer(RequireToken)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::builder()
+            .with_interceptor(|header: &mut ClientRequestHeader| {
+                header.insert_metadata("token", "secret");
+            })
+            .with_interceptor(|header: &mut ClientRequestHeader| {
+                let seq = header.id().to_string();
+                header.insert_metadata("seq", seq);
+            })
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+        rpc::test_get_magic_u32(&client).await;
+        for _ in 0..3 {
+            let token: Option<String> = client
+                .call("CommonTest.get_metadata", "token".to_string())
+                .await
+                .expect("Unexpected error executing RPC");
+            assert_eq!(token.as_deref(), Some("secret"));
+        }
+        let call = client.call::<_, Option<String>>("CommonTest.get_metadata", "seq".to_string());
+        let id = call.id();
+        let seq = call.await.expect("Unexpected error executing RPC");
+        assert_eq!(seq, Some(id.to_string()));
+
+        // metadata passed to the call is sent along with that of the interceptors
+        let mut metadata = RequestMetadata::new();
+        metadata.insert("tenant".into(), "acme".into());
+        let tenant: Option<String> = client
+            .call_with_metadata("CommonTest.get_metadata", "tenant".to_string(), metadata)
+            .await
+            .expect("Unexpected error executing RPC");
+        assert_eq!(tenant.as_deref(), Some("acme"));
+        client.close().await;
+
+        // requests without metadata are still understood by the server
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let reply: Result<u32, Error> = client.call("CommonTest.get_magic_u32", ()).await;
+        match reply {
+            Err(Error::ExecutionError(msg)) => assert_eq!(msg, "Unauthorized"),
+            other => panic!("Expected the request to be rejected, got {:?}", other),
+        }
+        client.close().await;
+
+        server_handle.abort();
+    }
+
+    /// Records the calls made by the client and their outcome
+    struct Observe {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl ClientInterceptor for Observe {
+        fn before_send(
+            &self,
+            header: &mut ClientRequestHeader,
+            _: &(dyn erased_serde::Serialize + Send + Sync),
+        ) {
+            self.log.lock().unwrap().push(format!(
+                "{}:send:{}",
+                self.name,
+                header.service_method()
+            ));
+        }
+
+        fn after_receive(
+            &self,
+            header: &ClientRequestHeader,
+            elapsed: Duration,
+            result: Result<(), &Error>,
+        ) {
+            assert!(elapsed > Duration::ZERO);
+            let outcome = match result {
+                Ok(()) => "ok",
+                Err(Error::ExecutionError(_)) => "execution_error",
+                Err(_) => "other_error",
+            };
+            self.log.lock().unwrap().push(format!(
+                "{}:{}:{}",
+                self.name,
+                header.service_method(),
+                outcome
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn client_interceptors_observe_calls() {
+        let common_test_service = Arc::new(rpc::CommonTest::new());
+        let server = Server::builder().register(common_test_service).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let log = Log::default();
+        let client = Client::builder()
+            .with_client_interceptor(Observe {
+                name: "first",
+                log: log.clone(),
+            })
+            .with_interceptor(|header: &mut ClientRequestHeader| {
+                header.insert_metadata("token", "secret");
+            })
+            .with_client_interceptor(Observe {
+                name: "second",
+                log: log.clone(),
+            })
+            .dial(addr)
+            .await
+            .expect("Error dialing server");
+
+        // the metadata attached by the closure is seen by the server
+        let token: Option<String> = client
+            .call("CommonTest.get_metadata", "token".to_string())
+            .await
+            .expect("Unexpected error executing RPC");
+        assert_eq!(token.as_deref(), Some("secret"));
+        let reply: Result<(), Error> = client
+            .call("CommonTest.echo_error", "oops".to_string())
+            .await;
+        assert!(reply.is_err());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "first:send:CommonTest.get_metadata",
+                "second:send:CommonTest.get_metadata",
+                "first:CommonTest.get_metadata:ok",
+                "second:CommonTest.get_metadata:ok",
+                "first:send:CommonTest.echo_error",
+                "second:send:CommonTest.echo_error",
+                "first:CommonTest.echo_error:execution_error",
+                "second:CommonTest.echo_error:execution_error",
+            ]
+        );
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod duplicate_request_id {
+    //! A request that reuses the id of a request that is still being handled
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::net::TcpStream;
+    use toy_rpc::{
+        codec::{split::SplittableCodec, CodecRead, CodecWrite, DefaultCodec},
+        protocol::Header,
+        Server,
+    };
+
+    use super::common::{self, Sleeper};
+
+    #[tokio::test]
+    async fn duplicate_request_id_is_rejected() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let stream = TcpStream::connect(addr).await.expect("Error connecting");
+        let (mut writer, mut reader) = DefaultCodec::new(stream).split();
+        for millis in [500u64, 600] {
+            let header = Header::Request {
+                id: 7,
+                service_method: "Sleeper.sleep".into(),
+                timeout: Duration::from_secs(10),
+            };
+            writer.write_header(header).await.unwrap();
+            writer.write_body(7, &millis).await.unwrap();
+        }
+
+        // the second request is rejected right away
+        let header: Header = reader.read_header().await.unwrap().unwrap();
+        assert!(matches!(
+            header,
+            Header::Response {
+                id: 7,
+                is_ok: false
+            }
+        ));
+        reader.read_body().await.unwrap().unwrap();
+
+        // and the first one still completes
+        let header: Header = reader.read_header().await.unwrap().unwrap();
+        assert!(matches!(header, Header::Response { id: 7, is_ok: true }));
+        let mut body = reader.read_body().await.unwrap().unwrap();
+        let reply: u64 = erased_serde::deserialize(&mut *body).unwrap();
+        assert_eq!(reply, 500);
+
+        server_handle.abort();
+    }
+}
+
+mod truncated_request {
+    //! A connection that is closed between the header and the body of a request
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::net::TcpStream;
+    use toy_rpc::{
+        codec::{split::SplittableCodec, CodecWrite, DefaultCodec},
+        protocol::Header,
+        Client, Server,
+    };
+
+    use super::common::{self, Sleeper};
+
+    async fn wait_for_connections(server: &Server, expected: usize) {
+        for _ in 0..50 {
+            if server.num_connections() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(server.num_connections(), expected);
+    }
+
+    #[tokio::test]
+    async fn connection_closed_before_request_body() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (addr, server_handle) = common::serve(server.clone()).await;
+
+        // only the header of the request is sent before the connection is closed
+        let stream = TcpStream::connect(addr).await.expect("Error connecting");
+        let (mut writer, reader) = DefaultCodec::new(stream).split();
+        let header = Header::Request {
+            id: 0,
+            service_method: "Sleeper.echo".into(),
+            timeout: Duration::from_secs(10),
+        };
+        writer.write_header(header).await.unwrap();
+        wait_for_connections(&server, 1).await;
+        drop(writer);
+        drop(reader);
+
+        // the connection is closed on the server instead of waiting for the body forever
+        wait_for_connections(&server, 0).await;
+
+        // and the server keeps serving the other clients
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let reply: i32 = client.call("Sleeper.echo", 7i32).await.unwrap();
+        assert_eq!(reply, 7);
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod service_alias {
+    //! One instance of a service registered under several names, and several instances of
+    //! the same type under different names
+
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+    use toy_rpc::{macros::export_impl, Client, Server};
+
+    use super::common;
+
+    struct Counter {
+        count: AtomicU32,
+        step: u32,
+    }
+
+    impl Counter {
+        fn new(step: u32) -> Self {
+            Self {
+                count: AtomicU32::new(0),
+                step,
+            }
+        }
+    }
+
+    #[export_impl]
+    impl Counter {
+        #[export_method]
+        async fn increment(&self, _: ()) -> Result<u32, String> {
+            Ok(self.count.fetch_add(self.step, Ordering::Relaxed) + self.step)
+        }
+    }
+
+    #[tokio::test]
+    async fn service_alias() {
+        let server = Server::builder()
+            .register_as(&["Counter", "CounterV1"], Arc::new(Counter::new(1)))
+            // another instance of the same type under a name that is not static
+            .register_with_name(String::from("Legacy"), Arc::new(Counter::new(10)))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        // the aliases share the same instance
+        let reply: u32 = client.call("Counter.increment", ()).await.unwrap();
+        assert_eq!(reply, 1);
+        let reply: u32 = client.call("CounterV1.increment", ()).await.unwrap();
+        assert_eq!(reply, 2);
+        let reply: u32 = client.call("Legacy.increment", ()).await.unwrap();
+        assert_eq!(reply, 10);
+
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[test]
+    #[should_panic(expected = "Service name `Counter` is registered more than once")]
+    fn duplicate_service_name() {
+        Server::builder()
+            .register_as(&["Counter", "CounterV1"], Arc::new(Counter::new(1)))
+            .register_with_name("Counter", Arc::new(Counter::new(10)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Service name `__introspect` is registered more than once")]
+    fn service_named_like_introspection() {
+        Server::builder()
+            .register_with_name("__introspect", Arc::new(Counter::new(1)))
+            .enable_introspection()
+            .build();
+    }
+}
+
+mod generic_service {
+    //! A generic service registered twice with different type arguments
+
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::sync::Mutex;
+    use toy_rpc::{macros::export_impl, Client, Server};
+
+    use super::common;
+
+    pub trait Storage {
+        type Value: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + Sync + 'static;
+
+        fn get(&self, key: &str) -> Option<Self::Value>;
+        fn put(&mut self, key: String, value: Self::Value);
+    }
+
+    #[derive(Default)]
+    pub struct Memory<V> {
+        map: HashMap<String, V>,
+    }
+
+    impl<V> Storage for Memory<V>
+    where
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        type Value = V;
+
+        fn get(&self, key: &str) -> Option<V> {
+            self.map.get(key).cloned()
+        }
+
+        fn put(&mut self, key: String, value: V) {
+            self.map.insert(key, value);
+        }
+    }
+
+    pub struct KvService<S> {
+        storage: Mutex<S>,
+    }
+
+    impl<S: Default> KvService<S> {
+        fn new() -> Self {
+            Self {
+                storage: Mutex::new(S::default()),
+            }
+        }
+    }
+
+    #[export_impl]
+    impl<S> KvService<S>
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        #[export_method]
+        async fn get(&self, key: String) -> Result<Option<S::Value>, String> {
+            Ok(self.storage.lock().await.get(&key))
+        }
+
+        #[export_method]
+        async fn put(&self, args: (String, S::Value)) -> Result<(), String> {
+            self.storage.lock().await.put(args.0, args.1);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn generic_service() {
+        let server = Server::builder()
+            .register_with_name("Numbers", Arc::new(KvService::<Memory<u32>>::new()))
+            .register_with_name("Names", Arc::new(KvService::<Memory<String>>::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let numbers = KvServiceClient::<_, Memory<u32>>::with_service_name(&client, "Numbers");
+        let names = KvServiceClient::<_, Memory<String>>::with_service_name(&client, "Names");
+
+        numbers.put(("one".to_string(), 1)).await.unwrap();
+        names
+            .put(("one".to_string(), "uno".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(numbers.get("one".to_string()).await.unwrap(), Some(1));
+        assert_eq!(
+            names.get("one".to_string()).await.unwrap(),
+            Some("uno".to_string())
+        );
+        assert_eq!(numbers.get("two".to_string()).await.unwrap(), None);
+
+        // the default name follows the name of the struct
+        let client_stub = client.kv_service::<Memory<u32>>();
+        assert_eq!(client_stub.service_name(), "KvService");
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod export_cfg {
+    //! Methods that are compiled out with `#[cfg]` and helper methods that are not exported
+
+    use std::sync::Arc;
+    use toy_rpc::{macros::export_impl, Client, Error, Server};
+
+    use super::common;
+
+    struct Admin;
+
+    #[export_impl]
+    impl Admin {
+        #[export_method]
+        async fn status(&self, _: ()) -> Result<String, String> {
+            Ok(self.helper())
+        }
+
+        // always compiled
+        #[cfg(all())]
+        #[export_method]
+        async fn enabled(&self, _: ()) -> Result<u32, String> {
+            Ok(1)
+        }
+
+        // never compiled
+        #[cfg(any())]
+        #[export_method]
+        async fn disabled(&self, _: ()) -> Result<u32, String> {
+            Ok(2)
+        }
+
+        // not callable over RPC
+        fn helper(&self) -> String {
+            "ok".into()
+        }
+    }
+
+    #[tokio::test]
+    async fn export_cfg() {
+        let server = Server::builder().register(Arc::new(Admin)).build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        let reply: String = client.call("Admin.status", ()).await.unwrap();
+        assert_eq!(reply, "ok");
+        let reply: u32 = client.admin().enabled(()).await.unwrap();
+        assert_eq!(reply, 1);
+
+        for method in ["Admin.disabled", "Admin.helper"] {
+            let reply: Result<u32, Error> = client.call(method, ()).await;
+            match reply {
+                Err(Error::MethodNotFound) => {}
+                res => panic!("Expecting Error::MethodNotFound, found {:?}", res),
+            }
+        }
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod runtime_registration {
+    //! Registering and deregistering services on a running server
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::time;
+    use toy_rpc::{macros::export_impl, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    struct Plugin {}
+
+    #[export_impl]
+    impl Plugin {
+        #[export_method]
+        async fn ping(&self, _: ()) -> Result<String, String> {
+            Ok("pong".into())
+        }
+
+        #[export_method]
+        async fn slow(&self, millis: u64) -> Result<u64, String> {
+            time::sleep(Duration::from_millis(millis)).await;
+            Ok(millis)
+        }
+    }
+
+    #[tokio::test]
+    async fn runtime_registration() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .enable_introspection()
+            .build();
+        let (addr, server_handle) = common::serve(server.clone()).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let result: Result<String, Error> = client.call("Plugin.ping", ()).await;
+        assert!(matches!(result, Err(Error::ServiceNotFound)));
+
+        // the service is available on the connection that is already open
+        server
+            .register(Arc::new(Plugin {}))
+            .expect("Error registering service");
+        let reply: String = client.call("Plugin.ping", ()).await.expect("Call failed");
+        assert_eq!(reply, "pong");
+        assert!(server.register(Arc::new(Plugin {})).is_err());
+        let services: Vec<String> = client.call("__introspect.list", ()).await.unwrap();
+        assert_eq!(services, vec!["CommonTest", "Plugin"]);
+
+        // a call that has started is completed after the service is deregistered
+        let slow = client.call::<_, u64>("Plugin.slow", 300u64);
+        futures::pin_mut!(slow);
+        assert!(time::timeout(Duration::from_millis(100), slow.as_mut())
+            .await
+            .is_err());
+        assert!(server.deregister("Plugin"));
+        assert_eq!(slow.await.expect("In-flight call failed"), 300);
+
+        let result: Result<String, Error> = client.call("Plugin.ping", ()).await;
+        assert!(matches!(result, Err(Error::ServiceNotFound)));
+        assert!(!server.deregister("Plugin"));
+        let services: Vec<String> = client.call("__introspect.list", ()).await.unwrap();
+        assert_eq!(services, vec!["CommonTest"]);
+        rpc::test_get_magic_u32(&client).await;
+
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod introspect {
+    //! Listing the registered services and their methods with the introspection service
+
+    use std::sync::Arc;
+    use toy_rpc::{server::INTROSPECTION_SERVICE, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    #[tokio::test]
+    async fn introspection_lists_services_and_methods() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .register_with_name("CommonTest2", Arc::new(rpc::CommonTest::new()))
+            .enable_introspection()
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+
+        let services: Vec<String> = client
+            .call("__introspect.list", ())
+            .await
+            .expect("Error listing services");
+        assert_eq!(services, vec!["CommonTest", "CommonTest2"]);
+
+        let methods: Vec<String> = client
+            .call(format!("{}.methods", INTROSPECTION_SERVICE), "CommonTest2")
+            .await
+            .expect("Error listing methods");
+        assert!(methods.windows(2).all(|w| w[0] < w[1]));
+        for method in ["get_magic_u32", "echo_error", "wait_forever"] {
+            assert!(
+                methods.iter().any(|m| m == method),
+                "{} is not listed",
+                method
+            );
+        }
+
+        match client
+            .call::<_, Vec<String>>("__introspect.methods", "Unknown")
+            .await
+        {
+            Err(Error::ServiceNotFound) => {}
+            res => panic!("Expecting Error::ServiceNotFound, found {:?}", res),
+        }
+        match client.call::<_, ()>("__introspect.unknown", ()).await {
+            Err(Error::MethodNotFound) => {}
+            res => panic!("Expecting Error::MethodNotFound, found {:?}", res),
+        }
+
+        client.close().await;
+        server_handle.abort();
+
+        // the service is opt-in
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+        let client = Client::dial(addr).await.expect("Error dialing");
+        match client.call::<_, Vec<String>>("__introspect.list", ()).await {
+            Err(Error::ServiceNotFound) => {}
+            res => panic!("Expecting Error::ServiceNotFound, found {:?}", res),
+        }
+        client.close().await;
+        server_handle.abort();
+    }
+}
+
+mod health {
+    //! Built-in health-check service
+
+    use std::sync::Arc;
+    use toy_rpc::{health::HealthStatus, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    #[tokio::test]
+    async fn health_check_pings_the_server() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .enable_health_check()
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        client.health_check().await.expect("Server is not healthy");
+
+        let status: HealthStatus = client
+            .call("__health.ping", ())
+            .await
+            .expect("Error pinging");
+        assert_eq!(status.num_connections, 1);
+
+        // the other services are still served
+        let reply: u32 = client
+            .call("CommonTest.get_magic_u32", ())
+            .await
+            .expect("Call failed");
+        assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U32);
+
+        client.close().await;
+        server_handle.abort();
+
+        // the service is opt-in
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+        let client = Client::dial(addr).await.expect("Error dialing");
+        match client.health_check().await {
+            Err(Error::ServiceNotFound) => {}
+            res => panic!("Expecting Error::ServiceNotFound, found {:?}", res),
+        }
+        client.close().await;
+        server_handle.abort();
+    }
+
+    #[test]
+    #[should_panic(expected = "Service name `__health` is registered more than once")]
+    fn service_named_like_health_check() {
+        Server::builder()
+            .register_with_name("__health", Arc::new(rpc::CommonTest::new()))
+            .enable_health_check()
+            .build();
+    }
+}
+
+mod max_connections {
+    //! Limit of the number of connections served at the same time
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::time;
+    use toy_rpc::{server::ConnectionLimitMode, Client, Error, Server};
+
+    use super::{common, get_magic, rpc};
+
+    #[tokio::test]
+    async fn max_connections_wait() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .max_connections(1)
+            .build();
+        let (addr, server_handle) = common::serve(server.clone()).await;
+
+        let first = Client::dial(addr).await.expect("Error dialing");
+        get_magic(&first).await.expect("Call failed");
+        assert_eq!(server.num_connections(), 1);
+
+        // the second connection waits in the backlog of the listener
+        let second = Client::dial(addr).await.expect("Error dialing");
+        let call = get_magic(&second);
+        futures::pin_mut!(call);
+        assert!(
+            time::timeout(Duration::from_millis(500), call.as_mut())
+                .await
+                .is_err(),
+            "Connection beyond the limit is served"
+        );
+
+        first.close().await;
+        let reply = time::timeout(Duration::from_secs(5), call)
+            .await
+            .expect("Connection is not served after another one is closed")
+            .expect("Call failed");
+        assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U32);
+        assert_eq!(server.num_connections(), 1);
+
+        second.close().await;
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn max_connections_reject() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .max_connections(1)
+            .connection_limit_mode(ConnectionLimitMode::Reject)
+            .build();
+        let (addr, server_handle) = common::serve(server).await;
+
+        let first = Client::dial(addr).await.expect("Error dialing");
+        get_magic(&first).await.expect("Call failed");
+
+        // the second connection is closed right after it is accepted
+        let rejected = Client::dial(addr).await.expect("Error dialing");
+        let result = time::timeout(Duration::from_secs(5), get_magic(&rejected)).await;
+        assert!(
+            !matches!(result, Ok(Ok(_))),
+            "Rejected connection is served"
+        );
+        first.close().await;
+
+        // the permit is released shortly after the first connection is closed
+        let mut reply = None;
+        for _ in 0..20 {
+            let client = Client::dial(addr).await.expect("Error dialing");
+            if let Ok(Ok(value)) = time::timeout(Duration::from_secs(1), get_magic(&client)).await {
+                reply = Some(value);
+                client.close().await;
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(reply, Some(rpc::COMMON_TEST_MAGIC_U32));
+
+        server_handle.abort();
+    }
+}
+
+mod connection_stream {
+    //! A custom accept loop over `Server::connection_stream`
+
+    use futures::StreamExt;
+    use std::{sync::Arc, time::Duration};
+    use tokio::task;
+    use toy_rpc::{Client, Server};
+
+    use super::common;
+    use super::rpc;
+
+    #[tokio::test]
+    async fn connection_stream() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (listener, addr) = common::bind().await;
+
+        // serves the first connection and rejects the rest
+        let (outcome_tx, outcome_rx) = flume::unbounded();
+        let mut connections = server.connection_stream(listener);
+        let accept_loop = task::spawn(async move {
+            let mut num_accepted = 0;
+            while let Some(conn) = connections.next().await {
+                let conn = conn.expect("Error accepting connection");
+                num_accepted += 1;
+                if num_accepted > 1 {
+                    continue;
+                }
+                let outcome_tx = outcome_tx.clone();
+                task::spawn(async move {
+                    let client_id = conn.client_id();
+                    let peer_addr = conn.peer_addr();
+                    let outcome = conn.serve().await.expect("Error serving the connection");
+                    assert_eq!(outcome.client_id, client_id);
+                    assert_eq!(outcome.peer_addr, Some(peer_addr));
+                    outcome_tx.send(outcome).unwrap();
+                });
+            }
+        });
+
+        let client = Client::dial(addr).await.expect("Error dialing server");
+        rpc::test_get_magic_u32(&client).await;
+
+        let rejected = Client::dial(addr).await.expect("Error dialing server");
+        let call = rejected.call::<_, u32>("CommonTest.get_magic_u32", ());
+        let result = tokio::time::timeout(Duration::from_secs(5), call).await;
+        assert!(
+            !matches!(result, Ok(Ok(_))),
+            "Rejected connection is served"
+        );
+
+        client.close().await;
+        let outcome = outcome_rx
+            .recv_async()
+            .await
+            .expect("Connection is not served");
+        assert!(outcome.error.is_none(), "{:?}", outcome.error);
+
+        accept_loop.abort();
+    }
+}
+
+mod serve_one {
+    //! Serving exactly one connection with `Server::serve_one`
+
+    use std::{io::ErrorKind, sync::Arc, time::Duration};
+    use toy_rpc::{Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    #[tokio::test]
+    async fn serve_one_tcp_connection() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (listener, addr) = common::bind().await;
+
+        let client = async {
+            let client = Client::dial(addr).await.expect("Error dialing server");
+            rpc::test_get_magic_u32(&client).await;
+            rpc::test_execution_error(&client).await;
+            rpc::test_invalid_argument(&client).await;
+            let last_activity = client.last_activity().expect("No frame on the connection");
+            assert!(client.stats().liveness.last_frame_sent_at <= Some(last_activity));
+            client.close().await;
+        };
+        let (outcome, _) = futures::join!(
+            server.serve_one(listener, Some(Duration::from_secs(5))),
+            client
+        );
+        let outcome = outcome.expect("Error serving the connection");
+        assert!(outcome.error.is_none(), "{:?}", outcome.error);
+        assert!(outcome.peer_addr.is_some());
+        assert!(outcome.stats.total.num_items >= 2);
+        assert!(outcome.stats.liveness.last_frame_received_at.is_some());
+        assert!(outcome.stats.liveness.last_frame_sent_at.is_some());
+
+        // nothing about the connection is left behind
+        assert!(server.connections().is_empty());
+        assert_eq!(server.num_in_flight(), 0);
+    }
+
+    #[cfg(feature = "ws_tokio")]
+    #[tokio::test]
+    async fn serve_one_websocket_connection() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (listener, addr) = common::bind().await;
+
+        let client = async {
+            let url = format!("ws://{}", addr);
+            let client = Client::dial_websocket(&url)
+                .await
+                .expect("Error dialing server");
+            rpc::test_get_magic_str(&client).await;
+            client.close().await;
+        };
+        let (outcome, _) = futures::join!(
+            server.serve_one_ws(listener, Some(Duration::from_secs(5))),
+            client
+        );
+        let outcome = outcome.expect("Error serving the connection");
+        assert!(outcome.error.is_none(), "{:?}", outcome.error);
+        assert!(server.connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn serve_one_times_out_without_client() {
+        let server = Server::builder().build();
+        let (listener, _) = common::bind().await;
+
+        match server
+            .serve_one(listener, Some(Duration::from_millis(100)))
+            .await
+        {
+            Err(Error::IoError(err)) => assert_eq!(err.kind(), ErrorKind::TimedOut),
+            res => panic!("Expecting a timeout, found {:?}", res),
+        }
+    }
+}
+
+mod graceful_shutdown {
+    //! Graceful shutdown with `Server::accept_with_shutdown`
+
+    use std::{sync::Arc, time::Duration};
+    use tokio::{task, time};
+    use toy_rpc::{Client, Server};
+
+    use super::common::{self, Sleeper};
+
+    #[tokio::test]
+    async fn graceful_shutdown_drains_requests() {
+        let server = Server::builder().register(Arc::new(Sleeper {})).build();
+        let (listener, addr) = common::bind().await;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server_handle = task::spawn(async move {
+            server
+                .accept_with_shutdown(listener, async {
+                    shutdown_rx.await.ok();
+                })
+                .await
+        });
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let calls: Vec<_> = (0..4)
+            .map(|i| task::spawn(client.call::<_, u64>("Sleeper.sleep", 300 + i)))
+            .collect();
+        // the requests are being handled when the shutdown signal fires
+        time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(()).unwrap();
+
+        // no response is lost
+        for (i, call) in calls.into_iter().enumerate() {
+            let reply = call.await.unwrap().expect("Response is lost");
+            assert_eq!(reply, 300 + i as u64);
+        }
+        time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not shut down")
+            .unwrap()
+            .expect("Error accepting connections");
+
+        // the connection is closed once the responses are written
+        let result = time::timeout(
+            Duration::from_secs(5),
+            client.call::<_, u64>("Sleeper.sleep", 0u64),
+        )
+        .await;
+        assert!(
+            !matches!(result, Ok(Ok(_))),
+            "Request is read after shutdown"
+        );
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_timeout() {
+        let server = Server::builder()
+            .register(Arc::new(Sleeper {}))
+            .shutdown_timeout(Duration::from_millis(200))
+            .build();
+        let (listener, addr) = common::bind().await;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server_handle = task::spawn(async move {
+            server
+                .accept_with_shutdown(listener, async {
+                    shutdown_rx.await.ok();
+                })
+                .await
+        });
+
+        let client = Client::dial(addr).await.expect("Error dialing");
+        let call = task::spawn(client.call::<_, u64>("Sleeper.sleep", 10_000u64));
+        time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(()).unwrap();
+
+        // the request is canceled after the shutdown timeout
+        let result = time::timeout(Duration::from_secs(5), call)
+            .await
+            .expect("Request is not canceled after the shutdown timeout")
+            .unwrap();
+        assert!(result.is_err());
+        time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("Server did not shut down")
+            .unwrap()
+            .expect("Error accepting connections");
+        client.close().await;
+    }
+}
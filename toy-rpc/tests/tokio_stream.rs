@@ -0,0 +1,163 @@
+//! Handlers send the items of a streaming response before they return, and the
+//! client reads them as a `Stream`. On a bidirectional stream, the handler also reads
+//! the items sent by the client.
+
+use futures::{SinkExt, StreamExt};
+use std::{sync::Arc, time::Duration};
+use toy_rpc::{
+    client::{CallStream, StreamSender},
+    macros::export_impl,
+    server::Context,
+    Client, Error, Server,
+};
+
+mod common;
+
+struct Counter;
+
+#[export_impl]
+impl Counter {
+    #[export_method]
+    async fn count(&self, n: u32) -> Result<(), String> {
+        let ctx = Context::current().ok_or("Missing request context")?;
+        for i in 0..n {
+            ctx.send_item(i).map_err(|err| err.to_string())?;
+            // items that are sent over time arrive as they are sent
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        Ok(())
+    }
+
+    #[export_method]
+    async fn count_then_fail(&self, n: u32) -> Result<(), String> {
+        let ctx = Context::current().ok_or("Missing request context")?;
+        ctx.send_stream(futures::stream::iter(0..n))
+            .await
+            .map_err(|err| err.to_string())?;
+        Err("Out of numbers".into())
+    }
+
+    /// Sends back every item of the client doubled, and the sum once the client is done
+    #[export_method]
+    async fn double(&self, offset: u32) -> Result<(), String> {
+        let ctx = Context::current().ok_or("Missing request context")?;
+        let mut incoming = ctx.incoming::<u32>().ok_or("Not a stream")?;
+        // the stream can only be taken once
+        assert!(ctx.incoming::<u32>().is_none());
+        let mut sum = 0;
+        while let Some(item) = incoming.next().await {
+            let item = item.map_err(|err| err.to_string())?;
+            sum += item;
+            ctx.send_item(item * 2 + offset)
+                .map_err(|err| err.to_string())?;
+        }
+        ctx.send_item(sum).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    #[export_method]
+    async fn count_forever(&self, _: ()) -> Result<(), String> {
+        let ctx = Context::current().ok_or("Missing request context")?;
+        let mut i = 0u32;
+        loop {
+            ctx.send_item(i).map_err(|err| err.to_string())?;
+            i += 1;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn streaming_responses() {
+    let server = Server::builder().register(Arc::new(Counter)).build();
+    let (addr, server_handle) = common::serve(server).await;
+
+    let client = Client::dial(addr).await.expect("Error dialing server");
+
+    let stream: CallStream<u32> = client.call_stream("Counter.count", 100u32);
+    let items: Vec<u32> = stream
+        .map(|item| item.expect("Unexpected error in stream"))
+        .collect()
+        .await;
+    assert_eq!(items, (0..100).collect::<Vec<u32>>());
+
+    // the error returned by the handler is the last item
+    let stream: CallStream<u32> = client.call_stream("Counter.count_then_fail", 3u32);
+    let items: Vec<Result<u32, Error>> = stream.collect().await;
+    assert_eq!(items.len(), 4);
+    for (i, item) in items[..3].iter().enumerate() {
+        assert_eq!(*item.as_ref().unwrap(), i as u32);
+    }
+    match &items[3] {
+        Err(Error::ExecutionError(msg)) => assert_eq!(msg, "Out of numbers"),
+        other => panic!("Expected the handler error, got {:?}", other),
+    }
+
+    // a stream that does not end on its own is canceled
+    let mut stream: CallStream<u32> = client.call_stream("Counter.count_forever", ());
+    let id = stream.id().expect("Missing id of the call");
+    assert_eq!(stream.next().await.unwrap().unwrap(), 0);
+    assert_eq!(stream.next().await.unwrap().unwrap(), 1);
+    stream.cancel();
+    let mut last = None;
+    while let Some(item) = stream.next().await {
+        last = Some(item);
+    }
+    match last {
+        Some(Err(Error::Canceled(canceled))) => assert_eq!(canceled, id),
+        other => panic!("Expected the stream to be canceled, got {:?}", other),
+    }
+
+    // regular calls are not affected
+    let reply: Result<(), Error> = client.call("Counter.count", 0u32).await;
+    assert!(reply.is_ok());
+
+    client.close().await;
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn bidirectional_streams() {
+    let server = Server::builder().register(Arc::new(Counter)).build();
+    let (addr, server_handle) = common::serve(server).await;
+
+    let client = Client::dial(addr).await.expect("Error dialing server");
+
+    // each item of the handler answers an item of the client
+    let (mut tx, mut rx): (StreamSender<u32>, CallStream<u32>) =
+        client.open_stream("Counter.double", 1u32);
+    for i in 0..5u32 {
+        tx.send(i).await.expect("Error sending item");
+        let item = rx.next().await.expect("Stream ended early");
+        assert_eq!(item.expect("Unexpected error in stream"), i * 2 + 1);
+    }
+    tx.close().await.expect("Error closing stream");
+    let rest: Vec<u32> = rx
+        .map(|item| item.expect("Unexpected error in stream"))
+        .collect()
+        .await;
+    assert_eq!(rest, vec![10]);
+
+    // dropping the sender closes the stream of the client
+    let (tx, rx): (StreamSender<u32>, CallStream<u32>) = client.open_stream("Counter.double", 0u32);
+    for i in 1..=3u32 {
+        tx.send_item(i).expect("Error sending item");
+    }
+    drop(tx);
+    let items: Vec<u32> = rx
+        .map(|item| item.expect("Unexpected error in stream"))
+        .collect()
+        .await;
+    assert_eq!(items, vec![2, 4, 6, 6]);
+
+    // a request that is not a stream has no incoming items
+    let stream: CallStream<u32> = client.call_stream("Counter.double", 0u32);
+    let items: Vec<Result<u32, Error>> = stream.collect().await;
+    match items.as_slice() {
+        [Err(Error::ExecutionError(msg))] => assert_eq!(msg, "Not a stream"),
+        other => panic!("Expected the request to be rejected, got {:?}", other),
+    }
+
+    client.close().await;
+    server_handle.abort();
+}
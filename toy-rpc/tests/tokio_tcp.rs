@@ -25,9 +25,31 @@ async fn test_client(addr: &'static str, mut ready: Receiver<()>) -> Result<()>
     rpc::test_get_magic_bool(&client).await;
     rpc::test_get_magic_str(&client).await;
     rpc::test_imcomplete_service_method(&client).await;
+    rpc::test_bad_calls_keep_connection(&client).await;
     rpc::test_service_not_found(&client).await;
     rpc::test_method_not_found(&client).await;
     rpc::test_execution_error(&client).await;
+    rpc::test_borrowed_args(&client).await;
+    rpc::test_invalid_argument(&client).await;
+    rpc::test_cancel_ack(&client).await;
+    rpc::test_concurrent_calls(&client).await;
+    rpc::test_batch(&client).await;
+    rpc::test_call_raw(&client).await;
+    rpc::test_cancellation_handle(&client).await;
+    rpc::test_peer_addr(&client).await;
+
+    let limited_client = Client::builder()
+        .set_max_in_flight(2)
+        .dial(addr)
+        .await
+        .expect("Error dialing server");
+    rpc::test_concurrent_calls(&limited_client).await;
+    rpc::test_cancel_all(&limited_client).await;
+    limited_client.close().await;
+
+    let stats_client = Client::dial(addr).await.expect("Error dialing server");
+    rpc::test_call_stats(&stats_client).await;
+    stats_client.close().await;
 
     println!("Client received all correct RPC result");
     client.close().await;
@@ -70,3 +92,33 @@ fn test_main() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(run(rpc::ADDR));
 }
+
+async fn run_with_shutdown(addr: &'static str) {
+    let common_test_service = Arc::new(rpc::CommonTest::new());
+    let server = Server::builder().register(common_test_service).build();
+    let listener = TcpListener::bind(addr)
+        .await
+        .expect("Cannot bind to address");
+
+    let (shutdown_tx, shutdown_rx) = channel::<()>();
+    let server_handle =
+        task::spawn(async move { server.accept_with_shutdown(listener, shutdown_rx).await });
+
+    let client = Client::dial(addr).await.expect("Error dialing server");
+    rpc::test_get_magic_u32(&client).await;
+
+    shutdown_tx.send(()).expect("Error sending shutdown");
+    // the idle connection is closed by the server, which then returns
+    tokio::time::timeout(std::time::Duration::from_secs(5), server_handle)
+        .await
+        .expect("Server did not shut down")
+        .expect("Error joining server thread")
+        .expect("Error accepting connections");
+    client.close().await;
+}
+
+#[test]
+fn test_accept_with_shutdown() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(run_with_shutdown("127.0.0.1:8081"));
+}
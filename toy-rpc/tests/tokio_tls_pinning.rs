@@ -0,0 +1,74 @@
+//! Certificate pinning on the TLS client
+
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::sync::Arc;
+use tokio::task;
+use toy_rpc::{
+    client::tls::{spki_sha256, TlsOptions},
+    Client, Error, Server,
+};
+
+mod common;
+mod rpc;
+
+const CERT: &[u8] = include_bytes!("certs/self_signed.der");
+const KEY: &[u8] = include_bytes!("certs/self_signed.key.der");
+
+/// Digest of the public key of `CERT`, computed by `openssl`
+const PIN: [u8; 32] = [
+    0x75, 0xf0, 0x39, 0xfe, 0xc1, 0x9a, 0x14, 0x55, 0xfb, 0x41, 0x30, 0x37, 0xc9, 0xaf, 0x76, 0x5e,
+    0x3e, 0x1d, 0xb2, 0xd8, 0x7a, 0xb4, 0xe6, 0xfe, 0xec, 0xe9, 0xf9, 0xa3, 0xec, 0x9e, 0xb5, 0x7f,
+];
+
+#[tokio::test]
+async fn tls_pinning() {
+    assert_eq!(spki_sha256(CERT).unwrap(), PIN);
+    assert!(spki_sha256(&CERT[..100]).is_err());
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![Certificate(CERT.to_vec())], PrivateKey(KEY.to_vec()))
+        .unwrap();
+    let server = Server::builder()
+        .register(Arc::new(rpc::CommonTest::new()))
+        .build();
+    let (listener, addr) = common::bind().await;
+    let server_handle =
+        task::spawn(async move { server.accept_with_tls_config(listener, config).await });
+
+    // the certificate is self-signed and the server name is not checked
+    let config = TlsOptions::new().pin_sha256(&PIN).client_config();
+    let client = Client::dial_with_tls_config(addr, "example.com", config)
+        .await
+        .expect("Error dialing with the correct pin");
+    let reply: u32 = client
+        .call("CommonTest.get_magic_u32", ())
+        .await
+        .expect("Call failed");
+    assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U32);
+    client.close().await;
+
+    let mut wrong_pin = PIN;
+    wrong_pin[0] ^= 0xff;
+    let config = TlsOptions::new().pin_sha256(&wrong_pin).client_config();
+    match Client::dial_with_tls_config(addr, "localhost", config).await {
+        Err(Error::IoError(err)) => {
+            assert!(err.to_string().contains("pin mismatch"), "{}", err)
+        }
+        Err(err) => panic!("Unexpected error {:?}", err),
+        Ok(_) => panic!("Server with a different key is trusted"),
+    }
+
+    // any of the pins can match
+    let config = TlsOptions::new()
+        .pin_sha256(&wrong_pin)
+        .pin_sha256(&PIN)
+        .client_config();
+    let client = Client::dial_with_tls_config(addr, "localhost", config)
+        .await
+        .expect("Error dialing with the second pin");
+    client.close().await;
+
+    server_handle.abort();
+}
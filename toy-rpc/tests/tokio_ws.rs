@@ -5,6 +5,7 @@ use tokio::net::TcpListener;
 use tokio::task;
 use toy_rpc::{Client, Server};
 
+mod common;
 mod rpc;
 
 async fn test_client(base: &'static str, mut ready: Receiver<()>) -> Result<()> {
@@ -31,6 +32,9 @@ async fn test_client(base: &'static str, mut ready: Receiver<()>) -> Result<()>
     rpc::test_service_not_found(&client).await;
     rpc::test_method_not_found(&client).await;
     rpc::test_execution_error(&client).await;
+    rpc::test_invalid_argument(&client).await;
+    rpc::test_cancel_ack(&client).await;
+    rpc::test_peer_addr(&client).await;
 
     println!("Client received all correct RPC result");
     client.close().await;
@@ -72,3 +76,88 @@ fn websocket_with_tokio() {
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(run(rpc::ADDR));
 }
+
+mod ws_headers {
+    //! WebSocket connections with a custom upgrade request
+
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task,
+    };
+    use toy_rpc::{http::Request, Client, Error, Server};
+
+    use super::common;
+    use super::rpc;
+
+    fn request(addr: SocketAddr, token: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder()
+            .uri(format!("ws://{}/_rpc_", addr))
+            .header("X-Client", "toy-rpc");
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        builder.body(()).unwrap()
+    }
+
+    /// Rejects every upgrade request, and reports whether it carried the token
+    async fn gateway(listener: TcpListener) -> bool {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 4096];
+        let mut len = 0;
+        while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf[len..]).await.unwrap();
+            assert!(n > 0, "Upgrade request is incomplete");
+            len += n;
+        }
+        let has_token = String::from_utf8_lossy(&buf[..len]).contains("Bearer expired");
+        stream
+            .write_all(
+                b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        has_token
+    }
+
+    #[tokio::test]
+    async fn ws_headers() {
+        let server = Server::builder()
+            .register(Arc::new(rpc::CommonTest::new()))
+            .build();
+        let (listener, addr) = common::bind().await;
+        let server_handle = task::spawn(async move { server.accept_websocket(listener).await });
+
+        let client = Client::dial_websocket_with_request(request(addr, Some("secret")))
+            .await
+            .expect("Error dialing");
+        let reply: u32 = client
+            .call("CommonTest.get_magic_u32", ())
+            .await
+            .expect("Call failed");
+        assert_eq!(reply, rpc::COMMON_TEST_MAGIC_U32);
+        client.close().await;
+        server_handle.abort();
+
+        // the response of a rejected upgrade is returned to the caller
+        let (listener, gateway_addr) = common::bind().await;
+        let gateway_handle = task::spawn(gateway(listener));
+        let res = Client::dial_websocket_with_request(request(gateway_addr, Some("expired"))).await;
+        match res {
+            Err(Error::WebSocketRejected {
+                status, headers, ..
+            }) => {
+                assert_eq!(status, 401);
+                let challenge = headers
+                    .iter()
+                    .find(|(name, _)| name == "www-authenticate")
+                    .map(|(_, value)| value.as_str());
+                assert_eq!(challenge, Some("Bearer"));
+            }
+            Err(err) => panic!("Unexpected error {:?}", err),
+            Ok(_) => panic!("Upgrade should be rejected"),
+        }
+        assert!(gateway_handle.await.unwrap(), "Token is not sent");
+    }
+}